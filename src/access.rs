@@ -0,0 +1,282 @@
+use anyhow::{anyhow, Result};
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::mem::ManuallyDrop;
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::Path;
+use thinp::io_engine::*;
+
+use crate::failure::{fail, FailureKind};
+
+//------------------------------------------
+
+fn group_name(gid: u32) -> String {
+    let grp = unsafe { libc::getgrgid(gid) };
+    if grp.is_null() {
+        return gid.to_string();
+    }
+    unsafe { CStr::from_ptr((*grp).gr_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Explains how an unprivileged user could get access to the metadata
+fn permission_hint(path: &Path, write: bool, fd_option: &str) -> String {
+    let mut hints = Vec::new();
+
+    if let Ok(md) = std::fs::metadata(path) {
+        let group = group_name(md.gid());
+        let mode = if write { "read-write" } else { "read" };
+        hints.push(format!(
+            "{} access needs membership of the group '{}' owning it",
+            mode, group
+        ));
+
+        if md.file_type().is_block_device() {
+            let kernel_name = std::fs::canonicalize(path)
+                .ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| "dm-*".to_string());
+            hints.push(format!(
+                "a udev rule could grant it, e.g., KERNEL==\"{}\", GROUP=\"{}\", MODE=\"0660\"",
+                kernel_name, group
+            ));
+        }
+    }
+
+    hints.push(format!(
+        "or let a privileged wrapper open it and pass the descriptor with {}",
+        fd_option
+    ));
+    hints.join("; ")
+}
+
+// Opens the file the way the merge would, turning permission errors into
+// actionable ones rather than a bare EACCES halfway through the setup
+pub fn check_access(path: &Path, write: bool, fd_option: &str) -> Result<()> {
+    match OpenOptions::new().read(true).write(write).open(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(anyhow!(
+            "permission denied opening {}: {}",
+            path.display(),
+            permission_hint(path, write, fd_option)
+        )),
+        // left to the usual checks
+        Err(_) => Ok(()),
+    }
+}
+
+//------------------------------------------
+
+// Where the metadata is read from or written to, as given by the caller
+pub enum IoTarget<'a> {
+    Fd(i32),
+    Path(&'a Path),
+}
+
+impl IoTarget<'_> {
+    // The device or file behind the target, however it's named or opened.
+    // Nothing is told for pipes and the like.
+    pub fn identity(&self) -> Option<(u64, u64)> {
+        let meta = match self {
+            IoTarget::Fd(fd) => {
+                // borrowed, as the engine takes the descriptor over later
+                let file = ManuallyDrop::new(unsafe { File::from_raw_fd(*fd) });
+                file.metadata().ok()?
+            }
+            IoTarget::Path(path) => std::fs::metadata(path).ok()?,
+        };
+        if meta.file_type().is_block_device() {
+            Some((0, meta.rdev()))
+        } else if meta.file_type().is_file() {
+            Some((meta.dev(), meta.ino()))
+        } else {
+            None
+        }
+    }
+}
+
+// Refuses to write the metadata being read. The engines take over the
+// descriptors, so one passed as both would also be closed twice.
+pub fn check_distinct_io(input: &IoTarget, output: &IoTarget) -> Result<()> {
+    if let (IoTarget::Fd(i), IoTarget::Fd(o)) = (input, output) {
+        if i == o {
+            return Err(fail(
+                FailureKind::Usage,
+                anyhow!("--input-fd and --output-fd both pass the descriptor {}", i),
+            ));
+        }
+    }
+    match (input.identity(), output.identity()) {
+        (Some(i), Some(o)) if i == o => Err(fail(
+            FailureKind::Usage,
+            anyhow!("the input and the output are the same file or device"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+//------------------------------------------
+
+// An engine over a descriptor opened by a privileged caller, so the merge
+// itself could run unprivileged
+pub struct FdIoEngine {
+    file: File,
+    nr_blocks: u64,
+    writable: bool,
+}
+
+impl FdIoEngine {
+    pub fn new(fd: i32, writable: bool) -> Result<FdIoEngine> {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(anyhow!("bad descriptor {}", fd));
+        }
+        if writable && (flags & libc::O_ACCMODE) == libc::O_RDONLY {
+            return Err(anyhow!("descriptor {} is not opened for writing", fd));
+        }
+
+        // takes over the descriptor, which is closed once the engine is dropped
+        let mut file = unsafe { File::from_raw_fd(fd) };
+
+        // works for block devices as well as regular files
+        let size = file.seek(SeekFrom::End(0))?;
+
+        Ok(FdIoEngine {
+            file,
+            nr_blocks: size / BLOCK_SIZE as u64,
+            writable,
+        })
+    }
+
+    /// Opens a metadata file or device, e.g., with the privileges the tool was
+    /// started with, before they're dropped
+    pub fn open(path: &Path, writable: bool) -> Result<FdIoEngine> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .map_err(|e| anyhow!("unable to open {}: {}", path.display(), e))?;
+        Self::new(file.into_raw_fd(), writable)
+    }
+}
+
+impl IoEngine for FdIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn get_batch_size(&self) -> usize {
+        1
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        1
+    }
+
+    fn read(&self, b: u64) -> io::Result<Block> {
+        if b >= self.nr_blocks {
+            return Err(io::Error::other(format!("block {} out of range", b)));
+        }
+        let blk = Block::new(b);
+        self.file
+            .read_exact_at(blk.get_data(), b * BLOCK_SIZE as u64)?;
+        Ok(blk)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+        Ok(blocks.iter().map(|&b| self.read(b)).collect())
+    }
+
+    fn write(&self, block: &Block) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::other("the descriptor is read-only"));
+        }
+        if block.loc >= self.nr_blocks {
+            return Err(io::Error::other(format!(
+                "block {} out of range",
+                block.loc
+            )));
+        }
+        self.file
+            .write_all_at(block.get_data(), block.loc * BLOCK_SIZE as u64)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write(b)).collect())
+    }
+}
+
+//------------------------------------------
+
+/// The unprivileged user to switch to once the metadata is opened, so a bug
+/// parsing an untrusted image is confined to what the user could do
+#[derive(Clone, Debug)]
+pub struct RunAs {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl RunAs {
+    /// Looks up a user by name or numeric uid, taking its primary group
+    pub fn lookup(user: &str) -> Result<RunAs> {
+        let pw = match user.parse::<u32>() {
+            Ok(uid) => unsafe { libc::getpwuid(uid) },
+            Err(_) => {
+                let name = std::ffi::CString::new(user)
+                    .map_err(|_| anyhow!("invalid user name '{}'", user))?;
+                unsafe { libc::getpwnam(name.as_ptr()) }
+            }
+        };
+        if pw.is_null() {
+            return Err(anyhow!("unknown user '{}'", user));
+        }
+
+        let pw = unsafe { &*pw };
+        Ok(RunAs {
+            name: unsafe { CStr::from_ptr(pw.pw_name) }
+                .to_string_lossy()
+                .into_owned(),
+            uid: pw.pw_uid,
+            gid: pw.pw_gid,
+        })
+    }
+
+    /// Switches the whole process to the user for good. The supplementary
+    /// groups go first, and the uid last, as it takes away the right to
+    /// change the others.
+    pub fn drop_privileges(&self) -> Result<()> {
+        let failed = |what: &str| {
+            anyhow!(
+                "unable to run as {}: {} failed: {}; --run-as requires root",
+                self.name,
+                what,
+                io::Error::last_os_error()
+            )
+        };
+
+        if unsafe { libc::setgroups(1, &self.gid) } != 0 {
+            return Err(failed("setgroups"));
+        }
+        if unsafe { libc::setgid(self.gid) } != 0 {
+            return Err(failed("setgid"));
+        }
+        if unsafe { libc::setuid(self.uid) } != 0 {
+            return Err(failed("setuid"));
+        }
+
+        // a dropped root must not be able to come back
+        if self.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(anyhow!(
+                "unable to run as {}: root privileges could be regained",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------
@@ -1,11 +1,32 @@
+use anyhow::anyhow;
+use clap::builder::PossibleValuesParser;
 use clap::{value_parser, Arg, ArgAction};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
 use thinp::commands::engine::*;
 use thinp::commands::utils::*;
 use thinp::commands::Command;
+use thinp::report::{LogLevel, Report};
 
+use thin_merge::access::*;
+use thin_merge::copy::CopyData;
+use thin_merge::diff::*;
+use thin_merge::discover::*;
+use thin_merge::doctor::*;
+use thin_merge::failure::*;
+use thin_merge::format::*;
+use thin_merge::jobs::*;
+use thin_merge::memory::DiscardIoEngine;
 use thin_merge::merge::*;
+use thin_merge::pool::PoolSnapshot;
+use thin_merge::progress::{ReportFormat, ReportInterval};
+use thin_merge::remap::RemapPolicy;
+use thin_merge::stats::{overlap_stats, ThinOverlapOptions};
+use thin_merge::temp::install_cleanup;
+use thin_merge::verify::*;
+use thin_merge::zeroed::ZeroedBlocks;
 
 //------------------------------------------
 
@@ -17,6 +38,11 @@ impl ThinMergeCommand {
             .next_display_order(None)
             .version(env!("CARGO_PKG_VERSION"))
             .about("Merge an external snapshot with its origin into one device")
+            .subcommand_negates_reqs(true)
+            .args_conflicts_with_subcommands(true)
+            .subcommand(Self::verify_cli())
+            .subcommand(Self::stats_cli())
+            .subcommand(Self::diff_cli())
             // flags
             .arg(
                 Arg::new("METADATA_SNAPSHOT")
@@ -25,20 +51,487 @@ impl ThinMergeCommand {
                     .long("metadata-snap")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("AUTO_RESERVE_METASNAP")
+                    .help("Reserve the metadata snapshot of the live pool if missing, then release it")
+                    .long("auto-reserve-metasnap")
+                    .action(ArgAction::SetTrue)
+                    .requires("METADATA_SNAPSHOT")
+                    .conflicts_with_all(["INPUT_FD", "POOL"]),
+            )
+            .arg(
+                Arg::new("FORCE")
+                    .help("Merge from the metadata device of an active pool regardless")
+                    .long("force")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("QUIET")
+                    .help("Suppress all output but the errors")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("VERBOSE")
+                    .help("Report the merge phases, or the batches too if given twice")
+                    .short('v')
+                    .long("verbose")
+                    .action(ArgAction::Count)
+                    .conflicts_with("QUIET"),
+            )
             .arg(
                 Arg::new("REBASE")
                     .help("Choose rebase instead of merge")
                     .long("rebase")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("EMIT_RESIDUE")
+                    .help("Keep the origin device in the output when rebasing")
+                    .long("emit-residue")
+                    .action(ArgAction::SetTrue)
+                    .requires("REBASE"),
+            )
+            .arg(
+                Arg::new("SORT_LEAVES")
+                    .help("Reorder mapping leaves with unordered key ranges")
+                    .long("sort-leaves")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("ACCEPT_DIVERGED_ORIGIN")
+                    .help("Merge even if the origin was written after the snapshot")
+                    .long("accept-diverged-origin")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("REPAIR_COMPAT_CHECK")
+                    .help("Check the output is structurally fit for thin_repair")
+                    .long("repair-compat-check")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("APPEND")
+                    .help("Add the merged device to the metadata already in the output")
+                    .long("append")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["DRY_RUN", "OUTPUT_FD", "WHAT_CHANGES"]),
+            )
+            .arg(
+                Arg::new("ATOMIC_RENAME")
+                    .help("Merge into a file beside the output, then rename it over the output")
+                    .long("atomic-rename")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["DRY_RUN", "OUTPUT_FD", "WHAT_CHANGES"]),
+            )
+            .arg(
+                Arg::new("AUTO_REPAIR")
+                    .help("Rebuild a damaged input superblock as thin_repair does")
+                    .long("auto-repair")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("METADATA_SNAPSHOT"),
+            )
+            .arg(
+                Arg::new("AUTO_SNAPSHOT")
+                    .help("Check the snapshot against the devices, or list the candidates")
+                    .long("auto-snapshot")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("CHAIN"),
+            )
+            .arg(
+                Arg::new("COPY_DATA")
+                    .help("Merge with an external origin, copying its data into the pool")
+                    .long("copy-data")
+                    .action(ArgAction::SetTrue)
+                    .requires_all(["ORIGIN_DEV", "POOL_DATA_DEV", "SNAPSHOT"])
+                    .conflicts_with_all([
+                        "ORIGIN",
+                        "CHAIN",
+                        "AUTO_SNAPSHOT",
+                        "EMIT_RESIDUE",
+                        "APPEND",
+                        "DATA_BLOCK_SIZE",
+                        "DRY_RUN",
+                        "WHAT_CHANGES",
+                        "METADATA_SNAPSHOT",
+                        "AUTO_RESERVE_METASNAP",
+                    ]),
+            )
+            .arg(
+                Arg::new("KEEP_OTHER_DEVICES")
+                    .help("Copy the devices not taking part in the merge into the output")
+                    .long("keep-other-devices")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("STATS")
+                    .help("Compare the source devices with the merged output")
+                    .long("stats")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("SKIP_BAD_BLOCKS")
+                    .help("Move the nodes failing to write to other output blocks")
+                    .long("skip-bad-blocks")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("DRY_RUN"),
+            )
+            .arg(
+                Arg::new("SKIP_BAD_NODES")
+                    .help("Skip the damaged mapping leaves, losing their mappings")
+                    .long("skip-bad-nodes")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("TIMINGS")
+                    .help("Report how long the reads and the writes waited on each other")
+                    .long("timings")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("VERIFY")
+                    .help("Check the output once written, as thin_check would")
+                    .long("verify")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["DRY_RUN", "WHAT_CHANGES"]),
+            )
+            .arg(
+                Arg::new("DETERMINISTIC")
+                    .help("Zero the unused output blocks, so the same merge writes the same bytes")
+                    .long("deterministic")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("DOCTOR")
+                    .help("Report the capabilities of this host and exit")
+                    .long("doctor")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("DRY_RUN")
+                    .help("Merge without writing the output, reporting the space it would take")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["STATS", "REPAIR_COMPAT_CHECK"]),
+            )
+            .arg(
+                Arg::new("WHAT_CHANGES")
+                    .help("Report the ranges the merge would change in the origin, then exit")
+                    .long("what-changes")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["DRY_RUN", "STATS", "REPAIR_COMPAT_CHECK"]),
+            )
+            .arg(
+                Arg::new("CLAMP_TIMES")
+                    .help("Clamp mapping times to the superblock time")
+                    .long("clamp-times")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("RESET_TIME")
+                    .help("Write every mapping, device and superblock time as 0")
+                    .long("reset-time")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("CLAMP_TIMES"),
+            )
+            .arg(
+                Arg::new("ABORT_ON_STALL")
+                    .help("Abort with an error once a stall is detected")
+                    .long("abort-on-stall")
+                    .action(ArgAction::SetTrue)
+                    .requires("STALL_TIMEOUT"),
+            )
             // options
+            .arg(
+                Arg::new("INPUT_FD")
+                    .help("Read the input metadata from a descriptor opened by the caller")
+                    .long("input-fd")
+                    .value_name("FD")
+                    .value_parser(value_parser!(i32).range(0..))
+                    .conflicts_with("INPUT"),
+            )
+            .arg(
+                Arg::new("OUTPUT_FD")
+                    .help("Write the output metadata to a descriptor opened by the caller")
+                    .long("output-fd")
+                    .value_name("FD")
+                    .value_parser(value_parser!(i32).range(0..))
+                    .conflicts_with("OUTPUT"),
+            )
+            .arg(
+                Arg::new("BASE_BATCH")
+                    .help("Read the given number of origin leaves at a time")
+                    .long("base-batch")
+                    .value_name("LEAVES")
+                    .value_parser(value_parser!(u64).range(1..=65536)),
+            )
+            .arg(
+                Arg::new("BEGIN")
+                    .help("Merge only the thin blocks from the given one")
+                    .long("begin")
+                    .value_name("BLOCK")
+                    .value_parser(value_parser!(u64))
+                    .conflicts_with_all(["COPY_DATA", "WHAT_CHANGES"]),
+            )
+            .arg(
+                Arg::new("BUFFER_LEN")
+                    .help("Hand the runs to the writer in batches of the given number")
+                    .long("buffer-len")
+                    .value_name("RUNS")
+                    .value_parser(value_parser!(u64).range(1..=1048576)),
+            )
+            .arg(
+                Arg::new("CHAIN")
+                    .help("Merge a chain of snapshots, listed from the origin up")
+                    .long("chain")
+                    .value_name("DEV_IDS")
+                    .value_parser(value_parser!(u64))
+                    .value_delimiter(',')
+                    .conflicts_with_all(["ORIGIN", "SNAPSHOT"]),
+            )
+            .arg(
+                Arg::new("CHUNK_BLOCKS")
+                    .help("Checkpoint after every chunk of the given number of thin blocks")
+                    .long("chunk-blocks")
+                    .value_name("BLOCKS")
+                    .value_parser(value_parser!(u64).range(1..)),
+            )
+            .arg(
+                Arg::new("CHUNK_PAUSE")
+                    .help("Sleep for the given milliseconds at every checkpoint")
+                    .long("chunk-pause")
+                    .value_name("MS")
+                    .value_parser(value_parser!(u64))
+                    .requires("CHUNK_BLOCKS"),
+            )
+            .arg(
+                Arg::new("DATA_BLOCK_SIZE")
+                    .help("Rescale the mappings for the given data block size")
+                    .long("data-block-size")
+                    .value_name("SECTORS")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("END")
+                    .help("Merge only the thin blocks before the given one")
+                    .long("end")
+                    .value_name("BLOCK")
+                    .value_parser(value_parser!(u64))
+                    .conflicts_with_all(["COPY_DATA", "WHAT_CHANGES"]),
+            )
+            .arg(
+                Arg::new("ENGINE")
+                    .help("Choose the io engine for the input")
+                    .long("engine")
+                    .value_name("ENGINE")
+                    .value_parser(["sync", "async", "auto"]),
+            )
+            .arg(
+                Arg::new("EXPAND_NR_DATA_BLOCKS")
+                    .help("Pair the output with a data device of the given number of blocks")
+                    .long("expand-nr-data-blocks")
+                    .alias("nr-data-blocks")
+                    .value_name("BLOCKS")
+                    .value_parser(value_parser!(u64).range(1..)),
+            )
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the output format, or by the output file extension")
+                    .long("format")
+                    .alias("output-format")
+                    .value_name("FORMAT")
+                    .value_parser(PossibleValuesParser::new(REGISTRY.writable_names())),
+            )
+            .arg(
+                Arg::new("OUTPUT_ENGINE")
+                    .help("Choose the io engine for the output")
+                    .long("output-engine")
+                    .value_name("ENGINE")
+                    .value_parser(["sync", "async", "auto"])
+                    .default_value("auto"),
+            )
+            .arg(
+                Arg::new("NEW_DEV_ID")
+                    .help("Write the merged device under the given identifier")
+                    .long("new-dev-id")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64).range(0..(1 << 24))),
+            )
+            .arg(
+                Arg::new("JOBS")
+                    .help("Run the merges listed in the given job file")
+                    .long("jobs")
+                    .value_name("FILE")
+                    .conflicts_with_all(["INPUT", "INPUT_FD", "OUTPUT", "OUTPUT_FD"]),
+            )
+            .arg(
+                Arg::new("LIST_SNAPSHOTS_OF")
+                    .help("List the devices sharing mappings with the given origin and exit")
+                    .long("list-snapshots-of")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64))
+                    .conflicts_with("INPUT_FD"),
+            )
+            .arg(
+                Arg::new("MAX_MEM")
+                    .help("Bound the estimated memory use to the given MiB, reading fewer leaves at a time to fit")
+                    .long("max-mem")
+                    .alias("max-memory")
+                    .value_name("MIB")
+                    .value_parser(value_parser!(u64).range(1..)),
+            )
             .arg(
                 Arg::new("ORIGIN")
-                    .help("The numeric identifier for the external origin")
+                    .help("The numeric identifier for the external origin, or none if lost")
                     .long("origin")
                     .value_name("DEV_ID")
-                    .value_parser(value_parser!(u64))
-                    .required(true),
+                    .value_parser(parse_origin)
+                    .required_unless_present_any([
+                        "DOCTOR",
+                        "CHAIN",
+                        "COPY_DATA",
+                        "JOBS",
+                        "LIST_SNAPSHOTS_OF",
+                    ]),
+            )
+            .arg(
+                Arg::new("ORIGIN_DEV")
+                    .help("The external origin device to copy the data from")
+                    .long("origin-dev")
+                    .value_name("DEV")
+                    .requires("COPY_DATA"),
+            )
+            .arg(
+                Arg::new("OUTPUT_VERSION")
+                    .help("Write the output in the given metadata version rather than that of the input")
+                    .long("output-version")
+                    .value_name("VERSION")
+                    .value_parser(value_parser!(u32).range(1..=2)),
+            )
+            .arg(
+                Arg::new("TRANSACTION_ID")
+                    .help("Write the given transaction id to the output superblock")
+                    .long("transaction-id")
+                    .value_name("N")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("UUID")
+                    .help("Write the given uuid, up to 16 bytes, to the output superblock")
+                    .long("uuid")
+                    .value_name("UUID")
+                    .conflicts_with("PRESERVE_UUID"),
+            )
+            .arg(
+                Arg::new("PRESERVE_UUID")
+                    .help("Keep the uuid of the input superblock in the output")
+                    .long("preserve-uuid")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("POOL")
+                    .help("Merge from the metadata snapshot of the given live pool")
+                    .long("pool")
+                    .value_name("DM_NAME")
+                    .conflicts_with_all([
+                        "INPUT",
+                        "INPUT_FD",
+                        "AUTO_REPAIR",
+                        "JOBS",
+                        "LIST_SNAPSHOTS_OF",
+                    ]),
+            )
+            .arg(
+                Arg::new("POOL_DATA_DEV")
+                    .help("The data device of the pool to copy the origin data into")
+                    .long("pool-data-dev")
+                    .value_name("DEV")
+                    .requires("COPY_DATA"),
+            )
+            .arg(
+                Arg::new("PREFETCH")
+                    .help("Read the given number of leaf batches ahead of the merge, per tree")
+                    .long("prefetch")
+                    .value_name("BATCHES")
+                    .value_parser(value_parser!(u64).range(1..=64)),
+            )
+            .arg(
+                Arg::new("DUMP_THREADS")
+                    .help("Dump the given number of kept devices at a time, beside the merge")
+                    .long("dump-threads")
+                    .value_name("THREADS")
+                    .value_parser(value_parser!(u64).range(1..=64))
+                    .requires("KEEP_OTHER_DEVICES"),
+            )
+            .arg(
+                Arg::new("QUEUE_DEPTH")
+                    .help("Queue the given number of run batches for the writer")
+                    .long("queue-depth")
+                    .value_name("BATCHES")
+                    .value_parser(value_parser!(u64).range(1..=1024)),
+            )
+            .arg(
+                Arg::new("REMAP_DATA")
+                    .help("Move the data blocks for another pool, by offset:BLOCKS or free-list:FILE")
+                    .long("remap-data")
+                    .value_name("POLICY")
+                    .conflicts_with_all(["COPY_DATA", "WHAT_CHANGES"]),
+            )
+            .arg(
+                Arg::new("REMAP_TABLE")
+                    .help("Write where the data blocks were moved to the given file")
+                    .long("remap-table")
+                    .value_name("FILE")
+                    .requires("REMAP_DATA"),
+            )
+            .arg(
+                Arg::new("REPORT_FD")
+                    .help("Write the json progress events to the given descriptor")
+                    .long("report-fd")
+                    .value_name("FD")
+                    .value_parser(value_parser!(i32).range(0..)),
+            )
+            .arg(
+                Arg::new("REPORT_INTERVAL")
+                    .help("Update the progress every given seconds, or blocks if suffixed by blocks")
+                    .long("report-interval")
+                    .value_name("INTERVAL"),
+            )
+            .arg(
+                Arg::new("REPORT_FORMAT")
+                    .help("Choose json for machine-readable progress events")
+                    .long("report-format")
+                    .value_name("FORMAT")
+                    .value_parser(["text", "json"])
+                    .default_value("text"),
+            )
+            .arg(
+                Arg::new("RUN_AS")
+                    .help("Switch to the given user once the metadata is opened")
+                    .long("run-as")
+                    .value_name("USER"),
+            )
+            .arg(
+                Arg::new("SINCE_TIME")
+                    .help("Merge only the snapshot mappings of the given time or newer")
+                    .long("since-time")
+                    .value_name("TIME")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("SKIP_ZEROED")
+                    .help("Drop the mappings to the data blocks listed as zeroed in the file")
+                    .long("skip-zeroed")
+                    .value_name("FILE")
+                    .conflicts_with("WHAT_CHANGES"),
+            )
+            .arg(
+                Arg::new("SNAP_BATCH")
+                    .help("Read the given number of snapshot leaves at a time")
+                    .long("snap-batch")
+                    .value_name("LEAVES")
+                    .value_parser(value_parser!(u64).range(1..=65536)),
             )
             .arg(
                 Arg::new("SNAPSHOT")
@@ -47,26 +540,342 @@ impl ThinMergeCommand {
                     .value_name("DEV_ID")
                     .value_parser(value_parser!(u64)),
             )
+            .arg(
+                Arg::new("STALL_TIMEOUT")
+                    .help("Warn about stages making no progress for the given seconds")
+                    .long("stall-timeout")
+                    .value_name("SECS")
+                    .value_parser(value_parser!(u64).range(1..)),
+            )
+            .arg(
+                Arg::new("SUMMARY_FILE")
+                    .help("Write the json summary of the merge to the given file, or - for stdout")
+                    .long("summary-file")
+                    .value_name("FILE")
+                    .conflicts_with("WHAT_CHANGES"),
+            )
+            .arg(
+                Arg::new("TRACE_MERGES")
+                    .help("Log every decision of the merge to the given file")
+                    .long("trace-merges")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("WRITE_BATCH")
+                    .help("Write the given number of metadata blocks at a time")
+                    .long("write-batch")
+                    .value_name("BLOCKS")
+                    .value_parser(value_parser!(u64).range(1..=4096)),
+            )
             // arguments
             .arg(
                 Arg::new("INPUT")
-                    .help("Specify the input metadata")
+                    .help("Specify the input metadata, or - for stdin")
                     .short('i')
                     .long("input")
                     .value_name("FILE")
-                    .required(true),
+                    .required_unless_present_any(["DOCTOR", "INPUT_FD", "JOBS", "POOL"]),
             )
             .arg(
                 Arg::new("OUTPUT")
-                    .help("Specify the output metadata")
+                    .help("Specify the output metadata, or - for stdout")
                     .short('o')
                     .long("output")
                     .value_name("FILE")
+                    .required_unless_present_any([
+                        "DOCTOR",
+                        "DRY_RUN",
+                        "JOBS",
+                        "LIST_SNAPSHOTS_OF",
+                        "OUTPUT_FD",
+                        "WHAT_CHANGES",
+                    ]),
+            );
+
+        engine_args(cmd)
+    }
+
+    fn verify_cli() -> clap::Command {
+        let cmd = clap::Command::new("verify")
+            .next_display_order(None)
+            .about("Verify a previously merged output against its input metadata")
+            // flags
+            .arg(
+                Arg::new("METADATA_SNAPSHOT")
+                    .help("Use metadata snapshot of the input")
+                    .short('m')
+                    .long("metadata-snap")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("REBASE")
+                    .help("The output was rebased instead of merged")
+                    .long("rebase")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("SORT_LEAVES")
+                    .help("Reorder mapping leaves with unordered key ranges")
+                    .long("sort-leaves")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            .arg(
+                Arg::new("ORIGIN")
+                    .help("The numeric identifier for the external origin")
+                    .long("origin")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64))
+                    .required(true),
+            )
+            .arg(
+                Arg::new("SNAPSHOT")
+                    .help("The numeric identifier for the external snapshot")
+                    .long("snapshot")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64)),
+            )
+            // arguments
+            .arg(
+                Arg::new("BEFORE")
+                    .help("Specify the input metadata of the merge")
+                    .long("before")
+                    .value_name("FILE")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("AFTER")
+                    .help("Specify the output metadata of the merge")
+                    .long("after")
+                    .value_name("FILE")
                     .required(true),
             );
 
         engine_args(cmd)
     }
+
+    // The arguments shared by the subcommands comparing a snapshot with its
+    // origin
+    fn device_pair_cli(name: &'static str, about: &'static str) -> clap::Command {
+        clap::Command::new(name)
+            .next_display_order(None)
+            .about(about)
+            // flags
+            .arg(
+                Arg::new("METADATA_SNAPSHOT")
+                    .help("Use metadata snapshot")
+                    .short('m')
+                    .long("metadata-snap")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("SORT_LEAVES")
+                    .help("Reorder mapping leaves with unordered key ranges")
+                    .long("sort-leaves")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            .arg(
+                Arg::new("ORIGIN")
+                    .help("The numeric identifier for the external origin")
+                    .long("origin")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64))
+                    .required(true),
+            )
+            .arg(
+                Arg::new("SNAPSHOT")
+                    .help("The numeric identifier for the external snapshot")
+                    .long("snapshot")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64))
+                    .required(true),
+            )
+            // arguments
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the input metadata")
+                    .short('i')
+                    .long("input")
+                    .value_name("FILE")
+                    .required(true),
+            )
+    }
+
+    fn stats_cli() -> clap::Command {
+        let cmd = Self::device_pair_cli(
+            "stats",
+            "Report how a snapshot overlaps its origin, without merging",
+        );
+
+        engine_args(cmd)
+    }
+
+    fn diff_cli() -> clap::Command {
+        let cmd = Self::device_pair_cli(
+            "diff",
+            "List the ranges where a snapshot differs from its origin, as thin_delta does",
+        )
+        .arg(
+            Arg::new("SINCE_TIME")
+                .help("List only the changes of the given time or newer")
+                .long("since-time")
+                .value_name("TIME")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("FORMAT")
+                .help("Choose the format of the ranges")
+                .long("format")
+                .value_name("FORMAT")
+                .value_parser(PossibleValuesParser::new(["xml", "json"]))
+                .default_value("xml"),
+        )
+        .arg(
+            Arg::new("OUTPUT")
+                .help("Write the ranges to the given file rather than stdout")
+                .short('o')
+                .long("output")
+                .value_name("FILE"),
+        );
+
+        engine_args(cmd)
+    }
+
+    // Checks the input and parses the engine options of the subcommands
+    // comparing a snapshot with its origin, or gives the exit code of the
+    // failure
+    fn device_pair_args<'m>(
+        matches: &'m clap::ArgMatches,
+        report: &Arc<Report>,
+    ) -> std::result::Result<(&'m Path, EngineOptions), exitcode::ExitCode> {
+        let input = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        if let Err(e) = check_input(input) {
+            return Err(exit_code::<()>(report, Err(e)));
+        }
+
+        match parse_engine_opts(ToolType::Thin, matches) {
+            Ok(engine_opts) => Ok((input, engine_opts)),
+            Err(e) => Err(exit_code::<()>(report, Err(e))),
+        }
+    }
+
+    fn run_diff(&self, matches: &clap::ArgMatches) -> exitcode::ExitCode {
+        let output = matches.get_one::<String>("OUTPUT").map(Path::new);
+
+        let report = mk_report(false);
+        let (input, engine_opts) = match Self::device_pair_args(matches, &report) {
+            Ok(args) => args,
+            Err(code) => return code,
+        };
+
+        let opts = ThinDiffOptions {
+            input,
+            output,
+            engine_opts,
+            report: report.clone(),
+            origin: *matches.get_one::<u64>("ORIGIN").unwrap(),
+            snapshot: *matches.get_one::<u64>("SNAPSHOT").unwrap(),
+            format: DiffFormat::from_name(matches.get_one::<String>("FORMAT").unwrap()).unwrap(),
+            sort_leaves: matches.get_flag("SORT_LEAVES"),
+            since_time: matches.get_one::<u32>("SINCE_TIME").cloned(),
+        };
+
+        exit_code(&report, diff_devices(opts))
+    }
+
+    fn run_stats(&self, matches: &clap::ArgMatches) -> exitcode::ExitCode {
+        let report = mk_report(false);
+        let (input, engine_opts) = match Self::device_pair_args(matches, &report) {
+            Ok(args) => args,
+            Err(code) => return code,
+        };
+
+        let opts = ThinOverlapOptions {
+            input,
+            engine_opts,
+            report: report.clone(),
+            origin: *matches.get_one::<u64>("ORIGIN").unwrap(),
+            snapshot: *matches.get_one::<u64>("SNAPSHOT").unwrap(),
+            sort_leaves: matches.get_flag("SORT_LEAVES"),
+        };
+
+        exit_code(&report, overlap_stats(opts).map(|_| ()))
+    }
+
+    fn run_verify(&self, matches: &clap::ArgMatches) -> exitcode::ExitCode {
+        let before = Path::new(matches.get_one::<String>("BEFORE").unwrap());
+        let after = Path::new(matches.get_one::<String>("AFTER").unwrap());
+
+        let report = mk_report(false);
+
+        for f in [before, after] {
+            if let Err(e) = check_input(f) {
+                return exit_code::<()>(&report, Err(e));
+            }
+        }
+
+        let engine_opts = parse_engine_opts(ToolType::Thin, matches);
+        if engine_opts.is_err() {
+            return exit_code(&report, engine_opts);
+        }
+
+        let opts = ThinVerifyOptions {
+            before,
+            after,
+            engine_opts: engine_opts.unwrap(),
+            report: report.clone(),
+            origin: *matches.get_one::<u64>("ORIGIN").unwrap(),
+            snapshot: matches.get_one::<u64>("SNAPSHOT").cloned(),
+            rebase: matches.get_flag("REBASE"),
+            sort_leaves: matches.get_flag("SORT_LEAVES"),
+        };
+
+        exit_code(&report, verify_merge(opts))
+    }
+
+    fn run_list_snapshots(&self, matches: &clap::ArgMatches, origin: u64) -> exitcode::ExitCode {
+        let input = Path::new(matches.get_one::<String>("INPUT").unwrap());
+
+        let report = mk_leveled_report(matches);
+
+        if let Err(e) = check_input(input) {
+            return exit_code::<()>(&report, Err(e));
+        }
+
+        let engine_opts = parse_engine_opts(ToolType::Thin, matches);
+        if engine_opts.is_err() {
+            return exit_code(&report, engine_opts);
+        }
+
+        let opts = ListSnapshotsOptions {
+            input,
+            engine_opts: engine_opts.unwrap(),
+            report: report.clone(),
+            origin,
+        };
+
+        exit_code(&report, list_snapshots(opts))
+    }
+
+    fn run_jobs(&self, matches: &clap::ArgMatches, path: &Path) -> exitcode::ExitCode {
+        let report = mk_leveled_report(matches);
+
+        let jobs = match JobFile::from_file(path) {
+            Ok(jobs) => jobs,
+            Err(e) => return exit_code::<()>(&report, Err(e)),
+        };
+
+        let engine_opts = parse_engine_opts(ToolType::Thin, matches);
+        if engine_opts.is_err() {
+            return exit_code(&report, engine_opts);
+        }
+
+        exit_code(
+            &report,
+            run_jobs(&jobs, &engine_opts.unwrap(), report.clone()),
+        )
+    }
 }
 
 impl<'a> Command<'a> for ThinMergeCommand {
@@ -77,42 +886,422 @@ impl<'a> Command<'a> for ThinMergeCommand {
     fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
         let matches = self.cli().get_matches_from(args);
 
-        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
-        let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
+        if let Some(("verify", sub_matches)) = matches.subcommand() {
+            return self.run_verify(sub_matches);
+        }
+        if let Some(("stats", sub_matches)) = matches.subcommand() {
+            return self.run_stats(sub_matches);
+        }
+        if let Some(("diff", sub_matches)) = matches.subcommand() {
+            return self.run_diff(sub_matches);
+        }
 
-        let report = mk_report(false);
+        if matches.get_flag("DOCTOR") {
+            let input = matches.get_one::<String>("INPUT").map(Path::new);
+            let output = matches.get_one::<String>("OUTPUT").map(Path::new);
+            let report = mk_report(false);
+            return exit_code(&report, run_doctor(input, output, report.clone()));
+        }
+
+        if let Some(&origin) = matches.get_one::<u64>("LIST_SNAPSHOTS_OF") {
+            return self.run_list_snapshots(&matches, origin);
+        }
+
+        if let Some(path) = matches.get_one::<String>("JOBS") {
+            return self.run_jobs(&matches, Path::new(path));
+        }
+
+        let report = mk_leveled_report(&matches);
+
+        // the metadata snapshot of a live pool is held until the merge is over
+        let pool = if let Some(name) = matches.get_one::<String>("POOL") {
+            PoolSnapshot::reserve(name, report.clone()).map(Some)
+        } else if matches.get_flag("AUTO_RESERVE_METASNAP") {
+            let input = Path::new(matches.get_one::<String>("INPUT").unwrap());
+            PoolSnapshot::reserve_if_missing(input, report.clone())
+        } else {
+            Ok(None)
+        };
+        let pool = match pool {
+            Ok(pool) => pool,
+            Err(e) => return exit_code::<()>(&report, Err(e)),
+        };
+
+        let input_target = match (matches.get_one::<i32>("INPUT_FD"), &pool) {
+            (Some(&fd), _) => IoTarget::Fd(fd),
+            (None, Some(pool)) => IoTarget::Path(pool.metadata_dev()),
+            (None, None) => IoTarget::Path(Path::new(std_stream(
+                matches.get_one::<String>("INPUT").unwrap(),
+                STDIN_PATH,
+            ))),
+        };
+        let output_target = match (
+            matches.get_one::<i32>("OUTPUT_FD"),
+            matches.get_one::<String>("OUTPUT"),
+        ) {
+            (Some(&fd), _) => Some(IoTarget::Fd(fd)),
+            (None, Some(arg)) => Some(IoTarget::Path(Path::new(arg))),
+            (None, None) => None,
+        };
+        // nothing is written when reporting the changes, or in a dry run
+        let writes = !matches.get_flag("DRY_RUN") && !matches.get_flag("WHAT_CHANGES");
+        if let (true, Some(output_target)) = (writes, &output_target) {
+            if let Err(e) = check_distinct_io(&input_target, output_target) {
+                return exit_code::<()>(&report, Err(e));
+            }
+        }
+
+        // descriptors passed by a privileged wrapper are taken as binary metadata
+        let input = match (matches.get_one::<i32>("INPUT_FD"), &pool) {
+            (Some(&fd), _) => match FdIoEngine::new(fd, false) {
+                Ok(engine) => MetadataLocation::Engine(Arc::new(engine)),
+                Err(e) => return exit_code::<()>(&report, Err(e)),
+            },
+            (None, Some(pool)) => MetadataLocation::Path(pool.metadata_dev()),
+            (None, None) => MetadataLocation::from_arg(std_stream(
+                matches.get_one::<String>("INPUT").unwrap(),
+                STDIN_PATH,
+            )),
+        };
+        let what_changes = matches.get_flag("WHAT_CHANGES");
+        let output = match (
+            matches.get_one::<i32>("OUTPUT_FD"),
+            matches.get_one::<String>("OUTPUT"),
+        ) {
+            (Some(&fd), _) => match FdIoEngine::new(fd, true) {
+                Ok(engine) => MetadataLocation::Engine(Arc::new(engine)),
+                Err(e) => return exit_code::<()>(&report, Err(e)),
+            },
+            (None, Some(arg)) => MetadataLocation::from_arg(arg),
+            // nothing is written when reporting the changes, or in a dry run
+            (None, None) => MetadataLocation::Engine(Arc::new(DiscardIoEngine::new(0))),
+        };
 
-        if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
-            return to_exit_code::<()>(&report, Err(e));
+        if let MetadataLocation::Path(input_file) = input {
+            // xml or compressed input could be smaller than a metadata block, and xml
+            // could come from a pipe
+            let r = match is_stream(input_file) {
+                Ok(true) => Ok(input_file),
+                _ => check_input_file(input_file)
+                    .map_err(|e| fail(FailureKind::InputNotFound, e))
+                    .and_then(|f| {
+                        check_access(f, false, "--input-fd")?;
+                        match REGISTRY.sniff(f)? {
+                            Some(MetadataFormat::Xml | MetadataFormat::Zstd) => Ok(f),
+                            _ => check_file_not_tiny(f)
+                                .map_err(|e| fail(FailureKind::CorruptMetadata, e)),
+                        }
+                    }),
+            };
+            if let Err(e) = r {
+                return exit_code::<()>(&report, Err(e));
+            }
+        }
+
+        let dry_run = matches.get_flag("DRY_RUN");
+
+        // opening a pipe would block until the reader shows up
+        if let MetadataLocation::Path(output_file) = output {
+            if !dry_run
+                && !what_changes
+                && !is_stdout(output_file)
+                && !is_stream(output_file).unwrap_or(false)
+            {
+                if let Err(e) = check_access(output_file, true, "--output-fd") {
+                    return exit_code::<()>(&report, Err(e));
+                }
+            }
         }
 
         let engine_opts = parse_engine_opts(ToolType::Thin, &matches);
         if engine_opts.is_err() {
-            return to_exit_code(&report, engine_opts);
+            return exit_code(&report, engine_opts);
         }
 
-        let origin = *matches.get_one::<u64>("ORIGIN").unwrap();
-        let snapshot = matches.get_one::<u64>("SNAPSHOT").cloned();
+        let mut engine_opts = engine_opts.unwrap();
+        engine_opts.use_metadata_snap |= pool.is_some();
+
+        // the input follows --async-io unless specified
+        let input_engine = match matches.get_one::<String>("ENGINE") {
+            Some(name) => parse_engine_choice(name),
+            None if engine_opts.engine_type == EngineType::Async => EngineChoice::Async,
+            None => EngineChoice::Sync,
+        };
+        let output_engine =
+            parse_engine_choice(matches.get_one::<String>("OUTPUT_ENGINE").unwrap());
+
+        // the chain starts from the origin and ends at the topmost snapshot
+        let (origin, snapshot, intermediates) = match matches.get_many::<u64>("CHAIN") {
+            Some(ids) => {
+                let mut ids: Vec<u64> = ids.cloned().collect();
+                if ids.len() < 2 {
+                    return exit_code::<()>(
+                        &report,
+                        Err(fail(
+                            FailureKind::Usage,
+                            anyhow!("--chain requires at least two devices"),
+                        )),
+                    );
+                }
+                let snapshot = ids.pop();
+                let origin = ids.remove(0);
+                (Some(origin), snapshot, ids)
+            }
+            None => (
+                matches.get_one::<Option<u64>>("ORIGIN").cloned().flatten(),
+                matches.get_one::<u64>("SNAPSHOT").cloned(),
+                Vec::new(),
+            ),
+        };
+        let since_time = matches.get_one::<u32>("SINCE_TIME").cloned();
         let rebase = matches.get_flag("REBASE");
+        let emit_residue = matches.get_flag("EMIT_RESIDUE");
+        // the standard output defaults to xml, even if redirected to a file
+        let to_stdout = matches.get_one::<String>("OUTPUT").map(String::as_str) == Some("-");
+        let output_format = matches
+            .get_one::<String>("FORMAT")
+            .map(|name| REGISTRY.from_name(name).unwrap())
+            .or(to_stdout.then_some(MetadataFormat::Xml));
+        let sort_leaves = matches.get_flag("SORT_LEAVES");
+        let accept_diverged_origin = matches.get_flag("ACCEPT_DIVERGED_ORIGIN");
+        let clamp_times = matches.get_flag("CLAMP_TIMES");
+        let reset_time = matches.get_flag("RESET_TIME");
+        let stall_timeout = matches.get_one::<u64>("STALL_TIMEOUT").cloned();
+        let abort_on_stall = matches.get_flag("ABORT_ON_STALL");
+        let stats = matches.get_flag("STATS");
+        let keep_other_devices = matches.get_flag("KEEP_OTHER_DEVICES");
+        let auto_snapshot = matches.get_flag("AUTO_SNAPSHOT");
+        let append = matches.get_flag("APPEND");
+        let timings = matches.get_flag("TIMINGS");
+        let skip_bad_blocks = matches.get_flag("SKIP_BAD_BLOCKS");
+        let skip_bad_nodes = matches.get_flag("SKIP_BAD_NODES");
+        let auto_repair = matches.get_flag("AUTO_REPAIR");
+        let deterministic = matches.get_flag("DETERMINISTIC");
+        let force = matches.get_flag("FORCE");
+        let trace_merges = matches.get_one::<String>("TRACE_MERGES").map(Path::new);
+        let new_dev_id = matches.get_one::<u64>("NEW_DEV_ID").cloned();
+        let run_as = match matches.get_one::<String>("RUN_AS") {
+            Some(user) => match RunAs::lookup(user) {
+                Ok(user) => Some(user),
+                Err(e) => return exit_code::<()>(&report, Err(e)),
+            },
+            None => None,
+        };
+        let base_batch = matches.get_one::<u64>("BASE_BATCH").map(|&n| n as usize);
+        let snap_batch = matches.get_one::<u64>("SNAP_BATCH").map(|&n| n as usize);
+        let prefetch = matches.get_one::<u64>("PREFETCH").map(|&n| n as usize);
+        let dump_threads = matches.get_one::<u64>("DUMP_THREADS").map(|&n| n as usize);
+        let queue_depth = matches.get_one::<u64>("QUEUE_DEPTH").map(|&n| n as usize);
+        let buffer_len = matches.get_one::<u64>("BUFFER_LEN").map(|&n| n as usize);
+        let write_batch = matches.get_one::<u64>("WRITE_BATCH").map(|&n| n as usize);
+        let nr_data_blocks = matches.get_one::<u64>("EXPAND_NR_DATA_BLOCKS").cloned();
+        let data_block_size = matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned();
+        let chunk_blocks = matches.get_one::<u64>("CHUNK_BLOCKS").cloned();
+        let chunk_pause = matches
+            .get_one::<u64>("CHUNK_PAUSE")
+            .map(|&ms| Duration::from_millis(ms));
+        let zeroed = match matches.get_one::<String>("SKIP_ZEROED") {
+            Some(list) => match ZeroedBlocks::from_file(Path::new(list)) {
+                Ok(zeroed) => Some(Arc::new(zeroed)),
+                Err(e) => return exit_code::<()>(&report, Err(e)),
+            },
+            None => None,
+        };
+        let copy_data = if matches.get_flag("COPY_DATA") {
+            Some(CopyData {
+                origin_dev: PathBuf::from(matches.get_one::<String>("ORIGIN_DEV").unwrap()),
+                pool_data_dev: PathBuf::from(matches.get_one::<String>("POOL_DATA_DEV").unwrap()),
+            })
+        } else {
+            None
+        };
+        let remap = match matches.get_one::<String>("REMAP_DATA") {
+            Some(policy) => match RemapPolicy::from_arg(policy) {
+                Ok(policy) => Some(policy),
+                Err(e) => return exit_code::<()>(&report, Err(e)),
+            },
+            None => None,
+        };
+        let remap_table = matches.get_one::<String>("REMAP_TABLE").map(Path::new);
+        let key_range = match (
+            matches.get_one::<u64>("BEGIN"),
+            matches.get_one::<u64>("END"),
+        ) {
+            (None, None) => None,
+            (begin, end) => Some((*begin.unwrap_or(&0), *end.unwrap_or(&u64::MAX))),
+        };
+        let repair_compat_check = matches.get_flag("REPAIR_COMPAT_CHECK");
+        let report_format = match matches.get_one::<String>("REPORT_FORMAT").unwrap().as_str() {
+            "json" => ReportFormat::Json,
+            _ => ReportFormat::Text,
+        };
+        let progress_fd = matches.get_one::<i32>("REPORT_FD").cloned();
+        let report_interval = match matches.get_one::<String>("REPORT_INTERVAL") {
+            Some(arg) => match ReportInterval::from_arg(arg) {
+                Ok(interval) => Some(interval),
+                Err(e) => return exit_code::<()>(&report, Err(e)),
+            },
+            None => None,
+        };
+        let max_mem = matches
+            .get_one::<u64>("MAX_MEM")
+            .map(|mib| mib * 1024 * 1024);
+        let quiet = matches.get_flag("QUIET");
+        let verbose = matches.get_count("VERBOSE");
+        let summary_file = matches.get_one::<String>("SUMMARY_FILE").map(Path::new);
+        if to_stdout && summary_file == Some(Path::new("-")) {
+            return exit_code::<()>(
+                &report,
+                Err(fail(
+                    FailureKind::Usage,
+                    anyhow!("the output and the summary can't both go to stdout"),
+                )),
+            );
+        }
+        let output_version = matches.get_one::<u32>("OUTPUT_VERSION").cloned();
+        let uuid = matches.get_one::<String>("UUID").cloned();
+        let preserve_uuid = matches.get_flag("PRESERVE_UUID");
+        let transaction_id = matches.get_one::<u64>("TRANSACTION_ID").cloned();
+        let atomic_rename = matches.get_flag("ATOMIC_RENAME");
+        let verify = matches.get_flag("VERIFY");
 
         let opts = ThinMergeOptions {
-            input: input_file,
-            output: output_file,
-            engine_opts: engine_opts.unwrap(),
+            input,
+            output,
+            engine_opts,
+            input_engine,
+            output_engine,
             report: report.clone(),
             origin,
             snapshot,
+            intermediates,
+            since_time,
             rebase,
+            emit_residue,
+            output_format,
+            sort_leaves,
+            accept_diverged_origin,
+            clamp_times,
+            reset_time,
+            stall_timeout,
+            abort_on_stall,
+            max_mem,
+            stats,
+            repair_compat_check,
+            report_format,
+            progress_fd,
+            progress_sink: None,
+            cancel: None,
+            report_interval,
+            dry_run,
+            what_changes,
+            keep_other_devices,
+            append,
+            timings,
+            new_dev_id,
+            skip_bad_blocks,
+            skip_bad_nodes,
+            auto_repair,
+            deterministic,
+            force,
+            trace_merges,
+            zeroed,
+            copy_data,
+            remap,
+            remap_table,
+            key_range,
+            run_as,
+            base_batch,
+            snap_batch,
+            prefetch,
+            dump_threads,
+            queue_depth,
+            buffer_len,
+            write_batch,
+            nr_data_blocks,
+            data_block_size,
+            auto_snapshot,
+            chunk_blocks,
+            chunk_pause,
+            quiet,
+            verbose,
+            verify,
+            atomic_rename,
+            output_version,
+            uuid,
+            preserve_uuid,
+            transaction_id,
+            summary_file,
         };
 
-        to_exit_code(&report, merge_thins(opts))
+        let result = merge_thins(opts);
+        drop(pool);
+        exit_code(&report, result)
+    }
+}
+
+// Reports the error as the other thinp tools do, but exits with the code of
+// its class if known
+fn exit_code<T>(report: &Report, result: anyhow::Result<T>) -> exitcode::ExitCode {
+    let kind = result.as_ref().err().and_then(classify);
+    let code = to_exit_code(report, result);
+    kind.map_or(code, FailureKind::exit_code)
+}
+
+// The input given as - stands for stdin, so xml could be piped through. The
+// output given as - is written to stdout by the export itself.
+const STDIN_PATH: &str = "/dev/stdin";
+
+fn std_stream<'a>(arg: &'a str, stream: &'static str) -> &'a str {
+    if arg == "-" {
+        stream
+    } else {
+        arg
+    }
+}
+
+// Tells a missing input from one too small to hold any metadata
+fn check_input(path: &Path) -> anyhow::Result<&Path> {
+    let path = check_input_file(path).map_err(|e| fail(FailureKind::InputNotFound, e))?;
+    check_file_not_tiny(path).map_err(|e| fail(FailureKind::CorruptMetadata, e))
+}
+
+// --quiet leaves the report to the errors, while the -v are taken by the merge
+fn mk_leveled_report(matches: &clap::ArgMatches) -> Arc<Report> {
+    let report = mk_report(false);
+    if matches.get_flag("QUIET") {
+        report.set_level(LogLevel::Fatal);
+    }
+    report
+}
+
+fn parse_engine_choice(name: &str) -> EngineChoice {
+    match name {
+        "async" => EngineChoice::Async,
+        "auto" => EngineChoice::Auto,
+        _ => EngineChoice::Sync,
+    }
+}
+
+// The origin could be given as none if its content is lost or irrelevant
+fn parse_origin(s: &str) -> Result<Option<u64>, String> {
+    if s == "none" {
+        return Ok(None);
     }
+    s.parse::<u64>()
+        .map(Some)
+        .map_err(|_| format!("invalid device id '{}', expected a number or none", s))
 }
 
 fn main() {
-    let mut args = std::env::args_os();
-    let cmd = ThinMergeCommand;
-    exit(cmd.run(&mut args))
+    let code = {
+        // dropped before exiting, as exit() skips the destructors
+        let _cleanup = install_cleanup();
+        let mut args = std::env::args_os();
+        let cmd = ThinMergeCommand;
+        cmd.run(&mut args)
+    };
+    exit(code)
 }
 
 //------------------------------------------
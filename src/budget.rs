@@ -0,0 +1,214 @@
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+
+//------------------------------------------
+
+pub const LEAF_CACHES: &str = "leaf caches";
+pub const CHANNEL_BUFFERS: &str = "channel buffers";
+pub const SPACE_MAP: &str = "space map";
+pub const STAGING: &str = "staging";
+
+fn to_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+// Approximate accounting of the large buffers held during a merge. Only the
+// dominant allocations are estimated, so the actual usage runs a bit higher.
+pub struct MemoryBudget {
+    cap: Option<u64>,
+    usage: Mutex<Vec<(&'static str, u64)>>,
+    peak: Mutex<Vec<(&'static str, u64)>>, // the most held by each at a time
+}
+
+impl MemoryBudget {
+    pub fn new(cap: Option<u64>) -> Self {
+        Self {
+            cap,
+            usage: Mutex::new(Vec::new()),
+            peak: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn describe(usage: &[(&'static str, u64)]) -> String {
+        let total = usage.iter().map(|(_, b)| b).sum();
+        let mut parts: Vec<String> = usage
+            .iter()
+            .map(|(name, bytes)| format!("{} {}", name, to_mib(*bytes)))
+            .collect();
+        parts.push(format!("total {}", to_mib(total)));
+        parts.join(", ")
+    }
+
+    // Suggests a way out by the largest consumer
+    fn guidance(usage: &[(&'static str, u64)]) -> &'static str {
+        match usage.iter().max_by_key(|(_, b)| *b).map(|(n, _)| *n) {
+            Some(STAGING) => {
+                "raise the limit, or use binary input and output to avoid staging in memory"
+            }
+            Some(SPACE_MAP) => {
+                "raise the limit, or use a smaller output as the space map grows with it"
+            }
+            _ => "raise the limit",
+        }
+    }
+
+    fn add(usage: &mut Vec<(&'static str, u64)>, name: &'static str, bytes: u64) -> u64 {
+        match usage.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, b)) => *b += bytes,
+            None => usage.push((name, bytes)),
+        }
+        usage.iter().map(|(_, b)| b).sum()
+    }
+
+    fn note_peak(&self, usage: &[(&'static str, u64)]) {
+        let mut peak = self.peak.lock().unwrap();
+        for &(name, bytes) in usage {
+            match peak.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, b)) => *b = std::cmp::max(*b, bytes),
+                None => peak.push((name, bytes)),
+            }
+        }
+    }
+
+    fn check(&self, usage: &[(&'static str, u64)], name: &'static str, total: u64) -> Result<()> {
+        match self.cap {
+            Some(cap) if total > cap => Err(anyhow!(
+                "estimated memory use exceeds --max-mem of {} when allocating {}: {}; {}",
+                to_mib(cap),
+                name,
+                Self::describe(usage),
+                Self::guidance(usage)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Accounts the bytes to the given component before they are allocated,
+    // failing if that would take the total over the cap.
+    pub fn charge(&self, name: &'static str, bytes: u64) -> Result<()> {
+        let mut usage = self.usage.lock().unwrap();
+        let total = Self::add(&mut usage, name, bytes);
+        if let Err(e) = self.check(&usage, name, total) {
+            // left out, so the caller could fall back to something smaller
+            Self::sub(&mut usage, name, bytes);
+            return Err(e);
+        }
+        self.note_peak(&usage);
+        Ok(())
+    }
+
+    fn sub(usage: &mut [(&'static str, u64)], name: &'static str, bytes: u64) {
+        if let Some((_, b)) = usage.iter_mut().find(|(n, _)| *n == name) {
+            *b = b.saturating_sub(bytes);
+        }
+    }
+
+    // Gives back the bytes charged to the given component once freed
+    fn release(&self, name: &'static str, bytes: u64) {
+        Self::sub(&mut self.usage.lock().unwrap(), name, bytes);
+    }
+
+    // Accounts as many of the wanted units, e.g., cached blocks, as fit in half
+    // the room left under the cap, leaving the rest to the later charges, and
+    // at least one. Returns the number of units charged, so the caller could
+    // make do with fewer, failing only if a single one won't fit.
+    pub fn charge_fitting(&self, name: &'static str, unit: u64, wanted: usize) -> Result<usize> {
+        let mut usage = self.usage.lock().unwrap();
+        let used: u64 = usage.iter().map(|(_, b)| b).sum();
+        let n = match self.cap {
+            Some(cap) => {
+                let room = cap.saturating_sub(used) / 2 / std::cmp::max(unit, 1);
+                std::cmp::min(room, wanted as u64).max(1) as usize
+            }
+            None => wanted,
+        };
+        let total = Self::add(&mut usage, name, unit * n as u64);
+        if let Err(e) = self.check(&usage, name, total) {
+            Self::sub(&mut usage, name, unit * n as u64);
+            return Err(e);
+        }
+        self.note_peak(&usage);
+        Ok(n)
+    }
+
+    // The most each component held at a time, as the charges of the streams
+    // are given back once they end
+    pub fn summary(&self) -> String {
+        Self::describe(&self.peak.lock().unwrap())
+    }
+}
+
+// The charges for the buffers of a stream, given back to the budget once
+// dropped along with the stream, so the streams set up later get the room back
+pub struct Charges {
+    budget: Arc<MemoryBudget>,
+    held: Vec<(&'static str, u64)>,
+}
+
+impl Charges {
+    pub fn new(budget: &Arc<MemoryBudget>) -> Self {
+        Self {
+            budget: budget.clone(),
+            held: Vec::new(),
+        }
+    }
+
+    pub fn charge(&mut self, name: &'static str, bytes: u64) -> Result<()> {
+        self.budget.charge(name, bytes)?;
+        self.held.push((name, bytes));
+        Ok(())
+    }
+
+    pub fn charge_fitting(
+        &mut self,
+        name: &'static str,
+        unit: u64,
+        wanted: usize,
+    ) -> Result<usize> {
+        let n = self.budget.charge_fitting(name, unit, wanted)?;
+        self.held.push((name, unit * n as u64));
+        Ok(n)
+    }
+}
+
+impl Drop for Charges {
+    fn drop(&mut self) {
+        for &(name, bytes) in &self.held {
+            self.budget.release(name, bytes);
+        }
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_charges_are_given_back() {
+        let budget = Arc::new(MemoryBudget::new(Some(1000)));
+        let mut charges = Charges::new(&budget);
+        charges.charge(LEAF_CACHES, 800).unwrap();
+        assert!(budget.charge(LEAF_CACHES, 400).is_err());
+        drop(charges);
+        budget.charge(LEAF_CACHES, 400).unwrap();
+    }
+
+    #[test]
+    fn failed_charges_are_left_out() {
+        let budget = MemoryBudget::new(Some(1000));
+        assert!(budget.charge(STAGING, 2000).is_err());
+        budget.charge(STAGING, 1000).unwrap();
+    }
+
+    #[test]
+    fn fitting_charges_take_half_the_room() {
+        let budget = Arc::new(MemoryBudget::new(Some(1000)));
+        let mut charges = Charges::new(&budget);
+        assert_eq!(charges.charge_fitting(LEAF_CACHES, 100, 8).unwrap(), 5);
+        drop(charges);
+        let mut charges = Charges::new(&budget);
+        assert_eq!(charges.charge_fitting(LEAF_CACHES, 100, 8).unwrap(), 5);
+    }
+}
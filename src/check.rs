@@ -0,0 +1,306 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use thinp::checksum::{metadata_block_type, BT};
+use thinp::io_engine::IoEngine;
+use thinp::pdata::btree::*;
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::pdata::space_map::common::{Bitmap, BitmapEntry, IndexEntry, SMRoot};
+use thinp::pdata::unpack::unpack;
+use thinp::thin::device_detail::DeviceDetail;
+use thinp::thin::superblock::*;
+
+use crate::mapping_iterator::MappingIterator;
+use crate::progress::Progress;
+
+//------------------------------------------
+
+/// What a check of the output went through
+#[derive(Debug, Default)]
+pub struct OutputCheck {
+    pub nr_devices: u64,
+    pub mapped_blocks: u64,
+    pub nr_nodes: u64,
+}
+
+// Walks a mapping tree level by level, checking the checksums and the key
+// order of every node. Returns the number of nodes, and the leaves in key order.
+fn walk_tree(engine: &Arc<dyn IoEngine + Send + Sync>, root: u64) -> Result<(u64, Vec<u64>)> {
+    let mut level = vec![root];
+    let mut is_root = true;
+    let mut nr_nodes = 0;
+    let mut leaves = Vec::new();
+
+    while !level.is_empty() {
+        nr_nodes += level.len() as u64;
+
+        let mut children = Vec::new();
+        for chunk in level.chunks(engine.get_batch_size()) {
+            for (&loc, b) in chunk.iter().zip(engine.read_many(chunk)?) {
+                let b =
+                    b.map_err(|e| anyhow!("unable to read the node at block {}: {}", loc, e))?;
+                if !matches!(metadata_block_type(b.get_data()), BT::NODE) {
+                    return Err(anyhow!("bad checksum of the btree node at block {}", loc));
+                }
+
+                // both the child pointers and the block_time values are 64-bit
+                let node = unpack_node::<u64>(&[], b.get_data(), true, is_root)
+                    .map_err(|e| anyhow!("bad btree node at block {}: {}", loc, e))?;
+                match node {
+                    Node::Internal { keys, values, .. } => {
+                        if keys.windows(2).any(|pair| pair[1] <= pair[0]) {
+                            return Err(anyhow!(
+                                "keys of the internal node at block {} are out of order",
+                                loc
+                            ));
+                        }
+                        children.extend(values);
+                    }
+                    Node::Leaf { .. } => leaves.push(loc),
+                }
+            }
+        }
+        level = children;
+        is_root = false;
+    }
+
+    Ok((nr_nodes, leaves))
+}
+
+// Checks the mappings of a device against its details and the superblock,
+// returning the number of nodes of its tree along with its leaves
+fn check_device(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    nr_data_blocks: u64,
+    dev_id: u64,
+    root: u64,
+    details: &DeviceDetail,
+    progress: &Progress,
+) -> Result<(u64, Vec<u64>)> {
+    let (nr_nodes, leaves) = walk_tree(engine, root)?;
+
+    // the iterator rejects the keys out of order across the leaves
+    let mut iter = MappingIterator::new(engine.clone(), leaves.clone())?;
+    let mut mapped_blocks = 0;
+    let mut position = 0;
+    while let Some((key, bt, len)) = iter.next_range()? {
+        if bt.block.saturating_add(len) > nr_data_blocks {
+            return Err(anyhow!(
+                "device {} maps key {} to data block {}, beyond the {} data blocks",
+                dev_id,
+                key,
+                bt.block.saturating_add(len - 1),
+                nr_data_blocks
+            ));
+        }
+        if bt.time > sb.time {
+            return Err(anyhow!(
+                "device {} maps key {} at time {}, after the superblock time {}",
+                dev_id,
+                key,
+                bt.time,
+                sb.time
+            ));
+        }
+        mapped_blocks += len;
+        position = key + len;
+        progress.update(
+            dev_id as u32,
+            position,
+            mapped_blocks,
+            details.mapped_blocks,
+            false,
+        );
+    }
+    progress.update(
+        dev_id as u32,
+        position,
+        mapped_blocks,
+        details.mapped_blocks,
+        true,
+    );
+
+    if mapped_blocks != details.mapped_blocks {
+        return Err(anyhow!(
+            "device {} maps {} blocks, but its details count {}",
+            dev_id,
+            mapped_blocks,
+            details.mapped_blocks
+        ));
+    }
+    if details.creation_time > sb.time || details.snapshotted_time > sb.time {
+        return Err(anyhow!(
+            "device {} was created at time {} and snapshotted at time {}, after the superblock time {}",
+            dev_id,
+            details.creation_time,
+            details.snapshotted_time,
+            sb.time
+        ));
+    }
+    if details.transaction_id > sb.transaction_id {
+        return Err(anyhow!(
+            "device {} has the transaction {}, after the superblock transaction {}",
+            dev_id,
+            details.transaction_id,
+            sb.transaction_id
+        ));
+    }
+
+    Ok((nr_nodes, leaves))
+}
+
+// Counts the references to the data blocks, one per leaf mapping the block. A
+// leaf shared by several devices is counted once, as the pool does.
+fn count_data_refs(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    device_leaves: Vec<Vec<u64>>,
+) -> Result<HashMap<u64, u32>> {
+    let mut counts = HashMap::new();
+    let mut seen = HashSet::new();
+    for leaves in device_leaves {
+        let leaves: Vec<u64> = leaves.into_iter().filter(|&l| seen.insert(l)).collect();
+        if leaves.is_empty() {
+            continue;
+        }
+        let mut iter = MappingIterator::new(engine.clone(), leaves)?;
+        while let Some((_, bt, len)) = iter.next_range()? {
+            for b in bt.block..bt.block + len {
+                *counts.entry(b).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+// Checks the reference counts of the data space map against the ones counted
+// from the mappings, as thin_check does
+fn check_data_sm(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    root: &SMRoot,
+    counts: &HashMap<u64, u32>,
+) -> Result<()> {
+    let index = btree_to_map::<IndexEntry>(&mut vec![], engine.clone(), false, root.bitmap_root)
+        .map_err(|e| anyhow!("bad data space map index: {}", e))?;
+    let overflow = btree_to_map::<u32>(&mut vec![], engine.clone(), false, root.ref_count_root)
+        .map_err(|e| anyhow!("bad data space map reference counts: {}", e))?;
+
+    let mut begin = 0;
+    let mut nr_allocated = 0;
+    for entry in index.values() {
+        let b = engine.read(entry.blocknr).map_err(|e| {
+            anyhow!(
+                "unable to read the data space map bitmap at block {}: {}",
+                entry.blocknr,
+                e
+            )
+        })?;
+        if !matches!(metadata_block_type(b.get_data()), BT::BITMAP) {
+            return Err(anyhow!(
+                "bad checksum of the data space map bitmap at block {}",
+                entry.blocknr
+            ));
+        }
+        let bitmap = unpack::<Bitmap>(b.get_data())?;
+
+        for (i, e) in bitmap.entries.iter().enumerate() {
+            let block = begin + i as u64;
+            if block >= root.nr_blocks {
+                break;
+            }
+            let actual = match e {
+                BitmapEntry::Small(n) => *n as u32,
+                BitmapEntry::Overflow => *overflow.get(&block).unwrap_or(&0),
+            };
+            let expected = *counts.get(&block).unwrap_or(&0);
+            if actual != expected {
+                return Err(anyhow!(
+                    "data block {} has {} references in the data space map, but {} from the mappings",
+                    block,
+                    actual,
+                    expected
+                ));
+            }
+            if actual > 0 {
+                nr_allocated += 1;
+            }
+        }
+        begin += bitmap.entries.len() as u64;
+    }
+
+    if begin < root.nr_blocks {
+        return Err(anyhow!(
+            "the data space map covers {} of the {} data blocks",
+            begin,
+            root.nr_blocks
+        ));
+    }
+    if nr_allocated != root.nr_allocated {
+        return Err(anyhow!(
+            "the data space map counts {} allocated blocks, but {} are referenced",
+            root.nr_allocated,
+            nr_allocated
+        ));
+    }
+    Ok(())
+}
+
+/// Walks the whole metadata, checking the checksums and the key order of the
+/// btree nodes, the details of every device against its mappings, and the
+/// mappings against the data device and the superblock time, and the reference
+/// counts of the data space map against the mappings. Stops at the first
+/// problem found.
+pub fn check_output(engine: Arc<dyn IoEngine + Send + Sync>) -> Result<OutputCheck> {
+    check_output_with(engine, &Progress::disabled())
+}
+
+/// Checks the output as check_output does, reporting the progress through
+/// the mappings of every device
+pub fn check_output_with(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    progress: &Progress,
+) -> Result<OutputCheck> {
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root)?;
+    let nr_data_blocks = data_root.nr_blocks;
+
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)
+        .map_err(|e| anyhow!("bad top-level mapping tree: {}", e))?;
+    let details = btree_to_map::<DeviceDetail>(&mut vec![], engine.clone(), false, sb.details_root)
+        .map_err(|e| anyhow!("bad details tree: {}", e))?;
+    if !roots.keys().eq(details.keys()) {
+        return Err(anyhow!(
+            "devices in the mapping tree {:?} don't match the ones in the details tree {:?}",
+            roots.keys().collect::<Vec<_>>(),
+            details.keys().collect::<Vec<_>>()
+        ));
+    }
+
+    progress.expect(details.values().map(|d| d.mapped_blocks).sum());
+
+    let mut check = OutputCheck::default();
+    let mut device_leaves = Vec::new();
+    for (&dev_id, &root) in &roots {
+        let details = &details[&dev_id];
+        let (nr_nodes, leaves) = check_device(
+            &engine,
+            &sb,
+            nr_data_blocks,
+            dev_id,
+            root,
+            details,
+            progress,
+        )?;
+        check.nr_nodes += nr_nodes;
+        check.nr_devices += 1;
+        check.mapped_blocks += details.mapped_blocks;
+        device_leaves.push(leaves);
+    }
+
+    let counts = count_data_refs(&engine, device_leaves)?;
+    check_data_sm(&engine, &data_root, &counts)?;
+
+    Ok(check)
+}
+
+//------------------------------------------
@@ -0,0 +1,76 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use thinp::io_engine::IoEngine;
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::pdata::space_map::common::SMRoot;
+use thinp::pdata::unpack::unpack;
+use thinp::thin::device_detail::DeviceDetail;
+use thinp::thin::metadata_repair::is_superblock_consistent;
+use thinp::thin::superblock::*;
+
+//------------------------------------------
+
+fn check_sm_root(name: &str, root: &[u8], max_blocks: Option<u64>) -> Result<()> {
+    let root = unpack::<SMRoot>(root)?;
+    if root.nr_allocated > root.nr_blocks {
+        return Err(anyhow!(
+            "{} space map allocates {} of {} blocks",
+            name,
+            root.nr_allocated,
+            root.nr_blocks
+        ));
+    }
+    if let Some(max) = max_blocks {
+        if root.nr_blocks > max {
+            return Err(anyhow!(
+                "{} space map covers {} blocks beyond the {} blocks of the device",
+                name,
+                root.nr_blocks,
+                max
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Structural checks mirroring what thin_repair expects from the metadata, so
+// the output could still be repaired if the pool gets damaged later.
+pub fn check_repair_compat(engine: Arc<dyn IoEngine + Send + Sync>) -> Result<()> {
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+
+    // thin_repair only takes superblocks with walkable trees
+    is_superblock_consistent(sb.clone(), engine.clone(), false)
+        .map_err(|e| anyhow!("the superblock would be rejected by thin_repair: {}", e))?;
+
+    if sb.metadata_snap != 0 {
+        return Err(anyhow!(
+            "unexpected metadata snapshot at {}",
+            sb.metadata_snap
+        ));
+    }
+    if sb.data_block_size == 0 {
+        return Err(anyhow!("zero data block size"));
+    }
+
+    check_sm_root(
+        "metadata",
+        &sb.metadata_sm_root,
+        Some(engine.get_nr_blocks()),
+    )?;
+    check_sm_root("data", &sb.data_sm_root, None)?;
+
+    // every device needs both a mapping tree and the details
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let details = btree_to_map::<DeviceDetail>(&mut vec![], engine, false, sb.details_root)?;
+    if !roots.keys().eq(details.keys()) {
+        return Err(anyhow!(
+            "devices in the mapping tree {:?} don't match the ones in the details tree {:?}",
+            roots.keys().collect::<Vec<_>>(),
+            details.keys().collect::<Vec<_>>()
+        ));
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
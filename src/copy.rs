@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Result};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use thinp::file_utils::file_size;
+use thinp::thin::block_time::BlockTime;
+
+use crate::pool::check_not_held;
+use crate::stream::RunSource;
+
+//------------------------------------------
+
+/// The devices of a merge with an external origin living outside the pool,
+/// whose data is copied into the data device of the pool
+#[derive(Clone, Debug)]
+pub struct CopyData {
+    pub origin_dev: PathBuf,
+    pub pool_data_dev: PathBuf,
+}
+
+// Data blocks at and above the marker stand for the blocks of the origin
+// device, well past the data blocks of any pool, so the runs taken from the
+// origin are told from the others once merged
+pub const ORIGIN_MARKER: u64 = 1 << 62;
+
+// The bytes copied at a time
+const COPY_BUFFER_SIZE: u64 = 1 << 20;
+
+// Writes the data of the origin into the free data blocks of the pool
+pub struct DataCopier {
+    origin: File,
+    pool: File,
+    block_bytes: u64,
+    origin_size: u64,
+    nr_origin_blocks: u64,
+    free: Vec<(u64, u64)>, // sorted and disjoint half-open ranges
+    next_free: usize,
+    copied: Arc<AtomicU64>,
+}
+
+// The complement of the used ranges within the data blocks
+fn free_ranges(mut used: Vec<(u64, u64)>, nr_data_blocks: u64) -> Vec<(u64, u64)> {
+    used.sort_unstable();
+
+    let mut free = Vec::new();
+    let mut begin = 0;
+    for (b, e) in used {
+        if b > begin {
+            free.push((begin, std::cmp::min(b, nr_data_blocks)));
+        }
+        begin = std::cmp::max(begin, e);
+        if begin >= nr_data_blocks {
+            return free;
+        }
+    }
+    if begin < nr_data_blocks {
+        free.push((begin, nr_data_blocks));
+    }
+    free
+}
+
+impl DataCopier {
+    /// Opens both devices, given the data block size in sectors, the data
+    /// blocks of the pool, and the ones mapped by any of its devices. The
+    /// number of blocks copied is added to the given counter.
+    pub fn open(
+        copy: &CopyData,
+        data_block_size: u32,
+        nr_data_blocks: u64,
+        used: Vec<(u64, u64)>,
+        copied: Arc<AtomicU64>,
+    ) -> Result<Self> {
+        let block_bytes = data_block_size as u64 * 512;
+
+        let origin = File::open(&copy.origin_dev).map_err(|e| {
+            anyhow!(
+                "unable to open the origin device {}: {}",
+                copy.origin_dev.display(),
+                e
+            )
+        })?;
+        let origin_size = file_size(&copy.origin_dev)?;
+
+        check_not_held(&copy.pool_data_dev)?;
+        let pool = OpenOptions::new()
+            .write(true)
+            .open(&copy.pool_data_dev)
+            .map_err(|e| {
+                anyhow!(
+                    "unable to open the pool data device {}: {}",
+                    copy.pool_data_dev.display(),
+                    e
+                )
+            })?;
+        let pool_blocks = file_size(&copy.pool_data_dev)? / block_bytes;
+        if pool_blocks < nr_data_blocks {
+            return Err(anyhow!(
+                "the pool data device {} holds {} data blocks, fewer than the {} of the pool",
+                copy.pool_data_dev.display(),
+                pool_blocks,
+                nr_data_blocks
+            ));
+        }
+
+        Ok(Self {
+            origin,
+            pool,
+            block_bytes,
+            origin_size,
+            nr_origin_blocks: origin_size.div_ceil(block_bytes),
+            free: free_ranges(used, nr_data_blocks),
+            next_free: 0,
+            copied,
+        })
+    }
+
+    pub fn nr_origin_blocks(&self) -> u64 {
+        self.nr_origin_blocks
+    }
+
+    /// The runs of the whole origin device, mapped linearly past the marker
+    pub fn origin_source(&self) -> RunSource {
+        let mut pending = match self.nr_origin_blocks {
+            0 => None,
+            n => Some((
+                0,
+                BlockTime {
+                    block: ORIGIN_MARKER,
+                    time: 0,
+                },
+                n,
+            )),
+        };
+        Box::new(move || Ok(pending.take()))
+    }
+
+    // Takes up to the given number of free data blocks at the lowest address
+    fn allocate(&mut self, len: u64) -> Result<(u64, u64)> {
+        let Some(range) = self.free.get_mut(self.next_free) else {
+            return Err(anyhow!(
+                "the pool has no free data blocks left for the origin data, after copying {} blocks",
+                self.copied.load(Ordering::Relaxed)
+            ));
+        };
+        let begin = range.0;
+        let n = std::cmp::min(len, range.1 - begin);
+        range.0 += n;
+        if range.0 == range.1 {
+            self.next_free += 1;
+        }
+        Ok((begin, n))
+    }
+
+    fn copy(&self, origin_block: u64, pool_block: u64, len: u64) -> Result<()> {
+        let mut src = origin_block * self.block_bytes;
+        let mut dest = pool_block * self.block_bytes;
+        let end = src + len * self.block_bytes;
+        let mut buf = vec![0; std::cmp::min(COPY_BUFFER_SIZE, end - src) as usize];
+
+        while src < end {
+            let n = std::cmp::min(buf.len() as u64, end - src) as usize;
+
+            // the tail of the last block lies past the end of the origin
+            let nr_read = std::cmp::min(n as u64, self.origin_size.saturating_sub(src)) as usize;
+            self.origin
+                .read_exact_at(&mut buf[..nr_read], src)
+                .map_err(|e| anyhow!("unable to read the origin at byte {}: {}", src, e))?;
+            buf[nr_read..n].fill(0);
+            self.pool
+                .write_all_at(&buf[..n], dest)
+                .map_err(|e| anyhow!("unable to write the pool data at byte {}: {}", dest, e))?;
+            src += n as u64;
+            dest += n as u64;
+        }
+        self.copied.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+/// Copies the data of the runs taken from the origin into the free data
+/// blocks of the pool, splitting the runs across the free ranges, and remaps
+/// them there. The copied data is flushed once the source is exhausted, before
+/// any metadata could point at it.
+pub fn copy_origin_runs(mut source: RunSource, mut copier: DataCopier) -> RunSource {
+    let mut pending: Option<(u64, BlockTime, u64)> = None;
+    let mut synced = false;
+    Box::new(move || {
+        let (key, bt, len) = match pending.take() {
+            Some(run) => run,
+            None => match source()? {
+                Some(run) => run,
+                None => {
+                    if !synced {
+                        copier.pool.sync_data()?;
+                        synced = true;
+                    }
+                    return Ok(None);
+                }
+            },
+        };
+
+        if bt.block < ORIGIN_MARKER {
+            return Ok(Some((key, bt, len)));
+        }
+
+        let origin_block = bt.block - ORIGIN_MARKER;
+        let (pool_block, piece) = copier.allocate(len)?;
+        copier.copy(origin_block, pool_block, piece)?;
+        if piece < len {
+            pending = Some((
+                key + piece,
+                BlockTime {
+                    block: bt.block + piece,
+                    time: bt.time,
+                },
+                len - piece,
+            ));
+        }
+
+        Ok(Some((
+            key,
+            BlockTime {
+                block: pool_block,
+                time: bt.time,
+            },
+            piece,
+        )))
+    })
+}
+
+//------------------------------------------
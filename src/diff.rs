@@ -0,0 +1,251 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use thinp::commands::engine::*;
+use thinp::report::Report;
+use thinp::thin::superblock::*;
+
+use crate::merge::{device_runs, read_patched_superblock_snap};
+use crate::schema::SCHEMA_VERSION;
+use crate::stream::{since_time, walk_deltas, Delta};
+
+//------------------------------------------
+
+/// The layout of the report of the differences
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffFormat {
+    Xml,
+    Json,
+}
+
+impl DiffFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "xml" => Ok(DiffFormat::Xml),
+            "json" => Ok(DiffFormat::Json),
+            _ => Err(anyhow!("unknown diff format '{}'", name)),
+        }
+    }
+}
+
+// Writes the ranges one by one, so the report of a large device is never held
+// in memory
+trait DiffWriter {
+    fn begin(&mut self, left: u64, right: u64) -> Result<()>;
+    fn range(&mut self, begin: u64, len: u64, delta: Delta) -> Result<()>;
+    fn end(&mut self) -> Result<()>;
+}
+
+// The elements of thin_delta, with the data blocks of either side
+struct XmlDiff<W: Write> {
+    out: W,
+}
+
+impl<W: Write> DiffWriter for XmlDiff<W> {
+    fn begin(&mut self, left: u64, right: u64) -> Result<()> {
+        writeln!(self.out, "<diff left=\"{}\" right=\"{}\">", left, right)?;
+        Ok(())
+    }
+
+    fn range(&mut self, begin: u64, len: u64, delta: Delta) -> Result<()> {
+        match delta {
+            Delta::Same(data) => writeln!(
+                self.out,
+                "  <same begin=\"{}\" data_begin=\"{}\" length=\"{}\"/>",
+                begin, data, len
+            )?,
+            Delta::Different(left, right) => writeln!(
+                self.out,
+                "  <different begin=\"{}\" left_data_begin=\"{}\" right_data_begin=\"{}\" length=\"{}\"/>",
+                begin, left, right, len
+            )?,
+            Delta::LeftOnly(data) => writeln!(
+                self.out,
+                "  <left_only begin=\"{}\" data_begin=\"{}\" length=\"{}\"/>",
+                begin, data, len
+            )?,
+            Delta::RightOnly(data) => writeln!(
+                self.out,
+                "  <right_only begin=\"{}\" data_begin=\"{}\" length=\"{}\"/>",
+                begin, data, len
+            )?,
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<()> {
+        writeln!(self.out, "</diff>")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// A range of the json diff, told apart by the kind field
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DiffRange {
+    Same {
+        begin: u64,
+        length: u64,
+        data_begin: u64,
+    },
+    Different {
+        begin: u64,
+        length: u64,
+        left_data_begin: u64,
+        right_data_begin: u64,
+    },
+    LeftOnly {
+        begin: u64,
+        length: u64,
+        data_begin: u64,
+    },
+    RightOnly {
+        begin: u64,
+        length: u64,
+        data_begin: u64,
+    },
+}
+
+impl DiffRange {
+    fn new(begin: u64, length: u64, delta: Delta) -> Self {
+        match delta {
+            Delta::Same(data_begin) => DiffRange::Same {
+                begin,
+                length,
+                data_begin,
+            },
+            Delta::Different(left_data_begin, right_data_begin) => DiffRange::Different {
+                begin,
+                length,
+                left_data_begin,
+                right_data_begin,
+            },
+            Delta::LeftOnly(data_begin) => DiffRange::LeftOnly {
+                begin,
+                length,
+                data_begin,
+            },
+            Delta::RightOnly(data_begin) => DiffRange::RightOnly {
+                begin,
+                length,
+                data_begin,
+            },
+        }
+    }
+}
+
+/// The whole json diff, to read it back. It's written range by range.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffReport {
+    pub schema_version: u32,
+    pub left: u64,
+    pub right: u64,
+    pub ranges: Vec<DiffRange>,
+}
+
+// One object holding the ranges, each on a line of its own
+struct JsonDiff<W: Write> {
+    out: W,
+    first: bool,
+}
+
+impl<W: Write> DiffWriter for JsonDiff<W> {
+    fn begin(&mut self, left: u64, right: u64) -> Result<()> {
+        write!(
+            self.out,
+            "{{\"schema_version\":{},\"left\":{},\"right\":{},\"ranges\":[",
+            SCHEMA_VERSION, left, right
+        )?;
+        Ok(())
+    }
+
+    fn range(&mut self, begin: u64, len: u64, delta: Delta) -> Result<()> {
+        let sep = if self.first { "" } else { "," };
+        self.first = false;
+        write!(
+            self.out,
+            "{}\n{}",
+            sep,
+            serde_json::to_string(&DiffRange::new(begin, len, delta))?
+        )?;
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<()> {
+        writeln!(self.out, "\n]}}")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+pub struct ThinDiffOptions<'a> {
+    pub input: &'a Path,
+    pub output: Option<&'a Path>, // stdout if not given
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+    pub origin: u64,
+    pub snapshot: u64,
+    pub format: DiffFormat,
+    pub sort_leaves: bool,
+    pub since_time: Option<u32>, // the older mappings are dropped from the snapshot
+}
+
+// Lists the ranges where the snapshot differs from its origin, or not, as
+// thin_delta does, so what a merge would change could be audited
+pub fn diff_devices(opts: ThinDiffOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .exclusive(!opts.engine_opts.use_metadata_snap)
+        .build()?;
+    let sb = if opts.engine_opts.use_metadata_snap {
+        read_patched_superblock_snap(engine.as_ref())?
+    } else {
+        read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?
+    };
+
+    let runs = |dev_id| {
+        device_runs(
+            engine.clone(),
+            opts.report.clone(),
+            opts.sort_leaves,
+            &sb,
+            dev_id,
+            None,
+        )
+        .map(|(source, _)| source)
+    };
+    let origin = runs(opts.origin)?;
+
+    // the origin is left whole, so a block written to the snapshot since the
+    // time shows as different rather than right_only
+    let snapshot = match opts.since_time {
+        Some(time) => since_time(runs(opts.snapshot)?, time),
+        None => runs(opts.snapshot)?,
+    };
+
+    let out: Box<dyn Write> = match opts.output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| {
+                anyhow!("unable to create the diff {}: {}", path.display(), e)
+            })?))
+        }
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    let mut w: Box<dyn DiffWriter> = match opts.format {
+        DiffFormat::Xml => Box::new(XmlDiff { out }),
+        DiffFormat::Json => Box::new(JsonDiff { out, first: true }),
+    };
+
+    w.begin(opts.origin, opts.snapshot)?;
+    walk_deltas(origin, snapshot, |begin, len, delta| {
+        w.range(begin, len, delta)
+    })?;
+    w.end()
+}
+
+//------------------------------------------
@@ -0,0 +1,224 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use thinp::commands::engine::*;
+use thinp::io_engine::IoEngine;
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::report::Report;
+use thinp::thin::device_detail::DeviceDetail;
+use thinp::thin::superblock::*;
+
+use crate::merge::{device_runs, read_patched_superblock_snap};
+use crate::stream::{count_shared_with, RunSource};
+
+//------------------------------------------
+
+struct Candidate {
+    dev_id: u64,
+    details: DeviceDetail,
+    shared: u64,
+}
+
+impl Candidate {
+    fn describe(&self, origin_id: u64, origin_time: u32) -> String {
+        let mapped = self.details.mapped_blocks;
+        let diverged = if mapped > 0 {
+            mapped.saturating_sub(self.shared) as f64 * 100.0 / mapped as f64
+        } else {
+            0.0
+        };
+        let written_since = if origin_time > self.details.creation_time {
+            "; the origin was written since"
+        } else {
+            ""
+        };
+        format!(
+            "snapshot {}: created at time {}, snapshotted at time {}, {} of {} mapped blocks shared with origin {}, {:.1}% diverged{}",
+            self.dev_id,
+            self.details.creation_time,
+            self.details.snapshotted_time,
+            self.shared,
+            mapped,
+            origin_id,
+            diverged,
+            written_since
+        )
+    }
+}
+
+//------------------------------------------
+
+// Compares every device other than the origin with it, returning the latest
+// mapping time of the origin along with the devices. The devices are walked
+// side by side with the origin, so the origin is read once, but every device
+// is walked in full, which takes a while on large pools.
+fn scan_devices(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    report: &Arc<Report>,
+    sb: &Superblock,
+    origin: u64,
+) -> Result<(u32, usize, Vec<Candidate>)> {
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let details =
+        btree_to_map::<DeviceDetail>(&mut vec![], engine.clone(), false, sb.details_root)?;
+
+    let origin_root = *roots
+        .get(&origin)
+        .ok_or_else(|| anyhow!("Unable to find mapping tree for the device {}", origin))?;
+    let runs = |dev_id| -> Result<RunSource> {
+        let (source, _) = device_runs(engine.clone(), report.clone(), false, sb, dev_id, None)?;
+        Ok(source)
+    };
+    // the latest mapping time of the origin, taken in the same walk
+    let origin_time = Arc::new(AtomicU32::new(0));
+    let mut origin_runs = runs(origin)?;
+    let latest = origin_time.clone();
+    let origin_runs: RunSource = Box::new(move || {
+        let run = origin_runs()?;
+        if let Some((_, bt, _)) = &run {
+            latest.fetch_max(bt.time, Ordering::Relaxed);
+        }
+        Ok(run)
+    });
+
+    let mut devices = Vec::new();
+    let mut walked = Vec::new();
+    let mut sources = Vec::new();
+    for (&dev_id, &root) in &roots {
+        if dev_id == origin {
+            continue;
+        }
+        let details = *details
+            .get(&dev_id)
+            .ok_or_else(|| anyhow!("Unable to find the details for the device {}", dev_id))?;
+
+        // a snapshot never written since taken shares the whole tree
+        if root != origin_root {
+            walked.push(devices.len());
+            sources.push(runs(dev_id)?);
+        }
+        devices.push(Candidate {
+            dev_id,
+            details,
+            shared: details.mapped_blocks,
+        });
+    }
+
+    // compared all together, so the origin is walked once
+    let shared = count_shared_with(origin_runs, sources)?;
+    for (idx, shared) in walked.into_iter().zip(shared) {
+        devices[idx].shared = shared;
+    }
+
+    let origin_time = origin_time.load(Ordering::Relaxed);
+    Ok((origin_time, roots.len(), devices))
+}
+
+//------------------------------------------
+
+pub struct ListSnapshotsOptions<'a> {
+    pub input: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+    pub origin: u64,
+}
+
+// Lists the devices mapping any block to the same data block as the origin,
+// which are likely its snapshots, with the most shared ones first.
+pub fn list_snapshots(opts: ListSnapshotsOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .exclusive(!opts.engine_opts.use_metadata_snap)
+        .build()?;
+
+    let sb = if opts.engine_opts.use_metadata_snap {
+        read_patched_superblock_snap(engine.as_ref())?
+    } else {
+        read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?
+    };
+
+    let (origin_time, nr_devices, mut candidates) =
+        scan_devices(&engine, &opts.report, &sb, opts.origin)?;
+    candidates.retain(|c| c.shared > 0);
+    candidates.sort_by(|a, b| b.shared.cmp(&a.shared).then(a.dev_id.cmp(&b.dev_id)));
+
+    opts.report.info(&format!(
+        "{} candidate snapshots of origin {} among {} devices",
+        candidates.len(),
+        opts.origin,
+        nr_devices
+    ));
+    for c in &candidates {
+        opts.report.info(&c.describe(opts.origin, origin_time));
+    }
+
+    Ok(())
+}
+
+// Checks the snapshot the user paired with the origin against the devices of
+// the pool. Nothing in the metadata ties an external snapshot to its origin,
+// as it shares no data blocks with it, so the absence of sharing is no proof,
+// and the pairing has to come from the user. Without one, the devices that
+// could be the snapshot are listed, but none is picked.
+pub(crate) fn find_external_snapshot(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    report: &Arc<Report>,
+    sb: &Superblock,
+    origin: u64,
+    paired: Option<u64>,
+) -> Result<u64> {
+    let (origin_time, _, mut devices) = scan_devices(engine, report, sb, origin)?;
+
+    if let Some(snap) = paired.and_then(|id| devices.iter().find(|c| c.dev_id == id)) {
+        if snap.shared > 0 {
+            return Err(anyhow!(
+                "device {} shares {} data blocks with origin {}, so it's a snapshot taken \
+                 within the pool rather than an external one",
+                snap.dev_id,
+                snap.shared,
+                origin
+            ));
+        }
+        if snap.details.creation_time < origin_time {
+            return Err(anyhow!(
+                "device {} was created at time {}, before the origin {} was last written at \
+                 time {}, so it can't be an external snapshot of it",
+                snap.dev_id,
+                snap.details.creation_time,
+                origin,
+                origin_time
+            ));
+        }
+        report.info(&format!(
+            "confirmed device {} as the external snapshot of origin {}: created at time {}, \
+             sharing no data blocks with it",
+            snap.dev_id, origin, snap.details.creation_time
+        ));
+        return Ok(snap.dev_id);
+    }
+    if let Some(id) = paired {
+        return Err(anyhow!("unable to find the snapshot {} in the pool", id));
+    }
+
+    devices.retain(|c| c.shared == 0 && c.details.creation_time >= origin_time);
+    if devices.is_empty() {
+        return Err(anyhow!(
+            "no device looks like an external snapshot of origin {}, i.e., sharing no data \
+             blocks with it and created at time {} or later",
+            origin,
+            origin_time
+        ));
+    }
+    for c in &devices {
+        report.info(&c.describe(origin, origin_time));
+    }
+    let ids: Vec<String> = devices.iter().map(|c| c.dev_id.to_string()).collect();
+    Err(anyhow!(
+        "devices that could be the external snapshot of origin {}: {}; \
+         pair one with it by --snapshot",
+        origin,
+        ids.join(", ")
+    ))
+}
+
+//------------------------------------------
@@ -0,0 +1,140 @@
+use anyhow::Result;
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use thinp::file_utils;
+use thinp::io_engine::BLOCK_SIZE;
+use thinp::report::Report;
+
+//------------------------------------------
+
+// Sets up a tiny io_uring to see if the kernel allows it
+fn probe_io_uring() -> io::Result<()> {
+    let mut params = [0u8; 120]; // struct io_uring_params
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, 1u32, params.as_mut_ptr()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { libc::close(fd as libc::c_int) };
+    Ok(())
+}
+
+fn probe_o_direct(path: &Path) -> io::Result<()> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+        .map(|_| ())
+}
+
+fn get_nofile_limit() -> io::Result<(libc::rlim_t, libc::rlim_t)> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((rlim.rlim_cur, rlim.rlim_max))
+}
+
+fn get_page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+// Free space of the filesystem holding the path
+fn get_free_space(path: &Path) -> io::Result<u64> {
+    let dir = if path.exists() {
+        path
+    } else {
+        match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        }
+    };
+    let c_path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(st.f_bavail as u64 * st.f_frsize as u64)
+}
+
+fn find_in_path(tool: &str) -> Option<std::path::PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(tool))
+        .find(|p| p.is_file())
+}
+
+//------------------------------------------
+
+const TOOLS: [&str; 5] = ["lvm", "dmsetup", "thin_check", "thin_dump", "thin_restore"];
+
+// Reports the host capabilities behind the usual merge failures. Problems are
+// reported as warnings, leaving the decision to the user.
+pub fn run_doctor(input: Option<&Path>, output: Option<&Path>, report: Arc<Report>) -> Result<()> {
+    match probe_io_uring() {
+        Ok(()) => report.info("io_uring: available"),
+        Err(e) => report.warning(&format!("io_uring: unavailable ({}), use sync io", e)),
+    }
+
+    for (name, path) in [("input", input), ("output", output)] {
+        let Some(path) = path else {
+            continue;
+        };
+        if !path.exists() {
+            report.warning(&format!("{} {}: not found", name, path.display()));
+            continue;
+        }
+        match probe_o_direct(path) {
+            Ok(()) => report.info(&format!("O_DIRECT on {}: supported", path.display())),
+            Err(e) => report.warning(&format!(
+                "O_DIRECT on {}: unsupported ({})",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    match get_nofile_limit() {
+        Ok((soft, hard)) => report.info(&format!("max open files: {} (hard {})", soft, hard)),
+        Err(e) => report.warning(&format!("max open files: unknown ({})", e)),
+    }
+
+    let page_size = get_page_size();
+    let msg = format!(
+        "page size: {}, metadata block size: {}",
+        page_size, BLOCK_SIZE
+    );
+    if page_size as usize > BLOCK_SIZE {
+        report.warning(&format!("{}, direct io might be refused", msg));
+    } else {
+        report.info(&msg);
+    }
+
+    if let Some(output) = output {
+        if let Ok(size) = file_utils::file_size(output) {
+            report.info(&format!("output size: {} bytes", size));
+        }
+        match get_free_space(output) {
+            Ok(free) => report.info(&format!("free space at the output: {} bytes", free)),
+            Err(e) => report.warning(&format!("free space at the output: unknown ({})", e)),
+        }
+    }
+
+    for tool in TOOLS {
+        match find_in_path(tool) {
+            Some(p) => report.info(&format!("{}: {}", tool, p.display())),
+            None => report.warning(&format!("{}: not found in PATH", tool)),
+        }
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
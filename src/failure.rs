@@ -0,0 +1,73 @@
+use std::fmt;
+use thinp::pdata::btree::BTreeError;
+
+//------------------------------------------
+
+/// The classes of failures told apart by the exit code, so the scripts driving
+/// the merge could retry the ones worth retrying
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    Usage,           // the options don't make sense together
+    InputNotFound,   // the input metadata is missing
+    CorruptMetadata, // the input metadata is damaged
+    NoSpace,         // the output is too small, and could be grown
+    VerifyFailed,    // the output doesn't match the merge
+    Cancelled,       // the caller of the library cancelled the merge
+}
+
+impl FailureKind {
+    /// The exit code of the class, where 1 is left to the other failures and 2
+    /// matches the usage errors caught by the argument parser
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Usage => 2,
+            FailureKind::InputNotFound => 3,
+            FailureKind::CorruptMetadata => 4,
+            FailureKind::NoSpace => 5,
+            FailureKind::VerifyFailed => 6,
+            FailureKind::Cancelled => 7,
+        }
+    }
+}
+
+/// An error tagged with its class, displayed as the error itself
+#[derive(Debug)]
+pub struct Failure {
+    kind: FailureKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Tags the error with its class
+pub fn fail(kind: FailureKind, error: impl Into<anyhow::Error>) -> anyhow::Error {
+    Failure {
+        kind,
+        error: error.into(),
+    }
+    .into()
+}
+
+/// Classifies the error by the first tag along its chain, or by the errors of
+/// the metadata beneath it. The io errors aren't classified, as the same errno
+/// comes from the input, the output, or any other file named by the options,
+/// so the failures are tagged where they happen instead.
+pub fn classify(e: &anyhow::Error) -> Option<FailureKind> {
+    for cause in e.chain() {
+        if let Some(f) = cause.downcast_ref::<Failure>() {
+            return Some(f.kind);
+        }
+        if cause.downcast_ref::<BTreeError>().is_some() {
+            return Some(FailureKind::CorruptMetadata);
+        }
+    }
+    None
+}
+
+//------------------------------------------
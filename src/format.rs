@@ -0,0 +1,469 @@
+use anyhow::{anyhow, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::Arc;
+use thinp::io_engine::*;
+use thinp::pdata::space_map::metadata::core_metadata_sm;
+use thinp::report::Report;
+use thinp::thin::dump::dump_metadata;
+use thinp::thin::ir::{self, MetadataVisitor, Visit};
+use thinp::thin::metadata::build_metadata;
+use thinp::thin::restore::Restorer;
+use thinp::thin::superblock::*;
+use thinp::thin::xml;
+use thinp::write_batcher::WriteBatcher;
+
+use crate::memory::*;
+use crate::temp::TempPath;
+
+//------------------------------------------
+
+const SUPERBLOCK_MAGIC: u64 = 27022010;
+const PACK_MAGIC: u64 = 0xa537a0aa6309ef77;
+const ZSTD_MAGIC: u32 = 0xfd2fb528;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataFormat {
+    Binary,
+    Xml,
+    Pack,
+    Jsonl,
+    Zstd,
+}
+
+struct FormatEntry {
+    format: MetadataFormat,
+    name: &'static str,
+    extensions: &'static [&'static str],
+    sniff: fn(&[u8]) -> bool,
+    readable: bool,
+    writable: bool,
+}
+
+fn sniff_binary(buf: &[u8]) -> bool {
+    buf.len() >= 40 && u64::from_le_bytes(buf[32..40].try_into().unwrap()) == SUPERBLOCK_MAGIC
+}
+
+fn sniff_pack(buf: &[u8]) -> bool {
+    buf.len() >= 8 && u64::from_le_bytes(buf[0..8].try_into().unwrap()) == PACK_MAGIC
+}
+
+fn sniff_zstd(buf: &[u8]) -> bool {
+    buf.len() >= 4 && u32::from_le_bytes(buf[0..4].try_into().unwrap()) == ZSTD_MAGIC
+}
+
+fn first_char(buf: &[u8]) -> Option<u8> {
+    buf.iter().copied().find(|c| !c.is_ascii_whitespace())
+}
+
+fn sniff_xml(buf: &[u8]) -> bool {
+    first_char(buf) == Some(b'<')
+}
+
+fn sniff_jsonl(buf: &[u8]) -> bool {
+    first_char(buf) == Some(b'{')
+}
+
+// The known metadata formats. New formats only need an entry here, and the
+// conversions in stage_input() and export().
+pub struct FormatRegistry {
+    entries: &'static [FormatEntry],
+}
+
+pub static REGISTRY: FormatRegistry = FormatRegistry {
+    entries: &[
+        FormatEntry {
+            format: MetadataFormat::Binary,
+            name: "binary",
+            extensions: &[],
+            sniff: sniff_binary,
+            readable: true,
+            writable: true,
+        },
+        FormatEntry {
+            format: MetadataFormat::Xml,
+            name: "xml",
+            extensions: &["xml"],
+            sniff: sniff_xml,
+            readable: true,
+            writable: true,
+        },
+        FormatEntry {
+            format: MetadataFormat::Pack,
+            name: "pack",
+            extensions: &["pack"],
+            sniff: sniff_pack,
+            readable: true,
+            writable: true,
+        },
+        FormatEntry {
+            format: MetadataFormat::Jsonl,
+            name: "jsonl",
+            extensions: &["jsonl"],
+            sniff: sniff_jsonl,
+            readable: false,
+            writable: false,
+        },
+        FormatEntry {
+            format: MetadataFormat::Zstd,
+            name: "zstd",
+            extensions: &["zst"],
+            sniff: sniff_zstd,
+            readable: cfg!(feature = "zstd"),
+            writable: false,
+        },
+    ],
+};
+
+impl FormatRegistry {
+    fn entry(&self, format: MetadataFormat) -> &FormatEntry {
+        self.entries.iter().find(|e| e.format == format).unwrap()
+    }
+
+    pub fn name(&self, format: MetadataFormat) -> &'static str {
+        self.entry(format).name
+    }
+
+    pub fn writable_names(&self) -> Vec<&'static str> {
+        self.entries
+            .iter()
+            .filter(|e| e.writable)
+            .map(|e| e.name)
+            .collect()
+    }
+
+    pub fn from_name(&self, name: &str) -> Result<MetadataFormat> {
+        self.entries
+            .iter()
+            .find(|e| e.name == name)
+            .map(|e| e.format)
+            .ok_or_else(|| anyhow!("unknown metadata format '{}'", name))
+    }
+
+    fn sniff_buf(&self, buf: &[u8]) -> Option<MetadataFormat> {
+        self.entries
+            .iter()
+            .find(|e| (e.sniff)(buf))
+            .map(|e| e.format)
+    }
+
+    // Detects the format from the content, returns None if unrecognized
+    pub fn sniff(&self, path: &Path) -> Result<Option<MetadataFormat>> {
+        if is_stream(path)? {
+            return Ok(None); // peeking would consume the stream
+        }
+        let mut buf = Vec::with_capacity(BLOCK_SIZE);
+        File::open(path)?
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut buf)?;
+        Ok(self.sniff_buf(&buf))
+    }
+
+    // Unrecognized content is taken as binary, leaving the superblock validation to
+    // report damaged metadata
+    pub fn detect_input(&self, path: &Path) -> Result<MetadataFormat> {
+        let format = self.sniff(path)?.unwrap_or(MetadataFormat::Binary);
+        if !self.entry(format).readable {
+            return Err(anyhow!("{} input is not supported", self.name(format)));
+        }
+        Ok(format)
+    }
+
+    // Selects the output format by the extension, defaulting to binary
+    pub fn detect_output(&self, path: &Path) -> MetadataFormat {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        self.entries
+            .iter()
+            .find(|e| e.writable && e.extensions.contains(&ext))
+            .map_or(MetadataFormat::Binary, |e| e.format)
+    }
+}
+
+//------------------------------------------
+
+// Named pipes and character devices could only be read or written sequentially
+pub fn is_stream(path: &Path) -> Result<bool> {
+    let ft = std::fs::metadata(path)?.file_type();
+    Ok(ft.is_fifo() || ft.is_char_device())
+}
+
+// Tells the output given as -, written to stdout
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// Converts non-binary input into a temporary binary copy. The in-memory copy of
+// xml input takes the size of the binary output. Seekable zstd images are read
+// in place instead.
+pub fn stage_input(
+    path: &Path,
+    format: MetadataFormat,
+    output_blocks: Option<u64>,
+    report: Arc<Report>,
+) -> Result<(Arc<dyn IoEngine + Send + Sync>, Option<TempPath>)> {
+    match format {
+        MetadataFormat::Pack => {
+            let tmp = TempPath::new("unpacked")?;
+            thinp::pack::toplevel::unpack(path, tmp.path())?;
+            let engine = Arc::new(SyncIoEngine::new(tmp.path(), false)?);
+            Ok((engine, Some(tmp)))
+        }
+        MetadataFormat::Xml => {
+            let engine = restore_xml(File::open(path)?, output_blocks, report)?;
+            Ok((engine, None))
+        }
+        // read in place, decompressing the frames on demand
+        #[cfg(feature = "zstd")]
+        MetadataFormat::Zstd => {
+            let engine = Arc::new(crate::seekable::SeekableIoEngine::new(path)?);
+            Ok((engine, None))
+        }
+        _ => Err(anyhow!("unable to stage {} input", REGISTRY.name(format))),
+    }
+}
+
+fn restore_xml<R: Read>(
+    input: R,
+    output_blocks: Option<u64>,
+    report: Arc<Report>,
+) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+    let nr_blocks =
+        output_blocks.ok_or_else(|| anyhow!("xml input from a stream requires a binary output"))?;
+    let engine = zeroed_engine(nr_blocks)?;
+    let sm = core_metadata_sm(nr_blocks, u32::MAX);
+    let mut w = WriteBatcher::new(engine.clone(), sm, engine.get_batch_size());
+    let mut restorer = Restorer::new(&mut w, report);
+    xml::read(BufReader::new(input), &mut restorer)?;
+    Ok(engine)
+}
+
+// Counts what the binary copy of xml input has to hold
+#[derive(Default)]
+struct XmlSizer {
+    nr_data_blocks: u64,
+    nr_devices: u64,
+    mapped_blocks: u64,
+}
+
+impl MetadataVisitor for XmlSizer {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.nr_data_blocks = sb.nr_data_blocks;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, _d: &ir::Device) -> Result<Visit> {
+        self.nr_devices += 1;
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.mapped_blocks += m.len;
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+// Estimates the metadata blocks for restoring the xml file, for outputs that
+// don't tell the size, e.g., xml or pack. Assumes half-full leaves of 16-byte
+// entries, plus the data space map, with a fixed margin for the small pools.
+pub fn estimate_xml_blocks(path: &Path) -> Result<u64> {
+    let mut sizer = XmlSizer::default();
+    xml::read(BufReader::new(File::open(path)?), &mut sizer)?;
+
+    let entries_per_block = (BLOCK_SIZE / 16 / 2) as u64;
+    let leaves = sizer.mapped_blocks / entries_per_block + sizer.nr_devices;
+    let bitmaps = sizer.nr_data_blocks / (BLOCK_SIZE as u64 * 4) + 1;
+    Ok(2 * (leaves + bitmaps) + 1024)
+}
+
+// Reads xml from a named pipe or the like, where only the prefix read for
+// detecting the format could be examined. If the output doesn't tell the size,
+// e.g., xml piped out too, the stream is staged in a temporary file to be
+// sized first, rather than held in memory.
+pub fn stage_stream(
+    path: &Path,
+    output_blocks: Option<u64>,
+    report: Arc<Report>,
+) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+    let mut input = File::open(path)?;
+    let mut prefix = Vec::with_capacity(BLOCK_SIZE);
+    (&mut input)
+        .take(BLOCK_SIZE as u64)
+        .read_to_end(&mut prefix)?;
+
+    match REGISTRY.sniff_buf(&prefix) {
+        Some(MetadataFormat::Xml) if output_blocks.is_none() => {
+            let tmp = TempPath::new("stream")?;
+            let mut out = OpenOptions::new().write(true).open(tmp.path())?;
+            out.write_all(&prefix)?;
+            std::io::copy(&mut input, &mut out)?;
+            drop(out);
+            let nr_blocks = estimate_xml_blocks(tmp.path())?;
+            restore_xml(File::open(tmp.path())?, Some(nr_blocks), report)
+        }
+        Some(MetadataFormat::Xml) => {
+            restore_xml(Cursor::new(prefix).chain(input), output_blocks, report)
+        }
+        _ => Err(anyhow!(
+            "only xml input could be read from {}",
+            path.display()
+        )),
+    }
+}
+
+// The uuid sits past the checksum, the flags and the block number of the
+// superblock. Neither the reader nor the writer of the superblock keep it.
+pub const UUID_OFFSET: usize = 16;
+pub const UUID_SIZE: usize = 16;
+
+/// Reads the uuid of the binary superblock
+pub fn read_uuid(engine: &dyn IoEngine) -> Result<[u8; UUID_SIZE]> {
+    let b = engine.read(SUPERBLOCK_LOCATION)?;
+    let mut uuid = [0; UUID_SIZE];
+    uuid.copy_from_slice(&b.get_data()[UUID_OFFSET..UUID_OFFSET + UUID_SIZE]);
+    Ok(uuid)
+}
+
+/// Writes the superblock along with the given uuid, if any, at once
+pub fn write_superblock_with_uuid(
+    engine: &dyn IoEngine,
+    sb: &Superblock,
+    uuid: Option<&[u8; UUID_SIZE]>,
+) -> Result<()> {
+    let b = Block::zeroed(SUPERBLOCK_LOCATION);
+    pack_superblock(sb, &mut Cursor::new(b.get_data()))?;
+    if let Some(uuid) = uuid {
+        b.get_data()[UUID_OFFSET..UUID_OFFSET + UUID_SIZE].copy_from_slice(uuid);
+    }
+    thinp::checksum::write_checksum(b.get_data(), thinp::checksum::BT::SUPERBLOCK)?;
+    engine.write(&b)?;
+    Ok(())
+}
+
+// The uuid as the xml carries it, without the zeroes padding it
+fn uuid_string(uuid: &[u8; UUID_SIZE]) -> String {
+    let len = uuid.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    String::from_utf8_lossy(&uuid[..len]).into_owned()
+}
+
+// Passes the metadata through, but for the uuid of the superblock, which the
+// dump of binary metadata leaves empty
+struct WithUuid<'a> {
+    inner: &'a mut dyn MetadataVisitor,
+    uuid: String,
+}
+
+impl MetadataVisitor for WithUuid<'_> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(&ir::Superblock {
+            uuid: self.uuid.clone(),
+            ..sb.clone()
+        })
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.inner.def_shared_b(name)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.inner.def_shared_e()
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.inner.device_b(d)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.inner.device_e()
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.inner.map(m)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.inner.ref_shared(name)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.inner.eof()
+    }
+}
+
+// Writes the binary metadata held by the engine in the given format
+pub fn export(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    format: MetadataFormat,
+    path: &Path,
+) -> Result<()> {
+    match format {
+        MetadataFormat::Xml => {
+            let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+            let md = build_metadata(engine.clone(), &sb)?;
+            let out: Box<dyn Write> = if is_stdout(path) {
+                Box::new(std::io::stdout().lock())
+            } else {
+                Box::new(
+                    OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(path)?,
+                )
+            };
+            let mut w = xml::XmlWriter::new(BufWriter::new(out));
+            let mut v = WithUuid {
+                inner: &mut w,
+                uuid: uuid_string(&read_uuid(engine.as_ref())?),
+            };
+            dump_metadata(engine, &mut v as &mut dyn MetadataVisitor, &sb, &md)
+        }
+        // the packer reads only from a file, so packing straight from the
+        // engine is out of scope. The file is written sparse rather than at
+        // the full size of the output.
+        MetadataFormat::Pack => {
+            let tmp = TempPath::new("packing")?;
+            let out = OpenOptions::new().write(true).open(tmp.path())?;
+            save_engine_sparse(engine.as_ref(), &out)?;
+            // the packer takes nothing but a path
+            let path = if is_stdout(path) {
+                Path::new("/dev/stdout")
+            } else {
+                path
+            };
+            thinp::pack::toplevel::pack(tmp.path(), path)
+        }
+        _ => Err(anyhow!("unable to export {} output", REGISTRY.name(format))),
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,368 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use thinp::commands::engine::EngineOptions;
+use thinp::report::Report;
+
+use crate::failure::{classify, fail, FailureKind};
+use crate::merge::{merge_thins_with_summary, ThinMergeOptions};
+use crate::schema::Versioned;
+use crate::summary::RunSummary;
+
+//------------------------------------------
+
+/// One merge of a job file
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub origin: Option<u64>,
+    pub snapshot: Option<u64>,
+    pub rebase: bool,
+}
+
+/// The merges listed by a job file, run the given number at a time
+#[derive(Debug)]
+pub struct JobFile {
+    pub parallel: usize,
+    pub continue_on_failure: bool, // or start no job once one fails
+    pub manifest: Option<PathBuf>, // where the results of the jobs are written
+    pub jobs: Vec<Job>,
+}
+
+/// How a job ended, where a skipped one never started as an earlier one failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Done,
+    Failed,
+    Skipped,
+}
+
+/// The result of a job, as listed by the manifest
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobResult {
+    pub job: usize, // counted from 1, in the order of the job file
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub status: JobStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<RunSummary>, // of a job done
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>, // of a job failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>, // the job would have exited with alone
+}
+
+impl JobResult {
+    fn new(idx: usize, job: &Job, status: JobStatus) -> Self {
+        Self {
+            job: idx + 1,
+            input: job.input.clone(),
+            output: job.output.clone(),
+            status,
+            summary: None,
+            error: None,
+            exit_code: None,
+        }
+    }
+}
+
+/// The results of the jobs of a run, in the order of the job file
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobManifest {
+    pub nr_done: usize,
+    pub nr_failed: usize,
+    pub nr_skipped: usize,
+    pub jobs: Vec<JobResult>,
+}
+
+impl JobManifest {
+    pub fn to_json(&self) -> String {
+        Versioned::new(self).to_json()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_json() + "\n")
+            .map_err(|e| anyhow!("unable to write the job manifest {}: {}", path.display(), e))
+    }
+}
+
+// A device id, or none for an origin taken as lost, as for --origin
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawId {
+    Id(u64),
+    Name(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawJob {
+    input: PathBuf,
+    output: PathBuf,
+    origin: RawId,
+    snapshot: Option<u64>,
+    #[serde(default)]
+    rebase: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawJobFile {
+    parallel: Option<usize>,
+    continue_on_failure: Option<bool>,
+    manifest: Option<PathBuf>,
+    #[serde(default)]
+    job: Vec<RawJob>,
+}
+
+impl TryFrom<RawJob> for Job {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawJob) -> Result<Job> {
+        let origin = match raw.origin {
+            RawId::Id(id) => Some(id),
+            RawId::Name(name) if name == "none" => None,
+            RawId::Name(name) => {
+                return Err(anyhow!("origin takes a device id or none, not '{}'", name))
+            }
+        };
+        Ok(Job {
+            input: raw.input,
+            output: raw.output,
+            origin,
+            snapshot: raw.snapshot,
+            rebase: raw.rebase,
+        })
+    }
+}
+
+// Resolves the output to compare the jobs by, where the file might not exist
+// yet, but its directory does
+fn canonical_output(path: &Path) -> PathBuf {
+    if let Ok(path) = std::fs::canonicalize(path) {
+        return path;
+    }
+    let parent = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match (std::fs::canonicalize(parent), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+impl JobFile {
+    /// Parses a job file in TOML: an optional `parallel` count,
+    /// `continue_on_failure` flag and `manifest` path, then a `[[job]]` table
+    /// per merge with the input, output and origin, and optionally the snapshot
+    /// and rebase. An origin of "none" is taken as lost, as for --origin.
+    pub fn parse(text: &str) -> Result<JobFile> {
+        let raw: RawJobFile = toml::from_str(text).map_err(|e| anyhow!("{}", e))?;
+        if raw.parallel == Some(0) {
+            return Err(anyhow!("parallel takes a positive integer"));
+        }
+        let jobs = raw
+            .job
+            .into_iter()
+            .enumerate()
+            .map(|(i, job)| Job::try_from(job).map_err(|e| anyhow!("job {}: {}", i + 1, e)))
+            .collect::<Result<Vec<_>>>()?;
+
+        if jobs.is_empty() {
+            return Err(anyhow!("no job is listed"));
+        }
+
+        // parallel jobs writing the same output would corrupt each other,
+        // whatever the paths they're given by
+        let outputs: Vec<PathBuf> = jobs.iter().map(|j| canonical_output(&j.output)).collect();
+        for (i, output) in outputs.iter().enumerate() {
+            if let Some(j) = outputs[..i].iter().position(|other| other == output) {
+                return Err(anyhow!(
+                    "jobs {} and {} both write {}",
+                    j + 1,
+                    i + 1,
+                    jobs[i].output.display()
+                ));
+            }
+        }
+
+        Ok(JobFile {
+            parallel: raw.parallel.unwrap_or(1),
+            continue_on_failure: raw.continue_on_failure.unwrap_or(true),
+            manifest: raw.manifest,
+            jobs,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<JobFile> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("unable to read the job file {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| anyhow!("invalid job file {}: {}", path.display(), e))
+    }
+}
+
+// The device or file the metadata is read from, however the path names it
+fn input_identity(path: &Path) -> Option<(u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    if meta.file_type().is_block_device() {
+        Some((0, meta.rdev()))
+    } else {
+        Some((meta.dev(), meta.ino()))
+    }
+}
+
+// Jobs reading the metadata snapshot of the same pool side by side would race
+// on reserving and releasing it, as a pool holds a single one
+fn check_distinct_pools(jobs: &[Job]) -> Result<()> {
+    let inputs: Vec<_> = jobs.iter().map(|j| input_identity(&j.input)).collect();
+    for (i, input) in inputs.iter().enumerate() {
+        let Some(input) = input else {
+            continue;
+        };
+        if let Some(j) = inputs[..i]
+            .iter()
+            .position(|other| other.as_ref() == Some(input))
+        {
+            return Err(fail(
+                FailureKind::Usage,
+                anyhow!(
+                    "jobs {} and {} both read the metadata snapshot of {}, which can't be shared by parallel jobs",
+                    j + 1,
+                    i + 1,
+                    jobs[i].input.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+//------------------------------------------
+
+// Runs a job alone, as the merge of its pair would, to its result along with
+// the class of its failure, if any
+fn run_job(
+    idx: usize,
+    job: &Job,
+    engine_opts: &EngineOptions,
+    report: &Arc<Report>,
+) -> (JobResult, Option<FailureKind>) {
+    report.info(&format!(
+        "job {}: merging {} into {}",
+        idx + 1,
+        job.input.display(),
+        job.output.display()
+    ));
+
+    let mut opts =
+        ThinMergeOptions::from_paths(&job.input, &job.output, report.clone(), job.origin);
+    opts.engine_opts = engine_opts.clone();
+    opts.snapshot = job.snapshot;
+    opts.rebase = job.rebase;
+
+    let mut result = JobResult::new(idx, job, JobStatus::Done);
+    let mut kind = None;
+    match merge_thins_with_summary(opts) {
+        Ok(summary) => {
+            report.info(&format!("job {}: done", idx + 1));
+            result.summary = Some(summary);
+        }
+        Err(e) => {
+            report.warning(&format!("job {}: failed: {}", idx + 1, e));
+            kind = classify(&e);
+            result.status = JobStatus::Failed;
+            result.error = Some(format!("{:#}", e));
+            result.exit_code = Some(kind.map_or(1, FailureKind::exit_code));
+        }
+    }
+    (result, kind)
+}
+
+// The run fails with the class shared by all the jobs failed, if any, so the
+// exit code tells as much as a single merge would
+fn run_failure(manifest: &JobManifest, kinds: &[Option<FailureKind>]) -> anyhow::Error {
+    let mut msg = format!(
+        "{} of {} jobs failed",
+        manifest.nr_failed,
+        manifest.jobs.len()
+    );
+    if manifest.nr_skipped > 0 {
+        msg += &format!(", {} skipped", manifest.nr_skipped);
+    }
+    let error = anyhow!(msg);
+    match kinds.first() {
+        Some(&Some(kind)) if kinds.iter().all(|k| *k == Some(kind)) => fail(kind, error),
+        _ => error,
+    }
+}
+
+/// Runs the jobs, up to the parallel count at a time, sharing the report. A
+/// failed job doesn't stop the others unless continue_on_failure is off, in
+/// which case the jobs not started yet are skipped. The results of the jobs
+/// are written to the manifest, if any, and the run fails once all end if
+/// any job failed. Each job cleans up after itself alone, should it panic.
+pub fn run_jobs(file: &JobFile, engine_opts: &EngineOptions, report: Arc<Report>) -> Result<()> {
+    let next = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let results = Mutex::new(vec![None; file.jobs.len()]);
+    let kinds = Mutex::new(Vec::new());
+    let nr_workers = std::cmp::min(file.parallel, file.jobs.len());
+    if nr_workers > 1 && engine_opts.use_metadata_snap {
+        check_distinct_pools(&file.jobs)?;
+    }
+
+    thread::scope(|s| {
+        for _ in 0..nr_workers {
+            s.spawn(|| loop {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = file.jobs.get(idx) else {
+                    break;
+                };
+
+                let (result, kind) = run_job(idx, job, engine_opts, &report);
+                if result.status == JobStatus::Failed {
+                    kinds.lock().unwrap().push(kind);
+                    if !file.continue_on_failure {
+                        stopped.store(true, Ordering::Relaxed);
+                    }
+                }
+                results.lock().unwrap()[idx] = Some(result);
+            });
+        }
+    });
+
+    let mut manifest = JobManifest::default();
+    for (idx, result) in results.into_inner().unwrap().into_iter().enumerate() {
+        let result = result.unwrap_or_else(|| {
+            report.warning(&format!("job {}: skipped", idx + 1));
+            JobResult::new(idx, &file.jobs[idx], JobStatus::Skipped)
+        });
+        match result.status {
+            JobStatus::Done => manifest.nr_done += 1,
+            JobStatus::Failed => manifest.nr_failed += 1,
+            JobStatus::Skipped => manifest.nr_skipped += 1,
+        }
+        manifest.jobs.push(result);
+    }
+    if let Some(path) = &file.manifest {
+        manifest.write(path)?;
+    }
+
+    if manifest.nr_failed > 0 {
+        return Err(run_failure(&manifest, &kinds.into_inner().unwrap()));
+    }
+    report.info(&format!("all {} jobs done", file.jobs.len()));
+    Ok(())
+}
+
+//------------------------------------------
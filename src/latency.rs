@@ -0,0 +1,245 @@
+use std::io::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thinp::io_engine::*;
+
+//------------------------------------------
+
+const NR_BUCKETS: usize = 40;
+
+// Latencies bucketed by powers of two in microseconds, where the bucket i holds
+// latencies below 2^i us. Coarse, but cheap enough to record every write.
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; NR_BUCKETS],
+    max: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let idx = std::cmp::min((u64::BITS - us.leading_zeros()) as usize, NR_BUCKETS - 1);
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(us, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    // Returns the upper bound of the bucket holding the given percentile, in us
+    pub fn percentile(&self, p: u64) -> Option<u64> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let target = (total * p).div_ceil(100);
+        let mut seen = 0;
+        for (i, b) in self.buckets.iter().enumerate() {
+            seen += b.load(Ordering::Relaxed);
+            if seen >= target {
+                return Some(1 << i);
+            }
+        }
+        None
+    }
+
+    pub fn summary(&self) -> String {
+        let p = |n| self.percentile(n).unwrap_or(0);
+        format!(
+            "{} writes, p50 < {}us, p95 < {}us, p99 < {}us, max {}us",
+            self.count(),
+            p(50),
+            p(95),
+            p(99),
+            self.max.load(Ordering::Relaxed)
+        )
+    }
+}
+
+//------------------------------------------
+
+// The time the producer of runs blocks on a full channel, and the consumer on
+// an empty one. The side waiting longer is the faster one, so it tells which
+// io engine holds back the merge, or whether the channel is too shallow.
+#[derive(Default)]
+pub struct ChannelStalls {
+    send_us: AtomicU64,
+    nr_sends: AtomicU64,
+    recv_us: AtomicU64,
+    nr_recvs: AtomicU64,
+}
+
+impl ChannelStalls {
+    pub fn record_send(&self, waited: Duration) {
+        self.send_us
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        self.nr_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Timeouts of the receiver add to the wait, but aren't counted as receives
+    pub fn record_recv(&self, waited: Duration, received: bool) {
+        self.recv_us
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        if received {
+            self.nr_recvs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let send_us = self.send_us.load(Ordering::Relaxed);
+        let recv_us = self.recv_us.load(Ordering::Relaxed);
+        let bottleneck = if send_us > recv_us {
+            "the output writes are the bottleneck"
+        } else if recv_us > send_us {
+            "the input reads are the bottleneck"
+        } else {
+            "no stalls"
+        };
+        format!(
+            "reader blocked {}us over {} sends, writer waited {}us over {} receives; {}",
+            send_us,
+            self.nr_sends.load(Ordering::Relaxed),
+            recv_us,
+            self.nr_recvs.load(Ordering::Relaxed),
+            bottleneck
+        )
+    }
+}
+
+//------------------------------------------
+
+// Passes the io through to the inner engine, recording the latency of writes
+pub struct TimedIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    writes: Arc<LatencyHistogram>,
+}
+
+impl TimedIoEngine {
+    pub fn new(inner: Arc<dyn IoEngine + Send + Sync>, writes: Arc<LatencyHistogram>) -> Self {
+        Self { inner, writes }
+    }
+
+    fn timed<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let r = f();
+        self.writes.record(start.elapsed());
+        r
+    }
+}
+
+impl IoEngine for TimedIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, b: u64) -> Result<Block> {
+        self.inner.read(b)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        self.inner.read_many(blocks)
+    }
+
+    fn write(&self, block: &Block) -> Result<()> {
+        self.timed(|| self.inner.write(block))
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        self.timed(|| self.inner.write_many(blocks))
+    }
+}
+
+//------------------------------------------
+
+// The blocks read and written through an engine
+#[derive(Default)]
+pub struct IoCounters {
+    blocks_read: AtomicU64,
+    blocks_written: AtomicU64,
+}
+
+impl IoCounters {
+    pub fn bytes_read(&self) -> u64 {
+        self.blocks_read.load(Ordering::Relaxed) * BLOCK_SIZE as u64
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.blocks_written.load(Ordering::Relaxed) * BLOCK_SIZE as u64
+    }
+}
+
+// Passes the io through to the inner engine, counting the blocks transferred.
+// Failed reads and writes aren't counted.
+pub struct CountingIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    counters: Arc<IoCounters>,
+}
+
+impl CountingIoEngine {
+    pub fn new(inner: Arc<dyn IoEngine + Send + Sync>, counters: Arc<IoCounters>) -> Self {
+        Self { inner, counters }
+    }
+
+    fn count<T>(counter: &AtomicU64, results: &[Result<T>]) {
+        let nr = results.iter().filter(|r| r.is_ok()).count();
+        counter.fetch_add(nr as u64, Ordering::Relaxed);
+    }
+}
+
+impl IoEngine for CountingIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, b: u64) -> Result<Block> {
+        let block = self.inner.read(b)?;
+        self.counters.blocks_read.fetch_add(1, Ordering::Relaxed);
+        Ok(block)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        let results = self.inner.read_many(blocks)?;
+        Self::count(&self.counters.blocks_read, &results);
+        Ok(results)
+    }
+
+    fn write(&self, block: &Block) -> Result<()> {
+        self.inner.write(block)?;
+        self.counters.blocks_written.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        let results = self.inner.write_many(blocks)?;
+        Self::count(&self.counters.blocks_written, &results);
+        Ok(results)
+    }
+}
+
+//------------------------------------------
@@ -1,3 +1,55 @@
+//! Merges the mappings of a dm-thin external snapshot with its origin.
+//!
+//! The whole merge is driven by [`merge_thins`] with a [`ThinMergeOptions`].
+//! The building blocks are also exposed for tools working on the mappings
+//! directly: [`MappingIterator`] walks the runs of a list of mapping leaves,
+//! [`MappingStream`] consumes them piece by piece, and [`RangeMergeIterator`]
+//! overlays the runs of a snapshot on those of its origin. Both iterators
+//! yield their runs as `Result<(key, BlockTime, len)>` through
+//! [`Iterator`], so they compose with the standard adaptors and custom sinks.
+
+pub mod access;
+pub mod budget;
+pub mod check;
+pub mod compat;
+pub mod copy;
+pub mod diff;
+pub mod discover;
+pub mod doctor;
+pub mod failure;
+pub mod format;
+pub mod jobs;
+pub mod latency;
 pub mod mapping_iterator;
+pub mod memory;
 pub mod merge;
+pub mod pool;
+pub mod progress;
+pub mod relocate;
+pub mod remap;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod schema;
+#[cfg(feature = "zstd")]
+pub mod seekable;
+pub mod shared;
+pub mod stats;
 pub mod stream;
+pub mod summary;
+#[cfg(feature = "synth")]
+pub mod synth;
+pub mod temp;
+#[cfg(feature = "ffi")]
+pub mod thin_merge_ffi;
+pub mod trace;
+pub mod verify;
+pub mod watchdog;
+pub mod zeroed;
+
+pub use mapping_iterator::{LeafSource, MappingIterator};
+pub use merge::{
+    merge_thins, EngineChoice, MetadataLocation, RangeMergeIterator, ThinMergeOptions,
+};
+pub use progress::{ProgressEvent, ProgressSink};
+pub use stream::{MappingStream, RunSource};
+pub use watchdog::CancellationToken;
@@ -1,45 +1,332 @@
-use anyhow::Result;
-use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use thinp::checksum::{metadata_block_type, BT};
 use thinp::io_engine::Block;
 use thinp::io_engine::IoEngine;
 use thinp::pdata::btree::*;
 use thinp::pdata::unpack::Unpack;
 use thinp::thin::block_time::*;
 
+use crate::budget::Charges;
+use crate::failure::{fail, FailureKind};
+use crate::temp::spawn_scoped;
+use crate::watchdog::{Tripwire, WATCHDOG_TICK};
+
 //------------------------------------------
 
-pub struct MappingIterator {
+// A batch of leaves read, where an empty one marks the end of the leaves
+type LeafBatch = Result<Vec<Block>>;
+
+/// The leaves of a mapping tree in key order, either collected up front, or
+/// discovered by a walker as the iteration goes
+pub enum LeafSource {
+    List(Vec<u64>),
+    Stream(Receiver<Result<u64>>),
+}
+
+impl LeafSource {
+    fn into_leaves(self) -> Box<dyn Iterator<Item = Result<u64>> + Send> {
+        match self {
+            LeafSource::List(leaves) => Box::new(leaves.into_iter().map(Ok)),
+            LeafSource::Stream(rx) => Box::new(rx.into_iter()),
+        }
+    }
+}
+
+// Reads the batches of leaves in order on a thread of its own, up to the
+// given number of batches ahead of the consumer: one held by the thread until
+// taken, and the rest queued. The thread stops once the receiver is dropped,
+// or after the first failure.
+fn spawn_reader(
     engine: Arc<dyn IoEngine + Send + Sync>,
-    leaves: Vec<u64>,
+    leaves: LeafSource,
     batch_size: usize,
+    depth: usize,
+) -> Receiver<LeafBatch> {
+    let (tx, rx) = mpsc::sync_channel(depth.saturating_sub(1));
+    spawn_scoped(move || {
+        let mut leaves = leaves.into_leaves();
+        loop {
+            let chunk: Result<Vec<u64>> = leaves.by_ref().take(batch_size).collect();
+            let batch = match chunk {
+                Ok(chunk) if chunk.is_empty() => Ok(Vec::new()),
+                Ok(chunk) => {
+                    MappingIterator::read_blocks(&engine, &chunk).map_err(anyhow::Error::from)
+                }
+                Err(e) => Err(e),
+            };
+            let done = !matches!(&batch, Ok(b) if !b.is_empty());
+            if tx.send(batch).is_err() || done {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// A damaged leaf skipped by an iterator, along with the keys whose mappings
+/// are lost with it
+#[derive(Clone, Debug)]
+pub struct SkippedLeaf {
+    pub loc: u64,
+    pub error: String,
+    pub begin: u64,       // the key after the last one of the preceding leaf
+    pub end: Option<u64>, // the first key of the following leaf, if any
+}
+
+/// The damaged leaves skipped by the iterators sharing it, rather than failing
+#[derive(Default)]
+pub struct SkippedLeaves {
+    leaves: Mutex<Vec<SkippedLeaf>>,
+}
+
+impl SkippedLeaves {
+    pub fn take(&self) -> Vec<SkippedLeaf> {
+        std::mem::take(&mut *self.leaves.lock().unwrap())
+    }
+
+    fn add(&self, leaf: SkippedLeaf) {
+        self.leaves.lock().unwrap().push(leaf);
+    }
+}
+
+/// Iterates over the mappings held by a list of leaves of a mapping tree, in
+/// the order of the leaves. Leaves are read in batches of the engine, the next
+/// batch being read while the current one is consumed.
+pub struct MappingIterator {
+    batches: Option<Receiver<LeafBatch>>, // none if the leaves fit in a batch
     cached_leaves: Vec<Block>,
-    node: Node<BlockTime>,
-    nr_entries: usize, // nr_entries in the current visiting node
-    pos: [usize; 2],   // leaf index and entry index in leaf
+    node: Option<Node<BlockTime>>,
+    nr_entries: usize,     // nr_entries in the current visiting node
+    pos: [usize; 2],       // leaf index in the batch and entry index in leaf
+    done: bool,            // all the leaves are visited
+    last_key: Option<u64>, // the last key of the leaves visited
+    end: Option<u64>,      // the key the iteration stops at
+
+    // where the damaged leaves are recorded, if skipped rather than failing,
+    // along with the ones awaiting the first key of the following leaf
+    skipped: Option<Arc<SkippedLeaves>>,
+    pending: Vec<SkippedLeaf>,
+
+    tripwire: Option<Tripwire>, // polled while waiting for the reader
+    _charges: Option<Charges>,  // given back once the iterator is dropped
 }
 
 impl MappingIterator {
+    /// Starts at the first mapping of the leaves, which must not be empty.
     pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, leaves: Vec<u64>) -> Result<Self> {
         let batch_size = engine.get_batch_size();
-        let len = std::cmp::min(batch_size, leaves.len());
-        let cached_leaves = Self::read_blocks(&engine, &leaves[..len])?;
-        let node =
-            unpack_node::<BlockTime>(&[], cached_leaves[0].get_data(), true, leaves.len() > 1)?;
-        let nr_entries = Self::get_nr_entries(&node);
-
-        let pos = [0, 0];
-
-        Ok(Self {
-            engine,
-            leaves,
-            batch_size,
+        Self::with_batch_size(engine, leaves, batch_size)
+    }
+
+    /// Like new, but reads the given number of leaves at a time rather than
+    /// the batch size of the engine.
+    pub fn with_batch_size(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        leaves: Vec<u64>,
+        batch_size: usize,
+    ) -> Result<Self> {
+        Self::with_prefetch(engine, leaves, batch_size, 1)
+    }
+
+    /// Like with_batch_size, but reads up to the given number of batches
+    /// ahead of the current one rather than just the next, so the reads
+    /// proceed further ahead of a consumer that stalls now and then.
+    pub fn with_prefetch(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        leaves: Vec<u64>,
+        batch_size: usize,
+        depth: usize,
+    ) -> Result<Self> {
+        let batch_size = std::cmp::max(batch_size, 1);
+        if leaves.len() > batch_size {
+            return Self::from_source(engine, LeafSource::List(leaves), batch_size, depth);
+        }
+        let cached_leaves = Self::read_blocks(&engine, &leaves)?;
+        Self::start(None, cached_leaves, leaves.len() > 1, None, None)
+    }
+
+    /// Like with_prefetch, but takes the leaves from the given source, which
+    /// must yield at least one
+    pub fn from_source(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        leaves: LeafSource,
+        batch_size: usize,
+        depth: usize,
+    ) -> Result<Self> {
+        Self::from_source_skipping(engine, leaves, batch_size, depth, None, None)
+    }
+
+    /// Like from_source, but skips the leaves failing to unpack, or holding
+    /// keys out of order, recording them in the given list if any rather than
+    /// failing. The mappings of the keys they cover are lost. A stall of the
+    /// reads fails the iteration once the given tripwire, if any, is tripped.
+    pub fn from_source_skipping(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        leaves: LeafSource,
+        batch_size: usize,
+        depth: usize,
+        skipped: Option<Arc<SkippedLeaves>>,
+        tripwire: Option<Tripwire>,
+    ) -> Result<Self> {
+        let batch_size = std::cmp::max(batch_size, 1);
+        let batches = spawn_reader(engine, leaves, batch_size, std::cmp::max(depth, 1));
+        let cached_leaves = Self::next_batch(&batches, tripwire.as_ref())?;
+        Self::start(Some(batches), cached_leaves, true, skipped, tripwire)
+    }
+
+    /// Holds the charges for the buffers of the iterator, giving them back to
+    /// the budget along with the buffers
+    pub fn hold(&mut self, charges: Charges) {
+        self._charges = Some(charges);
+    }
+
+    fn start(
+        batches: Option<Receiver<LeafBatch>>,
+        cached_leaves: Vec<Block>,
+        ignore_non_fatal: bool,
+        skipped: Option<Arc<SkippedLeaves>>,
+        tripwire: Option<Tripwire>,
+    ) -> Result<Self> {
+        if cached_leaves.is_empty() {
+            return Err(anyhow!("the mapping tree has no leaves"));
+        }
+
+        let mut iter = Self {
+            batches,
             cached_leaves,
-            node,
-            nr_entries,
-            pos,
+            node: None,
+            nr_entries: 0,
+            pos: [0, 0],
+            done: false,
+            last_key: None,
+            end: None,
+            skipped,
+            pending: Vec::new(),
+            tripwire,
+            _charges: None,
+        };
+        iter.load_leaf(ignore_non_fatal)?;
+        Ok(iter)
+    }
+
+    // Unpacks the leaf at the current position, taking the next batch once
+    // the current one is consumed. Damaged leaves are passed over if skipping.
+    fn load_leaf(&mut self, ignore_non_fatal: bool) -> Result<()> {
+        loop {
+            if self.pos[0] == self.cached_leaves.len() {
+                let batch = match &self.batches {
+                    Some(batches) => Self::next_batch(batches, self.tripwire.as_ref())?,
+                    None => Vec::new(),
+                };
+                if batch.is_empty() {
+                    self.done = true;
+                    self.flush_skipped(None);
+                    return Ok(()); // reach the end
+                }
+                self.cached_leaves = batch;
+                self.pos[0] = 0;
+            }
+
+            let b = &self.cached_leaves[self.pos[0]];
+            let loaded = Self::unpack_leaf(b, ignore_non_fatal).and_then(|node| {
+                let last_key = Self::check_keys(&node, b.loc, self.last_key)?;
+                Ok((node, last_key))
+            });
+            match loaded {
+                Ok((node, last_key)) => {
+                    if let Node::Leaf { keys, .. } = &node {
+                        if let Some(&first) = keys.first() {
+                            self.flush_skipped(Some(first));
+                        }
+                    }
+                    self.nr_entries = Self::get_nr_entries(&node);
+                    self.node = Some(node);
+                    self.last_key = last_key;
+                    return Ok(());
+                }
+                Err(e) if self.skipped.is_some() => {
+                    self.pending.push(SkippedLeaf {
+                        loc: b.loc,
+                        error: format!("{:#}", e),
+                        begin: self.last_key.map_or(0, |k| k.saturating_add(1)),
+                        end: None,
+                    });
+                    self.pos[0] += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Records the leaves skipped since the last one visited, now the key they
+    // end at is known
+    fn flush_skipped(&mut self, end: Option<u64>) {
+        if let Some(skipped) = &self.skipped {
+            for mut leaf in self.pending.drain(..) {
+                leaf.end = end;
+                skipped.add(leaf);
+            }
+        }
+    }
+
+    // Unpacks a leaf, telling its location on failure. The checksum is checked
+    // first, as unpacking reads nothing but the header and the entries.
+    pub(crate) fn unpack_leaf(b: &Block, ignore_non_fatal: bool) -> Result<Node<BlockTime>> {
+        if !matches!(metadata_block_type(b.get_data()), BT::NODE) {
+            return Err(fail(
+                FailureKind::CorruptMetadata,
+                anyhow!("bad checksum of the mapping leaf at block {}", b.loc),
+            ));
+        }
+        unpack_node::<BlockTime>(&[], b.get_data(), true, ignore_non_fatal).map_err(|e| {
+            fail(
+                FailureKind::CorruptMetadata,
+                anyhow!("bad mapping leaf at block {}: {}", b.loc, e),
+            )
         })
     }
 
+    // Rejects keys out of order or duplicated within the leaf, or not following
+    // the keys of the preceding leaf, which would break the overlay of the runs
+    // far from the cause. Returns the last key of the leaf.
+    pub(crate) fn check_keys(
+        node: &Node<BlockTime>,
+        loc: u64,
+        last_key: Option<u64>,
+    ) -> Result<Option<u64>> {
+        let keys = match node {
+            Node::Leaf { keys, .. } => keys,
+            Node::Internal { .. } => {
+                return Err(anyhow!("the node at block {} is not a mapping leaf", loc))
+            }
+        };
+
+        if let (Some(last), Some(&first)) = (last_key, keys.first()) {
+            if first <= last {
+                return Err(anyhow!(
+                    "the mapping leaf at block {} starts at key {}, not after key {} of the preceding leaf",
+                    loc,
+                    first,
+                    last
+                ));
+            }
+        }
+        for pair in keys.windows(2) {
+            if pair[1] <= pair[0] {
+                return Err(anyhow!(
+                    "keys of the mapping leaf at block {} are out of order: key {} follows key {}",
+                    loc,
+                    pair[1],
+                    pair[0]
+                ));
+            }
+        }
+
+        Ok(keys.last().copied().or(last_key))
+    }
+
     fn read_blocks(
         engine: &Arc<dyn IoEngine + Send + Sync>,
         blocks: &[u64],
@@ -47,14 +334,36 @@ impl MappingIterator {
         engine.read_many(blocks)?.into_iter().collect()
     }
 
+    // Takes the batch read ahead, which is empty once all are taken
+    fn next_batch(
+        batches: &Receiver<LeafBatch>,
+        tripwire: Option<&Tripwire>,
+    ) -> Result<Vec<Block>> {
+        let Some(tripwire) = tripwire else {
+            return batches
+                .recv()
+                .map_err(|_| anyhow!("the leaf reader stopped early"))?;
+        };
+        loop {
+            match batches.recv_timeout(WATCHDOG_TICK) {
+                Ok(batch) => return batch,
+                Err(RecvTimeoutError::Timeout) => tripwire.check()?,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("the leaf reader stopped early"))
+                }
+            }
+        }
+    }
+
+    /// Returns the current mapping, or None once all the leaves are visited.
     pub fn get(&self) -> Option<(u64, &BlockTime)> {
-        if self.pos[0] < self.leaves.len() {
-            match &self.node {
+        if !self.done {
+            match self.node.as_ref()? {
                 Node::Internal { .. } => {
                     panic!("not a leaf");
                 }
                 Node::Leaf { keys, values, .. } => {
-                    if keys.is_empty() {
+                    if keys.is_empty() || self.end.is_some_and(|e| keys[self.pos[1]] >= e) {
                         None
                     } else {
                         Some((keys[self.pos[1]], &values[self.pos[1]]))
@@ -74,7 +383,7 @@ impl MappingIterator {
     }
 
     fn inc_pos(&mut self) -> bool {
-        if self.pos[0] < self.leaves.len() {
+        if !self.done {
             self.pos[1] += 1;
             self.pos[1] >= self.nr_entries
         } else {
@@ -85,26 +394,10 @@ impl MappingIterator {
     fn next_node(&mut self) -> Result<()> {
         self.pos[0] += 1;
         self.pos[1] = 0;
-
-        if self.pos[0] == self.leaves.len() {
-            return Ok(()); // reach the end
-        }
-
-        let idx = self.pos[0] % self.batch_size;
-
-        // FIXME: reuse the code in the constructor
-        if idx == 0 {
-            let endpos = std::cmp::min(self.pos[0] + self.batch_size, self.leaves.len());
-            self.cached_leaves =
-                Self::read_blocks(&self.engine, &self.leaves[self.pos[0]..endpos])?;
-        }
-
-        self.node = unpack_node::<BlockTime>(&[], self.cached_leaves[idx].get_data(), true, true)?;
-        self.nr_entries = Self::get_nr_entries(&self.node);
-
-        Ok(())
+        self.load_leaf(true)
     }
 
+    /// Moves to the next mapping.
     pub fn step(&mut self) -> Result<()> {
         if self.inc_pos() {
             self.next_node()?;
@@ -112,6 +405,22 @@ impl MappingIterator {
         Ok(())
     }
 
+    /// Limits the iteration to the keys within the half-open range, moving
+    /// past the mappings before its beginning. The leaves given are expected
+    /// to start around the beginning, as the mappings are skipped one by one.
+    pub fn restrict(&mut self, begin: u64, end: u64) -> Result<()> {
+        self.end = Some(end);
+        while let Some((key, _)) = self.get() {
+            if key >= begin {
+                break;
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the next run of contiguous mappings of the same time, as the
+    /// tuple of the key, the first data block, and the length.
     pub fn next_range(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
         let mut mapping: Option<(u64, BlockTime)> = None;
         let mut len = 0;
@@ -119,7 +428,10 @@ impl MappingIterator {
         while let Some((key, &bt)) = self.get() {
             match mapping {
                 Some(m) => {
-                    if m.0 + len == key && m.1.block + len == bt.block && m.1.time == bt.time {
+                    if m.0.checked_add(len) == Some(key)
+                        && m.1.block.checked_add(len) == Some(bt.block)
+                        && m.1.time == bt.time
+                    {
                         len += 1;
                         self.step()?;
                     } else {
@@ -142,4 +454,21 @@ impl MappingIterator {
     }
 }
 
+// The leaves skipped past the end of an iteration stopped early are recorded
+// as lost up to the end of the tree
+impl Drop for MappingIterator {
+    fn drop(&mut self) {
+        self.flush_skipped(None);
+    }
+}
+
+/// Yields the runs as next_range does
+impl Iterator for MappingIterator {
+    type Item = Result<(u64, BlockTime, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_range().transpose()
+    }
+}
+
 //------------------------------------------
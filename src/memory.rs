@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use thinp::io_engine::core::CoreIoEngine;
+use thinp::io_engine::*;
+
+//------------------------------------------
+
+// Creates a RAM-backed engine holding a copy of the given metadata
+pub fn load_engine(data: &[u8]) -> Result<Arc<CoreIoEngine>> {
+    if !data.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(anyhow!(
+            "metadata size {} is not a multiple of the block size",
+            data.len()
+        ));
+    }
+
+    let engine = Arc::new(CoreIoEngine::new((data.len() / BLOCK_SIZE) as u64));
+    for (b, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+        let blk = Block::new(b as u64);
+        blk.get_data().copy_from_slice(chunk);
+        engine.write(&blk)?;
+    }
+
+    Ok(engine)
+}
+
+// Creates a zeroed RAM-backed engine to hold the output
+pub fn zeroed_engine(nr_blocks: u64) -> Result<Arc<CoreIoEngine>> {
+    let engine = Arc::new(CoreIoEngine::new(nr_blocks));
+    for b in 0..nr_blocks {
+        engine.write(&Block::zeroed(b))?;
+    }
+    Ok(engine)
+}
+
+// An engine swallowing every write, for dry runs. Reads fail, as nothing is kept.
+pub struct DiscardIoEngine {
+    nr_blocks: u64,
+}
+
+impl DiscardIoEngine {
+    pub fn new(nr_blocks: u64) -> DiscardIoEngine {
+        DiscardIoEngine { nr_blocks }
+    }
+}
+
+impl IoEngine for DiscardIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn get_batch_size(&self) -> usize {
+        1
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        1
+    }
+
+    fn read(&self, b: u64) -> std::io::Result<Block> {
+        Err(std::io::Error::other(format!(
+            "block {} was discarded by the dry run",
+            b
+        )))
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> std::io::Result<Vec<std::io::Result<Block>>> {
+        Ok(blocks.iter().map(|&b| self.read(b)).collect())
+    }
+
+    fn write(&self, block: &Block) -> std::io::Result<()> {
+        if block.loc >= self.nr_blocks {
+            return Err(std::io::Error::other(format!(
+                "block {} out of range",
+                block.loc
+            )));
+        }
+        Ok(())
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> std::io::Result<Vec<std::io::Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write(b)).collect())
+    }
+}
+
+// Copies out the whole content of an engine
+pub fn save_engine(engine: &dyn IoEngine) -> Result<Vec<u8>> {
+    let nr_blocks = engine.get_nr_blocks();
+    let mut data = Vec::with_capacity(nr_blocks as usize * BLOCK_SIZE);
+    for b in 0..nr_blocks {
+        let blk = engine.read(b)?;
+        data.extend_from_slice(blk.get_data());
+    }
+    Ok(data)
+}
+
+// Copies out the content of an engine into a sparse file of the same size,
+// where the zeroed blocks are left as holes, so the copy takes only the space
+// of the blocks in use. The file is opened by the caller, e.g., created
+// exclusively as a temporary file.
+pub fn save_engine_sparse(engine: &dyn IoEngine, out: &File) -> Result<()> {
+    let nr_blocks = engine.get_nr_blocks();
+    out.set_len(nr_blocks * BLOCK_SIZE as u64)?;
+
+    let batch_size = std::cmp::max(engine.get_batch_size(), 1) as u64;
+    let mut begin = 0;
+    while begin < nr_blocks {
+        let end = std::cmp::min(begin + batch_size, nr_blocks);
+        let blocks: Vec<u64> = (begin..end).collect();
+        for blk in engine.read_many(&blocks)? {
+            let blk = blk?;
+            if blk.get_data().iter().any(|&b| b != 0) {
+                out.write_all_at(blk.get_data(), blk.loc * BLOCK_SIZE as u64)?;
+            }
+        }
+        begin = end;
+    }
+    out.sync_all()?;
+    Ok(())
+}
+
+//------------------------------------------
@@ -1,54 +1,286 @@
 use anyhow::{anyhow, Result};
-use std::collections::BTreeMap;
-use std::path::Path;
-use std::sync::{mpsc, Arc};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use thinp::commands::engine::*;
-use thinp::io_engine::IoEngine;
+use thinp::file_utils::file_size;
+use thinp::io_engine::{Block, IoEngine, BLOCK_SIZE};
 use thinp::pdata::btree::{self, *};
 use thinp::pdata::btree_error::KeyRange;
 use thinp::pdata::btree_leaf_walker::{LeafVisitor, LeafWalker};
-use thinp::pdata::btree_walker::btree_to_map;
 use thinp::pdata::space_map::common::SMRoot;
 use thinp::pdata::space_map::metadata::core_metadata_sm;
-use thinp::pdata::space_map::NoopSpaceMap;
-use thinp::pdata::unpack::unpack;
+use thinp::pdata::space_map::{NoopSpaceMap, SpaceMap};
+use thinp::pdata::unpack::{unpack, Unpack};
 use thinp::report::Report;
 use thinp::thin::block_time::*;
 use thinp::thin::device_detail::DeviceDetail;
 use thinp::thin::ir::{self, MetadataVisitor};
-use thinp::thin::metadata_repair::is_superblock_consistent;
+use thinp::thin::metadata_repair::{
+    is_superblock_consistent, read_or_rebuild_superblock, SuperblockOverrides,
+};
 use thinp::thin::restore::Restorer;
 use thinp::thin::superblock::*;
 use thinp::write_batcher::WriteBatcher;
 
-use crate::mapping_iterator::MappingIterator;
+use crate::access::{FdIoEngine, RunAs};
+use crate::budget::*;
+use crate::check::check_output_with;
+use crate::compat::check_repair_compat;
+use crate::copy::*;
+use crate::discover::find_external_snapshot;
+use crate::failure::*;
+use crate::format::*;
+use crate::latency::*;
+use crate::mapping_iterator::{LeafSource, MappingIterator, SkippedLeaves};
+use crate::memory::{zeroed_engine, DiscardIoEngine};
+use crate::pool::{check_not_active_pool, check_not_held};
+use crate::progress::*;
+use crate::relocate::RelocatingIoEngine;
+use crate::remap::*;
+use crate::shared::*;
+use crate::stats::*;
 use crate::stream::*;
+use crate::summary::RunSummary;
+use crate::temp::{spawn_scoped, CleanupScope, TempPath};
+use crate::trace::MergeTrace;
+use crate::watchdog::*;
+use crate::zeroed::*;
 
 //------------------------------------------
 
 const QUEUE_DEPTH: usize = 4;
+
+// The kept devices dumped at a time by default, beside the merge
+const DUMP_THREADS: usize = 4;
 const BUFFER_LEN: usize = 1024;
 const WRITE_BATCH_SIZE: usize = 32;
 
+// The leaves found by a walker ahead of their reads
+const LEAF_QUEUE_LEN: usize = 4096;
+
+// The leaves read back from a spilled list ahead of their reads, fewer as the
+// file is read far faster than the walker visits the nodes
+const SPILL_QUEUE_LEN: usize = 256;
+
 struct CollectLeaves {
     leaves: Vec<u64>,
+    key_ranges: Vec<(Option<u64>, Option<u64>)>,
+    stage: Arc<Stage>,
 }
 
 impl CollectLeaves {
-    fn new() -> CollectLeaves {
-        CollectLeaves { leaves: Vec::new() }
+    fn new(stage: Arc<Stage>) -> CollectLeaves {
+        CollectLeaves {
+            leaves: Vec::new(),
+            key_ranges: Vec::new(),
+            stage,
+        }
+    }
+
+    // Returns the indices of leaves starting before the end of any preceding leaf
+    fn misordered(&self) -> Vec<usize> {
+        let mut bad = Vec::new();
+        let mut last_end: Option<u64> = None;
+
+        for (i, (start, end)) in self.key_ranges.iter().enumerate() {
+            if let (Some(s), Some(e)) = (start, last_end) {
+                if *s < e {
+                    bad.push(i);
+                }
+            }
+            if let Some(e) = end {
+                last_end = Some(last_end.map_or(*e, |le| std::cmp::max(le, *e)));
+            }
+        }
+
+        bad
+    }
+
+    // Replaces the key ranges told by the parents with the first and the last
+    // keys of the leaves, so the keys out of order within a leaf are caught,
+    // and the leaves visited again are placed by their keys. A damaged leaf
+    // keeps the range of its parent if skipping, as the iterator drops it.
+    fn narrow_to_keys(
+        &mut self,
+        engine: &Arc<dyn IoEngine + Send + Sync>,
+        skip_bad: bool,
+    ) -> Result<()> {
+        let batch_size = engine.get_batch_size();
+        for (i, chunk) in self.leaves.chunks(batch_size).enumerate() {
+            for (j, b) in engine.read_many(chunk)?.into_iter().enumerate() {
+                let leaf = b.map_err(anyhow::Error::from).and_then(|b| {
+                    let node = MappingIterator::unpack_leaf(&b, true)?;
+                    MappingIterator::check_keys(&node, b.loc, None)?;
+                    Ok(node)
+                });
+                let keys = match leaf {
+                    Ok(Node::Leaf { keys, .. }) => keys,
+                    Ok(Node::Internal { .. }) => unreachable!("checked by check_keys"),
+                    Err(_) if skip_bad => continue,
+                    Err(e) => return Err(e),
+                };
+                if let (Some(&first), Some(&last)) = (keys.first(), keys.last()) {
+                    let end = last
+                        .checked_add(1)
+                        .ok_or_else(|| anyhow!("mapping key {} overflows", last))?;
+                    self.key_ranges[i * batch_size + j] = (Some(first), Some(end));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        let mut pairs: Vec<_> = self
+            .key_ranges
+            .iter()
+            .cloned()
+            .zip(self.leaves.iter().cloned())
+            .collect();
+        pairs.sort_by_key(|((start, _), _)| start.unwrap_or(0));
+        (self.key_ranges, self.leaves) = pairs.into_iter().unzip();
+    }
+
+    // Drops the leaves known to lie outside the half-open key range. The first
+    // leaf is kept if none is left, as an iterator needs one to start with.
+    fn retain_within(&mut self, begin: u64, end: u64) {
+        let first = self
+            .leaves
+            .first()
+            .copied()
+            .zip(self.key_ranges.first().copied());
+        let (key_ranges, leaves): (Vec<_>, Vec<_>) = self
+            .key_ranges
+            .iter()
+            .cloned()
+            .zip(self.leaves.iter().cloned())
+            .filter(|((start, e), _)| {
+                !(e.is_some_and(|e| e <= begin) || start.is_some_and(|s| s >= end))
+            })
+            .unzip();
+        (self.key_ranges, self.leaves) = (key_ranges, leaves);
+
+        if let (true, Some((leaf, kr))) = (self.leaves.is_empty(), first) {
+            self.leaves.push(leaf);
+            self.key_ranges.push(kr);
+        }
+    }
+
+    fn describe(&self, indices: &[usize]) -> String {
+        indices
+            .iter()
+            .map(|&i| {
+                let (start, end) = self.key_ranges[i];
+                format!(
+                    "{} [{}..{})",
+                    self.leaves[i],
+                    start.map_or("-".to_string(), |k| k.to_string()),
+                    end.map_or("-".to_string(), |k| k.to_string())
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
     }
 }
 
 impl LeafVisitor<BlockTime> for CollectLeaves {
-    fn visit(&mut self, _kr: &KeyRange, b: u64) -> btree::Result<()> {
+    fn visit(&mut self, kr: &KeyRange, b: u64) -> btree::Result<()> {
         self.leaves.push(b);
+        self.key_ranges.push((kr.start, kr.end));
+        self.stage.update(self.leaves.len() as u64);
         Ok(())
     }
 
     fn visit_again(&mut self, b: u64) -> btree::Result<()> {
         self.leaves.push(b);
+        self.key_ranges.push((None, None));
+        self.stage.update(self.leaves.len() as u64);
+        Ok(())
+    }
+
+    fn end_walk(&mut self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+// Sends the leaves of a mapping tree as the walker finds them, skipping the
+// ones outside the key range, if any. Leaves out of key order fail the stream,
+// as there's no reordering them without collecting them all.
+struct StreamLeaves {
+    tx: SyncSender<Result<u64>>,
+    root: u64,
+    key_range: Option<(u64, u64)>,
+    first: Option<u64>, // sent if no leaf lies within the key range
+    nr_leaves: u64,
+    nr_sent: u64,
+    last_end: Option<u64>,
+    stopped: bool, // on a failure, or once the receiver is dropped
+    stage: Arc<Stage>,
+    tripwire: Tripwire,
+}
+
+impl StreamLeaves {
+    fn send(&mut self, leaf: Result<u64>) {
+        if self.stage.wait(|| self.tx.send(leaf)).is_err() {
+            self.stopped = true;
+        }
+    }
+
+    fn push(&mut self, start: Option<u64>, end: Option<u64>, b: u64) {
+        if self.stopped {
+            return;
+        }
+        if let Err(e) = self.tripwire.check() {
+            self.send(Err(e));
+            self.stopped = true;
+            return;
+        }
+        self.nr_leaves += 1;
+        self.stage.update(self.nr_leaves);
+        self.first.get_or_insert(b);
+
+        if let (Some(s), Some(e)) = (start, self.last_end) {
+            if s < e {
+                let err = anyhow!(
+                    "leaves of the mapping tree at {} are out of key order: {} starts at key {}, \
+                     before the end {} of a preceding leaf; use --sort-leaves to reorder them",
+                    self.root,
+                    b,
+                    s,
+                    e
+                );
+                self.send(Err(err));
+                self.stopped = true;
+                return;
+            }
+        }
+        if let Some(e) = end {
+            self.last_end = Some(self.last_end.map_or(e, |le| std::cmp::max(le, e)));
+        }
+
+        if let Some((begin, range_end)) = self.key_range {
+            if end.is_some_and(|e| e <= begin) || start.is_some_and(|s| s >= range_end) {
+                return;
+            }
+        }
+        self.nr_sent += 1;
+        self.send(Ok(b));
+    }
+}
+
+impl LeafVisitor<BlockTime> for StreamLeaves {
+    fn visit(&mut self, kr: &KeyRange, b: u64) -> btree::Result<()> {
+        self.push(kr.start, kr.end, b);
+        Ok(())
+    }
+
+    fn visit_again(&mut self, b: u64) -> btree::Result<()> {
+        self.push(None, None, b);
         Ok(())
     }
 
@@ -57,77 +289,559 @@ impl LeafVisitor<BlockTime> for CollectLeaves {
     }
 }
 
-fn collect_leaves(engine: Arc<dyn IoEngine + Send + Sync>, root: u64) -> Result<Vec<u64>> {
+// Walks the mapping tree on a thread of its own, streaming the leaves within
+// the key range, if any, so their reads start long before the walk ends
+fn stream_leaves(
+    ctx: &Context,
+    charges: &mut Charges,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+    key_range: Option<(u64, u64)>,
+) -> Result<Receiver<Result<u64>>> {
+    // the queued leaves, with room for a failure
+    charges.charge(LEAF_CACHES, LEAF_QUEUE_LEN as u64 * 16)?;
+
+    let (tx, rx) = mpsc::sync_channel(LEAF_QUEUE_LEN);
+    let mut v = StreamLeaves {
+        tx,
+        root,
+        key_range,
+        first: None,
+        nr_leaves: 0,
+        nr_sent: 0,
+        last_end: None,
+        stopped: false,
+        stage: ctx.watchdog.stage("collect"),
+        tripwire: ctx.watchdog.tripwire(),
+    };
+    let report = ctx.report.clone();
+    let verbose = ctx.verbose;
+
+    spawn_scoped(move || {
+        // Using NoopSpaceMap is sufficient as the ref counts are irrelevant in this case.
+        let mut sm = NoopSpaceMap::new(engine.get_nr_blocks());
+        let mut w = LeafWalker::new(engine.clone(), &mut sm, false);
+        let mut path = vec![0];
+        if let Err(e) = w.walk::<StreamLeaves, BlockTime>(&mut path, &mut v, root) {
+            if !v.stopped {
+                v.send(Err(e.into()));
+            }
+            return;
+        }
+        if v.stopped {
+            return;
+        }
+
+        // an iterator needs a leaf to start with
+        if let (0, Some(first)) = (v.nr_sent, v.first) {
+            v.send(Ok(first));
+        }
+        if verbose >= 1 {
+            report.info(&format!(
+                "collected {} leaves of the mapping tree at block {}",
+                v.nr_leaves, root
+            ));
+        }
+    });
+
+    Ok(rx)
+}
+
+// Walks the mapping tree for its leaves in the order of the walk, without
+// reading them
+fn walk_leaves(
+    ctx: &Context,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+) -> Result<CollectLeaves> {
     // Using NoopSpaceMap is sufficient as the ref counts are irrelevant in this case.
     // Also, The LeafWalker ignores the ref counts in space map and walks visited nodes anyway.
     let mut sm = NoopSpaceMap::new(engine.get_nr_blocks());
 
     let mut w = LeafWalker::new(engine.clone(), &mut sm, false);
-    let mut v = CollectLeaves::new();
+    let mut v = CollectLeaves::new(ctx.watchdog.stage("collect"));
     let mut path = vec![0];
     w.walk::<CollectLeaves, BlockTime>(&mut path, &mut v, root)?;
+    Ok(v)
+}
+
+// The leaves of a btree of any value type, in the order of the walk
+#[derive(Default)]
+struct LeafList {
+    leaves: Vec<u64>,
+}
+
+impl<V: Unpack> LeafVisitor<V> for LeafList {
+    fn visit(&mut self, _kr: &KeyRange, b: u64) -> btree::Result<()> {
+        self.leaves.push(b);
+        Ok(())
+    }
+
+    fn visit_again(&mut self, b: u64) -> btree::Result<()> {
+        self.leaves.push(b);
+        Ok(())
+    }
+
+    fn end_walk(&mut self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+// Visits the entries of a small btree, e.g., the device details or the top
+// level of the mapping tree, a batch of leaves at a time, so the entries are
+// never held all at once
+fn for_each_entry<V: Unpack>(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+    mut visit: impl FnMut(u64, V) -> Result<()>,
+) -> Result<()> {
+    let mut sm = NoopSpaceMap::new(engine.get_nr_blocks());
+    let mut w = LeafWalker::new(engine.clone(), &mut sm, false);
+    let mut v = LeafList::default();
+    let mut path = vec![0];
+    w.walk::<LeafList, V>(&mut path, &mut v, root)?;
+
+    for chunk in v.leaves.chunks(engine.get_batch_size()) {
+        for (&loc, b) in chunk.iter().zip(engine.read_many(chunk)?) {
+            let b = b.map_err(|e| anyhow!("unable to read the leaf at block {}: {}", loc, e))?;
+            match unpack_node::<V>(&[], b.get_data(), false, loc == root)? {
+                Node::Leaf { keys, values, .. } => {
+                    for (k, v) in keys.into_iter().zip(values) {
+                        visit(k, v)?;
+                    }
+                }
+                Node::Internal { .. } => {
+                    return Err(anyhow!("expected a leaf at block {}", loc));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn collect_leaves_in(
+    ctx: &Context,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+    key_range: Option<(u64, u64)>,
+) -> Result<Vec<u64>> {
+    let mut v = walk_leaves(ctx, engine.clone(), root)?;
+    v.narrow_to_keys(&engine, ctx.skipped.is_some())?;
+
+    // Leaves out of key order break the assumptions of MappingStream, so they
+    // are either rejected up front, or reordered if the user asked for it.
+    let bad = v.misordered();
+    if !bad.is_empty() {
+        if !ctx.sort_leaves {
+            return Err(anyhow!(
+                "leaves of the mapping tree at {} are out of key order: {}; \
+                 use --sort-leaves to reorder them",
+                root,
+                v.describe(&bad)
+            ));
+        }
+
+        v.sort();
+
+        let bad = v.misordered();
+        if !bad.is_empty() {
+            return Err(anyhow!(
+                "leaves of the mapping tree at {} have overlapping key ranges: {}",
+                root,
+                v.describe(&bad)
+            ));
+        }
+    }
+
+    if let Some((begin, end)) = key_range {
+        v.retain_within(begin, end);
+    }
+
+    ctx.verbose_info(
+        1,
+        &format!(
+            "collected {} leaves of the mapping tree at block {}",
+            v.leaves.len(),
+            root
+        ),
+    );
 
     Ok(v.leaves)
 }
 
+// Writes the collected leaves to a temporary file, and streams them back from
+// there, so the list isn't held in memory for the whole merge
+fn spill_leaves(charges: &mut Charges, leaves: Vec<u64>) -> Result<Receiver<Result<u64>>> {
+    use std::io::{BufReader, BufWriter, Read, Write};
+
+    // the queued leaves, with room for a failure
+    charges.charge(LEAF_CACHES, SPILL_QUEUE_LEN as u64 * 16)?;
+
+    let tmp = TempPath::new("leaves")?;
+    let mut w = BufWriter::new(std::fs::OpenOptions::new().write(true).open(tmp.path())?);
+    for loc in &leaves {
+        w.write_all(&loc.to_le_bytes())?;
+    }
+    w.into_inner().map_err(|e| e.into_error())?;
+    let nr_leaves = leaves.len();
+    drop(leaves);
+
+    let mut r = BufReader::new(std::fs::File::open(tmp.path())?);
+    let (tx, rx) = mpsc::sync_channel(SPILL_QUEUE_LEN);
+    spawn_scoped(move || {
+        // removed once the leaves are read back
+        let _tmp = tmp;
+        let mut buf = [0; 8];
+        for _ in 0..nr_leaves {
+            let leaf = r
+                .read_exact(&mut buf)
+                .map(|_| u64::from_le_bytes(buf))
+                .map_err(|e| anyhow!("unable to read back the spilled leaves: {}", e));
+            let failed = leaf.is_err();
+            if tx.send(leaf).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+// Charges the blocks cached by an iterator over the leaves, reading fewer of
+// them at a time if the given batch doesn't fit under the memory limit. Each
+// leaf is charged twice, for the batch consumed and the next one read meanwhile.
+fn fit_leaf_batch(ctx: &Context, charges: &mut Charges, batch_size: usize) -> Result<usize> {
+    let wanted = std::cmp::max(batch_size, 1);
+    let n = charges.charge_fitting(LEAF_CACHES, 2 * BLOCK_SIZE as u64, wanted)?;
+    if n < wanted {
+        ctx.report.info(&format!(
+            "reading {} leaves at a time rather than {} to fit the memory limit",
+            n, wanted
+        ));
+        return Ok(n);
+    }
+    Ok(batch_size)
+}
+
+// The leaves of a mapping tree within the key range, if any. They are streamed
+// from a walker, unless they are to be sorted, which takes collecting them all
+// up front. A list not fitting under the memory limit is spilled to disk.
+fn leaf_source(
+    ctx: &Context,
+    charges: &mut Charges,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+    key_range: Option<(u64, u64)>,
+) -> Result<LeafSource> {
+    if ctx.sort_leaves {
+        let leaves = collect_leaves_in(ctx, engine, root, key_range)?;
+
+        // the leaf list with its key ranges
+        if charges
+            .charge(LEAF_CACHES, leaves.len() as u64 * 40)
+            .is_ok()
+        {
+            return Ok(LeafSource::List(leaves));
+        }
+        ctx.report.info(&format!(
+            "spilled the {} leaves of the mapping tree at block {} to disk to fit the memory limit",
+            leaves.len(),
+            root
+        ));
+        return Ok(LeafSource::Stream(spill_leaves(charges, leaves)?));
+    }
+    Ok(LeafSource::Stream(stream_leaves(
+        ctx, charges, engine, root, key_range,
+    )?))
+}
+
+// Iterates over the mappings of the leaves within the key range, if any,
+// reading the given number of leaves at a time and up to the given number of
+// batches ahead. The charges of the leaves and their caches are given back
+// once the iterator is dropped.
+fn leaf_iterator(
+    ctx: &Context,
+    mut charges: Charges,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    leaves: LeafSource,
+    key_range: Option<(u64, u64)>,
+    batch_size: usize,
+    depth: usize,
+) -> Result<MappingIterator> {
+    let batch_size = fit_leaf_batch(ctx, &mut charges, batch_size)?;
+    if depth > 1 {
+        // the batches read ahead beyond the next one
+        let n = batch_size * (depth - 1);
+        charges.charge(LEAF_CACHES, n as u64 * BLOCK_SIZE as u64)?;
+    }
+    let mut iter = MappingIterator::from_source_skipping(
+        engine,
+        leaves,
+        batch_size,
+        depth,
+        ctx.skipped.clone(),
+        Some(ctx.watchdog.tripwire()),
+    )?;
+    if let Some((begin, end)) = key_range {
+        iter.restrict(begin, end)?;
+    }
+    iter.hold(charges);
+    Ok(iter)
+}
+
 //------------------------------------------
 
-struct RangeMergeIterator {
+// The end of the key range of a leaf, after its last key
+fn leaf_key_end(keys: &[u64]) -> Result<Option<u64>> {
+    keys.last()
+        .map(|k| {
+            k.checked_add(1)
+                .ok_or_else(|| anyhow!("mapping key {} overflows", k))
+        })
+        .transpose()
+}
+
+// Returns the end of the key range covered by the leaves, by looking into the last leaf
+fn get_key_end(engine: &Arc<dyn IoEngine + Send + Sync>, leaves: &[u64]) -> Result<Option<u64>> {
+    if let Some(&loc) = leaves.last() {
+        let b = engine.read(loc)?;
+        if let Node::Leaf { keys, .. } = unpack_node::<BlockTime>(&[], b.get_data(), true, true)? {
+            return leaf_key_end(&keys);
+        }
+    }
+    Ok(None)
+}
+
+// Returns the end of the key range of a mapping tree by descending its rightmost path
+fn tree_key_end(engine: &dyn IoEngine, root: u64) -> Result<Option<u64>> {
+    rightmost_key_end(engine, root, false)
+}
+
+// Like tree_key_end, but if skipping the damaged leaves and the last one is
+// damaged, its mappings are lost, so the tree ends at its first key instead
+fn key_end_of(ctx: &Context, root: u64) -> Result<Option<u64>> {
+    rightmost_key_end(ctx.engine_in.as_ref(), root, ctx.skipped.is_some())
+}
+
+fn rightmost_key_end(engine: &dyn IoEngine, root: u64, skip_bad: bool) -> Result<Option<u64>> {
+    let mut loc = root;
+    let mut is_root = true;
+    let mut first_key = None; // of the node visited, by the key of its parent
+    loop {
+        let b = engine.read(loc)?;
+        // both the child pointers and the block_time values are 64-bit
+        let node = match unpack_node::<u64>(&[], b.get_data(), true, is_root) {
+            Ok(node) => node,
+            Err(_) if skip_bad && first_key.is_some() => return Ok(first_key),
+            Err(e) => return Err(e.into()),
+        };
+        match node {
+            Node::Internal { keys, values, .. } => match values.last() {
+                Some(&child) => {
+                    loc = child;
+                    first_key = keys.last().copied();
+                }
+                None => return Ok(None),
+            },
+            Node::Leaf { keys, .. } => return leaf_key_end(&keys),
+        }
+        is_root = false;
+    }
+}
+
+/// Overlays the runs of a snapshot on the runs of its base, e.g., the origin,
+/// yielding the merged runs in key order. Where the snapshot maps a range to
+/// the same data blocks as the base, the base runs are kept with their times.
+pub struct RangeMergeIterator {
     base_stream: MappingStream,
     snap_stream: MappingStream,
+    base_end: u64,
+    key_end: Option<u64>, // end of the key range covered by either stream
+    covered: Option<u64>, // end of the snapshot ranges covering the base contiguously
+    superset: bool,
+    identical: Arc<AtomicU64>, // nr blocks the snapshot maps identically to the base
+    trace: Option<(Arc<MergeTrace>, usize)>, // along with the level in the chain
 }
 
 impl RangeMergeIterator {
-    fn new(
-        engine: Arc<dyn IoEngine + Send + Sync>,
-        base_root: u64,
-        snap_root: u64,
-    ) -> Result<Self> {
-        let base_leaves = collect_leaves(engine.clone(), base_root)?;
-        let snap_leaves = collect_leaves(engine.clone(), snap_root)?;
-        let base_stream = MappingStream::new(engine.clone(), base_leaves)?;
-        let snap_stream = MappingStream::new(engine, snap_leaves)?;
+    /// Takes the streams of the base and the snapshot, each along with the end
+    /// of its key range if known. The count of blocks the snapshot remaps
+    /// identically is added to the given counter.
+    pub fn new(
+        (base_stream, base_end): (MappingStream, Option<u64>),
+        (snap_stream, snap_end): (MappingStream, Option<u64>),
+        identical: Arc<AtomicU64>,
+    ) -> Self {
+        let covered = match (base_stream.get_mapping(), base_end) {
+            (Some(m), Some(_)) => Some(m.0),
+            _ => None,
+        };
 
-        Ok(Self {
+        Self {
             base_stream,
             snap_stream,
-        })
+            base_end: base_end.unwrap_or(0),
+            key_end: std::cmp::max(base_end, snap_end),
+            covered,
+            superset: false,
+            identical,
+            trace: None,
+        }
+    }
+
+    /// Logs every decision to the given trace, tagged with the level of the
+    /// snapshot in the chain
+    pub fn with_trace(mut self, trace: Arc<MergeTrace>, level: usize) -> Self {
+        self.trace = Some((trace, level));
+        self
+    }
+
+    fn trace(
+        &self,
+        decision: &str,
+        base: Option<&(u64, BlockTime, u64)>,
+        snap: Option<&(u64, BlockTime, u64)>,
+        consumed: (u64, u64),
+    ) -> Result<()> {
+        match &self.trace {
+            Some((trace, level)) => trace.record(*level, decision, base, snap, consumed),
+            None => Ok(()),
+        }
+    }
+
+    /// The end of the key range covered by either stream, if known
+    pub fn key_end(&self) -> Option<u64> {
+        self.key_end
+    }
+
+    // Tracks the snapshot coverage from the beginning of the base. Once the snapshot
+    // is known to override every base mapping, the base stream is no longer visited.
+    fn cover(
+        &mut self,
+        run: Option<(u64, BlockTime, u64)>,
+    ) -> Result<Option<(u64, BlockTime, u64)>> {
+        if let (Some(covered), Some(r)) = (self.covered, &run) {
+            if r.0 <= covered {
+                let end = std::cmp::max(covered, r.end()?);
+                self.covered = Some(end);
+                self.superset = end >= self.base_end;
+            } else {
+                self.covered = None;
+            }
+        }
+        Ok(run)
     }
 
-    fn ends_before_started(left: &(u64, BlockTime, u64), right: &(u64, BlockTime, u64)) -> bool {
-        left.0 + left.2 <= right.0
+    fn ends_before_started(
+        left: &(u64, BlockTime, u64),
+        right: &(u64, BlockTime, u64),
+    ) -> Result<bool> {
+        Ok(left.end()? <= right.0)
     }
 
     fn overlays_tail(base: &(u64, BlockTime, u64), overlay: &(u64, BlockTime, u64)) -> bool {
         base.0 < overlay.0
     }
 
-    fn overlays_head(base: &(u64, BlockTime, u64), overlay: &(u64, BlockTime, u64)) -> bool {
-        overlay.0 + overlay.2 < base.0 + base.2
+    // The overlay maps the beginning of the base to the same data block, assuming
+    // the overlay starts at or before the base
+    fn remaps_identically(base: &(u64, BlockTime, u64), overlay: &(u64, BlockTime, u64)) -> bool {
+        overlay
+            .1
+            .block
+            .checked_add(base.0 - overlay.0)
+            .is_some_and(|b| b == base.1.block)
+    }
+
+    fn overlays_head(
+        base: &(u64, BlockTime, u64),
+        overlay: &(u64, BlockTime, u64),
+    ) -> Result<bool> {
+        Ok(overlay.end()? < base.end()?)
     }
 
-    fn overlays_all(base: &(u64, BlockTime, u64), overlay: &(u64, BlockTime, u64)) -> bool {
-        base.0 + base.2 <= overlay.0 + overlay.2
+    fn overlays_all(base: &(u64, BlockTime, u64), overlay: &(u64, BlockTime, u64)) -> Result<bool> {
+        Ok(base.end()? <= overlay.end()?)
     }
 
-    fn next(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
+    /// Returns the next merged run, or None once both streams are exhausted.
+    pub fn next_range(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
+        if self.superset {
+            if let Some(snap_map) = self.snap_stream.get_mapping() {
+                self.trace("superset", None, Some(snap_map), (0, snap_map.2))?;
+            }
+            return self.snap_stream.consume_all();
+        }
+
         while self.base_stream.more_mappings() && self.snap_stream.more_mappings() {
             let mut base_map = self.base_stream.get_mapping().unwrap();
             let snap_map = self.snap_stream.get_mapping().unwrap();
 
-            if Self::ends_before_started(snap_map, base_map) {
-                return self.snap_stream.consume_all();
-            } else if Self::ends_before_started(base_map, snap_map) {
+            if Self::ends_before_started(snap_map, base_map)? {
+                self.trace(
+                    "snap_ends_before",
+                    Some(base_map),
+                    Some(snap_map),
+                    (0, snap_map.2),
+                )?;
+                let run = self.snap_stream.consume_all()?;
+                return self.cover(run);
+            } else if Self::ends_before_started(base_map, snap_map)? {
+                self.trace(
+                    "base_ends_before",
+                    Some(base_map),
+                    Some(snap_map),
+                    (base_map.2, 0),
+                )?;
                 return self.base_stream.consume_all();
             } else if Self::overlays_tail(base_map, snap_map) {
                 let delta = snap_map.0 - base_map.0;
+                self.trace("overlays_tail", Some(base_map), Some(snap_map), (delta, 0))?;
                 return self.base_stream.consume(delta);
-            } else if Self::overlays_head(base_map, snap_map) {
-                let intersected = snap_map.0 + snap_map.2 - base_map.0;
+            } else if Self::remaps_identically(base_map, snap_map) {
+                // keep the base run along with its older time
+                if snap_map.0 < base_map.0 {
+                    let delta = base_map.0 - snap_map.0;
+                    self.trace(
+                        "remaps_identically",
+                        Some(base_map),
+                        Some(snap_map),
+                        (0, delta),
+                    )?;
+                    let run = self.snap_stream.consume(delta)?;
+                    return self.cover(run);
+                }
+                let len = std::cmp::min(base_map.2, snap_map.2);
+                self.trace(
+                    "remaps_identically",
+                    Some(base_map),
+                    Some(snap_map),
+                    (len, len),
+                )?;
+                self.snap_stream.skip(len)?;
+                self.identical.fetch_add(len, Ordering::Relaxed);
+                let run = self.base_stream.consume(len)?;
+                return self.cover(run);
+            } else if Self::overlays_head(base_map, snap_map)? {
+                // the snapshot run starts at or before the base run here
+                let intersected = snap_map.end()? - base_map.0;
+                self.trace(
+                    "overlays_head",
+                    Some(base_map),
+                    Some(snap_map),
+                    (intersected, snap_map.2),
+                )?;
                 self.base_stream.skip(intersected)?;
-                return self.snap_stream.consume(snap_map.2);
+                let run = self.snap_stream.consume(snap_map.2)?;
+                return self.cover(run);
             } else {
-                while Self::overlays_all(base_map, snap_map) {
+                while Self::overlays_all(base_map, snap_map)? {
+                    self.trace(
+                        "overlays_all",
+                        Some(base_map),
+                        Some(snap_map),
+                        (base_map.2, 0),
+                    )?;
                     self.base_stream.skip_all()?;
                     if !self.base_stream.more_mappings() {
                         break;
@@ -137,11 +851,13 @@ impl RangeMergeIterator {
             }
         }
 
-        if self.base_stream.more_mappings() {
+        if let Some(base_map) = self.base_stream.get_mapping() {
+            self.trace("base_rest", Some(base_map), None, (base_map.2, 0))?;
             return self.base_stream.consume_all();
         }
 
-        if self.snap_stream.more_mappings() {
+        if let Some(snap_map) = self.snap_stream.get_mapping() {
+            self.trace("snap_rest", None, Some(snap_map), (0, snap_map.2))?;
             return self.snap_stream.consume_all();
         }
 
@@ -149,221 +865,1911 @@ impl RangeMergeIterator {
     }
 }
 
+/// Yields the merged runs as next_range does, so the merge composes with the
+/// iterator adaptors
+impl Iterator for RangeMergeIterator {
+    type Item = Result<(u64, BlockTime, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_range().transpose()
+    }
+}
+
 //------------------------------------------
 
 fn update_device_details(
     engine: Arc<dyn IoEngine + Send + Sync>,
+    details_root: u64,
+    dev_id: u32,
     mapped_blocks: u64,
 ) -> Result<()> {
-    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
-    let b = engine.read(sb.details_root)?;
-    let mut details_leaf = unpack_node::<DeviceDetail>(&[], b.get_data(), false, true)?;
-
-    if let Node::Leaf { ref mut values, .. } = details_leaf {
-        values[0].mapped_blocks = mapped_blocks;
-    } else {
-        return Err(anyhow!("unexpected node type"));
+    let key = dev_id as u64;
+    let not_found = || anyhow!("Unable to find the details for the device {}", dev_id);
+
+    let (b, mut node) =
+        find_leaf::<DeviceDetail>(engine.as_ref(), details_root, key)?.ok_or_else(not_found)?;
+    if let Node::Leaf {
+        ref keys,
+        ref mut values,
+        ..
+    } = node
+    {
+        let idx = keys.binary_search(&key).map_err(|_| not_found())?;
+        values[idx].mapped_blocks = mapped_blocks;
     }
 
     let mut cursor = std::io::Cursor::new(b.get_data());
-    pack_node(&details_leaf, &mut cursor)?;
+    pack_node(&node, &mut cursor)?;
     thinp::checksum::write_checksum(b.get_data(), thinp::checksum::BT::NODE)?;
     engine.write(&b)?;
 
     Ok(())
 }
 
-fn merge(
-    engine_in: Arc<dyn IoEngine + Send + Sync>,
-    engine_out: Arc<dyn IoEngine + Send + Sync>,
-    report: Arc<Report>,
-    out_sb: &ir::Superblock,
-    out_dev: &ir::Device,
-    origin_root: u64,
-    snap_root: u64,
-) -> Result<()> {
-    let sm = core_metadata_sm(engine_out.get_nr_blocks(), 2);
-    let mut w = WriteBatcher::new(engine_out.clone(), sm.clone(), WRITE_BATCH_SIZE);
-    let mut restorer = Restorer::new(&mut w, report);
-
-    let mut iter = RangeMergeIterator::new(engine_in.clone(), origin_root, snap_root)?;
-
-    let (tx, rx) = mpsc::sync_channel::<Vec<ir::Map>>(QUEUE_DEPTH);
-
-    let merger = thread::spawn(move || -> Result<()> {
-        let mut runs = Vec::with_capacity(BUFFER_LEN);
+// Streams the runs of a mapping tree, along with the end of its key range. The
+// leaves are read the given number at a time, further ahead of the merge if
+// prefetching.
+fn leaf_stream(
+    ctx: &Context,
+    root: u64,
+    batch_size: usize,
+) -> Result<(MappingStream, Option<u64>)> {
+    let engine = ctx.engine_in.clone();
+    let mut charges = Charges::new(&ctx.budget);
+    let leaves = leaf_source(ctx, &mut charges, engine.clone(), root, ctx.key_range)?;
+    let mut end = match &leaves {
+        LeafSource::List(leaves) if ctx.skipped.is_none() => get_key_end(&engine, leaves)?,
+        _ => key_end_of(ctx, root)?,
+    };
+    if let Some((_, range_end)) = ctx.key_range {
+        end = end.map(|e| std::cmp::min(e, range_end));
+    }
+    let depth = ctx.prefetch.unwrap_or(1);
+    let iter = leaf_iterator(
+        ctx,
+        charges,
+        engine,
+        leaves,
+        ctx.key_range,
+        batch_size,
+        depth,
+    )?;
+    let mut stream = MappingStream::from_iterator(iter)?;
+    if let Some(&creation_time) = ctx.diverged.get(&root) {
+        stream = check_diverged(stream, creation_time, ctx.report.clone())?;
+    }
+    Ok((stream, end))
+}
 
-        while let Some((k, v, l)) = iter.next()? {
-            runs.push(ir::Map {
-                thin_begin: k,
-                data_begin: v.block,
-                time: v.time,
-                len: l,
-            });
-            if runs.len() == BUFFER_LEN {
-                tx.send(runs)?;
-                runs = Vec::with_capacity(BUFFER_LEN);
+// Fails on the first run of the tree mapped after the creation of the snapshot
+// overlaid on it, which implies the tree was written afterwards. Only the runs
+// the merge visits are checked, which leaves out the ones past the end of a
+// snapshot found to override the rest of the tree.
+fn check_diverged(
+    mut stream: MappingStream,
+    creation_time: u32,
+    report: Arc<Report>,
+) -> Result<MappingStream> {
+    MappingStream::from_source(Box::new(move || {
+        let run = stream.consume_all()?;
+        if let Some((_, bt, _)) = &run {
+            if bt.time > creation_time {
+                report.warning(&format!(
+                    "WARNING: the origin has mappings of time {}, newer than the snapshot creation time {}",
+                    bt.time, creation_time
+                ));
+                report.warning(
+                    "WARNING: merging would mix the writes of the origin and the snapshot",
+                );
+                return Err(anyhow!(
+                    "the origin diverged from the snapshot; use --accept-diverged-origin to merge anyway"
+                ));
             }
         }
+        Ok(run)
+    }))
+}
 
-        if !runs.is_empty() {
-            tx.send(runs)?;
-        }
+// Streams the runs of a snapshot, dropping the ones older than the given time
+// if any, so the origin keeps its version of those ranges.
+fn snap_stream(ctx: &Context, root: u64) -> Result<(MappingStream, Option<u64>)> {
+    let (mut stream, end) = leaf_stream(ctx, root, ctx.snap_batch)?;
+    let time = match ctx.since_time {
+        Some(t) => t,
+        None => return Ok((stream, end)),
+    };
 
-        drop(tx);
-        Ok(())
+    // every block read is counted as aged, until the filter lets it through
+    let read = ctx.aged.clone();
+    let source: RunSource = Box::new(move || {
+        let run = stream.consume_all()?;
+        if let Some(run) = &run {
+            read.fetch_add(run.2, Ordering::Relaxed);
+        }
+        Ok(run)
     });
-
-    restorer.superblock_b(out_sb)?;
-    restorer.device_b(out_dev)?;
-
-    let mut mapped_blocks = 0;
-    while let Ok(runs) = rx.recv() {
-        for run in &runs {
-            restorer.map(run)?;
-            mapped_blocks += run.len;
+    let mut source = since_time(source, time);
+    let kept = ctx.aged.clone();
+    let filtered = MappingStream::from_source(Box::new(move || {
+        let run = source()?;
+        if let Some(run) = &run {
+            kept.fetch_sub(run.2, Ordering::Relaxed);
         }
+        Ok(run)
+    }))?;
+    Ok((filtered, end))
+}
+
+// Merges a chain of mapping trees from the bottom. Each level is overlaid on
+// the merged runs of the levels beneath, without any intermediate metadata.
+fn merge_source(ctx: &Context, roots: &[u64]) -> Result<RunSource> {
+    if roots.len() < 2 {
+        return Err(anyhow!("a merge requires at least two devices"));
     }
 
-    merger
-        .join()
-        .expect("unexpected error")
-        .expect("metadata contains error");
+    let base = leaf_stream(ctx, roots[0], ctx.base_batch)?;
+    overlay_source(ctx, base, &roots[1..])
+}
 
-    restorer.device_e()?;
-    restorer.superblock_e()?;
-    restorer.eof()?;
+// Overlays the mapping trees on the given base, from the bottom
+fn overlay_source(
+    ctx: &Context,
+    base: (MappingStream, Option<u64>),
+    roots: &[u64],
+) -> Result<RunSource> {
+    let traced = |iter: RangeMergeIterator, level| match &ctx.trace {
+        Some(trace) => iter.with_trace(trace.clone(), level),
+        None => iter,
+    };
 
-    update_device_details(engine_out, mapped_blocks)?;
+    // whatever produced them, the runs are sanitized ahead of the classifier
+    let sanitized =
+        |source: RunSource| MappingStream::from_source(sanitize(source, ctx.sanitized.clone()));
+    let sanitized_stream = |(mut stream, end): (MappingStream, Option<u64>)| {
+        Ok::<_, anyhow::Error>((sanitized(Box::new(move || stream.consume_all()))?, end))
+    };
 
-    Ok(())
+    let base = sanitized_stream(base)?;
+    let snap = sanitized_stream(snap_stream(ctx, roots[0])?)?;
+    let mut iter = traced(
+        RangeMergeIterator::new(base, snap, ctx.identical.clone()),
+        1,
+    );
+
+    for (i, &root) in roots.iter().enumerate().skip(1) {
+        let end = iter.key_end();
+        let base = sanitized(Box::new(move || iter.next_range()))?;
+        let snap = sanitized_stream(snap_stream(ctx, root)?)?;
+        iter = traced(
+            RangeMergeIterator::new((base, end), snap, ctx.identical.clone()),
+            i + 1,
+        );
+    }
+
+    Ok(Box::new(move || iter.next_range()))
 }
 
-fn dump_single_device(
-    engine_in: Arc<dyn IoEngine + Send + Sync>,
-    engine_out: Arc<dyn IoEngine + Send + Sync>,
-    report: Arc<Report>,
-    out_sb: &ir::Superblock,
-    out_dev: &ir::Device,
+fn dump_source(ctx: &Context, root: u64) -> Result<RunSource> {
+    dump_source_in(ctx, ctx.engine_in.clone(), root)
+}
+
+fn dump_source_in(
+    ctx: &Context,
+    engine: Arc<dyn IoEngine + Send + Sync>,
     root: u64,
+) -> Result<RunSource> {
+    let mut charges = Charges::new(&ctx.budget);
+    let leaves = leaf_source(ctx, &mut charges, engine.clone(), root, None)?;
+    let batch_size = engine.get_batch_size();
+    let mut iter = leaf_iterator(ctx, charges, engine, leaves, None, batch_size, 1)?;
+    Ok(Box::new(move || iter.next_range()))
+}
+
+// Dumps the runs of the merged device within the key range to merge, if any
+fn dump_range_source(ctx: &Context, root: u64) -> Result<RunSource> {
+    let engine = ctx.engine_in.clone();
+    let mut charges = Charges::new(&ctx.budget);
+    let leaves = leaf_source(ctx, &mut charges, engine.clone(), root, ctx.key_range)?;
+    let batch_size = engine.get_batch_size();
+    let mut iter = leaf_iterator(ctx, charges, engine, leaves, ctx.key_range, batch_size, 1)?;
+    Ok(Box::new(move || iter.next_range()))
+}
+
+// Drops the mappings to the zeroed data blocks from the runs of the merged
+// device, if a list of them is given
+fn output_source(ctx: &Context, source: RunSource) -> RunSource {
+    match &ctx.zeroed {
+        Some(zeroed) => skip_zeroed(source, zeroed.clone(), ctx.dropped_zeroed.clone()),
+        None => source,
+    }
+}
+
+struct EmitStats {
+    mapped_blocks: u64,
+    nr_runs: u64,
+    nr_chunks: u64,
+    max_time: u32,
+}
+
+// Yields between the chunks of a device, once the runs of the chunk ending at
+// the given key are all handed to the restorer
+fn checkpoint(
+    ctx: &Context,
+    write_stage: &Stage,
+    dev_id: u32,
+    end: u64,
+    mapped_blocks: u64,
+    expected: u64,
 ) -> Result<()> {
-    let sm = core_metadata_sm(engine_out.get_nr_blocks(), 2);
-    let mut w = WriteBatcher::new(engine_out, sm.clone(), WRITE_BATCH_SIZE);
-    let mut restorer = Restorer::new(&mut w, report);
+    let begin = end.saturating_sub(ctx.chunk_blocks.unwrap_or(0));
+    if let Some(file) = &ctx.synced_output {
+        file.sync_data()?;
+    }
+    ctx.progress
+        .update(dev_id, end, mapped_blocks, expected, true);
+    ctx.progress.chunk(dev_id, begin, end, mapped_blocks);
 
-    let leaves = collect_leaves(engine_in.clone(), root)?;
-    let mut iter = MappingIterator::new(engine_in, leaves)?;
+    // a deliberate pause isn't a stall
+    if let Some(pause) = ctx.chunk_pause {
+        write_stage.wait(|| thread::sleep(pause));
+    }
+    ctx.watchdog.check()
+}
 
-    let (tx, rx) = mpsc::sync_channel::<Vec<ir::Map>>(QUEUE_DEPTH);
+// Streams the runs of one device into the restorer, along with its references
+// to the shared leaves in key order. Mapping times are clamped to the given
+// time if any. With chunks, the runs are split at the chunk bounds, and every
+// chunk is handed over whole to be followed by a checkpoint.
+fn emit_device(
+    ctx: &Context,
+    restorer: &mut Restorer,
+    dev: &ir::Device,
+    mut source: RunSource,
+    refs: Vec<SharedRef>,
+    key_end: Option<u64>,
+    clamp_time: Option<u32>,
+) -> Result<EmitStats> {
+    // the batches come with the end of the chunk they complete, if any
+    let (tx, rx) = mpsc::sync_channel::<(Vec<ir::Map>, Option<u64>)>(ctx.pipeline.queue_depth);
+
+    let read_stage = ctx.watchdog.stage("read");
+    let tripwire = ctx.watchdog.tripwire();
+    let stalls = ctx.stalls.clone();
+    let chunk_blocks = ctx.chunk_blocks;
+    let buffer_len = ctx.pipeline.buffer_len;
+    let producer = spawn_scoped(move || -> Result<()> {
+        let mut runs = Vec::with_capacity(buffer_len);
+        let mut chunk_end: Option<u64> = None;
+
+        while let Some((mut k, v, mut l)) = source()? {
+            let mut block = v.block;
+            while l > 0 {
+                let mut len = l;
+                if let Some(n) = chunk_blocks {
+                    if let Some(end) = chunk_end.filter(|&end| k >= end) {
+                        tripwire.check()?;
+                        read_stage.update(k);
+                        let started = Instant::now();
+                        read_stage.wait(|| tx.send((runs, Some(end))))?;
+                        stalls.record_send(started.elapsed());
+                        runs = Vec::with_capacity(buffer_len);
+                    }
+                    // the chunks without mappings are skipped
+                    let end = (k / n).saturating_add(1).saturating_mul(n);
+                    chunk_end = Some(end);
+                    if end > k {
+                        len = std::cmp::min(len, end - k);
+                    }
+                }
 
-    let dumper = thread::spawn(move || -> Result<()> {
-        let mut runs = Vec::with_capacity(BUFFER_LEN);
+                runs.push(ir::Map {
+                    thin_begin: k,
+                    data_begin: block,
+                    time: v.time,
+                    len,
+                });
+                if runs.len() == buffer_len {
+                    tripwire.check()?;
+                    read_stage.update(k);
+                    let started = Instant::now();
+                    read_stage.wait(|| tx.send((runs, None)))?;
+                    stalls.record_send(started.elapsed());
+                    runs = Vec::with_capacity(buffer_len);
+                }
 
-        while let Some((k, v, l)) = iter.next_range()? {
-            runs.push(ir::Map {
-                thin_begin: k,
-                data_begin: v.block,
-                time: v.time,
-                len: l,
-            });
-            if runs.len() == BUFFER_LEN {
-                tx.send(runs)?;
-                runs = Vec::with_capacity(BUFFER_LEN);
+                k += len;
+                block += len;
+                l -= len;
             }
         }
 
-        if !runs.is_empty() {
-            tx.send(runs)?;
+        if !runs.is_empty() || chunk_end.is_some() {
+            let started = Instant::now();
+            tx.send((runs, chunk_end))?;
+            stalls.record_send(started.elapsed());
         }
 
         drop(tx);
         Ok(())
     });
 
-    restorer.superblock_b(out_sb)?;
-    restorer.device_b(out_dev)?;
+    // the producer stops once the receiver is dropped, so it's joined on
+    // every failure of the writer
+    let written = write_runs(ctx, restorer, dev, &rx, refs, key_end, clamp_time);
+    drop(rx);
+    let produced = producer.join().expect("unexpected error");
+    let stats = written?;
+    produced?;
+    Ok(stats)
+}
 
-    while let Ok(runs) = rx.recv() {
-        for run in &runs {
-            restorer.map(run)?;
+// Hands the batches of runs received from the producer to the restorer
+fn write_runs(
+    ctx: &Context,
+    restorer: &mut Restorer,
+    dev: &ir::Device,
+    rx: &Receiver<(Vec<ir::Map>, Option<u64>)>,
+    refs: Vec<SharedRef>,
+    key_end: Option<u64>,
+    clamp_time: Option<u32>,
+) -> Result<EmitStats> {
+    restorer.device_b(dev)?;
+
+    let write_stage = ctx.watchdog.stage("write");
+    let mut mapped_blocks = 0;
+    let mut nr_runs = 0;
+    let mut nr_chunks = 0;
+    let mut max_time = 0;
+    let mut position = 0;
+    let mut last_batch = Instant::now();
+
+    let mut refs = refs.into_iter().peekable();
+
+    loop {
+        let started = Instant::now();
+        let received = write_stage.wait(|| rx.recv_timeout(WATCHDOG_TICK));
+        ctx.stalls.record_recv(started.elapsed(), received.is_ok());
+        match received {
+            Ok((mut runs, chunk)) => {
+                let batch_blocks = mapped_blocks;
+                for run in &mut runs {
+                    // the restorer would only fail deep in the data space map
+                    if let Some(limit) = ctx.nr_data_blocks {
+                        if run.data_begin.saturating_add(run.len) > limit {
+                            return Err(anyhow!(
+                                "device {} maps data block {}, beyond the {} data blocks of the output",
+                                dev.dev_id,
+                                run.data_begin.saturating_add(run.len - 1),
+                                limit
+                            ));
+                        }
+                    }
+                    // the shared leaves ahead of the run
+                    while let Some(r) = refs.next_if(|r| r.begin < run.thin_begin) {
+                        restorer.ref_shared(&r.name)?;
+                        mapped_blocks += r.mapped_blocks;
+                        nr_runs += r.nr_runs;
+                        max_time = std::cmp::max(max_time, r.max_time);
+                        position = r.end;
+                    }
+                    max_time = std::cmp::max(max_time, run.time);
+                    if let Some(t) = clamp_time {
+                        run.time = std::cmp::min(run.time, t);
+                    }
+                    restorer.map(run)?;
+                    mapped_blocks += run.len;
+                    nr_runs += 1;
+                    position = run.thin_begin.saturating_add(run.len);
+                }
+                write_stage.update(mapped_blocks);
+                ctx.progress.update(
+                    dev.dev_id,
+                    position,
+                    mapped_blocks,
+                    dev.mapped_blocks,
+                    false,
+                );
+
+                // the throughput since the previous batch, read and write alike
+                if ctx.verbose >= 2 {
+                    let elapsed = last_batch.elapsed().as_secs_f64();
+                    ctx.report.info(&format!(
+                        "device {}: wrote {} runs of {} blocks up to key {} in {:.1}ms, {:.0} runs/s",
+                        dev.dev_id,
+                        runs.len(),
+                        mapped_blocks - batch_blocks,
+                        position,
+                        elapsed * 1000.0,
+                        runs.len() as f64 / elapsed.max(1e-6)
+                    ));
+                }
+                last_batch = Instant::now();
+
+                if let Some(end) = chunk {
+                    checkpoint(
+                        ctx,
+                        &write_stage,
+                        dev.dev_id,
+                        end,
+                        mapped_blocks,
+                        dev.mapped_blocks,
+                    )?;
+                    nr_chunks += 1;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => ctx.watchdog.check()?,
+            Err(RecvTimeoutError::Disconnected) => break,
         }
     }
 
-    dumper
-        .join()
-        .expect("unexpected error")
-        .expect("metadata contains error");
-
+    for r in refs {
+        restorer.ref_shared(&r.name)?;
+        mapped_blocks += r.mapped_blocks;
+        nr_runs += r.nr_runs;
+        max_time = std::cmp::max(max_time, r.max_time);
+        position = r.end;
+    }
     restorer.device_e()?;
-    restorer.superblock_e()?;
-    restorer.eof()?;
+    ctx.progress.update(
+        dev.dev_id,
+        key_end.unwrap_or(position),
+        mapped_blocks,
+        dev.mapped_blocks,
+        true,
+    );
+
+    Ok(EmitStats {
+        mapped_blocks,
+        nr_runs,
+        nr_chunks,
+        max_time,
+    })
+}
 
+// Zeroes the superblock of the output, so the metadata it held, or the one
+// being written, doesn't look valid until the final superblock is in place
+fn invalidate_superblock(engine: &dyn IoEngine) -> Result<()> {
+    engine.write(&Block::zeroed(SUPERBLOCK_LOCATION))?;
     Ok(())
 }
 
-//------------------------------------------
-
+// Reserves the first blocks of the output, enough for the given blocks the
+// merge could take, by taking the blocks past them out of use under a single
+// lock of the space map. The trees can't spread beyond the reservation, and a
+// merge outgrowing it fails as running out of space.
+// Returns the blocks taken, or none if the reservation covers the output.
+fn reserve_blocks(
+    sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    nr_needed: u64,
+) -> Result<Option<std::ops::Range<u64>>> {
+    let mut sm = sm.lock().unwrap();
+    let nr_blocks = sm.get_nr_blocks()?;
+    if nr_needed >= nr_blocks {
+        return Ok(None);
+    }
+    for b in nr_needed..nr_blocks {
+        sm.set(b, 1)?;
+    }
+    Ok(Some(nr_needed..nr_blocks))
+}
+
+// Puts the blocks taken past a reservation back into use
+fn release_blocks(
+    sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    past: std::ops::Range<u64>,
+) -> Result<()> {
+    let mut sm = sm.lock().unwrap();
+    for b in past {
+        sm.set(b, 0)?;
+    }
+    Ok(())
+}
+
+// Tags a failure in writing the trees as running out of space if the output
+// has no block left, as the lower bound checked up front can fall short
+fn out_of_space(sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>, e: anyhow::Error) -> anyhow::Error {
+    let sm = sm.lock().unwrap();
+    match (sm.get_nr_allocated(), sm.get_nr_blocks()) {
+        (Ok(nr_allocated), Ok(nr_blocks)) if nr_allocated >= nr_blocks => {
+            fail(FailureKind::NoSpace, e)
+        }
+        _ => e,
+    }
+}
+
+// Zeroes the blocks left free by the space map, a batch at a time, returning
+// their number
+fn zero_free_blocks(
+    engine: &dyn IoEngine,
+    sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    batch_size: usize,
+) -> Result<u64> {
+    let nr_blocks = engine.get_nr_blocks();
+    let mut nr_zeroed = 0;
+    let mut begin = 0;
+    while begin < nr_blocks {
+        let end = std::cmp::min(begin + batch_size as u64, nr_blocks);
+        let mut blocks = Vec::new();
+        {
+            let sm = sm.lock().unwrap();
+            for b in begin..end {
+                if sm.get(b)? == 0 {
+                    blocks.push(Block::zeroed(b));
+                }
+            }
+        }
+        if !blocks.is_empty() {
+            for r in engine.write_many(&blocks)? {
+                r?;
+            }
+        }
+        nr_zeroed += blocks.len() as u64;
+        begin = end;
+    }
+    Ok(nr_zeroed)
+}
+
+// The blocks tracked by a bitmap of a space map, at 2 bits per block after
+// the header
+const ENTRIES_PER_BITMAP: u64 = (BLOCK_SIZE as u64 - 16) * 4;
+
+// The 64-bit keys and values held by a full btree node, after the header
+const MAX_NODE_ENTRIES: u64 = (BLOCK_SIZE as u64 - 32) / 16;
+
+// The nodes of a btree holding the given entries, with every node full
+fn min_tree_blocks(nr_entries: u64) -> u64 {
+    let mut nr_nodes = std::cmp::max(nr_entries.div_ceil(MAX_NODE_ENTRIES), 1);
+    let mut total = nr_nodes;
+    while nr_nodes > 1 {
+        nr_nodes = nr_nodes.div_ceil(MAX_NODE_ENTRIES);
+        total += nr_nodes;
+    }
+    total
+}
+
+// The nodes of a btree holding the given entries, with every node half full,
+// as the restorer splits the last entries between the last two nodes
+fn max_tree_blocks(nr_entries: u64) -> u64 {
+    let half = MAX_NODE_ENTRIES / 2;
+    let mut nr_nodes = std::cmp::max(nr_entries.div_ceil(half), 1);
+    let mut total = nr_nodes;
+    while nr_nodes > 1 {
+        nr_nodes = nr_nodes.div_ceil(half);
+        total += nr_nodes;
+    }
+    total
+}
+
+// A lower bound of the metadata blocks the output takes: the superblock, the
+// bitmaps of both space maps, and the trees packed full. The devices map at
+// least the blocks of their details, as the merged device holds every mapping
+// of the origin or the snapshot it's built from, unless the zeroed ranges
+// drop some of them.
+fn min_output_blocks(
+    ctx: &Context,
+    out_sb: &ir::Superblock,
+    devices: &[(ir::Device, RunSource, Option<u64>)],
+    nr_blocks: u64,
+) -> u64 {
+    let nr_devices = devices.len() as u64;
+    let shared = ctx.shared.lock().unwrap();
+    let mut total = 1 + nr_blocks.div_ceil(ENTRIES_PER_BITMAP) + 1;
+    total += out_sb.nr_data_blocks.div_ceil(ENTRIES_PER_BITMAP) + 1;
+    total += min_tree_blocks(nr_devices) * 2;
+    total += shared.nr_leaves() as u64;
+    for (dev, _, _) in devices {
+        // the shared leaves are counted once, by their definitions, taken as
+        // full as they aren't read until then
+        let mapped = if ctx.zeroed.is_some() {
+            0
+        } else {
+            dev.mapped_blocks
+                .saturating_sub(shared.nr_refs(dev.dev_id) * MAX_NODE_ENTRIES)
+        };
+        total += min_tree_blocks(mapped);
+    }
+    total
+}
+
+// An upper bound of the metadata blocks the output takes: the trees with
+// every node half full, and no leaf shared. A device maps a data block once
+// at most, and every data block could be held by the reference count tree.
+fn max_output_blocks(
+    out_sb: &ir::Superblock,
+    devices: &[(ir::Device, RunSource, Option<u64>)],
+    nr_blocks: u64,
+) -> u64 {
+    let nr_devices = devices.len() as u64;
+    let mut total = 1 + nr_blocks.div_ceil(ENTRIES_PER_BITMAP) + 1;
+    total += out_sb.nr_data_blocks.div_ceil(ENTRIES_PER_BITMAP) + 1;
+    total += max_tree_blocks(out_sb.nr_data_blocks);
+    total += max_tree_blocks(nr_devices) * 2;
+    total += max_tree_blocks(out_sb.nr_data_blocks) * nr_devices;
+    total
+}
+
+// Lists the first few blocks, as a failing device could have plenty
+fn describe_blocks<T: ToString>(blocks: &[T]) -> String {
+    const MAX_LISTED: usize = 16;
+    let mut desc: Vec<String> = blocks
+        .iter()
+        .take(MAX_LISTED)
+        .map(|b| b.to_string())
+        .collect();
+    if blocks.len() > MAX_LISTED {
+        desc.push(format!("and {} more", blocks.len() - MAX_LISTED));
+    }
+    desc.join(", ")
+}
+
+// The sizes of the pipeline between reading the runs and writing the output
+#[derive(Clone, Copy)]
+struct Pipeline {
+    queue_depth: usize, // batches of runs queued between the stages
+    buffer_len: usize,  // runs per batch
+    write_batch: usize, // metadata blocks written at a time
+}
+
+impl Pipeline {
+    // Fills in the sizes not given. An input engine reading many blocks at a
+    // time, e.g., io_uring, yields the runs in bursts, which a deeper queue
+    // absorbs. The writes are batched as deep as the queue of the output
+    // engine, so they are in flight together rather than one by one. Zero
+    // sizes are rejected as the command line does, as nothing would be sent
+    // or written.
+    fn tune(
+        queue_depth: Option<usize>,
+        buffer_len: Option<usize>,
+        write_batch: Option<usize>,
+        engine_in: &dyn IoEngine,
+        engine_out: &dyn IoEngine,
+    ) -> Result<Self> {
+        for (value, name) in [
+            (queue_depth, "--queue-depth"),
+            (buffer_len, "--buffer-len"),
+            (write_batch, "--write-batch"),
+        ] {
+            if value == Some(0) {
+                return Err(fail(
+                    FailureKind::Usage,
+                    anyhow!("{} must be at least 1", name),
+                ));
+            }
+        }
+
+        let bursty = engine_in.get_batch_size() > 1;
+        Ok(Self {
+            queue_depth: queue_depth.unwrap_or(if bursty { 2 * QUEUE_DEPTH } else { QUEUE_DEPTH }),
+            buffer_len: buffer_len.unwrap_or(BUFFER_LEN),
+            write_batch: write_batch
+                .unwrap_or(std::cmp::max(WRITE_BATCH_SIZE, engine_out.get_batch_size())),
+        })
+    }
+}
+
+// Charges the buffers of writing the output up front, as their size is known
+// once the output is open, so the leaf caches could make do with the rest
+fn charge_write_buffers(
+    budget: &MemoryBudget,
+    engine_out: &dyn IoEngine,
+    pipeline: &Pipeline,
+) -> Result<()> {
+    // the batches queued in the channel, plus the ones held by either end
+    let map_size = std::mem::size_of::<ir::Map>();
+    budget.charge(
+        CHANNEL_BUFFERS,
+        ((pipeline.queue_depth + 2) * pipeline.buffer_len * map_size) as u64,
+    )?;
+
+    // a byte per block for the reference counts up to 2, plus the write batch
+    budget.charge(
+        SPACE_MAP,
+        engine_out.get_nr_blocks() + (pipeline.write_batch * BLOCK_SIZE) as u64,
+    )
+}
+
+fn write_devices(
+    ctx: &Context,
+    sb: &Superblock,
+    out_sb: &ir::Superblock,
+    devices: Vec<(ir::Device, RunSource, Option<u64>)>,
+) -> Result<()> {
+    // relabelled for the data block size of the output up front, so the
+    // counts checked and reported below are those written
+    let devices = match data_rescale(ctx, sb)? {
+        Some(rescale) => devices
+            .into_iter()
+            .map(|(mut dev, source, key_end)| {
+                dev.mapped_blocks = rescale.blocks(dev.mapped_blocks)?;
+                let key_end = key_end.map(|end| rescale.key_end(end)).transpose()?;
+                let source = rescale_runs(source, rescale, dev.dev_id);
+                Ok((dev, source, key_end))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => devices,
+    };
+
+    // sorted by the id, so the same ids are adjacent
+    for pair in devices.windows(2) {
+        if pair[0].0.dev_id == pair[1].0.dev_id {
+            return Err(anyhow!(
+                "device id {} is used twice in the output",
+                pair[0].0.dev_id
+            ));
+        }
+    }
+
+    // a device can't be ahead of the pool, as the check of the output tells
+    if let Some(transaction) = ctx.transaction_id {
+        if let Some((dev, _, _)) = devices
+            .iter()
+            .find(|(dev, _, _)| dev.transaction > transaction)
+        {
+            return Err(fail(
+                FailureKind::Usage,
+                anyhow!(
+                    "the transaction id {} is below the transaction {} of device {}",
+                    transaction,
+                    dev.transaction,
+                    dev.dev_id
+                ),
+            ));
+        }
+    }
+
+    let batch_size = ctx.pipeline.write_batch;
+    let nr_blocks = ctx.engine_out.get_nr_blocks();
+
+    let sm = core_metadata_sm(nr_blocks, 2);
+
+    // fails before writing the trees, rather than running out of space
+    // halfway through
+    let nr_needed = min_output_blocks(ctx, out_sb, &devices, nr_blocks);
+    let nr_most = max_output_blocks(out_sb, &devices, nr_blocks);
+    ctx.report.info(&format!(
+        "the output takes {} to {} metadata blocks, has {}",
+        nr_needed, nr_most, nr_blocks
+    ));
+    if nr_needed > nr_blocks {
+        return Err(fail(
+            FailureKind::NoSpace,
+            anyhow!(
+                "output needs at least {} blocks, has {}",
+                nr_needed,
+                nr_blocks
+            ),
+        ));
+    }
+
+    // the metadata appended to takes the blocks from a region reserved in one
+    // go, rather than block by block as the trees grow
+    let reserved = if ctx.existing.is_some() && !ctx.dry_run {
+        let reserved = reserve_blocks(&sm, nr_most)?;
+        match &reserved {
+            Some(past) => ctx.report.info(&format!(
+                "reserved the first {} metadata blocks of the output for the merge",
+                past.start
+            )),
+            None => ctx.verbose_info(
+                1,
+                "the output may take all its metadata blocks, so none are reserved",
+            ),
+        }
+        reserved
+    } else {
+        None
+    };
+
+    // a failed merge leaves no superblock rather than one pointing at the
+    // trees being overwritten
+    if !ctx.dry_run {
+        invalidate_superblock(ctx.engine_out.as_ref())?;
+    }
+
+    // the restorer counts a reference to the data blocks of every leaf it
+    // writes, a shared leaf once, and writes the data space map from them
+    // the nodes failing to write are moved elsewhere, if asked to
+    let relocator = if ctx.skip_bad_blocks && !ctx.dry_run {
+        Some(Arc::new(RelocatingIoEngine::new(
+            ctx.engine_out.clone(),
+            sm.clone(),
+        )))
+    } else {
+        None
+    };
+    let engine: Arc<dyn IoEngine + Send + Sync> = match &relocator {
+        Some(relocator) => relocator.clone(),
+        None => ctx.engine_out.clone(),
+    };
+    let mut w = WriteBatcher::new(engine, sm.clone(), batch_size);
+    let mut restorer = Restorer::new(&mut w, ctx.report.clone());
+
+    restorer
+        .superblock_b(out_sb)
+        .map_err(|e| out_of_space(&sm, e))?;
+
+    // the superblock time is already zero if the times are reset
+    let clamp_time = if ctx.clamp_times || ctx.reset_time {
+        Some(out_sb.time)
+    } else {
+        None
+    };
+
+    // the shared leaves are defined ahead of the devices referencing them
+    let mut shared = std::mem::take(&mut *ctx.shared.lock().unwrap());
+    shared
+        .define(
+            ctx.engine_in.as_ref(),
+            &mut restorer,
+            clamp_time,
+            ctx.nr_data_blocks,
+        )
+        .map_err(|e| out_of_space(&sm, e))?;
+
+    // shared by the devices, so the data blocks they share move together
+    let remapper = ctx
+        .remap
+        .clone()
+        .map(|policy| Arc::new(Mutex::new(DataRemapper::new(policy, out_sb.nr_data_blocks))));
+
+    let mut stale = Vec::new();
+    let mut max_time = 0;
+    let mut nr_chunks = 0;
+    let mut mapped_blocks = 0;
+    let mut nr_runs = 0;
+    ctx.progress.phase("merge");
+    ctx.progress
+        .expect(devices.iter().map(|(dev, _, _)| dev.mapped_blocks).sum());
+
+    for (mut dev, source, key_end) in devices {
+        if ctx.reset_time {
+            dev.creation_time = 0;
+            dev.snap_time = 0;
+        }
+        ctx.verbose_info(1, &format!("writing device {}", dev.dev_id));
+        let source = match &remapper {
+            Some(remapper) => remap_runs(source, remapper.clone()),
+            None => source,
+        };
+        let refs = shared.take_refs(dev.dev_id);
+        let stats = emit_device(ctx, &mut restorer, &dev, source, refs, key_end, clamp_time)
+            .map_err(|e| out_of_space(&sm, e))?;
+        ctx.verbose_info(
+            1,
+            &format!(
+                "wrote device {}: {} mapped blocks in {} runs",
+                dev.dev_id, stats.mapped_blocks, stats.nr_runs
+            ),
+        );
+        if ctx.dry_run {
+            ctx.report.info(&format!(
+                "dry run: device {} would have {} mapped blocks in {} runs",
+                dev.dev_id, stats.mapped_blocks, stats.nr_runs
+            ));
+        }
+        if stats.mapped_blocks != dev.mapped_blocks {
+            stale.push((dev.dev_id, stats.mapped_blocks));
+        }
+        max_time = std::cmp::max(max_time, stats.max_time);
+        nr_chunks += stats.nr_chunks;
+        mapped_blocks += stats.mapped_blocks;
+        nr_runs += stats.nr_runs;
+    }
+
+    let zero_length = ctx.sanitized.zero_length.load(Ordering::Relaxed);
+    let joined = ctx.sanitized.joined.load(Ordering::Relaxed);
+    if zero_length > 0 || joined > 0 {
+        ctx.report.info(&format!(
+            "sanitized the runs ahead of the merge: dropped {} zero-length runs, joined {} fragments",
+            zero_length, joined
+        ));
+    }
+
+    // the space maps written count the blocks past the reservation as free
+    if let Some(past) = reserved {
+        release_blocks(&sm, past)?;
+    }
+    if let Some(relocator) = &relocator {
+        relocator.top_level()?;
+    }
+
+    restorer
+        .superblock_e()
+        .and_then(|_| restorer.eof())
+        .map_err(|e| out_of_space(&sm, e))?;
+    drop(restorer);
+
+    if let Some(relocator) = &relocator {
+        let moved = relocator.relocated();
+        if !moved.is_empty() {
+            let moved: Vec<String> = moved
+                .iter()
+                .map(|(from, to)| format!("{} -> {}", from, to))
+                .collect();
+            ctx.report.warning(&format!(
+                "relocated {} nodes failing to write: {}",
+                moved.len(),
+                describe_blocks(&moved)
+            ));
+            let unwritable = relocator.unwritable();
+            ctx.report.warning(&format!(
+                "skipped {} unwritable output blocks: {}",
+                unwritable.len(),
+                describe_blocks(&unwritable)
+            ));
+        }
+    }
+
+    if let Some(trace) = &ctx.trace {
+        trace.flush()?;
+    }
+
+    let nr_allocated = sm.lock().unwrap().get_nr_allocated()?;
+    {
+        let mut summary = ctx.summary.lock().unwrap();
+        summary.mapped_blocks = mapped_blocks;
+        summary.nr_runs = nr_runs;
+        summary.metadata_blocks = nr_allocated;
+    }
+
+    if let Some(zeroed) = &ctx.zeroed {
+        ctx.report.info(&format!(
+            "dropped the mappings of {} blocks to the {} listed ranges of zeroed data blocks",
+            ctx.dropped_zeroed.load(Ordering::Relaxed),
+            zeroed.nr_ranges()
+        ));
+    }
+
+    if let Some(remapper) = &remapper {
+        let remapper = remapper.lock().unwrap();
+        ctx.report.info(&format!(
+            "moved {} data blocks for the target pool",
+            remapper.nr_moved()
+        ));
+        if let (Some(path), false) = (&ctx.remap_table, ctx.dry_run) {
+            remapper.write_table(path)?;
+        }
+    }
+
+    if let Some(n) = ctx.chunk_blocks {
+        ctx.report.info(&format!(
+            "wrote the devices in {} chunks of {} blocks",
+            nr_chunks, n
+        ));
+    }
+
+    if ctx.timings {
+        ctx.report
+            .info(&format!("channel stalls: {}", ctx.stalls.summary()));
+    }
+
+    // the superblock and the details were discarded, so there's nothing to patch
+    if ctx.dry_run {
+        ctx.report.info(&format!(
+            "dry run: the output would take {} metadata blocks ({} bytes) out of {}",
+            nr_allocated,
+            nr_allocated * BLOCK_SIZE as u64,
+            nr_blocks
+        ));
+        ctx.verbose_info(1, &format!("memory use: {}", ctx.budget.summary()));
+        return Ok(());
+    }
+
+    // the restorer writes the superblock along with the trees, so it's pulled
+    // again until the patches below are done
+    let mut sb = read_superblock(ctx.engine_out.as_ref(), SUPERBLOCK_LOCATION)?;
+    invalidate_superblock(ctx.engine_out.as_ref())?;
+
+    // the kernel refuses mappings from the future
+    if ctx.reset_time {
+        ctx.report.info("reset the mapping and device times to 0");
+    } else if max_time > out_sb.time {
+        if ctx.clamp_times {
+            ctx.report.info(&format!(
+                "clamped mapping times newer than the superblock time {}",
+                out_sb.time
+            ));
+        } else {
+            ctx.report.info(&format!(
+                "bumped the superblock time from {} to {}",
+                out_sb.time, max_time
+            ));
+            sb.time = max_time;
+        }
+    }
+
+    // the restorer takes the mapped_blocks from the input details, which might
+    // not match the merged results.
+    for (dev_id, mapped_blocks) in stale {
+        ctx.verbose_info(
+            1,
+            &format!(
+                "patching the details of device {} to {} mapped blocks",
+                dev_id, mapped_blocks
+            ),
+        );
+        update_device_details(
+            ctx.engine_out.clone(),
+            sb.details_root,
+            dev_id,
+            mapped_blocks,
+        )?;
+    }
+
+    // the leftovers of the previous content of the output are zeroed, so the
+    // output depends on nothing but the input and the options
+    if ctx.deterministic && ctx.output_format == MetadataFormat::Binary {
+        let nr_zeroed = zero_free_blocks(ctx.engine_out.as_ref(), &sm, batch_size)?;
+        ctx.report.info(&format!(
+            "zeroed the {} unused output blocks for a deterministic output",
+            nr_zeroed
+        ));
+    }
+
+    // the uuid goes along, as the superblock is written last and only once
+    write_superblock_with_uuid(ctx.engine_out.as_ref(), &sb, ctx.uuid.as_ref())?;
+
+    ctx.verbose_info(1, &format!("memory use: {}", ctx.budget.summary()));
+    ctx.verbose_info(
+        1,
+        &format!("output latency: {}", ctx.output_writes.summary()),
+    );
+
+    Ok(())
+}
+
+//------------------------------------------
+
+/// Where the metadata lives, either a file or device, or an engine prepared by the caller
+pub enum MetadataLocation<'a> {
+    Path(&'a Path),
+    Engine(Arc<dyn IoEngine + Send + Sync>),
+    #[cfg(feature = "remote")]
+    Url(&'a str),
+}
+
+impl<'a> MetadataLocation<'a> {
+    /// Interprets a command line argument, which could be a URL to object storage
+    pub fn from_arg(arg: &'a str) -> Self {
+        #[cfg(feature = "remote")]
+        if crate::remote::is_url(arg) {
+            return MetadataLocation::Url(arg);
+        }
+        MetadataLocation::Path(Path::new(arg))
+    }
+}
+
+/// The io engine to open a metadata file or device with. Auto falls back to
+/// sync io if io_uring is unavailable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineChoice {
+    Sync,
+    Async,
+    Auto,
+}
+
+/// The options of a merge, mirroring the command line of thin_merge.
 pub struct ThinMergeOptions<'a> {
-    pub input: &'a Path,
-    pub output: &'a Path,
+    pub input: MetadataLocation<'a>,
+    pub output: MetadataLocation<'a>,
     pub engine_opts: EngineOptions,
+    pub input_engine: EngineChoice,
+    pub output_engine: EngineChoice,
     pub report: Arc<Report>,
-    pub origin: u64,
+    pub origin: Option<u64>, // none if the origin is lost
     pub snapshot: Option<u64>,
+    pub intermediates: Vec<u64>, // snapshots stacked between the origin and the snapshot
+    pub since_time: Option<u32>,
     pub rebase: bool,
+    pub emit_residue: bool,
+    pub output_format: Option<MetadataFormat>,
+    pub sort_leaves: bool,
+    pub accept_diverged_origin: bool,
+    pub clamp_times: bool,
+    pub reset_time: bool,
+    pub stall_timeout: Option<u64>,
+    pub abort_on_stall: bool,
+    pub max_mem: Option<u64>,
+    pub stats: bool,
+    pub repair_compat_check: bool,
+    pub report_format: ReportFormat,
+    pub progress_fd: Option<i32>,
+    pub progress_sink: Option<Arc<dyn ProgressSink>>, // takes the events rather than the report
+    pub cancel: Option<CancellationToken>,            // fails the merge once cancelled
+    pub report_interval: Option<ReportInterval>,      // between the progress updates of a device
+    pub dry_run: bool,
+    pub what_changes: bool, // report the ranges the merge changes, ignoring the output
+    pub keep_other_devices: bool,
+    pub append: bool, // add the devices to the metadata already in the output
+    pub timings: bool,
+    pub new_dev_id: Option<u64>, // written under this id rather than the source one
+    pub skip_bad_blocks: bool,
+    pub skip_bad_nodes: bool,
+    pub auto_repair: bool,
+    pub deterministic: bool,
+    pub force: bool,
+    pub trace_merges: Option<&'a Path>,
+    pub zeroed: Option<Arc<ZeroedBlocks>>, // data blocks whose mappings are dropped
+    pub copy_data: Option<CopyData>,       // an external origin copied into the pool
+    pub remap: Option<RemapPolicy>,        // where the data blocks go in another pool
+    pub remap_table: Option<&'a Path>,     // the moves of the data blocks written on success
+    pub key_range: Option<(u64, u64)>,     // the half-open range of thin blocks to merge
+    pub run_as: Option<RunAs>,             // the user to switch to once the engines are open
+    pub base_batch: Option<usize>, // leaves read at a time from the origin, or the engine batch
+    pub snap_batch: Option<usize>, // leaves read at a time from the snapshots
+    pub prefetch: Option<usize>,   // batches of leaves read ahead by a thread per merged tree
+    pub dump_threads: Option<usize>, // kept devices dumped at a time, beside the merge
+    pub queue_depth: Option<usize>, // batches of runs queued for the writer, or tuned to the engine
+    pub buffer_len: Option<usize>, // runs per batch handed to the writer
+    pub write_batch: Option<usize>, // metadata blocks written at a time, or the engine batch
+    pub nr_data_blocks: Option<u64>, // the size of the data device to pair the output with
+    pub data_block_size: Option<u32>, // in sectors, the one the target pool expects
+    pub auto_snapshot: bool,       // check the snapshot given, or list the candidates
+    pub chunk_blocks: Option<u64>, // thin blocks between the checkpoints of a device
+    pub chunk_pause: Option<Duration>, // slept at every checkpoint
+    pub quiet: bool,               // the report is left to the errors
+    pub verbose: u8,               // 1 for the phases, 2 for the batches too
+    pub verify: bool,              // walk the output once written, as thin_check would
+    pub atomic_rename: bool,       // merge into a file beside the output, then rename it over
+    pub output_version: Option<u32>, // the metadata version of the output, or that of the input
+    pub uuid: Option<String>,      // the uuid of the output superblock, up to 16 bytes
+    pub preserve_uuid: bool,       // the uuid of the input superblock is kept instead
+    pub transaction_id: Option<u64>, // the transaction of the output, or that of the input
+    pub summary_file: Option<&'a Path>, // the json totals written on success, or - for stdout
+}
+
+impl<'a> ThinMergeOptions<'a> {
+    /// Merges between in-memory engines, e.g., the ones built by crate::memory,
+    /// without touching any files.
+    pub fn in_memory(
+        input: Arc<dyn IoEngine + Send + Sync>,
+        output: Arc<dyn IoEngine + Send + Sync>,
+        report: Arc<Report>,
+        origin: u64,
+    ) -> Self {
+        Self::with_defaults(
+            MetadataLocation::Engine(input),
+            MetadataLocation::Engine(output),
+            report,
+            Some(origin),
+        )
+    }
+
+    /// Merges between metadata files or devices, with the defaults of the
+    /// command line, e.g., for the jobs of a job file.
+    pub fn from_paths(
+        input: &'a Path,
+        output: &'a Path,
+        report: Arc<Report>,
+        origin: Option<u64>,
+    ) -> Self {
+        let mut opts = Self::with_defaults(
+            MetadataLocation::Path(input),
+            MetadataLocation::Path(output),
+            report,
+            origin,
+        );
+        opts.output_engine = EngineChoice::Auto;
+        opts
+    }
+
+    fn with_defaults(
+        input: MetadataLocation<'a>,
+        output: MetadataLocation<'a>,
+        report: Arc<Report>,
+        origin: Option<u64>,
+    ) -> Self {
+        ThinMergeOptions {
+            input,
+            output,
+            engine_opts: EngineOptions {
+                tool: ToolType::Thin,
+                engine_type: EngineType::Sync,
+                use_metadata_snap: false,
+            },
+            input_engine: EngineChoice::Sync,
+            output_engine: EngineChoice::Sync,
+            report,
+            origin,
+            snapshot: None,
+            intermediates: Vec::new(),
+            since_time: None,
+            rebase: false,
+            emit_residue: false,
+            output_format: None,
+            sort_leaves: false,
+            accept_diverged_origin: false,
+            clamp_times: false,
+            reset_time: false,
+            stall_timeout: None,
+            abort_on_stall: false,
+            max_mem: None,
+            stats: false,
+            repair_compat_check: false,
+            report_format: ReportFormat::Text,
+            progress_fd: None,
+            progress_sink: None,
+            cancel: None,
+            report_interval: None,
+            dry_run: false,
+            what_changes: false,
+            keep_other_devices: false,
+            append: false,
+            timings: false,
+            new_dev_id: None,
+            skip_bad_blocks: false,
+            skip_bad_nodes: false,
+            auto_repair: false,
+            deterministic: false,
+            force: false,
+            trace_merges: None,
+            zeroed: None,
+            copy_data: None,
+            remap: None,
+            remap_table: None,
+            key_range: None,
+            run_as: None,
+            base_batch: None,
+            snap_batch: None,
+            prefetch: None,
+            dump_threads: None,
+            queue_depth: None,
+            buffer_len: None,
+            write_batch: None,
+            nr_data_blocks: None,
+            data_block_size: None,
+            auto_snapshot: false,
+            chunk_blocks: None,
+            chunk_pause: None,
+            quiet: false,
+            verbose: 0,
+            verify: false,
+            atomic_rename: false,
+            output_version: None,
+            uuid: None,
+            preserve_uuid: false,
+            transaction_id: None,
+            summary_file: None,
+        }
+    }
 }
 
 struct Context {
     report: Arc<Report>,
     engine_in: Arc<dyn IoEngine + Send + Sync>,
     engine_out: Arc<dyn IoEngine + Send + Sync>,
+    output_format: MetadataFormat,
+    _staged_input: Option<TempPath>,
+    staged_output: Option<TempPath>, // renamed over the output, or uploaded, once complete
+    sort_leaves: bool,
+    accept_diverged_origin: bool,
+    diverged: HashMap<u64, u32>, // snapshot creation times the trees are checked against, by root
+    clamp_times: bool,
+    reset_time: bool, // every time of the output is zeroed
+    dry_run: bool,
+    keep_other_devices: bool,
+    new_dev_id: Option<u64>,
+    skip_bad_blocks: bool,
+    skipped: Option<Arc<SkippedLeaves>>, // the damaged leaves skipped, if skipping
+    deterministic: bool,
+    trace: Option<Arc<MergeTrace>>,
+    zeroed: Option<Arc<ZeroedBlocks>>,
+    dropped_zeroed: Arc<AtomicU64>,
+    copy_data: Option<CopyData>,
+    copied: Arc<AtomicU64>, // nr blocks of the origin copied into the pool
+    remap: Option<RemapPolicy>,
+    remap_table: Option<PathBuf>,
+    key_range: Option<(u64, u64)>, // the thin blocks of the merged device to write
+    base_batch: usize,
+    snap_batch: usize,
+    prefetch: Option<usize>,
+    dump_threads: usize,
+    pipeline: Pipeline,
+    nr_data_blocks: Option<u64>,
+    data_block_size: Option<u32>,
+    output_version: Option<u32>,
+    uuid: Option<[u8; UUID_SIZE]>, // stamped on the output superblock
+    transaction_id: Option<u64>,
+    chunk_blocks: Option<u64>,
+    chunk_pause: Option<Duration>,
+    synced_output: Option<std::fs::File>, // the output file, synced at every checkpoint
+    verbose: u8,
+    summary: Arc<Mutex<RunSummary>>,
+    input_io: Arc<IoCounters>,
+    output_io: Arc<IoCounters>,
+    existing: Option<(Arc<dyn IoEngine + Send + Sync>, Superblock)>, // the output appended to
+    since_time: Option<u32>,
+    identical: Arc<AtomicU64>,
+    aged: Arc<AtomicU64>, // nr blocks of the snapshot left to the origin by since_time
+    sanitized: Arc<SanitizeStats>,
+    output_writes: Arc<LatencyHistogram>,
+    stalls: Arc<ChannelStalls>,
+    shared: Mutex<SharedLeaves>, // the leaves shared by the kept devices
+    timings: bool,
+    progress: Arc<Progress>,
+    watchdog: Watchdog,
+    budget: Arc<MemoryBudget>,
+}
+
+impl Context {
+    // Reports the messages of the given verbosity, shown with as many -v
+    fn verbose_info(&self, level: u8, msg: &str) {
+        if self.verbose >= level {
+            self.report.info(msg);
+        }
+    }
+}
+
+// Builds the engine of the given choice, where the auto choice falls back to
+// sync io if io_uring is unavailable on the host.
+fn open_engine(
+    path: &Path,
+    opts: &ThinMergeOptions,
+    choice: EngineChoice,
+    configure: impl Fn(EngineBuilder) -> EngineBuilder,
+) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+    let build = |engine_type| {
+        let mut engine_opts = opts.engine_opts.clone();
+        engine_opts.engine_type = engine_type;
+        configure(EngineBuilder::new(path, &engine_opts)).build()
+    };
+
+    match choice {
+        EngineChoice::Sync => build(EngineType::Sync),
+        EngineChoice::Async => build(EngineType::Async)
+            .map_err(|e| anyhow!("async io engine unavailable for {}: {}", path.display(), e)),
+        EngineChoice::Auto => build(EngineType::Async).or_else(|e| {
+            opts.report.info(&format!(
+                "falling back to sync io for {}: {}",
+                path.display(),
+                e
+            ));
+            build(EngineType::Sync)
+        }),
+    }
+}
+
+// Opens the binary metadata files or devices given by path, with the privileges
+// the tool was started with, so the user to switch to needn't have access to
+// them. They're taken as binary metadata, as with descriptors passed in, and
+// nothing is read from them yet. Pipes, and the outputs written by name once
+// merged, are left to be opened after the switch.
+fn open_before_switch(opts: ThinMergeOptions) -> Result<ThinMergeOptions> {
+    check_input_found(&opts.input)?;
+    let output_format = get_output_format(&opts)?;
+    let input = match opts.input {
+        MetadataLocation::Path(path) if !is_stream(path)? => {
+            if !opts.engine_opts.use_metadata_snap && !opts.force {
+                check_not_active_pool(path)?;
+            }
+            if opts.copy_data.is_some() {
+                check_not_held(path)?;
+            }
+            MetadataLocation::Engine(Arc::new(FdIoEngine::open(path, false)?))
+        }
+        input => input,
+    };
+
+    let renamed = opts.atomic_rename || opts.append;
+    let output = match opts.output {
+        MetadataLocation::Path(path)
+            if !opts.dry_run
+                && !opts.what_changes
+                && !renamed
+                && output_format == MetadataFormat::Binary =>
+        {
+            MetadataLocation::Engine(Arc::new(FdIoEngine::open(path, true)?))
+        }
+        output => output,
+    };
+
+    Ok(ThinMergeOptions {
+        input,
+        output,
+        ..opts
+    })
+}
+
+// A missing input is told apart from one that can't be read, unlike the other
+// files named by the options
+fn check_input_found(input: &MetadataLocation) -> Result<()> {
+    match input {
+        MetadataLocation::Path(path) if !path.exists() => Err(fail(
+            FailureKind::InputNotFound,
+            anyhow!("the input {} is not found", path.display()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn get_output_format(opts: &ThinMergeOptions) -> Result<MetadataFormat> {
+    // non-existent output files are created on exporting
+    let stream = match &opts.output {
+        MetadataLocation::Path(path) => is_stdout(path) || is_stream(path).unwrap_or(false),
+        _ => false,
+    };
+
+    let format = match (&opts.output, opts.output_format) {
+        (_, Some(format)) => format,
+        (MetadataLocation::Path(_), None) if stream => MetadataFormat::Xml,
+        (MetadataLocation::Path(path), None) => REGISTRY.detect_output(path),
+        _ => MetadataFormat::Binary,
+    };
+
+    if format != MetadataFormat::Binary && !matches!(opts.output, MetadataLocation::Path(_)) {
+        return Err(fail(
+            FailureKind::Usage,
+            anyhow!("{} output requires an output file", REGISTRY.name(format)),
+        ));
+    }
+
+    if format == MetadataFormat::Binary && stream {
+        return Err(fail(
+            FailureKind::Usage,
+            anyhow!("binary output requires a seekable file or device"),
+        ));
+    }
+
+    Ok(format)
+}
+
+// Creates the file beside the output to merge into, as large as the output
+fn stage_output(path: &Path) -> Result<TempPath> {
+    let meta = std::fs::metadata(path)?;
+    if !meta.file_type().is_file() {
+        return Err(anyhow!(
+            "renaming into place requires a regular output file, but {} isn't one",
+            path.display()
+        ));
+    }
+    TempPath::create_beside(path, meta.len()).map_err(|e| {
+        anyhow!(
+            "unable to create the file beside the output {}: {}",
+            path.display(),
+            e
+        )
+    })
+}
+
+// Flushes the merged file, then renames it over the output
+fn commit_output(staged: TempPath, path: &Path) -> Result<()> {
+    std::fs::File::open(staged.path())?.sync_all()?;
+    let tmp = staged.path().to_path_buf();
+    staged
+        .persist(path)
+        .map_err(|e| anyhow!("unable to rename {} over the output: {}", tmp.display(), e))?;
+
+    // the rename itself is durable once the directory is
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// The size of metadata held in memory, unknown if the output isn't binary
+fn staged_size(nr_blocks: Option<u64>) -> u64 {
+    nr_blocks.unwrap_or(0) * BLOCK_SIZE as u64
 }
 
 fn mk_context(opts: &ThinMergeOptions) -> Result<Context> {
-    let engine_in = EngineBuilder::new(opts.input, &opts.engine_opts)
-        .exclusive(!opts.engine_opts.use_metadata_snap)
-        .build()?;
+    check_input_found(&opts.input)?;
+    let output_format = get_output_format(opts)?;
+    let budget = Arc::new(MemoryBudget::new(opts.max_mem));
+
+    let output_blocks = match &opts.output {
+        _ if opts.what_changes => None,
+        // a dry run without an output is sized from the input
+        MetadataLocation::Engine(engine) if opts.dry_run && engine.get_nr_blocks() == 0 => None,
+        MetadataLocation::Path(path) if output_format == MetadataFormat::Binary => {
+            Some(file_size(path)? / BLOCK_SIZE as u64)
+        }
+        MetadataLocation::Engine(engine) => Some(engine.get_nr_blocks()),
+        _ => None,
+    };
 
-    let mut out_opts = opts.engine_opts.clone();
-    out_opts.engine_type = EngineType::Sync; // sync write temporarily
-    let engine_out = EngineBuilder::new(opts.output, &out_opts)
-        .write(true)
-        .build()?;
+    // converted to binary from another format, or read from a stream
+    let mut input_staged = true;
+    let (engine_in, staged_input) = match &opts.input {
+        MetadataLocation::Path(path) if is_stream(path)? => {
+            budget.charge(STAGING, staged_size(output_blocks))?;
+            let engine = stage_stream(path, output_blocks, opts.report.clone())?;
+            if output_blocks.is_none() {
+                // sized by the content, once read
+                budget.charge(STAGING, staged_size(Some(engine.get_nr_blocks())))?;
+            }
+            (engine, None)
+        }
+        MetadataLocation::Path(path) => match REGISTRY.detect_input(path)? {
+            MetadataFormat::Binary => {
+                let exclusive = !opts.engine_opts.use_metadata_snap;
+                if exclusive && !opts.force {
+                    check_not_active_pool(path)?;
+                }
+                if opts.copy_data.is_some() {
+                    check_not_held(path)?;
+                }
+                let engine =
+                    open_engine(path, opts, opts.input_engine, |b| b.exclusive(exclusive))?;
+                input_staged = false;
+                (engine, None)
+            }
+            MetadataFormat::Xml => {
+                // sized by the content if the output doesn't tell
+                let nr_blocks = match output_blocks {
+                    Some(nr_blocks) => nr_blocks,
+                    None => estimate_xml_blocks(path)?,
+                };
+                budget.charge(STAGING, staged_size(Some(nr_blocks)))?;
+                stage_input(
+                    path,
+                    MetadataFormat::Xml,
+                    Some(nr_blocks),
+                    opts.report.clone(),
+                )?
+            }
+            format => {
+                // compressed binary is read in place, along with its uuid
+                input_staged = format != MetadataFormat::Zstd;
+                stage_input(path, format, output_blocks, opts.report.clone())?
+            }
+        },
+        MetadataLocation::Engine(engine) => {
+            input_staged = false;
+            (engine.clone(), None)
+        }
+        #[cfg(feature = "remote")]
+        MetadataLocation::Url(url) => {
+            let engine: Arc<dyn IoEngine + Send + Sync> =
+                Arc::new(crate::remote::HttpIoEngine::new(url)?);
+            input_staged = false;
+            (engine, None)
+        }
+    };
+
+    // the devices already in the output are read from it as it is, while the
+    // merge is staged beside it
+    let existing = if opts.append {
+        let path = match &opts.output {
+            MetadataLocation::Path(path)
+                if !opts.dry_run
+                    && !opts.what_changes
+                    && output_format == MetadataFormat::Binary =>
+            {
+                path
+            }
+            _ => {
+                return Err(fail(
+                    FailureKind::Usage,
+                    anyhow!("appending requires writing a binary output file"),
+                ))
+            }
+        };
+        let engine = open_engine(path, opts, opts.output_engine, |b| b)?;
+        let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)
+            .map_err(|e| anyhow!("the output holds no metadata to append to: {}", e))?;
+        Some((engine, sb))
+    } else {
+        None
+    };
+
+    let mut staged_output = None;
+    let engine_out: Arc<dyn IoEngine + Send + Sync> = match &opts.output {
+        // sized as the output, or the input if unknown, i.e., the estimate of
+        // xml input, but never written
+        _ if opts.dry_run || opts.what_changes => Arc::new(DiscardIoEngine::new(
+            output_blocks.unwrap_or_else(|| engine_in.get_nr_blocks()),
+        )),
+        MetadataLocation::Path(path) if output_format == MetadataFormat::Binary => {
+            if opts.atomic_rename || opts.append {
+                let tmp = stage_output(path)?;
+                let engine = open_engine(tmp.path(), opts, opts.output_engine, |b| b.write(true))?;
+                staged_output = Some(tmp);
+                engine
+            } else {
+                open_engine(path, opts, opts.output_engine, |b| b.write(true))?
+            }
+        }
+        _ if opts.atomic_rename => {
+            return Err(fail(
+                FailureKind::Usage,
+                anyhow!("renaming into place requires a binary output file"),
+            ));
+        }
+        // staged in memory, then exported once completed
+        MetadataLocation::Path(_) => {
+            budget.charge(STAGING, staged_size(Some(engine_in.get_nr_blocks())))?;
+            zeroed_engine(engine_in.get_nr_blocks())?
+        }
+        MetadataLocation::Engine(engine) => engine.clone(),
+        // staged in a temporary file, then uploaded once completed
+        #[cfg(feature = "remote")]
+        MetadataLocation::Url(_) => {
+            let (engine, tmp) = crate::remote::stage_upload(engine_in.get_nr_blocks())?;
+            staged_output = Some(tmp);
+            engine
+        }
+    };
+
+    // synced at every checkpoint, so the blocks written by then are on disk
+    let synced_output = match (&staged_output, &opts.output) {
+        _ if opts.chunk_blocks.is_none() || opts.dry_run || opts.what_changes => None,
+        (Some(tmp), _) => Some(std::fs::File::open(tmp.path())?),
+        (None, MetadataLocation::Path(path)) if output_format == MetadataFormat::Binary => {
+            Some(std::fs::File::open(path)?)
+        }
+        _ => None,
+    };
+
+    let output_writes = Arc::new(LatencyHistogram::default());
+    let engine_out = Arc::new(TimedIoEngine::new(engine_out, output_writes.clone()));
+
+    // counted for the summary
+    let input_io = Arc::new(IoCounters::default());
+    let output_io = Arc::new(IoCounters::default());
+    let engine_in: Arc<dyn IoEngine + Send + Sync> =
+        Arc::new(CountingIoEngine::new(engine_in, input_io.clone()));
+    let engine_out = Arc::new(CountingIoEngine::new(engine_out, output_io.clone()));
+
+    let pipeline = Pipeline::tune(
+        opts.queue_depth,
+        opts.buffer_len,
+        opts.write_batch,
+        engine_in.as_ref(),
+        engine_out.as_ref(),
+    )?;
+    if !opts.what_changes {
+        charge_write_buffers(&budget, engine_out.as_ref(), &pipeline)?;
+    }
+
+    // a sink or a descriptor given takes the events whatever the format
+    let progress = match (opts.report_format, opts.progress_fd) {
+        _ if opts.progress_sink.is_some() => Progress::sink(opts.progress_sink.clone().unwrap()),
+        (_, Some(fd)) => Progress::from_fd(fd)?,
+        (ReportFormat::Json, None) => Progress::stderr(),
+        (ReportFormat::Text, _) if opts.quiet => Progress::disabled(),
+        (ReportFormat::Text, _) => Progress::text(opts.report.clone()),
+    };
+    let progress = progress.with_interval(opts.report_interval.unwrap_or_default());
+
+    // the origin tree could be much larger than the snapshot one, so either
+    // could be tuned apart from the engine
+    let base_batch = opts.base_batch.unwrap_or(engine_in.get_batch_size());
+    let snap_batch = opts.snap_batch.unwrap_or(engine_in.get_batch_size());
+
+    let uuid = output_uuid(opts, engine_in.as_ref(), input_staged)?;
 
     Ok(Context {
         report: opts.report.clone(),
         engine_in,
         engine_out,
+        output_format,
+        _staged_input: staged_input,
+        staged_output,
+        sort_leaves: opts.sort_leaves,
+        accept_diverged_origin: opts.accept_diverged_origin,
+        diverged: HashMap::new(),
+        clamp_times: opts.clamp_times,
+        reset_time: opts.reset_time,
+        dry_run: opts.dry_run,
+        keep_other_devices: opts.keep_other_devices,
+        new_dev_id: opts.new_dev_id,
+        skip_bad_blocks: opts.skip_bad_blocks,
+        skipped: opts
+            .skip_bad_nodes
+            .then(|| Arc::new(SkippedLeaves::default())),
+        deterministic: opts.deterministic,
+        trace: match opts.trace_merges {
+            Some(path) => Some(Arc::new(MergeTrace::create(path)?)),
+            None => None,
+        },
+        zeroed: opts.zeroed.clone(),
+        dropped_zeroed: Arc::new(AtomicU64::new(0)),
+        copy_data: opts.copy_data.clone(),
+        copied: Arc::new(AtomicU64::new(0)),
+        remap: opts.remap.clone(),
+        remap_table: opts.remap_table.map(Path::to_path_buf),
+        key_range: opts.key_range,
+        base_batch,
+        snap_batch,
+        prefetch: opts.prefetch,
+        dump_threads: opts.dump_threads.unwrap_or(DUMP_THREADS),
+        pipeline,
+        nr_data_blocks: opts.nr_data_blocks,
+        data_block_size: opts.data_block_size,
+        output_version: opts.output_version,
+        uuid,
+        transaction_id: opts.transaction_id,
+        chunk_blocks: opts.chunk_blocks,
+        chunk_pause: opts.chunk_pause,
+        synced_output,
+        verbose: opts.verbose,
+        summary: Arc::new(Mutex::new(RunSummary::default())),
+        input_io,
+        output_io,
+        existing,
+        since_time: opts.since_time,
+        identical: Arc::new(AtomicU64::new(0)),
+        aged: Arc::new(AtomicU64::new(0)),
+        sanitized: Arc::new(SanitizeStats::default()),
+        output_writes,
+        stalls: Arc::new(ChannelStalls::default()),
+        shared: Mutex::new(SharedLeaves::default()),
+        timings: opts.timings,
+        progress: Arc::new(progress),
+        watchdog: Watchdog::new(
+            opts.report.clone(),
+            opts.stall_timeout.map(Duration::from_secs),
+            opts.abort_on_stall,
+            opts.cancel.clone(),
+        ),
+        budget,
     })
 }
 
-fn read_patched_superblock_snap(engine: &dyn IoEngine) -> Result<Superblock> {
-    // here we don't use read_superblock_snap() as we need both the main superblock and the
-    // metadata snapshot.
+// A live pool could release and reserve the metadata snapshot at any time, so
+// a failed read is retried a few times before giving up
+const SNAP_RETRIES: usize = 5;
+const SNAP_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+// Reads the metadata snapshot along with its location. The read is taken as
+// valid only if the main superblock still points at the same snapshot
+// afterwards, i.e., it wasn't released or replaced midway.
+fn read_metadata_snap(engine: &dyn IoEngine) -> Result<(u64, Superblock)> {
+    let mut last_err = anyhow!("no attempt to read the metadata snapshot");
+    for attempt in 0..SNAP_RETRIES {
+        if attempt > 0 {
+            thread::sleep(SNAP_RETRY_DELAY);
+        }
+
+        // here we don't use read_superblock_snap() as we need both the main superblock and the
+        // metadata snapshot.
+        let actual_sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+        let loc = actual_sb.metadata_snap;
+        if loc == 0 {
+            return Err(anyhow!(
+                "no current metadata snap; reserve one, or use --auto-reserve-metasnap"
+            ));
+        }
+        let snap = read_superblock(engine, loc);
+
+        let after = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+        if after.metadata_snap != loc {
+            last_err = match after.metadata_snap {
+                0 => anyhow!(
+                    "the metadata snapshot at block {} was released while being read",
+                    loc
+                ),
+                moved => anyhow!(
+                    "the metadata snapshot moved from block {} to {} while being read",
+                    loc,
+                    moved
+                ),
+            };
+            continue;
+        }
+
+        match snap {
+            Ok(mut sb_snap) => {
+                // patch the metadata snapshot to carry the data space map size information
+                sb_snap.data_sm_root.copy_from_slice(&after.data_sm_root);
+                return Ok((loc, sb_snap));
+            }
+            Err(e) => {
+                last_err = anyhow!(
+                    "unable to read the metadata snapshot at block {}: {}",
+                    loc,
+                    e
+                )
+            }
+        }
+    }
+
+    Err(anyhow!("{}, after {} attempts", last_err, SNAP_RETRIES))
+}
+
+pub(crate) fn read_patched_superblock_snap(engine: &dyn IoEngine) -> Result<Superblock> {
+    read_metadata_snap(engine).map(|(_, sb)| sb)
+}
+
+// Reads the input superblock, ensuring the metadata is consistent. With
+// auto_repair, a damaged superblock is rebuilt from the most recent roots found
+// by scanning the metadata, as thin_repair does, rather than refusing to run.
+fn read_input_superblock(ctx: &Context, auto_repair: bool) -> Result<Superblock> {
+    let read = || -> Result<Superblock> {
+        let sb = read_superblock(ctx.engine_in.as_ref(), SUPERBLOCK_LOCATION)?;
+        is_superblock_consistent(sb.clone(), ctx.engine_in.clone(), false)?;
+        Ok(sb)
+    };
+
+    let e = match read() {
+        Ok(sb) => return Ok(sb),
+        Err(e) if auto_repair => e,
+        Err(e) => return Err(fail(FailureKind::CorruptMetadata, e)),
+    };
+    ctx.report.warning(&format!(
+        "the input superblock is damaged ({:#}), searching the metadata for the most recent roots",
+        e
+    ));
+    let overrides = SuperblockOverrides {
+        transaction_id: None,
+        data_block_size: None,
+        nr_data_blocks: None,
+    };
+    let sb = read_or_rebuild_superblock(
+        ctx.engine_in.clone(),
+        ctx.report.clone(),
+        SUPERBLOCK_LOCATION,
+        &overrides,
+    )
+    .map_err(|e| {
+        fail(
+            FailureKind::CorruptMetadata,
+            anyhow!("unable to rebuild the input superblock: {}", e),
+        )
+    })?;
+    ctx.report.info(&format!(
+        "rebuilt the input superblock: transaction {}, time {}, data block size {}",
+        sb.transaction_id, sb.time, sb.data_block_size
+    ));
+    Ok(sb)
+}
+
+// Fails if the metadata snapshot merged from was released during the merge, as
+// the live pool could have reused its blocks, leaving the output unreliable
+fn check_snap_held(engine: &dyn IoEngine, loc: u64) -> Result<()> {
     let actual_sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
-    if actual_sb.metadata_snap == 0 {
-        return Err(anyhow!("no current metadata snap"));
+    if actual_sb.metadata_snap != loc {
+        return Err(anyhow!(
+            "the metadata snapshot at block {} was released during the merge, and the pool \
+             could have reused its blocks; reserve a new metadata snapshot and merge again",
+            loc
+        ));
     }
-    let mut sb_snap = read_superblock(engine, actual_sb.metadata_snap)?;
+    Ok(())
+}
 
-    // patch the metadata snapshot to carry the data space map size information
-    sb_snap
-        .data_sm_root
-        .copy_from_slice(&actual_sb.data_sm_root);
+// Descends the btree to the leaf that would hold the key, returning it along
+// with its block, or None if the key lies before the first key of the tree
+fn find_leaf<V: Unpack>(
+    engine: &dyn IoEngine,
+    root: u64,
+    key: u64,
+) -> Result<Option<(Block, Node<V>)>> {
+    let mut loc = root;
+    let mut is_root = true;
+    loop {
+        let b = engine.read(loc)?;
+        let node = unpack_node::<V>(&[], b.get_data(), false, is_root)?;
+        if let Node::Internal { keys, values, .. } = &node {
+            let idx = keys.partition_point(|&k| k <= key);
+            if idx == 0 {
+                return Ok(None);
+            }
+            loc = values[idx - 1];
+            is_root = false;
+            continue;
+        }
+        return Ok(Some((b, node)));
+    }
+}
 
-    Ok(sb_snap)
+fn lookup<V: Unpack + Copy>(engine: &dyn IoEngine, root: u64, key: u64) -> Result<Option<V>> {
+    match find_leaf::<V>(engine, root, key)? {
+        Some((_, Node::Leaf { keys, values, .. })) => {
+            Ok(keys.binary_search(&key).ok().map(|idx| values[idx]))
+        }
+        _ => Ok(None),
+    }
 }
 
-fn get_device_root_and_details(
+pub(crate) fn get_device_root_and_details(
+    engine: &dyn IoEngine,
+    sb: &Superblock,
     dev_id: u64,
-    roots: &BTreeMap<u64, u64>,
-    details: &BTreeMap<u64, DeviceDetail>,
 ) -> Result<(u64, DeviceDetail)> {
-    let root = *roots
-        .get(&dev_id)
+    let root = lookup::<u64>(engine, sb.mapping_root, dev_id)?
         .ok_or_else(|| anyhow!("Unable to find mapping tree for the device {}", dev_id))?;
-    let details = *details
-        .get(&dev_id)
+    let details = lookup::<DeviceDetail>(engine, sb.details_root, dev_id)?
         .ok_or_else(|| anyhow!("Unable to find the details for the device {}", dev_id))?;
     Ok((root, details))
 }
@@ -382,6 +2788,155 @@ fn build_output_superblock(sb: &Superblock) -> Result<ir::Superblock> {
     })
 }
 
+// The uuid given for the output, padded with zeroes, or the one of the input
+// if preserved. Input staged from another format has lost its uuid by then.
+fn output_uuid(
+    opts: &ThinMergeOptions,
+    engine_in: &dyn IoEngine,
+    input_staged: bool,
+) -> Result<Option<[u8; UUID_SIZE]>> {
+    let mut uuid = [0; UUID_SIZE];
+    if let Some(given) = &opts.uuid {
+        if given.len() > UUID_SIZE {
+            return Err(anyhow!(
+                "the uuid '{}' is longer than {} bytes",
+                given,
+                UUID_SIZE
+            ));
+        }
+        uuid[..given.len()].copy_from_slice(given.as_bytes());
+        return Ok(Some(uuid));
+    }
+    if opts.preserve_uuid {
+        if input_staged {
+            return Err(fail(
+                FailureKind::Usage,
+                anyhow!(
+                    "the uuid of the input is lost in converting it to binary; give it by --uuid"
+                ),
+            ));
+        }
+        return Ok(Some(read_uuid(engine_in)?));
+    }
+    Ok(None)
+}
+
+// The versions the kernel target takes
+const MIN_METADATA_VERSION: u32 = 1;
+const MAX_METADATA_VERSION: u32 = 2;
+
+// Checks the input carries nothing the output version couldn't record
+fn check_output_version(sb: &Superblock, version: u32) -> Result<()> {
+    if !(MIN_METADATA_VERSION..=MAX_METADATA_VERSION).contains(&version) {
+        return Err(anyhow!(
+            "unsupported metadata version {}; choose {} or {} with --output-version",
+            version,
+            MIN_METADATA_VERSION,
+            MAX_METADATA_VERSION
+        ));
+    }
+
+    // the needs_check flag came with version 2
+    if version < 2 && sb.flags.needs_check {
+        return Err(anyhow!(
+            "the input is flagged as needing a check, which version {} metadata couldn't record; \
+             check the input first, or write version 2",
+            version
+        ));
+    }
+    Ok(())
+}
+
+// The rescaling of the mappings for the data block size of the target pool,
+// if it differs from the input
+fn data_rescale(ctx: &Context, sb: &Superblock) -> Result<Option<Rescale>> {
+    let Some(data_block_size) = ctx.data_block_size else {
+        return Ok(None);
+    };
+    // the same bounds as the kernel target
+    if data_block_size % 128 != 0 || !(128..=2097152).contains(&data_block_size) {
+        return Err(fail(
+            FailureKind::Usage,
+            anyhow!(
+                "invalid data block size of {} sectors, expected a multiple of 128 from 128 to 2097152",
+                data_block_size
+            ),
+        ));
+    }
+    if data_block_size == sb.data_block_size {
+        return Ok(None);
+    }
+    if ctx.existing.is_some() {
+        return Err(fail(
+            FailureKind::Usage,
+            anyhow!("the mappings can't be rescaled for another data block size when appending"),
+        ));
+    }
+    Rescale::new(sb.data_block_size, data_block_size)
+        .map(Some)
+        .map_err(|e| fail(FailureKind::Usage, e))
+}
+
+// Builds the output superblock, which also takes over the time and the
+// transaction of the metadata appended to
+fn output_superblock(ctx: &Context, sb: &Superblock) -> Result<ir::Superblock> {
+    let mut out_sb = build_output_superblock(sb)?;
+    if let Some(rescale) = data_rescale(ctx, sb)? {
+        let nr_data_blocks = rescale.blocks(out_sb.nr_data_blocks)?;
+        ctx.report.info(&format!(
+            "rescaled the data blocks of the output from {} sectors to {}, {} blocks in all",
+            rescale.from, rescale.to, nr_data_blocks
+        ));
+        out_sb.data_block_size = rescale.to;
+        out_sb.nr_data_blocks = nr_data_blocks;
+    }
+    if let Some(nr_data_blocks) = ctx.nr_data_blocks {
+        ctx.report.info(&format!(
+            "resized the data device of the output from {} to {} blocks",
+            out_sb.nr_data_blocks, nr_data_blocks
+        ));
+        out_sb.nr_data_blocks = nr_data_blocks;
+    }
+    if let Some(version) = ctx.output_version {
+        if version != sb.version {
+            ctx.report.info(&format!(
+                "writing metadata version {} for the input of version {}",
+                version, sb.version
+            ));
+        }
+        out_sb.version = Some(version);
+    }
+    check_output_version(sb, out_sb.version.unwrap_or(sb.version))?;
+    if let Some((_, existing)) = &ctx.existing {
+        let base = build_output_superblock(existing)?;
+        if base.data_block_size != out_sb.data_block_size
+            || base.nr_data_blocks != out_sb.nr_data_blocks
+        {
+            return Err(anyhow!(
+                "the output metadata belongs to a pool of {} data blocks of {} sectors, but the input to {} of {}",
+                base.nr_data_blocks,
+                base.data_block_size,
+                out_sb.nr_data_blocks,
+                out_sb.data_block_size
+            ));
+        }
+        out_sb.time = std::cmp::max(out_sb.time, base.time);
+        out_sb.transaction = std::cmp::max(out_sb.transaction, base.transaction);
+    }
+    if ctx.reset_time {
+        out_sb.time = 0;
+    }
+    // the one lvm expects of the pool taking the output
+    if let Some(transaction) = ctx.transaction_id {
+        ctx.report.info(&format!(
+            "set the transaction id of the output from {} to {}",
+            out_sb.transaction, transaction
+        ));
+        out_sb.transaction = transaction;
+    }
+    Ok(out_sb)
+}
+
 fn build_output_device(dev_id: u64, details: &DeviceDetail) -> ir::Device {
     ir::Device {
         dev_id: dev_id as u32,
@@ -392,78 +2947,983 @@ fn build_output_device(dev_id: u64, details: &DeviceDetail) -> ir::Device {
     }
 }
 
-fn merge_thins_(
-    ctx: Context,
+// Re-computes the runs of a merged device without writing anything, or dumps
+// the origin if there's no snapshot. Also returns the details of the origin.
+pub(crate) fn device_runs(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    sort_leaves: bool,
     sb: &Superblock,
     origin_id: u64,
     snap_id: Option<u64>,
+) -> Result<(RunSource, DeviceDetail)> {
+    let ctx = Context {
+        report: report.clone(),
+        engine_in: engine.clone(),
+        engine_out: engine.clone(), // never written
+        output_format: MetadataFormat::Binary,
+        _staged_input: None,
+        staged_output: None,
+        sort_leaves,
+        accept_diverged_origin: true,
+        diverged: HashMap::new(),
+        clamp_times: false,
+        reset_time: false,
+        dry_run: false,
+        keep_other_devices: false,
+        new_dev_id: None,
+        skip_bad_blocks: false,
+        skipped: None,
+        deterministic: false,
+        trace: None,
+        zeroed: None,
+        dropped_zeroed: Arc::new(AtomicU64::new(0)),
+        copy_data: None,
+        copied: Arc::new(AtomicU64::new(0)),
+        remap: None,
+        remap_table: None,
+        key_range: None,
+        base_batch: engine.get_batch_size(),
+        snap_batch: engine.get_batch_size(),
+        prefetch: None,
+        dump_threads: 1,
+        pipeline: Pipeline::tune(None, None, None, engine.as_ref(), engine.as_ref())?,
+        nr_data_blocks: None,
+        data_block_size: None,
+        output_version: None,
+        uuid: None,
+        transaction_id: None,
+        chunk_blocks: None,
+        chunk_pause: None,
+        synced_output: None,
+        verbose: 0,
+        summary: Arc::new(Mutex::new(RunSummary::default())),
+        input_io: Arc::new(IoCounters::default()),
+        output_io: Arc::new(IoCounters::default()),
+        existing: None,
+        since_time: None,
+        identical: Arc::new(AtomicU64::new(0)),
+        aged: Arc::new(AtomicU64::new(0)),
+        sanitized: Arc::new(SanitizeStats::default()),
+        output_writes: Arc::new(LatencyHistogram::default()),
+        stalls: Arc::new(ChannelStalls::default()),
+        shared: Mutex::new(SharedLeaves::default()),
+        timings: false,
+        progress: Arc::new(Progress::disabled()),
+        watchdog: Watchdog::new(report, None, false, None),
+        budget: Arc::new(MemoryBudget::new(None)),
+    };
+
+    let (origin_root, origin_details) =
+        get_device_root_and_details(engine.as_ref(), sb, origin_id)?;
+
+    match snap_id {
+        Some(snap_id) => {
+            let (snap_root, _) = get_device_root_and_details(engine.as_ref(), sb, snap_id)?;
+            let source = if origin_root == snap_root {
+                dump_source(&ctx, origin_root)?
+            } else {
+                merge_source(&ctx, &[origin_root, snap_root])?
+            };
+            Ok((source, origin_details))
+        }
+        None => Ok((dump_source(&ctx, origin_root)?, origin_details)),
+    }
+}
+
+// Copies the devices not taking part in the merge unchanged, so the output
+// could replace the metadata of the whole pool
+fn add_other_devices(
+    ctx: &Context,
+    sb: &Superblock,
+    participants: &[u64],
+    devices: &mut Vec<(ir::Device, RunSource, Option<u64>)>,
+) -> Result<()> {
+    if !ctx.keep_other_devices {
+        return Ok(());
+    }
+
+    let mut kept = Vec::new();
+    for_each_entry::<DeviceDetail>(&ctx.engine_in, sb.details_root, |id, details| {
+        if participants.contains(&id) {
+            return Ok(());
+        }
+        let root = lookup::<u64>(ctx.engine_in.as_ref(), sb.mapping_root, id)?
+            .ok_or_else(|| anyhow!("Unable to find mapping tree for the device {}", id))?;
+        kept.push((id, details, root));
+        Ok(())
+    })?;
+
+    // the leaves the kept devices share, e.g., with their snapshots, are
+    // written once rather than duplicated. The data blocks must stay in place,
+    // complete and of the same size for that, and the leaves undamaged.
+    // They're looked for only if the leaf lists fit under the memory limit,
+    // otherwise the devices are written in full.
+    let mut trees = Vec::new();
+    let mut tree_charges = Vec::new();
+    let mut fits = true;
+    if ctx.remap.is_none()
+        && ctx.zeroed.is_none()
+        && ctx.skipped.is_none()
+        && ctx.data_block_size.is_none()
+    {
+        for (id, _, root) in &kept {
+            // the iterators check the order of the leaves as they read them,
+            // so they're only read up front if they're to be sorted
+            let leaves = if ctx.sort_leaves {
+                collect_leaves_in(ctx, ctx.engine_in.clone(), *root, None)?
+            } else {
+                walk_leaves(ctx, ctx.engine_in.clone(), *root)?.leaves
+            };
+            let mut charges = Charges::new(&ctx.budget);
+            if charges
+                .charge(LEAF_CACHES, leaves.len() as u64 * 8)
+                .is_err()
+            {
+                fits = false;
+                break;
+            }
+            trees.push((*id as u32, leaves));
+            tree_charges.push(charges);
+        }
+    }
+    let found = match fits {
+        true => SharedLeaves::find(&ctx.budget, &trees).ok(),
+        false => None,
+    };
+    let shared = match found {
+        Some(shared) => shared,
+        None => {
+            ctx.report.info(
+                "writing the kept devices in full, as their leaf lists don't fit the memory limit",
+            );
+            trees.clear();
+            tree_charges.clear();
+            SharedLeaves::default()
+        }
+    };
+
+    let nr_kept = kept.len();
+    let mut trees = trees.into_iter().zip(tree_charges);
+    let mut kept_devices = Vec::new();
+    for (id, details, root) in kept {
+        let key_end = key_end_of(ctx, root)?;
+        let source = match trees.next() {
+            Some(((_, leaves), charges)) if shared.nr_leaves() > 0 => {
+                unshared_source(ctx, &shared, leaves, charges)?
+            }
+            _ => dump_source(ctx, root)?,
+        };
+        kept_devices.push((build_output_device(id, &details), source, key_end));
+    }
+    devices.extend(dump_in_parallel(ctx, kept_devices)?);
+    devices.sort_by_key(|(dev, _, _)| dev.dev_id);
+
+    ctx.report
+        .info(&format!("kept {} other devices unchanged", nr_kept));
+    if shared.nr_leaves() > 0 {
+        ctx.report.info(&format!(
+            "the kept devices share {} leaves, written once",
+            shared.nr_leaves()
+        ));
+    }
+    *ctx.shared.lock().unwrap() = shared;
+    Ok(())
+}
+
+// A batch of runs dumped from a kept device, where an empty one marks the end
+type RunBatch = Result<Vec<(u64, BlockTime, u64)>>;
+
+// Sends the runs of a source in batches, then the empty batch ending them, or
+// the error of the source. Stops early once the receiver is dropped.
+fn dump_runs(mut source: RunSource, tx: SyncSender<RunBatch>, buffer_len: usize) {
+    let mut runs = Vec::with_capacity(buffer_len);
+    loop {
+        match source() {
+            Ok(Some(run)) => {
+                runs.push(run);
+                if runs.len() == buffer_len {
+                    if tx.send(Ok(runs)).is_err() {
+                        return;
+                    }
+                    runs = Vec::with_capacity(buffer_len);
+                }
+            }
+            Ok(None) => {
+                if !runs.is_empty() && tx.send(Ok(runs)).is_err() {
+                    return;
+                }
+                let _ = tx.send(Ok(Vec::new()));
+                return;
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+// Takes the runs of a kept device from the worker dumping it
+fn batch_source(dev_id: u32, rx: Receiver<RunBatch>) -> RunSource {
+    let mut batch = Vec::new().into_iter();
+    let mut done = false;
+    Box::new(move || loop {
+        if let Some(run) = batch.next() {
+            return Ok(Some(run));
+        }
+        if done {
+            return Ok(None);
+        }
+        match rx.recv() {
+            Ok(Ok(runs)) if runs.is_empty() => done = true,
+            Ok(Ok(runs)) => batch = runs.into_iter(),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(anyhow!("the dump of device {} stopped early", dev_id)),
+        }
+    })
+}
+
+// Dumps the kept devices on worker threads, ahead of the writer and beside the
+// merge, as their trees are independent. The writer still takes the devices one
+// at a time in the order given, so each worker takes the next device not
+// started yet, and queues a few batches of its runs at most. The device being
+// written is then always dumped by a worker or done, and the workers waiting on
+// the writer never hold it up. The workers are fewer if their batches don't fit
+// the memory limit, and none leave the devices dumped as they're written.
+fn dump_in_parallel(
+    ctx: &Context,
+    devices: Vec<(ir::Device, RunSource, Option<u64>)>,
+) -> Result<Vec<(ir::Device, RunSource, Option<u64>)>> {
+    let wanted = std::cmp::min(ctx.dump_threads, devices.len());
+    if wanted < 2 {
+        return Ok(devices);
+    }
+
+    // the batches queued, plus the ones held by either end
+    let run_size = std::mem::size_of::<(u64, BlockTime, u64)>();
+    let per_worker = (ctx.pipeline.queue_depth + 2) * ctx.pipeline.buffer_len * run_size;
+    let mut charges = Charges::new(&ctx.budget);
+    let nr_workers = charges
+        .charge_fitting(CHANNEL_BUFFERS, per_worker as u64, wanted)
+        .unwrap_or(0);
+    if nr_workers < 2 {
+        return Ok(devices);
+    }
+
+    let mut queue = VecDeque::new();
+    let mut dumped = Vec::new();
+    for (dev, source, key_end) in devices {
+        let (tx, rx) = mpsc::sync_channel::<RunBatch>(ctx.pipeline.queue_depth);
+        queue.push_back((source, tx));
+        let source = batch_source(dev.dev_id, rx);
+        dumped.push((dev, source, key_end));
+    }
+    ctx.verbose_info(
+        1,
+        &format!(
+            "dumping the {} kept devices on {} threads",
+            dumped.len(),
+            nr_workers
+        ),
+    );
+
+    // the charges are given back once the last worker ends
+    let queue = Arc::new(Mutex::new(queue));
+    let charges = Arc::new(charges);
+    let buffer_len = ctx.pipeline.buffer_len;
+    for _ in 0..nr_workers {
+        let queue = queue.clone();
+        let charges = charges.clone();
+        spawn_scoped(move || {
+            let _charges = charges;
+            loop {
+                let next = queue.lock().unwrap().pop_front();
+                let Some((source, tx)) = next else {
+                    break;
+                };
+                dump_runs(source, tx, buffer_len);
+            }
+        });
+    }
+    Ok(dumped)
+}
+
+// Dumps the runs of the leaves of a kept device, but for the ones it shares with
+// the other kept devices, which it references instead
+fn unshared_source(
+    ctx: &Context,
+    shared: &SharedLeaves,
+    leaves: Vec<u64>,
+    charges: Charges,
+) -> Result<RunSource> {
+    let engine = ctx.engine_in.clone();
+    let leaves: Vec<u64> = leaves
+        .into_iter()
+        .filter(|&loc| !shared.is_shared(loc))
+        .collect();
+    if leaves.is_empty() {
+        return Ok(Box::new(|| Ok(None)));
+    }
+    let batch_size = engine.get_batch_size();
+    let mut iter = leaf_iterator(
+        ctx,
+        charges,
+        engine,
+        LeafSource::List(leaves),
+        None,
+        batch_size,
+        1,
+    )?;
+    Ok(Box::new(move || iter.next_range()))
+}
+
+// Adds the devices already in the output appended to, which must not collide
+// with the ones being written
+fn add_existing_devices(
+    ctx: &Context,
+    devices: &mut Vec<(ir::Device, RunSource, Option<u64>)>,
+) -> Result<()> {
+    let Some((engine, sb)) = &ctx.existing else {
+        return Ok(());
+    };
+
+    let mut nr_existing = 0;
+    for_each_entry::<DeviceDetail>(engine, sb.details_root, |id, details| {
+        if devices.iter().any(|(dev, _, _)| dev.dev_id as u64 == id) {
+            return Err(anyhow!("device {} already exists in the output", id));
+        }
+        let root = lookup::<u64>(engine.as_ref(), sb.mapping_root, id)?.ok_or_else(|| {
+            anyhow!(
+                "Unable to find mapping tree for the device {} in the output",
+                id
+            )
+        })?;
+        let key_end = tree_key_end(engine.as_ref(), root)?;
+        devices.push((
+            build_output_device(id, &details),
+            dump_source_in(ctx, engine.clone(), root)?,
+            key_end,
+        ));
+        nr_existing += 1;
+        Ok(())
+    })?;
+    devices.sort_by_key(|(dev, _, _)| dev.dev_id);
+
+    ctx.report.info(&format!(
+        "appended to the {} devices already in the output",
+        nr_existing
+    ));
+    Ok(())
+}
+
+// Explains the output of a snapshot sharing the mapping tree of its origin,
+// e.g., one never written since taken, where nothing needs merging. The details
+// of both should agree, as they describe the same mappings.
+fn report_shared_tree(
+    ctx: &Context,
+    (origin_id, origin_details): (u64, &DeviceDetail),
+    (snap_id, snap_details): (u64, &DeviceDetail),
+    rebase: bool,
+) {
+    if origin_details.mapped_blocks != snap_details.mapped_blocks {
+        ctx.report.warning(&format!(
+            "origin {} and snapshot {} share the mapping tree, but their details say {} and {} mapped blocks; \
+             the output takes the count of the mappings written",
+            origin_id, snap_id, origin_details.mapped_blocks, snap_details.mapped_blocks
+        ));
+    }
+
+    let (details_id, role) = if rebase {
+        (snap_id, "snapshot")
+    } else {
+        (origin_id, "origin")
+    };
+    ctx.report.info(&format!(
+        "snapshot {} shares the mapping tree of origin {}, so no merge was necessary; \
+         the output device takes the details of the {} {}",
+        snap_id, origin_id, role, details_id
+    ));
+}
+
+// Writes the snapshot alone where the origin is lost, leaving holes where the
+// origin would have provided the data
+fn write_snapshot_only(ctx: &Context, sb: &Superblock, snap_id: u64) -> Result<()> {
+    let out_sb = output_superblock(ctx, sb)?;
+
+    let (snap_root, snap_details) =
+        get_device_root_and_details(ctx.engine_in.as_ref(), sb, snap_id)?;
+    let out_dev = build_output_device(ctx.new_dev_id.unwrap_or(snap_id), &snap_details);
+    let key_end = key_end_of(ctx, snap_root)?;
+
+    let mapped = Arc::new(AtomicU64::new(0));
+    let counter = mapped.clone();
+    let mut runs = output_source(ctx, dump_range_source(ctx, snap_root)?);
+    let source: RunSource = Box::new(move || {
+        let run = runs()?;
+        if let Some((_, _, len)) = &run {
+            counter.fetch_add(*len, Ordering::Relaxed);
+        }
+        Ok(run)
+    });
+
+    let mut devices = vec![(out_dev, source, key_end)];
+    add_other_devices(ctx, sb, &[snap_id], &mut devices)?;
+    add_existing_devices(ctx, &mut devices)?;
+    write_devices(ctx, sb, &out_sb, devices)?;
+
+    let holes = key_end
+        .unwrap_or(0)
+        .saturating_sub(mapped.load(Ordering::Relaxed));
+    ctx.report.info(&format!(
+        "without the origin, {} blocks below the last snapshot mapping fall through to zeroes",
+        holes
+    ));
+
+    Ok(())
+}
+
+// The data blocks mapped by any device of the input, which the copied data
+// must not overwrite while the pool is still in use
+fn used_data_blocks(ctx: &Context, sb: &Superblock) -> Result<Vec<(u64, u64)>> {
+    let mut used = Vec::new();
+    for_each_entry::<u64>(&ctx.engine_in, sb.mapping_root, |_, root| {
+        let mut runs = dump_source(ctx, root)?;
+        while let Some((_, bt, len)) = runs()? {
+            used.push((bt.block, bt.block + len));
+        }
+        Ok(())
+    })?;
+    Ok(used)
+}
+
+// Merges the snapshot with an external origin living outside the pool. The
+// origin device stands in as the bottom of the merge, and its data under the
+// ranges left unmapped by the snapshot is copied into the free data blocks of
+// the pool.
+fn merge_external_origin(
+    ctx: &Context,
+    sb: &Superblock,
+    snap_id: Option<u64>,
+    intermediates: &[u64],
+    copy: &CopyData,
+) -> Result<()> {
+    let Some(snap_id) = snap_id else {
+        return Err(fail(
+            FailureKind::Usage,
+            anyhow!("copying the origin data requires the snapshot"),
+        ));
+    };
+    let out_sb = output_superblock(ctx, sb)?;
+
+    let (snap_root, snap_details) =
+        get_device_root_and_details(ctx.engine_in.as_ref(), sb, snap_id)?;
+    let out_dev = build_output_device(ctx.new_dev_id.unwrap_or(snap_id), &snap_details);
+
+    let copier = DataCopier::open(
+        copy,
+        sb.data_block_size,
+        out_sb.nr_data_blocks,
+        used_data_blocks(ctx, sb)?,
+        ctx.copied.clone(),
+    )?;
+    let origin_end = match copier.nr_origin_blocks() {
+        0 => None,
+        n => Some(n),
+    };
+    let base = (
+        MappingStream::from_source(copier.origin_source())?,
+        origin_end,
+    );
+
+    let mut roots = Vec::new();
+    for &id in intermediates {
+        roots.push(get_device_root_and_details(ctx.engine_in.as_ref(), sb, id)?.0);
+    }
+    roots.push(snap_root);
+
+    let mut key_end = origin_end;
+    for &root in &roots {
+        key_end = std::cmp::max(key_end, key_end_of(ctx, root)?);
+    }
+
+    let merged = output_source(ctx, overlay_source(ctx, base, &roots)?);
+    let source = copy_origin_runs(merged, copier);
+    let mut devices = vec![(out_dev, source, key_end)];
+
+    let mut participants = vec![snap_id];
+    participants.extend_from_slice(intermediates);
+    add_other_devices(ctx, sb, &participants, &mut devices)?;
+    add_existing_devices(ctx, &mut devices)?;
+    write_devices(ctx, sb, &out_sb, devices)?;
+
+    ctx.report.info(&format!(
+        "copied {} blocks of the origin {} into the pool data device {}",
+        ctx.copied.load(Ordering::Relaxed),
+        copy.origin_dev.display(),
+        copy.pool_data_dev.display()
+    ));
+    Ok(())
+}
+
+// Reports the key ranges where the merged device would differ from the origin,
+// i.e., the effective overrides of the snapshots, without writing anything
+fn report_changes(
+    ctx: &Context,
+    sb: &Superblock,
+    origin_id: Option<u64>,
+    snap_id: Option<u64>,
+    intermediates: &[u64],
+) -> Result<()> {
+    let origin_id = origin_id
+        .ok_or_else(|| anyhow!("the changes are relative to the origin, which is required"))?;
+    if snap_id.is_none() && !intermediates.is_empty() {
+        return Err(anyhow!(
+            "a chain of snapshots requires the topmost snapshot"
+        ));
+    }
+
+    let (origin_root, _) = get_device_root_and_details(ctx.engine_in.as_ref(), sb, origin_id)?;
+    let mut roots = vec![origin_root];
+    for &id in intermediates.iter().chain(snap_id.iter()) {
+        roots.push(get_device_root_and_details(ctx.engine_in.as_ref(), sb, id)?.0);
+    }
+    roots.dedup();
+
+    let merged = if roots.len() == 1 {
+        dump_source(ctx, origin_root)?
+    } else {
+        merge_source(ctx, &roots)?
+    };
+    let changes = diff_ranges(merged, dump_source(ctx, origin_root)?)?;
+    if let Some(trace) = &ctx.trace {
+        trace.flush()?;
+    }
+
+    let mut nr_blocks = 0;
+    for (begin, end) in &changes {
+        ctx.report.info(&format!("changed: {}..{}", begin, end));
+        nr_blocks += end - begin;
+    }
+    ctx.report.info(&format!(
+        "the merge would change {} blocks of origin {} in {} ranges",
+        nr_blocks,
+        origin_id,
+        changes.len()
+    ));
+
+    Ok(())
+}
+
+fn merge_thins_(
+    mut ctx: Context,
+    sb: &Superblock,
+    origin_id: Option<u64>,
+    snap_id: Option<u64>,
+    intermediates: &[u64],
     rebase: bool,
+    emit_residue: bool,
 ) -> Result<()> {
-    let out_sb = build_output_superblock(sb)?;
+    if snap_id.is_none() && !intermediates.is_empty() {
+        return Err(anyhow!(
+            "a chain of snapshots requires the topmost snapshot"
+        ));
+    }
+
+    if let Some((begin, end)) = ctx.key_range {
+        if begin >= end {
+            return Err(anyhow!(
+                "the range of thin blocks to merge {}..{} is empty",
+                begin,
+                end
+            ));
+        }
+        ctx.report
+            .info(&format!("merging the thin blocks {}..{} only", begin, end));
+    }
+
+    if let Some(copy) = &ctx.copy_data {
+        return merge_external_origin(&ctx, sb, snap_id, intermediates, copy);
+    }
+
+    let Some(origin_id) = origin_id else {
+        return match (snap_id, intermediates.is_empty()) {
+            (Some(snap_id), true) => write_snapshot_only(&ctx, sb, snap_id),
+            (Some(_), false) => Err(fail(
+                FailureKind::Usage,
+                anyhow!("a chain of snapshots requires the origin"),
+            )),
+            (None, _) => Err(fail(
+                FailureKind::Usage,
+                anyhow!("a snapshot is required without the origin"),
+            )),
+        };
+    };
 
-    let roots = btree_to_map::<u64>(&mut vec![], ctx.engine_in.clone(), false, sb.mapping_root)?;
-    let details =
-        btree_to_map::<DeviceDetail>(&mut vec![], ctx.engine_in.clone(), false, sb.details_root)?;
+    let out_sb = output_superblock(&ctx, sb)?;
 
-    let (origin_root, origin_details) = get_device_root_and_details(origin_id, &roots, &details)?;
+    let (origin_root, origin_details) =
+        get_device_root_and_details(ctx.engine_in.as_ref(), sb, origin_id)?;
 
     if let Some(snap_id) = snap_id {
-        let (snap_root, snap_details) = get_device_root_and_details(snap_id, &roots, &details)?;
+        let (snap_root, snap_details) =
+            get_device_root_and_details(ctx.engine_in.as_ref(), sb, snap_id)?;
 
         let out_dev = if rebase {
-            build_output_device(snap_id, &snap_details)
+            build_output_device(ctx.new_dev_id.unwrap_or(snap_id), &snap_details)
         } else {
-            build_output_device(origin_id, &origin_details)
+            build_output_device(ctx.new_dev_id.unwrap_or(origin_id), &origin_details)
         };
 
-        if origin_root == snap_root {
-            // fallback to dump a single device
-            dump_single_device(
-                ctx.engine_in,
-                ctx.engine_out,
-                ctx.report,
-                &out_sb,
-                &out_dev,
-                origin_root,
-            )
+        // the mapping trees of the chain from the bottom, where each level is
+        // checked against the one beneath
+        let mut levels = Vec::new();
+        for &id in intermediates {
+            let (root, details) = get_device_root_and_details(ctx.engine_in.as_ref(), sb, id)?;
+            levels.push((id, root, details));
+        }
+        levels.push((snap_id, snap_root, snap_details));
+
+        let mut roots = vec![origin_root];
+        let mut empty = Vec::new();
+        for (id, root, details) in levels {
+            let lower_root = *roots.last().unwrap();
+            if lower_root == root {
+                continue;
+            }
+
+            // an empty tree, e.g., of a read-only snapshot, overrides nothing,
+            // so there's neither a merge nor a divergence to check
+            if key_end_of(&ctx, root)?.is_none() {
+                empty.push(id);
+                continue;
+            }
+
+            // checked as the merge goes, rather than in a pass of its own
+            if !ctx.accept_diverged_origin {
+                ctx.diverged.insert(lower_root, details.creation_time);
+            }
+            roots.push(root);
+        }
+
+        if !empty.is_empty() {
+            let ids: Vec<String> = empty.iter().map(|id| id.to_string()).collect();
+            ctx.report.info(&format!(
+                "left the snapshots without mappings out of the merge: {}",
+                ids.join(", ")
+            ));
+        }
+
+        let source = if roots.len() == 1 {
+            // fallback to dump a single device; the runs of its leaves are read
+            // as they are, but the restorer packs them into new leaves
+            if empty.is_empty() {
+                report_shared_tree(
+                    &ctx,
+                    (origin_id, &origin_details),
+                    (snap_id, &snap_details),
+                    rebase,
+                );
+            }
+            dump_range_source(&ctx, origin_root)?
         } else {
-            merge(
-                ctx.engine_in,
-                ctx.engine_out,
-                ctx.report,
-                &out_sb,
-                &out_dev,
-                origin_root,
-                snap_root,
-            )
+            merge_source(&ctx, &roots)?
+        };
+
+        let mut key_end = None;
+        for &root in &roots {
+            key_end = std::cmp::max(key_end, key_end_of(&ctx, root)?);
         }
+
+        let mut devices = vec![(out_dev, output_source(&ctx, source), key_end)];
+
+        // keep the origin untouched alongside the rebased device
+        if rebase && emit_residue && origin_id != snap_id {
+            let residue = build_output_device(origin_id, &origin_details);
+            let origin_end = key_end_of(&ctx, origin_root)?;
+            devices.push((residue, dump_source(&ctx, origin_root)?, origin_end));
+            devices.sort_by_key(|(dev, _, _)| dev.dev_id);
+        }
+
+        let mut participants = vec![origin_id, snap_id];
+        participants.extend_from_slice(intermediates);
+        add_other_devices(&ctx, sb, &participants, &mut devices)?;
+        add_existing_devices(&ctx, &mut devices)?;
+
+        write_devices(&ctx, sb, &out_sb, devices)?;
+
+        let identical = ctx.identical.load(Ordering::Relaxed);
+        if identical > 0 {
+            ctx.report.info(&format!(
+                "kept the origin mappings for {} blocks the snapshot remaps identically",
+                identical
+            ));
+        }
+
+        let aged = ctx.aged.load(Ordering::Relaxed);
+        if aged > 0 {
+            ctx.report.info(&format!(
+                "left {} blocks of snapshot mappings older than time {} to the origin",
+                aged,
+                ctx.since_time.unwrap_or(0)
+            ));
+        }
+
+        Ok(())
     } else {
-        let out_dev = build_output_device(origin_id, &origin_details);
-
-        dump_single_device(
-            ctx.engine_in,
-            ctx.engine_out,
-            ctx.report,
-            &out_sb,
-            &out_dev,
-            origin_root,
-        )
+        let out_dev = build_output_device(ctx.new_dev_id.unwrap_or(origin_id), &origin_details);
+        let source = output_source(&ctx, dump_range_source(&ctx, origin_root)?);
+        let key_end = key_end_of(&ctx, origin_root)?;
+
+        let mut devices = vec![(out_dev, source, key_end)];
+        add_other_devices(&ctx, sb, &[origin_id], &mut devices)?;
+        add_existing_devices(&ctx, &mut devices)?;
+        write_devices(&ctx, sb, &out_sb, devices)
     }
 }
 
+/// Merges the snapshot with its origin, or the chain of snapshots, from the
+/// input metadata into the output, leaving one merged device in the output.
+/// Without a snapshot, the origin is copied alone, and without the origin,
+/// the snapshot is copied alone.
 pub fn merge_thins(opts: ThinMergeOptions) -> Result<()> {
-    let ctx = mk_context(&opts)?;
+    merge_thins_with_summary(opts).map(|_| ())
+}
+
+/// Merges as merge_thins does, returning the totals of the run, as written
+/// to the summary file
+pub fn merge_thins_with_summary(opts: ThinMergeOptions) -> Result<RunSummary> {
+    let started = Instant::now();
+    if let Some(cancel) = &opts.cancel {
+        cancel.check()?;
+    }
+
+    // a panic cleans up after this merge alone, as others might run beside it
+    let _scope = CleanupScope::enter();
+
+    // nothing is read from the metadata before the switch, so a parsing bug
+    // couldn't do more than the user could
+    let opts = match &opts.run_as {
+        Some(user) => {
+            let user = user.clone();
+            let opts = open_before_switch(opts)?;
+            user.drop_privileges()?;
+            opts.report.info(&format!(
+                "running as {} (uid {}, gid {})",
+                user.name, user.uid, user.gid
+            ));
+            opts
+        }
+        None => opts,
+    };
 
-    let sb = if opts.engine_opts.use_metadata_snap {
-        read_patched_superblock_snap(ctx.engine_in.as_ref())?
+    let mut ctx = mk_context(&opts)?;
+
+    // removed on failure, leaving the output untouched
+    let staged_output = ctx.staged_output.take();
+
+    let (sb, held_snap) = if opts.engine_opts.use_metadata_snap {
+        let (loc, sb) = read_metadata_snap(ctx.engine_in.as_ref())?;
+
+        // ensure the metadata is consistent
+        is_superblock_consistent(sb.clone(), ctx.engine_in.clone(), false)
+            .map_err(|e| fail(FailureKind::CorruptMetadata, e))?;
+        (sb, Some(loc))
     } else {
-        read_superblock(ctx.engine_in.as_ref(), SUPERBLOCK_LOCATION)?
+        (read_input_superblock(&ctx, opts.auto_repair)?, None)
+    };
+
+    let snapshot = match (opts.auto_snapshot, opts.origin) {
+        (false, _) => opts.snapshot,
+        (true, Some(origin)) => Some(find_external_snapshot(
+            &ctx.engine_in,
+            &ctx.report,
+            &sb,
+            origin,
+            opts.snapshot,
+        )?),
+        (true, None) => {
+            return Err(fail(
+                FailureKind::Usage,
+                anyhow!("checking the snapshot requires the origin"),
+            ))
+        }
     };
 
-    // ensure the metadata is consistent
-    is_superblock_consistent(sb.clone(), ctx.engine_in.clone(), false)?;
+    if opts.what_changes {
+        return report_changes(&ctx, &sb, opts.origin, snapshot, &opts.intermediates);
+    }
+
+    let engine_in = ctx.engine_in.clone();
+    let engine_out = ctx.engine_out.clone();
+    let output_format = ctx.output_format;
+    let report = ctx.report.clone();
+    let progress = ctx.progress.clone();
+    let summary = ctx.summary.clone();
+    let input_io = ctx.input_io.clone();
+    let output_io = ctx.output_io.clone();
+
+    let mut stats = if opts.stats {
+        let mut devices = Vec::new();
+        if let Some(origin) = opts.origin {
+            devices.push((format!("origin {}", origin), origin));
+        }
+        for &id in &opts.intermediates {
+            devices.push((format!("snapshot {}", id), id));
+        }
+        if let Some(snap) = snapshot {
+            devices.push((format!("snapshot {}", snap), snap));
+        }
+        Some(collect_stats(engine_in, &sb, &devices)?)
+    } else {
+        None
+    };
+
+    // numbered and timed apart, so the progress doesn't restart unannounced
+    let mut phases = vec![PlannedPhase::new("merge", true)];
+    if !opts.dry_run {
+        if opts.repair_compat_check {
+            phases.push(PlannedPhase::new("check", false));
+        }
+        if opts.verify {
+            phases.push(PlannedPhase::new("verify", true));
+        }
+        match opts.output {
+            MetadataLocation::Path(_) if output_format != MetadataFormat::Binary => {
+                phases.push(PlannedPhase::new("export", false));
+            }
+            #[cfg(feature = "remote")]
+            MetadataLocation::Url(_) => phases.push(PlannedPhase::new("upload", false)),
+            _ => {}
+        }
+    }
+    progress.plan(phases);
+
+    let snap_engine = ctx.engine_in.clone();
+    let skipped = ctx.skipped.clone();
+    let merged = merge_thins_(
+        ctx,
+        &sb,
+        opts.origin,
+        snapshot,
+        &opts.intermediates,
+        opts.rebase,
+        opts.emit_residue,
+    );
+
+    // a released snapshot explains any failure of the merge, so it's checked first
+    if let Some(loc) = held_snap {
+        check_snap_held(snap_engine.as_ref(), loc)?;
+    }
+    merged?;
+
+    if let Some(skipped) = skipped {
+        let leaves = skipped.take();
+        for leaf in &leaves {
+            let end = leaf
+                .end
+                .map_or("the end".to_string(), |e| format!("key {}", e));
+            report.warning(&format!(
+                "skipped the damaged mapping leaf at block {}, losing the mappings from key {} up to {}: {}",
+                leaf.loc, leaf.begin, end, leaf.error
+            ));
+        }
+        if !leaves.is_empty() {
+            report.warning(&format!(
+                "skipped {} damaged mapping leaves, the output misses their mappings",
+                leaves.len()
+            ));
+        }
+    }
+
+    let write_summary = || -> Result<RunSummary> {
+        let mut summary = summary.lock().unwrap().clone();
+        summary.wall_time = started.elapsed();
+        summary.bytes_read = input_io.bytes_read();
+        summary.bytes_written = output_io.bytes_written();
+        if let Some(path) = opts.summary_file {
+            summary.write(path)?;
+        }
+        Ok(summary)
+    };
+
+    if opts.dry_run {
+        let summary = write_summary()?;
+        progress.done();
+        return Ok(summary);
+    }
+
+    // the phases after the merge don't poll the stages
+    let cancelled = || match &opts.cancel {
+        Some(cancel) => cancel.check(),
+        None => Ok(()),
+    };
+    cancelled()?;
+
+    if opts.repair_compat_check {
+        progress.phase("check");
+        check_repair_compat(engine_out.clone())
+            .map_err(|e| anyhow!("the output fails the repair compatibility checks: {}", e))?;
+        report.info("the output passed the repair compatibility checks");
+    }
+
+    if opts.verify {
+        cancelled()?;
+        progress.phase("verify");
+        let check = check_output_with(engine_out.clone(), &progress).map_err(|e| {
+            fail(
+                FailureKind::VerifyFailed,
+                anyhow!("the output fails the verification: {}", e),
+            )
+        })?;
+        report.info(&format!(
+            "verified the output: {} devices, {} mapped blocks, {} mapping tree nodes",
+            check.nr_devices, check.mapped_blocks, check.nr_nodes
+        ));
+    }
+
+    if let Some(rows) = &mut stats {
+        let out_id = match (opts.new_dev_id, opts.origin, snapshot) {
+            (Some(id), _, _) => id,
+            (None, Some(origin), Some(_)) if !opts.rebase => origin,
+            (None, _, Some(snap)) => snap,
+            (None, Some(origin), None) => origin,
+            (None, None, None) => unreachable!("rejected by the merge"),
+        };
+        let out_sb = read_superblock(engine_out.as_ref(), SUPERBLOCK_LOCATION)?;
+        let devices = [(format!("merged {}", out_id), out_id)];
+        rows.extend(collect_stats(engine_out.clone(), &out_sb, &devices)?);
+        for line in format_table(rows) {
+            report.info(&line);
+        }
+    }
+
+    cancelled()?;
+    match opts.output {
+        MetadataLocation::Path(path) if output_format != MetadataFormat::Binary => {
+            progress.phase("export");
+            export(engine_out, output_format, path)?;
+        }
+        #[cfg(feature = "remote")]
+        MetadataLocation::Url(url) => {
+            progress.phase("upload");
+            crate::remote::upload_engine(url, engine_out.as_ref())?;
+        }
+        MetadataLocation::Path(path) => {
+            if let Some(staged) = staged_output {
+                commit_output(staged, path)?;
+                report.info(&format!(
+                    "renamed the merged output over {}",
+                    path.display()
+                ));
+            }
+        }
+        _ => {}
+    }
 
-    merge_thins_(ctx, &sb, opts.origin, opts.snapshot, opts.rebase)
+    let summary = write_summary()?;
+    progress.done();
+    Ok(summary)
 }
 
 //------------------------------------------
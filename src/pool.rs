@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use thinp::io_engine::SyncIoEngine;
+use thinp::report::Report;
+use thinp::thin::superblock::*;
+
+use crate::failure::{fail, FailureKind};
+use crate::temp::{defer_cleanup, withdraw_cleanup};
+
+//------------------------------------------
+
+fn dmsetup(args: &[&str]) -> Result<String> {
+    let output = Command::new("dmsetup")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("couldn't run dmsetup: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "dmsetup {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The metadata device follows the target type in the table of a pool, as
+// <start> <len> thin-pool <metadata dev> <data dev> ...
+fn parse_pool_table(name: &str, table: &str) -> Result<PathBuf> {
+    let mut fields = table.lines().next().unwrap_or("").split_whitespace();
+    match (fields.nth(2), fields.next()) {
+        (Some("thin-pool"), Some(dev)) => Ok(Path::new("/dev/block").join(dev)),
+        _ => Err(fail(
+            FailureKind::Usage,
+            anyhow!("{} isn't a thin-pool", name),
+        )),
+    }
+}
+
+// Finds the pool whose table names the given metadata device, comparing the
+// nodes both resolve to
+fn pool_on(metadata_dev: &Path) -> Result<Option<String>> {
+    let target = std::fs::canonicalize(metadata_dev)?;
+    let tables = dmsetup(&["table", "--target", "thin-pool"])?;
+    for line in tables.lines() {
+        let Some((name, table)) = line.split_once(": ") else {
+            continue;
+        };
+        let Ok(dev) = parse_pool_table(name, table) else {
+            continue;
+        };
+        if std::fs::canonicalize(dev).ok().as_ref() == Some(&target) {
+            return Ok(Some(name.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn find_pool(metadata_dev: &Path) -> Result<String> {
+    pool_on(metadata_dev)?.ok_or_else(|| {
+        anyhow!(
+            "no live pool found with {} as its metadata device",
+            metadata_dev.display()
+        )
+    })
+}
+
+// The devices stacked on the given block device, as listed by sysfs
+fn holders(dev: &Path) -> Vec<String> {
+    let Some(name) = std::fs::canonicalize(dev)
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+    else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(Path::new("/sys/class/block").join(name).join("holders"))
+    else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Fails if the given device is the metadata device of an active pool, as
+/// reading it behind the back of the pool gives an inconsistent view. Only
+/// block devices held by another device, as told by sysfs, are looked up among
+/// the pools.
+pub fn check_not_active_pool(dev: &Path) -> Result<()> {
+    let is_block_dev = std::fs::metadata(dev)
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false);
+    if !is_block_dev {
+        return Ok(());
+    }
+    let holders = holders(dev);
+    if holders.is_empty() {
+        return Ok(());
+    }
+
+    let held_by = match pool_on(dev) {
+        Ok(Some(pool)) => format!("the metadata device of the active pool {}", pool),
+        Ok(None) => return Ok(()),
+        Err(e) => format!(
+            "held by {}, which couldn't be told from a pool: {}",
+            holders.join(", "),
+            e
+        ),
+    };
+    Err(fail(
+        FailureKind::Usage,
+        anyhow!(
+            "{} is {}; merge from its metadata snapshot with --metadata-snap, \
+             or use --force to merge regardless",
+            dev.display(),
+            held_by
+        ),
+    ))
+}
+
+/// Fails if the given device is held by another device, as the data device of
+/// an active pool is, since the data copied there could clash with the writes
+/// of the pool. Unlike the check of the metadata device, there's no forcing it.
+pub fn check_not_held(dev: &Path) -> Result<()> {
+    let is_block_dev = std::fs::metadata(dev)
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false);
+    if !is_block_dev {
+        return Ok(());
+    }
+    let holders = holders(dev);
+    if holders.is_empty() {
+        return Ok(());
+    }
+
+    Err(fail(
+        FailureKind::Usage,
+        anyhow!(
+            "{} is held by {}; deactivate the pool before copying data into it",
+            dev.display(),
+            holders.join(", ")
+        ),
+    ))
+}
+
+fn release(name: &str) -> Result<()> {
+    dmsetup(&["message", name, "0", "release_metadata_snap"]).map(|_| ())
+}
+
+/// The metadata snapshot of a live pool, reserved through dmsetup and released
+/// once dropped, so the merge could read the metadata while the pool runs.
+/// The release is also left to the cleanup of temporary files, in case the
+/// process is terminated by a signal.
+pub struct PoolSnapshot {
+    name: String,
+    metadata_dev: PathBuf,
+    report: Arc<Report>,
+    cleanup: usize,
+}
+
+impl PoolSnapshot {
+    /// Locates the metadata device of the named pool from its table, then
+    /// reserves the metadata snapshot
+    pub fn reserve(name: &str, report: Arc<Report>) -> Result<PoolSnapshot> {
+        let table = dmsetup(&["table", name]).map_err(|e| fail(FailureKind::InputNotFound, e))?;
+        let metadata_dev = parse_pool_table(name, &table)?;
+        Self::hold(name, metadata_dev, report)
+    }
+
+    /// Reserves the metadata snapshot of the pool running on the given metadata
+    /// device, unless one is reserved already, which is left to its owner
+    pub fn reserve_if_missing(
+        metadata_dev: &Path,
+        report: Arc<Report>,
+    ) -> Result<Option<PoolSnapshot>> {
+        // held by the live pool, so it's opened shared, as the merge does
+        // with a metadata snapshot
+        let engine = SyncIoEngine::new_with(metadata_dev, false, false)?;
+        let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+        if sb.metadata_snap != 0 {
+            report.info(&format!(
+                "using the metadata snapshot reserved at block {}",
+                sb.metadata_snap
+            ));
+            return Ok(None);
+        }
+
+        let name = find_pool(metadata_dev)?;
+        Self::hold(&name, metadata_dev.to_path_buf(), report).map(Some)
+    }
+
+    fn hold(name: &str, metadata_dev: PathBuf, report: Arc<Report>) -> Result<PoolSnapshot> {
+        dmsetup(&["message", name, "0", "reserve_metadata_snap"])?;
+        report.info(&format!(
+            "reserved the metadata snapshot of pool {}, on {}",
+            name,
+            metadata_dev.display()
+        ));
+
+        let owned = name.to_string();
+        let cleanup = defer_cleanup(move || {
+            let _ = release(&owned);
+        });
+
+        Ok(PoolSnapshot {
+            name: name.to_string(),
+            metadata_dev,
+            report,
+            cleanup,
+        })
+    }
+
+    pub fn metadata_dev(&self) -> &Path {
+        &self.metadata_dev
+    }
+}
+
+impl Drop for PoolSnapshot {
+    fn drop(&mut self) {
+        // released already if the cleanup has run, e.g., on a panic
+        if !withdraw_cleanup(self.cleanup) {
+            return;
+        }
+        match release(&self.name) {
+            Ok(_) => self.report.info(&format!(
+                "released the metadata snapshot of pool {}",
+                self.name
+            )),
+            Err(e) => self.report.warning(&format!(
+                "couldn't release the metadata snapshot of pool {}: {}",
+                self.name, e
+            )),
+        }
+    }
+}
+
+//------------------------------------------
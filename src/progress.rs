@@ -0,0 +1,729 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thinp::report::Report;
+
+use crate::schema::Versioned;
+
+//------------------------------------------
+
+// The minimum interval between the progress updates of a device, by default
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// How often the progress of a device is updated, either by the time elapsed
+/// or by the thin blocks processed since the last update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportInterval {
+    Time(Duration),
+    Blocks(u64),
+}
+
+impl Default for ReportInterval {
+    fn default() -> Self {
+        ReportInterval::Time(PROGRESS_INTERVAL)
+    }
+}
+
+impl ReportInterval {
+    /// Parses seconds, with an optional s suffix, or thin blocks suffixed by
+    /// blocks, e.g., 10s or 65536blocks
+    pub fn from_arg(arg: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow!(
+                "invalid report interval '{}', expected seconds or a number of blocks, e.g., 10s or 65536blocks",
+                arg
+            )
+        };
+        let interval = match arg.strip_suffix("blocks") {
+            Some(n) => ReportInterval::Blocks(n.trim().parse().map_err(|_| invalid())?),
+            None => {
+                let secs: f64 = arg
+                    .strip_suffix('s')
+                    .unwrap_or(arg)
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid())?;
+                if !secs.is_finite() || secs < 0.0 {
+                    return Err(invalid());
+                }
+                ReportInterval::Time(Duration::from_secs_f64(secs))
+            }
+        };
+        if interval == ReportInterval::Blocks(0) {
+            return Err(invalid());
+        }
+        Ok(interval)
+    }
+}
+
+/// A json progress event, told apart by the event field
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    Phase {
+        phase: String,
+        // the place of the phase among the planned ones, counted from 1
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<usize>,
+    },
+    PhaseDone {
+        phase: String,
+        elapsed_secs: f64,
+    },
+    Progress {
+        phase: String,
+        dev_id: u32,
+        position: u64,
+        mapped_blocks: u64,
+        expected_mapped_blocks: u64,
+        percent: Option<f64>, // to a tenth, unknown if nothing is expected
+        // through the rest of the planned phases, unknown until estimated
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        eta_secs: Option<f64>,
+    },
+    Chunk {
+        phase: String,
+        dev_id: u32,
+        chunk_begin: u64,
+        chunk_end: u64,
+        mapped_blocks: u64,
+    },
+    Done,
+}
+
+/// Takes the progress events of a merge, e.g., for a library user to show its
+/// own progress bar. Called from the threads of the merge, one event at a time.
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, event: &ProgressEvent);
+}
+
+enum Output {
+    Disabled,
+    Text(Arc<Report>),
+    Json(Mutex<Box<dyn Write + Send>>),
+    Sink(Mutex<Arc<dyn ProgressSink>>),
+}
+
+/// A phase the run is planned to go through, and whether it walks the
+/// mappings of the devices, reporting their progress
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedPhase {
+    pub name: String,
+    pub walks_mappings: bool,
+}
+
+impl PlannedPhase {
+    pub fn new(name: &str, walks_mappings: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            walks_mappings,
+        }
+    }
+}
+
+struct PhaseTiming {
+    name: String,
+    started: Instant,
+    expected_blocks: u64, // through all the devices of the phase
+    done_blocks: u64,     // of the devices finished
+    device_blocks: u64,   // of the device being walked
+}
+
+#[derive(Default)]
+struct Timing {
+    device: Option<(u32, Instant)>, // the device being written, and its start
+    last_update: Option<(Instant, u64)>, // along with the blocks processed by then
+    planned: Vec<PlannedPhase>,
+    phase: Option<PhaseTiming>,
+    finished: Vec<(String, Duration)>,
+}
+
+impl Timing {
+    // The place of the current phase among the planned ones, counted from 1
+    fn phase_index(&self) -> Option<usize> {
+        let name = &self.phase.as_ref()?.name;
+        self.planned
+            .iter()
+            .position(|p| &p.name == name)
+            .map(|i| i + 1)
+    }
+
+    // The blocks the planned phases after the current one are expected to go
+    // through, taken as those of the current phase for each walking the mappings
+    fn later_blocks(&self) -> u64 {
+        let (Some(phase), Some(index)) = (&self.phase, self.phase_index()) else {
+            return 0;
+        };
+        let nr_later = self.planned[index..]
+            .iter()
+            .filter(|p| p.walks_mappings)
+            .count() as u64;
+        phase.expected_blocks.saturating_mul(nr_later)
+    }
+
+    fn finish_phase(&mut self, now: Instant) -> Option<(String, Duration)> {
+        let phase = self.phase.take()?;
+        let elapsed = now.duration_since(phase.started);
+        self.finished.push((phase.name.clone(), elapsed));
+        Some((phase.name, elapsed))
+    }
+}
+
+// Reports the progress of walking the devices through the phases of a run,
+// either as the percentage and ETA through the Report, or as newline-delimited
+// JSON events for orchestration tools. Once the phases are planned, each is
+// numbered and timed apart, and the ETA covers the phases left too. Updates are
+// best-effort, so errors never fail the merge.
+pub struct Progress {
+    output: Output,
+    interval: ReportInterval,
+    timing: Mutex<Timing>,
+}
+
+fn format_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+// The share of the expected blocks mapped so far, unknown for a device
+// expected to map nothing
+fn percent_of(mapped_blocks: u64, expected: u64) -> Option<f64> {
+    if expected == 0 {
+        return None;
+    }
+    Some((mapped_blocks as f64 * 100.0 / expected as f64).min(100.0))
+}
+
+// The time left through the current phase and the later ones, extrapolated
+// from the blocks gone through so far in the current phase, or none before
+// there's anything to extrapolate from
+fn remaining_time(elapsed: Duration, done: u64, expected: u64, later: u64) -> Option<Duration> {
+    if done == 0 || elapsed.is_zero() {
+        return None;
+    }
+    let rate = done as f64 / elapsed.as_secs_f64();
+    let left = expected.saturating_sub(done).saturating_add(later);
+    Some(Duration::from_secs_f64(left as f64 / rate))
+}
+
+// The percentage and ETA of a device, extrapolated from the time elapsed since
+// it started, or none at either end where there's nothing to estimate. The
+// phase and the overall ETA are given once the phases are planned.
+fn progress_title(
+    phase: Option<(usize, usize, &str)>,
+    dev_id: u32,
+    percent: f64,
+    elapsed: Duration,
+    overall: Option<Duration>,
+) -> Option<String> {
+    if percent <= 0.0 || percent >= 100.0 {
+        return None;
+    }
+    let remaining = elapsed.as_secs_f64() * (100.0 - percent) / percent;
+    let mut title = format!(
+        "device {}: {:.0}%, ETA {}",
+        dev_id,
+        percent,
+        format_duration(remaining as u64)
+    );
+    if let Some((index, count, name)) = phase {
+        title = format!("phase {}/{} {}, {}", index, count, name, title);
+    }
+    if let Some(overall) = overall {
+        title = format!(
+            "{}, overall ETA {}",
+            title,
+            format_duration(overall.as_secs())
+        );
+    }
+    Some(title)
+}
+
+fn progress_event(
+    phase: &str,
+    dev_id: u32,
+    position: u64,
+    mapped_blocks: u64,
+    expected: u64,
+    percent: Option<f64>,
+    eta: Option<Duration>,
+) -> ProgressEvent {
+    ProgressEvent::Progress {
+        phase: phase.to_string(),
+        dev_id,
+        position,
+        mapped_blocks,
+        expected_mapped_blocks: expected,
+        percent: percent.map(|p| (p * 10.0).round() / 10.0),
+        eta_secs: eta.map(|d| d.as_secs() as f64),
+    }
+}
+
+// The time taken by each phase, e.g., merge 1m02s, verify 0m10s
+fn format_timings(finished: &[(String, Duration)]) -> String {
+    finished
+        .iter()
+        .map(|(name, elapsed)| format!("{} {}", name, format_duration(elapsed.as_secs())))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Progress {
+    fn new(output: Output) -> Self {
+        Self {
+            output,
+            interval: ReportInterval::default(),
+            timing: Mutex::new(Timing::default()),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: ReportInterval) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(Output::Disabled)
+    }
+
+    pub fn text(report: Arc<Report>) -> Self {
+        Self::new(Output::Text(report))
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(Output::Json(Mutex::new(Box::new(std::io::stderr()))))
+    }
+
+    pub fn sink(sink: Arc<dyn ProgressSink>) -> Self {
+        Self::new(Output::Sink(Mutex::new(sink)))
+    }
+
+    // Takes over a descriptor opened by the caller, e.g., a pipe to the
+    // management software
+    pub fn from_fd(fd: i32) -> Result<Self> {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(anyhow!("bad progress descriptor {}", fd));
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(Self::new(Output::Json(Mutex::new(Box::new(file)))))
+    }
+
+    fn emit(&self, event: ProgressEvent) {
+        match &self.output {
+            Output::Json(out) => {
+                let mut out = out.lock().unwrap();
+                let _ = writeln!(out, "{}", Versioned::new(event).to_json());
+                let _ = out.flush();
+            }
+            Output::Sink(sink) => sink.lock().unwrap().event(&event),
+            _ => {}
+        }
+    }
+
+    /// Plans the phases of the run, in order, so each is numbered, and the ETA
+    /// accounts for the ones left
+    pub fn plan(&self, phases: Vec<PlannedPhase>) {
+        self.timing.lock().unwrap().planned = phases;
+    }
+
+    /// Starts a phase, ending the previous one
+    pub fn phase(&self, phase: &str) {
+        let now = Instant::now();
+        let (finished, index, count) = {
+            let mut timing = self.timing.lock().unwrap();
+            let finished = timing.finish_phase(now);
+            timing.phase = Some(PhaseTiming {
+                name: phase.to_string(),
+                started: now,
+                expected_blocks: 0,
+                done_blocks: 0,
+                device_blocks: 0,
+            });
+            timing.device = None;
+            timing.last_update = None;
+            let count = timing.planned.len();
+            (finished, timing.phase_index(), (count > 0).then_some(count))
+        };
+
+        if let Some((name, elapsed)) = finished {
+            self.emit(ProgressEvent::PhaseDone {
+                phase: name,
+                elapsed_secs: elapsed.as_secs_f64(),
+            });
+        }
+        if let (Output::Text(report), Some(index), Some(count)) = (&self.output, index, count) {
+            if count > 1 {
+                report.info(&format!("phase {}/{}: {}", index, count, phase));
+            }
+        }
+        self.emit(ProgressEvent::Phase {
+            phase: phase.to_string(),
+            index,
+            count,
+        });
+    }
+
+    /// Sets the mapped blocks the current phase is expected to go through,
+    /// across all its devices
+    pub fn expect(&self, blocks: u64) {
+        if let Some(phase) = &mut self.timing.lock().unwrap().phase {
+            phase.expected_blocks = blocks;
+        }
+    }
+
+    // Reports the blocks mapped so far out of those the device is expected to
+    // map, at most once per interval unless forced. The position is the thin
+    // block the device is written up to, counted from the start of each device.
+    pub fn update(
+        &self,
+        dev_id: u32,
+        position: u64,
+        mapped_blocks: u64,
+        expected: u64,
+        force: bool,
+    ) {
+        if let Output::Disabled = self.output {
+            return;
+        }
+
+        let now = Instant::now();
+        let (elapsed, name, index, count, overall) = {
+            let mut timing = self.timing.lock().unwrap();
+            let started = match timing.device {
+                Some((id, started)) if id == dev_id => started,
+                _ => {
+                    timing.device = Some((dev_id, now));
+                    timing.last_update = timing.last_update.map(|(t, _)| (t, 0));
+                    if let Some(phase) = &mut timing.phase {
+                        phase.done_blocks += phase.device_blocks;
+                        phase.device_blocks = 0;
+                    }
+                    now
+                }
+            };
+            if let Some(phase) = &mut timing.phase {
+                phase.device_blocks = mapped_blocks;
+            }
+            let due = match (timing.last_update, self.interval) {
+                (None, _) => true,
+                (Some((t, _)), ReportInterval::Time(d)) => now.duration_since(t) >= d,
+                (Some((_, p)), ReportInterval::Blocks(n)) => position.saturating_sub(p) >= n,
+            };
+            if !force && !due {
+                return;
+            }
+            timing.last_update = Some((now, position));
+
+            // nothing is left to estimate from if the phase expects no blocks
+            let later = timing.later_blocks();
+            let (name, overall) = match &timing.phase {
+                Some(p) => (
+                    p.name.clone(),
+                    remaining_time(
+                        now.duration_since(p.started),
+                        p.done_blocks + p.device_blocks,
+                        p.expected_blocks,
+                        later,
+                    )
+                    .filter(|_| p.expected_blocks > 0),
+                ),
+                None => ("merge".to_string(), None),
+            };
+            let index = timing.phase_index();
+            let count = timing.planned.len();
+            (now.duration_since(started), name, index, count, overall)
+        };
+
+        let percent = percent_of(mapped_blocks, expected);
+
+        match &self.output {
+            Output::Text(report) => {
+                let Some(percent) = percent else {
+                    return;
+                };
+                report.progress(percent as u8);
+                let phase = index.map(|i| (i, count, name.as_str()));
+                if let Some(title) = progress_title(phase, dev_id, percent, elapsed, overall) {
+                    report.set_sub_title(&title);
+                }
+            }
+            Output::Json(_) | Output::Sink(_) => {
+                self.emit(progress_event(
+                    &name,
+                    dev_id,
+                    position,
+                    mapped_blocks,
+                    expected,
+                    percent,
+                    overall,
+                ));
+            }
+            Output::Disabled => {}
+        }
+    }
+
+    // Marks the thin blocks of a device up to the end as written, so an
+    // orchestration tool could pause, or abort at a known point
+    pub fn chunk(&self, dev_id: u32, begin: u64, end: u64, mapped_blocks: u64) {
+        self.emit(ProgressEvent::Chunk {
+            phase: "merge".to_string(),
+            dev_id,
+            chunk_begin: begin,
+            chunk_end: end,
+            mapped_blocks,
+        });
+    }
+
+    /// Ends the last phase, and the run. The time taken by each phase is
+    /// reported if there were several.
+    pub fn done(&self) {
+        let (finished, timings) = {
+            let mut timing = self.timing.lock().unwrap();
+            let finished = timing.finish_phase(Instant::now());
+            (finished, timing.finished.clone())
+        };
+        if let Some((name, elapsed)) = finished {
+            self.emit(ProgressEvent::PhaseDone {
+                phase: name,
+                elapsed_secs: elapsed.as_secs_f64(),
+            });
+        }
+        if let Output::Text(report) = &self.output {
+            if timings.len() > 1 {
+                report.info(&format!("phase timings: {}", format_timings(&timings)));
+            }
+        }
+        self.emit(ProgressEvent::Done);
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::SCHEMA_VERSION;
+
+    #[test]
+    fn parse_intervals() {
+        assert_eq!(
+            ReportInterval::from_arg("10").unwrap(),
+            ReportInterval::Time(Duration::from_secs(10))
+        );
+        assert_eq!(
+            ReportInterval::from_arg("2.5s").unwrap(),
+            ReportInterval::Time(Duration::from_millis(2500))
+        );
+        assert_eq!(
+            ReportInterval::from_arg("0s").unwrap(),
+            ReportInterval::Time(Duration::ZERO)
+        );
+        assert_eq!(
+            ReportInterval::from_arg("65536blocks").unwrap(),
+            ReportInterval::Blocks(65536)
+        );
+        assert_eq!(
+            ReportInterval::from_arg("64 blocks").unwrap(),
+            ReportInterval::Blocks(64)
+        );
+    }
+
+    #[test]
+    fn reject_bad_intervals() {
+        for arg in [
+            "",
+            "s",
+            "-1",
+            "-1s",
+            "nan",
+            "inf",
+            "0blocks",
+            "1.5blocks",
+            "10m",
+            "blocks",
+        ] {
+            assert!(ReportInterval::from_arg(arg).is_err(), "accepted '{}'", arg);
+        }
+    }
+
+    #[test]
+    fn percent_follows_the_mapped_blocks() {
+        assert_eq!(percent_of(0, 0), None);
+        assert_eq!(percent_of(5, 0), None);
+        assert_eq!(percent_of(0, 200), Some(0.0));
+        assert_eq!(percent_of(50, 200), Some(25.0));
+        // the input details might be stale
+        assert_eq!(percent_of(300, 200), Some(100.0));
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!(format_duration(0), "0m00s");
+        assert_eq!(format_duration(61), "1m01s");
+        assert_eq!(format_duration(3599), "59m59s");
+        assert_eq!(format_duration(3600), "1h00m");
+        assert_eq!(format_duration(7325), "2h02m");
+    }
+
+    #[test]
+    fn format_titles() {
+        assert_eq!(
+            progress_title(None, 30, 25.0, Duration::from_secs(60), None).unwrap(),
+            "device 30: 25%, ETA 3m00s"
+        );
+        assert_eq!(
+            progress_title(
+                Some((2, 2, "verify")),
+                30,
+                25.0,
+                Duration::from_secs(60),
+                Some(Duration::from_secs(200))
+            )
+            .unwrap(),
+            "phase 2/2 verify, device 30: 25%, ETA 3m00s, overall ETA 3m20s"
+        );
+        assert!(progress_title(None, 30, 0.0, Duration::from_secs(60), None).is_none());
+        assert!(progress_title(None, 30, 100.0, Duration::from_secs(60), None).is_none());
+    }
+
+    #[test]
+    fn remaining_time_covers_the_later_phases() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(remaining_time(minute, 0, 100, 100), None);
+        assert_eq!(remaining_time(Duration::ZERO, 10, 100, 100), None);
+        // a quarter through the merge, the verify after it goes through as many
+        assert_eq!(
+            remaining_time(minute, 25, 100, 0),
+            Some(Duration::from_secs(180))
+        );
+        assert_eq!(
+            remaining_time(minute, 25, 100, 100),
+            Some(Duration::from_secs(420))
+        );
+        // the details might be stale
+        assert_eq!(remaining_time(minute, 150, 100, 0), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn later_blocks_follow_the_plan() {
+        let progress = Progress::disabled();
+        progress.plan(vec![
+            PlannedPhase::new("merge", true),
+            PlannedPhase::new("check", false),
+            PlannedPhase::new("verify", true),
+        ]);
+        progress.phase("merge");
+        progress.expect(1000);
+        {
+            let timing = progress.timing.lock().unwrap();
+            assert_eq!(timing.phase_index(), Some(1));
+            assert_eq!(timing.later_blocks(), 1000);
+        }
+        progress.phase("verify");
+        progress.expect(1200);
+        {
+            let timing = progress.timing.lock().unwrap();
+            assert_eq!(timing.phase_index(), Some(3));
+            assert_eq!(timing.later_blocks(), 0);
+            assert_eq!(timing.finished.len(), 1);
+            assert_eq!(timing.finished[0].0, "merge");
+        }
+        progress.done();
+        let names: Vec<_> = progress
+            .timing
+            .lock()
+            .unwrap()
+            .finished
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        assert_eq!(names, ["merge", "verify"]);
+    }
+
+    #[test]
+    fn format_phase_timings() {
+        assert_eq!(
+            format_timings(&[
+                ("merge".to_string(), Duration::from_secs(62)),
+                ("verify".to_string(), Duration::from_secs(10)),
+            ]),
+            "merge 1m02s, verify 0m10s"
+        );
+    }
+
+    #[test]
+    fn format_progress_events() {
+        assert_eq!(
+            Versioned::new(progress_event("merge", 30, 1024, 50, 200, Some(25.0), None)).to_json(),
+            "{\"schema_version\":1,\"event\":\"progress\",\"phase\":\"merge\",\"dev_id\":30,\"position\":1024,\"mapped_blocks\":50,\"expected_mapped_blocks\":200,\"percent\":25.0}"
+        );
+        assert_eq!(
+            Versioned::new(progress_event("merge", 40, 0, 0, 0, None, None)).to_json(),
+            "{\"schema_version\":1,\"event\":\"progress\",\"phase\":\"merge\",\"dev_id\":40,\"position\":0,\"mapped_blocks\":0,\"expected_mapped_blocks\":0,\"percent\":null}"
+        );
+        assert_eq!(
+            Versioned::new(ProgressEvent::Phase {
+                phase: "verify".to_string(),
+                index: Some(2),
+                count: Some(2),
+            })
+            .to_json(),
+            "{\"schema_version\":1,\"event\":\"phase\",\"phase\":\"verify\",\"index\":2,\"count\":2}"
+        );
+        assert_eq!(
+            Versioned::new(ProgressEvent::PhaseDone {
+                phase: "merge".to_string(),
+                elapsed_secs: 1.5,
+            })
+            .to_json(),
+            "{\"schema_version\":1,\"event\":\"phase_done\",\"phase\":\"merge\",\"elapsed_secs\":1.5}"
+        );
+        assert_eq!(
+            Versioned::new(ProgressEvent::Done).to_json(),
+            "{\"schema_version\":1,\"event\":\"done\"}"
+        );
+    }
+
+    #[test]
+    fn events_read_back() {
+        let event = Versioned::new(progress_event(
+            "verify",
+            30,
+            1024,
+            50,
+            300,
+            Some(100.0 / 3.0),
+            Some(Duration::from_secs(90)),
+        ));
+        let json = event.to_json();
+        let read: Versioned<ProgressEvent> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.schema_version, SCHEMA_VERSION);
+        assert_eq!(
+            read.body,
+            progress_event(
+                "verify",
+                30,
+                1024,
+                50,
+                300,
+                Some(33.3),
+                Some(Duration::from_secs(90))
+            )
+        );
+    }
+}
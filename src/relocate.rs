@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::io::{Error, Result};
+use std::sync::{Arc, Mutex};
+use thinp::checksum::{metadata_block_type, write_checksum, BT};
+use thinp::io_engine::*;
+use thinp::pdata::space_map::SpaceMap;
+
+//------------------------------------------
+
+// The header of a btree node: the checksum, the flags, the location of the
+// node itself, the number of entries, the max entries and the value size.
+// The keys follow, then the values.
+const NODE_FLAGS: usize = 4;
+const NODE_BLOCKNR: usize = 8;
+const NODE_NR_ENTRIES: usize = 16;
+const NODE_MAX_ENTRIES: usize = 20;
+const NODE_VALUE_SIZE: usize = 24;
+const NODE_HEADER_SIZE: usize = 32;
+const INTERNAL_NODE: u32 = 1;
+
+// The blocks tried for a payload before giving up on it
+const MAX_ATTEMPTS: usize = 8;
+
+fn get_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn get_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn put_u64(data: &mut [u8], at: usize, v: u64) {
+    data[at..at + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+#[derive(Default)]
+struct Relocations {
+    // the failed locations of the nodes, and where they went instead
+    moved: BTreeMap<u64, u64>,
+
+    // the blocks failing the writes, including the relocation targets
+    unwritable: Vec<u64>,
+
+    // The blocks allocated before the top level trees are written. Both the
+    // leaves of the top level mapping tree and those of the devices hold
+    // 8 byte values, but only the former point at nodes, and they're
+    // allocated last.
+    before_top_level: Option<Vec<u64>>,
+}
+
+impl Relocations {
+    fn allocated_before(&self, loc: u64) -> bool {
+        match &self.before_top_level {
+            Some(bits) => bits[(loc / 64) as usize] & (1 << (loc % 64)) != 0,
+            None => true,
+        }
+    }
+}
+
+// Passes the writes through to the inner engine, moving the btree nodes
+// failing to write, e.g., on bad sectors, to blocks newly allocated from the
+// space map. The nodes written later, i.e., their parents, are made to point
+// at the new locations. The failed blocks stay allocated, out of use.
+//
+// Once the top level trees are being written, the space maps could be packed
+// already, so a failure is no longer relocated.
+pub struct RelocatingIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    relocations: Mutex<Relocations>,
+}
+
+impl RelocatingIoEngine {
+    pub fn new(
+        inner: Arc<dyn IoEngine + Send + Sync>,
+        sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    ) -> Self {
+        Self {
+            inner,
+            sm,
+            relocations: Mutex::new(Relocations::default()),
+        }
+    }
+
+    // Tells the writes of the devices are done, and the top level trees and
+    // the space maps follow
+    pub fn top_level(&self) -> anyhow::Result<()> {
+        let bits = {
+            let sm = self.sm.lock().unwrap();
+            let nr_blocks = sm.get_nr_blocks()?;
+            let mut bits = vec![0u64; nr_blocks.div_ceil(64) as usize];
+            for b in 0..nr_blocks {
+                if sm.get(b)? > 0 {
+                    bits[(b / 64) as usize] |= 1 << (b % 64);
+                }
+            }
+            bits
+        };
+        self.relocations.lock().unwrap().before_top_level = Some(bits);
+        Ok(())
+    }
+
+    // The nodes moved, by their failed locations
+    pub fn relocated(&self) -> Vec<(u64, u64)> {
+        let rel = self.relocations.lock().unwrap();
+        rel.moved.iter().map(|(&from, &to)| (from, to)).collect()
+    }
+
+    // The blocks failing the writes, in order
+    pub fn unwritable(&self) -> Vec<u64> {
+        let mut blocks = self.relocations.lock().unwrap().unwritable.clone();
+        blocks.sort_unstable();
+        blocks
+    }
+
+    // Points the node at the new locations of its children, returning whether
+    // it changed
+    fn patch(&self, rel: &Relocations, b: &Block) -> Result<bool> {
+        let data = b.get_data();
+        if rel.moved.is_empty() || metadata_block_type(data) != BT::NODE {
+            return Ok(false);
+        }
+
+        let internal = get_u32(data, NODE_FLAGS) & INTERNAL_NODE != 0;
+        let top_level = get_u32(data, NODE_VALUE_SIZE) == 8 && !rel.allocated_before(b.loc);
+        if !internal && !top_level {
+            return Ok(false);
+        }
+
+        let nr_entries = get_u32(data, NODE_NR_ENTRIES) as usize;
+        let values = NODE_HEADER_SIZE + get_u32(data, NODE_MAX_ENTRIES) as usize * 8;
+        if values + nr_entries * 8 > BLOCK_SIZE {
+            return Ok(false);
+        }
+
+        let mut changed = false;
+        for i in 0..nr_entries {
+            let at = values + i * 8;
+            if let Some(&to) = rel.moved.get(&get_u64(data, at)) {
+                put_u64(data, at, to);
+                changed = true;
+            }
+        }
+        if changed {
+            write_checksum(data, BT::NODE).map_err(|e| Error::other(e.to_string()))?;
+        }
+        Ok(changed)
+    }
+
+    // Writes the payload of a failed node to a new block
+    fn relocate(&self, rel: &mut Relocations, b: &Block, e: &Error) -> Result<()> {
+        rel.unwritable.push(b.loc);
+        if rel.before_top_level.is_some() || metadata_block_type(b.get_data()) != BT::NODE {
+            return Err(Error::other(format!(
+                "unable to write the output block {}: {}",
+                b.loc, e
+            )));
+        }
+
+        for _ in 0..MAX_ATTEMPTS {
+            let to = self
+                .sm
+                .lock()
+                .unwrap()
+                .alloc()
+                .map_err(|e| Error::other(e.to_string()))?
+                .ok_or_else(|| {
+                    Error::other(format!("no free block left to relocate block {}", b.loc))
+                })?;
+
+            let moved = Block::new(to);
+            let data = moved.get_data();
+            data.copy_from_slice(b.get_data());
+            put_u64(data, NODE_BLOCKNR, to);
+            write_checksum(data, BT::NODE).map_err(|e| Error::other(e.to_string()))?;
+
+            if self.inner.write(&moved).is_ok() {
+                rel.moved.insert(b.loc, to);
+                return Ok(());
+            }
+            rel.unwritable.push(to);
+        }
+
+        Err(Error::other(format!(
+            "unable to relocate the output block {} after {} attempts: {}",
+            b.loc, MAX_ATTEMPTS, e
+        )))
+    }
+}
+
+impl IoEngine for RelocatingIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, b: u64) -> Result<Block> {
+        self.inner.read(b)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        self.inner.read_many(blocks)
+    }
+
+    fn write(&self, block: &Block) -> Result<()> {
+        self.write_many(std::slice::from_ref(block))?.remove(0)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        let mut rel = self.relocations.lock().unwrap();
+        for b in blocks {
+            self.patch(&rel, b)?;
+        }
+
+        let nr_moved = rel.moved.len();
+        let mut results = match self.inner.write_many(blocks) {
+            Ok(results) => results,
+            // retried one by one to tell the failing ones
+            Err(_) => blocks.iter().map(|b| self.inner.write(b)).collect(),
+        };
+
+        for (b, r) in blocks.iter().zip(results.iter_mut()) {
+            // the parent of a node relocated earlier in the batch is written
+            // again, pointing at the new location
+            if rel.moved.len() > nr_moved && self.patch(&rel, b)? {
+                *r = self.inner.write(b);
+            }
+            if let Err(e) = r {
+                self.relocate(&mut rel, b, e)?;
+                *r = Ok(());
+            }
+        }
+        Ok(results)
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,306 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use thinp::thin::block_time::BlockTime;
+
+use crate::stream::RunSource;
+use crate::zeroed::read_block_list;
+
+//------------------------------------------
+
+/// Where the data blocks of the output go in the data device of another pool
+#[derive(Clone, Debug)]
+pub enum RemapPolicy {
+    Offset(u64),               // every block moves by the offset
+    FreeList(Vec<(u64, u64)>), // the blocks are taken from the half-open ranges in order
+}
+
+impl RemapPolicy {
+    /// Parses offset:BLOCKS, or free-list:FILE with a list of the free data
+    /// blocks of the target pool, one block or begin..end range per line
+    pub fn from_arg(arg: &str) -> Result<Self> {
+        match arg.split_once(':') {
+            Some(("offset", n)) => n
+                .parse::<u64>()
+                .map(RemapPolicy::Offset)
+                .map_err(|_| anyhow!("invalid remapping offset '{}'", n)),
+            Some(("free-list", path)) => {
+                let mut ranges = read_block_list(Path::new(path), "free block list")?;
+                ranges.sort_unstable();
+                for pair in ranges.windows(2) {
+                    if pair[1].0 < pair[0].1 {
+                        return Err(anyhow!(
+                            "the free block list {} has overlapping ranges {}..{} and {}..{}",
+                            path,
+                            pair[0].0,
+                            pair[0].1,
+                            pair[1].0,
+                            pair[1].1
+                        ));
+                    }
+                }
+                Ok(RemapPolicy::FreeList(ranges))
+            }
+            _ => Err(anyhow!(
+                "invalid remapping policy '{}', expected offset:BLOCKS or free-list:FILE",
+                arg
+            )),
+        }
+    }
+}
+
+// Moves the data blocks of the output by the policy. A data block shared by
+// several mappings, or devices, is moved once, so the sharing holds in the
+// target pool.
+pub struct DataRemapper {
+    policy: RemapPolicy,
+    next_free: usize,
+    nr_data_blocks: u64, // of the target pool
+
+    // the source ranges moved, by their begin, along with their length and
+    // destination
+    moved: BTreeMap<u64, (u64, u64)>,
+}
+
+impl DataRemapper {
+    pub fn new(policy: RemapPolicy, nr_data_blocks: u64) -> Self {
+        Self {
+            policy,
+            next_free: 0,
+            nr_data_blocks,
+            moved: BTreeMap::new(),
+        }
+    }
+
+    /// The number of source data blocks moved so far
+    pub fn nr_moved(&self) -> u64 {
+        self.moved.values().map(|&(len, _)| len).sum()
+    }
+
+    // Picks the destination of a source range not moved yet
+    fn allocate(&mut self, block: u64, len: u64) -> Result<(u64, u64)> {
+        match &mut self.policy {
+            RemapPolicy::Offset(offset) => {
+                let dest = block.checked_add(*offset).ok_or_else(|| {
+                    anyhow!("data block {} overflows once moved by {}", block, offset)
+                })?;
+                Ok((dest, len))
+            }
+            RemapPolicy::FreeList(free) => {
+                let Some(range) = free.get_mut(self.next_free) else {
+                    return Err(anyhow!(
+                        "the free block list runs out after {} data blocks",
+                        self.nr_moved()
+                    ));
+                };
+                let dest = range.0;
+                let n = std::cmp::min(len, range.1 - dest);
+                range.0 += n;
+                if range.0 == range.1 {
+                    self.next_free += 1;
+                }
+                Ok((dest, n))
+            }
+        }
+    }
+
+    // Moves the leading piece of the source range, returning its destination
+    // and length
+    fn remap(&mut self, block: u64, len: u64) -> Result<(u64, u64)> {
+        if let Some((&begin, &(n, dest))) = self.moved.range(..=block).next_back() {
+            if block < begin + n {
+                return Ok((dest + block - begin, std::cmp::min(len, begin + n - block)));
+            }
+        }
+
+        // up to the next range moved already
+        let len = match self.moved.range(block + 1..).next() {
+            Some((&next, _)) => std::cmp::min(len, next - block),
+            None => len,
+        };
+        let (dest, n) = self.allocate(block, len)?;
+        if dest.saturating_add(n) > self.nr_data_blocks {
+            return Err(anyhow!(
+                "data block {} moves to {}, beyond the {} data blocks of the target pool",
+                block,
+                dest,
+                self.nr_data_blocks
+            ));
+        }
+        self.moved.insert(block, (n, dest));
+        Ok((dest, n))
+    }
+
+    /// Writes the moved ranges as begin..end of the source, followed by the
+    /// destination, one per line in the order of the source blocks
+    pub fn write_table(&self, path: &Path) -> Result<()> {
+        let mut out = Vec::new();
+        writeln!(
+            out,
+            "# source data blocks, and where they begin in the target pool"
+        )?;
+        for (&begin, &(len, dest)) in &self.moved {
+            writeln!(out, "{}..{} {}", begin, begin + len, dest)?;
+        }
+        std::fs::write(path, out)
+            .map_err(|e| anyhow!("unable to write the remap table {}: {}", path.display(), e))
+    }
+}
+
+//------------------------------------------
+
+/// Moves the data blocks of the runs of the source by the remapper, splitting
+/// the runs across the destinations
+pub fn remap_runs(mut source: RunSource, remapper: Arc<Mutex<DataRemapper>>) -> RunSource {
+    let mut pending: Option<(u64, BlockTime, u64)> = None;
+    Box::new(move || {
+        let (key, bt, len) = match pending.take() {
+            Some(run) => run,
+            None => match source()? {
+                Some(run) => run,
+                None => return Ok(None),
+            },
+        };
+
+        let (dest, piece) = remapper.lock().unwrap().remap(bt.block, len)?;
+        if piece < len {
+            pending = Some((
+                key + piece,
+                BlockTime {
+                    block: bt.block + piece,
+                    time: bt.time,
+                },
+                len - piece,
+            ));
+        }
+
+        Ok(Some((
+            key,
+            BlockTime {
+                block: dest,
+                time: bt.time,
+            },
+            piece,
+        )))
+    })
+}
+
+//------------------------------------------
+
+/// Relabels the mappings for data blocks of another size without moving the
+/// data. A block splits into several smaller ones, or several blocks, aligned
+/// and mapped contiguously at the same time, join into a larger one.
+#[derive(Clone, Copy, Debug)]
+pub struct Rescale {
+    pub from: u32, // data block sizes in sectors
+    pub to: u32,
+}
+
+impl Rescale {
+    pub fn new(from: u32, to: u32) -> Result<Self> {
+        if from % to != 0 && to % from != 0 {
+            return Err(anyhow!(
+                "data blocks of {} sectors can't be rescaled to {}, as neither size divides the other",
+                from,
+                to
+            ));
+        }
+        Ok(Rescale { from, to })
+    }
+
+    fn factor(&self) -> u64 {
+        (std::cmp::max(self.from, self.to) / std::cmp::min(self.from, self.to)) as u64
+    }
+
+    /// Converts a number of blocks, rounding down when joining, e.g., the
+    /// blocks of the data device, whose partial last block goes unused
+    pub fn blocks(&self, nr: u64) -> Result<u64> {
+        if self.to < self.from {
+            nr.checked_mul(self.factor())
+                .ok_or_else(|| anyhow!("{} data blocks overflow once rescaled", nr))
+        } else {
+            Ok(nr / self.factor())
+        }
+    }
+
+    /// Converts an end of the keys, rounding up when joining
+    pub fn key_end(&self, end: u64) -> Result<u64> {
+        if self.to < self.from {
+            self.blocks(end)
+        } else {
+            Ok(end.div_ceil(self.factor()))
+        }
+    }
+}
+
+/// Rescales the runs of the source, failing at the first run that doesn't
+/// line up with the larger blocks, as a block can't be split between data
+/// blocks apart, or take two times
+pub fn rescale_runs(mut source: RunSource, rescale: Rescale, dev_id: u32) -> RunSource {
+    let k = rescale.factor();
+    if rescale.to < rescale.from {
+        return Box::new(move || match source()? {
+            Some((key, bt, len)) => {
+                let overflow = || anyhow!("device {} maps keys overflowing once rescaled", dev_id);
+                Ok(Some((
+                    key.checked_mul(k).ok_or_else(overflow)?,
+                    BlockTime {
+                        block: bt.block * k,
+                        time: bt.time,
+                    },
+                    len * k,
+                )))
+            }
+            None => Ok(None),
+        });
+    }
+
+    let join = move |(key, bt, len): (u64, BlockTime, u64)| {
+        if key % k != 0 || bt.block % k != 0 || len % k != 0 {
+            return Err(anyhow!(
+                "device {} maps the thin blocks {}..{} to the data blocks {}..{}, which don't line up \
+                 with data blocks of {} sectors",
+                dev_id,
+                key,
+                key + len,
+                bt.block,
+                bt.block + len,
+                rescale.to
+            ));
+        }
+        Ok((
+            key / k,
+            BlockTime {
+                block: bt.block / k,
+                time: bt.time,
+            },
+            len / k,
+        ))
+    };
+
+    // the runs contiguous in both the keys and the data blocks, at the same
+    // time, are joined first, as a larger block could span them
+    let mut pending: Option<(u64, BlockTime, u64)> = None;
+    let mut done = false;
+    Box::new(move || loop {
+        let next = if done { None } else { source()? };
+        done = next.is_none();
+        match (pending.take(), next) {
+            (None, None) => return Ok(None),
+            (None, Some(run)) => pending = Some(run),
+            (Some((key, bt, len)), Some((k2, bt2, len2)))
+                if key + len == k2 && bt.block + len == bt2.block && bt.time == bt2.time =>
+            {
+                pending = Some((key, bt, len + len2));
+            }
+            (Some(run), next) => {
+                pending = next;
+                return join(run).map(Some);
+            }
+        }
+    })
+}
+
+//------------------------------------------
@@ -0,0 +1,205 @@
+use anyhow::{anyhow, Result};
+use std::fs::OpenOptions;
+use std::io::{self, Read};
+use std::sync::Arc;
+use thinp::io_engine::*;
+
+use crate::temp::TempPath;
+
+//------------------------------------------
+
+// The blocks read at a time, and the gap between two blocks requested apart
+// below which the blocks in between are fetched along rather than requesting
+// them separately, as a request costs far more than a few extra blocks
+const BATCH_SIZE: usize = 64;
+const MAX_GAP: u64 = 16;
+
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+fn to_io_error(e: ureq::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+// A read-only engine serving blocks of a metadata image in object storage through
+// HTTP range requests, e.g., a presigned S3 URL.
+pub struct HttpIoEngine {
+    agent: ureq::Agent,
+    url: String,
+    nr_blocks: u64,
+}
+
+impl HttpIoEngine {
+    pub fn new(url: &str) -> Result<HttpIoEngine> {
+        let agent = ureq::Agent::new();
+        let resp = agent
+            .head(url)
+            .call()
+            .map_err(|e| anyhow!("unable to access {}: {}", url, e))?;
+        let len = resp
+            .header("Content-Length")
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| anyhow!("unable to get the size of {}", url))?;
+
+        Ok(HttpIoEngine {
+            agent,
+            url: url.to_string(),
+            nr_blocks: len / BLOCK_SIZE as u64,
+        })
+    }
+
+    // Reads consecutive blocks in one request
+    fn read_run(&self, begin: u64, count: u64) -> io::Result<Vec<Block>> {
+        let first = begin * BLOCK_SIZE as u64;
+        let last = (begin + count) * BLOCK_SIZE as u64 - 1;
+        let resp = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", first, last))
+            .call()
+            .map_err(to_io_error)?;
+
+        // servers ignoring the range would send the whole image
+        if resp.status() != 206 {
+            return Err(io::Error::other(format!(
+                "range requests not supported, status {}",
+                resp.status()
+            )));
+        }
+
+        let mut reader = resp.into_reader();
+        let mut blocks = Vec::with_capacity(count as usize);
+        for b in begin..(begin + count) {
+            let blk = Block::new(b);
+            reader.read_exact(blk.get_data())?;
+            blocks.push(blk);
+        }
+        Ok(blocks)
+    }
+}
+
+impl IoEngine for HttpIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn get_batch_size(&self) -> usize {
+        BATCH_SIZE
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        4
+    }
+
+    fn read(&self, b: u64) -> io::Result<Block> {
+        let mut blocks = self.read_run(b, 1)?;
+        Ok(blocks.remove(0))
+    }
+
+    // The blocks ascending with short gaps are fetched in one range request,
+    // e.g., the leaves of a tree written in order
+    fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+        let mut results = Vec::with_capacity(blocks.len());
+
+        let mut i = 0;
+        while i < blocks.len() {
+            let mut j = i + 1;
+            while j < blocks.len()
+                && blocks[j] > blocks[j - 1]
+                && blocks[j] - blocks[j - 1] <= MAX_GAP
+            {
+                j += 1;
+            }
+
+            let begin = blocks[i];
+            match self.read_run(begin, blocks[j - 1] - begin + 1) {
+                Ok(run) => {
+                    let mut run: Vec<Option<Block>> = run.into_iter().map(Some).collect();
+                    for &b in &blocks[i..j] {
+                        results.push(Ok(run[(b - begin) as usize].take().unwrap()));
+                    }
+                }
+                Err(e) => {
+                    for _ in i..j {
+                        results.push(Err(io::Error::new(e.kind(), e.to_string())));
+                    }
+                }
+            }
+            i = j;
+        }
+
+        Ok(results)
+    }
+
+    fn write(&self, _block: &Block) -> io::Result<()> {
+        Err(io::Error::other("remote metadata is read-only"))
+    }
+
+    fn write_many(&self, _blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+        Err(io::Error::other("remote metadata is read-only"))
+    }
+}
+
+//------------------------------------------
+
+// Feeds the blocks of an engine to the uploader without copying the whole image
+struct EngineReader<'a> {
+    engine: &'a dyn IoEngine,
+    block: u64,
+    buf: Option<Block>,
+    offset: usize,
+}
+
+impl Read for EngineReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_none() {
+            if self.block == self.engine.get_nr_blocks() {
+                return Ok(0);
+            }
+            self.buf = Some(self.engine.read(self.block)?);
+            self.block += 1;
+            self.offset = 0;
+        }
+
+        let data = self.buf.as_ref().unwrap().get_data();
+        let len = std::cmp::min(out.len(), BLOCK_SIZE - self.offset);
+        out[..len].copy_from_slice(&data[self.offset..self.offset + len]);
+        self.offset += len;
+        if self.offset == BLOCK_SIZE {
+            self.buf = None;
+        }
+        Ok(len)
+    }
+}
+
+// Stages the output to upload in a sparse temporary file rather than in
+// memory, so its size isn't bound by the memory limit
+pub fn stage_upload(nr_blocks: u64) -> Result<(Arc<dyn IoEngine + Send + Sync>, TempPath)> {
+    let tmp = TempPath::new("upload").map_err(|e| anyhow!("unable to stage the upload: {}", e))?;
+    let file = OpenOptions::new().write(true).open(tmp.path())?;
+    file.set_len(nr_blocks * BLOCK_SIZE as u64)?;
+    let engine = Arc::new(SyncIoEngine::new(tmp.path(), true)?);
+    Ok((engine, tmp))
+}
+
+// Streams the whole content of an engine to object storage
+pub fn upload_engine(url: &str, engine: &dyn IoEngine) -> Result<()> {
+    let len = engine.get_nr_blocks() * BLOCK_SIZE as u64;
+    let reader = EngineReader {
+        engine,
+        block: 0,
+        buf: None,
+        offset: 0,
+    };
+
+    ureq::put(url)
+        .set("Content-Length", &len.to_string())
+        .set("Content-Type", "application/octet-stream")
+        .send(reader)
+        .map_err(|e| anyhow!("unable to upload to {}: {}", url, e))?;
+
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+pub use crate::diff::{DiffRange, DiffReport};
+pub use crate::jobs::{JobManifest, JobResult, JobStatus};
+pub use crate::progress::ProgressEvent;
+pub use crate::summary::RunSummary;
+
+//------------------------------------------
+
+/// The version of the json outputs, shared by the summary, the progress
+/// events, the diff and the job manifest, and bumped on incompatible changes to any of them
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A json output along with the schema version it was written in, so the
+/// consumers could deserialize the outputs of this version, and reject others
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(body: T) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            body,
+        }
+    }
+}
+
+impl<T: Serialize> Versioned<T> {
+    pub fn to_json(&self) -> String {
+        // nothing but plain fields, which always serialize
+        serde_json::to_string(self).expect("serializing a json output")
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+use thinp::io_engine::*;
+
+//------------------------------------------
+
+const SEEKABLE_MAGIC: u32 = 0x8f92eab1;
+const SKIPPABLE_MAGIC: u32 = 0x184d2a5e;
+const FOOTER_SIZE: u64 = 9;
+const CHECKSUM_FLAG: u8 = 0x80;
+
+fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+struct Frame {
+    offset: u64, // in the compressed image
+    compressed_size: u32,
+    decompressed_begin: u64,
+    decompressed_size: u32,
+}
+
+// Reads the seek table stored as a skippable frame at the end of the image
+fn read_seek_table(file: &File, path: &Path) -> Result<Vec<Frame>> {
+    let not_seekable = || anyhow!("{} is not in the zstd seekable format", path.display());
+
+    let len = file.metadata()?.len();
+    if len < FOOTER_SIZE {
+        return Err(not_seekable());
+    }
+    let mut footer = [0u8; FOOTER_SIZE as usize];
+    file.read_exact_at(&mut footer, len - FOOTER_SIZE)?;
+    if get_u32(&footer, 5) != SEEKABLE_MAGIC {
+        return Err(not_seekable());
+    }
+
+    let nr_frames = get_u32(&footer, 0) as u64;
+    let entry_size = if footer[4] & CHECKSUM_FLAG != 0 {
+        12
+    } else {
+        8
+    };
+    let table_size = nr_frames * entry_size + FOOTER_SIZE;
+    let frame_size = table_size + 8; // with the skippable frame header
+    if len < frame_size {
+        return Err(not_seekable());
+    }
+
+    let mut table = vec![0u8; frame_size as usize];
+    file.read_exact_at(&mut table, len - frame_size)?;
+    if get_u32(&table, 0) != SKIPPABLE_MAGIC || get_u32(&table, 4) as u64 != table_size {
+        return Err(not_seekable());
+    }
+
+    let mut frames = Vec::with_capacity(nr_frames as usize);
+    let mut offset = 0;
+    let mut decompressed_begin = 0;
+    for i in 0..nr_frames as usize {
+        let entry = 8 + i * entry_size as usize;
+        let compressed_size = get_u32(&table, entry);
+        let decompressed_size = get_u32(&table, entry + 4);
+        frames.push(Frame {
+            offset,
+            compressed_size,
+            decompressed_begin,
+            decompressed_size,
+        });
+        offset += compressed_size as u64;
+        decompressed_begin += decompressed_size as u64;
+    }
+
+    if offset > len - frame_size {
+        return Err(anyhow!("the seek table of {} is corrupted", path.display()));
+    }
+
+    Ok(frames)
+}
+
+// A read-only engine decompressing blocks out of a zstd seekable metadata image on
+// demand. Only the frames holding the requested blocks are decompressed.
+pub struct SeekableIoEngine {
+    file: File,
+    frames: Vec<Frame>,
+    nr_blocks: u64,
+    cached: Mutex<Option<(usize, Vec<u8>)>>, // the last decompressed frame
+}
+
+impl SeekableIoEngine {
+    pub fn new(path: &Path) -> Result<SeekableIoEngine> {
+        let file = File::open(path)?;
+        let frames = read_seek_table(&file, path)?;
+        let size = frames
+            .last()
+            .map_or(0, |f| f.decompressed_begin + f.decompressed_size as u64);
+
+        Ok(SeekableIoEngine {
+            file,
+            frames,
+            nr_blocks: size / BLOCK_SIZE as u64,
+            cached: Mutex::new(None),
+        })
+    }
+
+    fn decompress_frame(&self, idx: usize) -> io::Result<Vec<u8>> {
+        let frame = &self.frames[idx];
+        let mut buf = vec![0u8; frame.compressed_size as usize];
+        self.file.read_exact_at(&mut buf, frame.offset)?;
+        let data = zstd::bulk::decompress(&buf, frame.decompressed_size as usize)?;
+        if data.len() != frame.decompressed_size as usize {
+            return Err(io::Error::other(format!("frame {} is truncated", idx)));
+        }
+        Ok(data)
+    }
+
+    // Copies the decompressed bytes from the given offset, which might span frames
+    fn read_at(&self, mut out: &mut [u8], mut offset: u64) -> io::Result<()> {
+        let mut cached = self.cached.lock().unwrap();
+        while !out.is_empty() {
+            // the first frame ending after the offset, skipping empty frames
+            let idx = self
+                .frames
+                .partition_point(|f| f.decompressed_begin + f.decompressed_size as u64 <= offset);
+            if idx == self.frames.len() {
+                return Err(io::Error::other(format!("offset {} out of range", offset)));
+            }
+
+            if cached.as_ref().map(|(i, _)| *i) != Some(idx) {
+                *cached = Some((idx, self.decompress_frame(idx)?));
+            }
+            let data = &cached.as_ref().unwrap().1;
+
+            let begin = (offset - self.frames[idx].decompressed_begin) as usize;
+            let len = std::cmp::min(out.len(), data.len() - begin);
+            out[..len].copy_from_slice(&data[begin..begin + len]);
+            out = &mut out[len..];
+            offset += len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl IoEngine for SeekableIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn get_batch_size(&self) -> usize {
+        1
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        1
+    }
+
+    fn read(&self, b: u64) -> io::Result<Block> {
+        if b >= self.nr_blocks {
+            return Err(io::Error::other(format!("block {} out of range", b)));
+        }
+        let blk = Block::new(b);
+        self.read_at(blk.get_data(), b * BLOCK_SIZE as u64)?;
+        Ok(blk)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+        Ok(blocks.iter().map(|&b| self.read(b)).collect())
+    }
+
+    fn write(&self, _block: &Block) -> io::Result<()> {
+        Err(io::Error::other("compressed metadata is read-only"))
+    }
+
+    fn write_many(&self, _blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+        Err(io::Error::other("compressed metadata is read-only"))
+    }
+}
+
+//------------------------------------------
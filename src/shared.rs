@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use thinp::io_engine::IoEngine;
+use thinp::pdata::btree::*;
+use thinp::thin::block_time::BlockTime;
+use thinp::thin::ir::{self, MetadataVisitor};
+
+use crate::budget::{Charges, MemoryBudget, LEAF_CACHES};
+use crate::mapping_iterator::MappingIterator;
+
+//------------------------------------------
+
+/// A reference of a device to a shared leaf, standing in for its mappings
+#[derive(Clone)]
+pub struct SharedRef {
+    pub name: String,
+    pub begin: u64, // the first key
+    pub end: u64,   // the key after the last one
+    pub mapped_blocks: u64,
+    pub nr_runs: u64,
+    pub max_time: u32,
+}
+
+/// The leaves shared by the mapping trees of the devices copied unchanged,
+/// so the output shares them as the input does rather than duplicating them.
+/// Only their locations are kept until they're defined, when each is read
+/// once and its mappings passed straight to the output.
+#[derive(Default)]
+pub struct SharedLeaves {
+    leaves: BTreeSet<u64>,            // by the location in the input
+    refs: HashMap<u32, Vec<u64>>,     // the shared leaves of each device, in key order
+    defined: HashMap<u64, SharedRef>, // the leaves written as definitions
+    _charges: Option<Charges>,        // for the locations and the references
+}
+
+// The bytes held for each shared leaf, by its location, the references to it
+// and the one standing for its definition
+const SHARED_LEAF_BYTES: u64 = 3 * 8 + std::mem::size_of::<SharedRef>() as u64;
+
+// Joins the entries of a leaf continuing one another into runs
+fn leaf_runs(keys: &[u64], values: &[BlockTime]) -> Vec<ir::Map> {
+    let mut runs: Vec<ir::Map> = Vec::new();
+    for (&key, bt) in keys.iter().zip(values) {
+        if let Some(last) = runs.last_mut() {
+            if last.thin_begin + last.len == key
+                && last.data_begin + last.len == bt.block
+                && last.time == bt.time
+            {
+                last.len += 1;
+                continue;
+            }
+        }
+        runs.push(ir::Map {
+            thin_begin: key,
+            data_begin: bt.block,
+            time: bt.time,
+            len: 1,
+        });
+    }
+    runs
+}
+
+impl SharedLeaves {
+    /// Finds the leaves appearing in the trees of more than one device, given
+    /// the leaves of each tree in key order. Nothing is read, and the memory
+    /// the locations take is charged to the given budget.
+    pub fn find(budget: &Arc<MemoryBudget>, trees: &[(u32, Vec<u64>)]) -> Result<Self> {
+        let mut owners: HashMap<u64, usize> = HashMap::new();
+        for (_, leaves) in trees {
+            let unique: HashSet<u64> = leaves.iter().copied().collect();
+            for loc in unique {
+                *owners.entry(loc).or_default() += 1;
+            }
+        }
+        owners.retain(|_, n| *n > 1);
+
+        let mut charges = Charges::new(budget);
+        charges.charge(LEAF_CACHES, owners.len() as u64 * SHARED_LEAF_BYTES)?;
+
+        let mut shared = SharedLeaves {
+            leaves: owners.into_keys().collect(),
+            _charges: Some(charges),
+            ..Default::default()
+        };
+        for (dev_id, leaves) in trees {
+            let refs: Vec<u64> = leaves
+                .iter()
+                .copied()
+                .filter(|loc| shared.leaves.contains(loc))
+                .collect();
+            if !refs.is_empty() {
+                shared.refs.insert(*dev_id, refs);
+            }
+        }
+        Ok(shared)
+    }
+
+    pub fn nr_leaves(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Whether the leaf is written as a definition rather than with the device
+    pub fn is_shared(&self, loc: u64) -> bool {
+        self.leaves.contains(&loc)
+    }
+
+    /// The shared leaves the device maps through
+    pub fn nr_refs(&self, dev_id: u32) -> u64 {
+        self.refs.get(&dev_id).map_or(0, |refs| refs.len() as u64)
+    }
+
+    /// Writes the definitions of the shared leaves, ahead of the devices,
+    /// reading them a batch at a time. The leaves are checked as the
+    /// iterators over the mappings do. Mapping times are clamped to the given
+    /// time if any.
+    pub fn define(
+        &mut self,
+        engine: &dyn IoEngine,
+        out: &mut dyn MetadataVisitor,
+        clamp_time: Option<u32>,
+        nr_data_blocks: Option<u64>,
+    ) -> Result<()> {
+        let locs: Vec<u64> = self.leaves.iter().copied().collect();
+        for chunk in locs.chunks(std::cmp::max(engine.get_batch_size(), 1)) {
+            for b in engine.read_many(chunk)? {
+                let b = b?;
+                let node = MappingIterator::unpack_leaf(&b, true)?;
+                MappingIterator::check_keys(&node, b.loc, None)?;
+                let Node::Leaf { keys, values, .. } = node else {
+                    unreachable!("checked by check_keys");
+                };
+                let (Some(&begin), Some(&last)) = (keys.first(), keys.last()) else {
+                    return Err(anyhow!(
+                        "the shared mapping leaf at block {} is empty",
+                        b.loc
+                    ));
+                };
+
+                let name = b.loc.to_string();
+                let runs = leaf_runs(&keys, &values);
+                out.def_shared_b(&name)?;
+                for run in &runs {
+                    if let Some(limit) = nr_data_blocks {
+                        if run.data_begin + run.len > limit {
+                            return Err(anyhow!(
+                                "the shared mapping leaf at block {} maps data block {}, beyond the {} data blocks of the output",
+                                b.loc,
+                                run.data_begin + run.len - 1,
+                                limit
+                            ));
+                        }
+                    }
+                    let time = clamp_time.map_or(run.time, |t| std::cmp::min(run.time, t));
+                    out.map(&ir::Map { time, ..*run })?;
+                }
+                out.def_shared_e()?;
+
+                self.defined.insert(
+                    b.loc,
+                    SharedRef {
+                        name,
+                        begin,
+                        end: last + 1,
+                        mapped_blocks: keys.len() as u64,
+                        nr_runs: runs.len() as u64,
+                        max_time: values.iter().map(|bt| bt.time).max().unwrap_or(0),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the references of the device to the shared leaves, in key order,
+    /// once they're defined
+    pub fn take_refs(&mut self, dev_id: u32) -> Vec<SharedRef> {
+        let Some(locs) = self.refs.remove(&dev_id) else {
+            return Vec::new();
+        };
+        locs.iter().map(|loc| self.defined[loc].clone()).collect()
+    }
+}
+
+//------------------------------------------
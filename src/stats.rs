@@ -0,0 +1,249 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use thinp::commands::engine::*;
+use thinp::io_engine::IoEngine;
+use thinp::pdata::btree::*;
+use thinp::report::Report;
+use thinp::thin::block_time::BlockTime;
+use thinp::thin::device_detail::DeviceDetail;
+use thinp::thin::superblock::*;
+
+use crate::mapping_iterator::MappingIterator;
+use crate::merge::{device_runs, get_device_root_and_details, read_patched_superblock_snap};
+use crate::stream::{walk_timed_pieces, RunSource};
+
+//------------------------------------------
+
+pub struct DeviceStats {
+    pub mapped_blocks: u64,
+    pub runs: u64,
+    pub depth: u32,
+    pub metadata_blocks: u64,
+}
+
+// Walks the mapping tree level by level, returning the depth, the number of
+// nodes, and the leaves in key order.
+fn walk_levels(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+) -> Result<(u32, u64, Vec<u64>)> {
+    let mut level = vec![root];
+    let mut depth = 0;
+    let mut nr_nodes = 0;
+    let mut leaves = Vec::new();
+
+    while !level.is_empty() {
+        depth += 1;
+        nr_nodes += level.len() as u64;
+
+        let mut children = Vec::new();
+        for chunk in level.chunks(engine.get_batch_size()) {
+            for (loc, b) in chunk.iter().zip(engine.read_many(chunk)?) {
+                // both the child pointers and the block_time values are 64-bit
+                match unpack_node::<u64>(&[], b?.get_data(), true, depth == 1)? {
+                    Node::Internal { values, .. } => children.extend(values),
+                    Node::Leaf { .. } => leaves.push(*loc),
+                }
+            }
+        }
+        level = children;
+    }
+
+    Ok((depth, nr_nodes, leaves))
+}
+
+fn device_stats(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+    details: &DeviceDetail,
+) -> Result<DeviceStats> {
+    let (depth, metadata_blocks, leaves) = walk_levels(engine, root)?;
+
+    let mut runs = 0;
+    let mut iter = MappingIterator::new(engine.clone(), leaves)?;
+    while iter.next_range()?.is_some() {
+        runs += 1;
+    }
+
+    Ok(DeviceStats {
+        mapped_blocks: details.mapped_blocks,
+        runs,
+        depth,
+        metadata_blocks,
+    })
+}
+
+// Gathers the stats of the labelled devices
+pub fn collect_stats(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    devices: &[(String, u64)],
+) -> Result<Vec<(String, DeviceStats)>> {
+    let mut rows = Vec::with_capacity(devices.len());
+    for (label, dev_id) in devices {
+        let (root, details) = get_device_root_and_details(engine.as_ref(), sb, *dev_id)?;
+        rows.push((label.clone(), device_stats(&engine, root, &details)?));
+    }
+    Ok(rows)
+}
+
+pub fn format_table(rows: &[(String, DeviceStats)]) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{:<16} {:>14} {:>12} {:>6} {:>16}",
+        "device", "mapped blocks", "runs", "depth", "metadata blocks"
+    )];
+    for (label, s) in rows {
+        lines.push(format!(
+            "{:<16} {:>14} {:>12} {:>6} {:>16}",
+            label, s.mapped_blocks, s.runs, s.depth, s.metadata_blocks
+        ));
+    }
+    lines
+}
+
+//------------------------------------------
+
+/// How the mappings of the origin and the snapshot overlap, and what their
+/// merge would map
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct OverlapStats {
+    pub origin_only: u64,   // blocks mapped by the origin alone
+    pub snapshot_only: u64, // blocks mapped by the snapshot alone
+    pub overlapping: u64,   // blocks mapped by both
+    pub merged_blocks: u64,
+    pub origin_runs: u64,
+    pub snapshot_runs: u64,
+    pub merged_runs: u64,
+}
+
+// Counts the runs taken from the source
+fn counting(mut source: RunSource, nr_runs: Arc<AtomicU64>) -> RunSource {
+    Box::new(move || {
+        let run = source()?;
+        if run.is_some() {
+            nr_runs.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(run)
+    })
+}
+
+// Walks the runs of the origin and the snapshot once, side by side, counting
+// the blocks and the runs of each, the blocks mapped by both, and the blocks
+// and the runs of their merge, where the snapshot overrides the origin
+fn count_overlap(origin: RunSource, snapshot: RunSource) -> Result<OverlapStats> {
+    let origin_runs = Arc::new(AtomicU64::new(0));
+    let snapshot_runs = Arc::new(AtomicU64::new(0));
+    let mut stats = OverlapStats::default();
+
+    // where the last merged run ends, and the mapping continuing it would take
+    let mut next: Option<(u64, BlockTime)> = None;
+    walk_timed_pieces(
+        counting(origin, origin_runs.clone()),
+        counting(snapshot, snapshot_runs.clone()),
+        |begin, len, o, s| {
+            match (o, s) {
+                (Some(_), Some(_)) => stats.overlapping += len,
+                (Some(_), None) => stats.origin_only += len,
+                (None, Some(_)) => stats.snapshot_only += len,
+                (None, None) => {}
+            }
+
+            let Some(bt) = s.or(o) else {
+                return Ok(());
+            };
+            stats.merged_blocks += len;
+            let continued = matches!(next, Some((key, n))
+                if key == begin && n.block == bt.block && n.time == bt.time);
+            if !continued {
+                stats.merged_runs += 1;
+            }
+            next = Some((
+                begin.saturating_add(len),
+                BlockTime {
+                    block: bt.block.saturating_add(len),
+                    time: bt.time,
+                },
+            ));
+            Ok(())
+        },
+    )?;
+
+    stats.origin_runs = origin_runs.load(Ordering::Relaxed);
+    stats.snapshot_runs = snapshot_runs.load(Ordering::Relaxed);
+    Ok(stats)
+}
+
+pub struct ThinOverlapOptions<'a> {
+    pub input: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+    pub origin: u64,
+    pub snapshot: u64,
+    pub sort_leaves: bool,
+}
+
+// Reports how the origin and the snapshot overlap, and what their merge would
+// map, without writing anything
+pub fn overlap_stats(opts: ThinOverlapOptions) -> Result<OverlapStats> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .exclusive(!opts.engine_opts.use_metadata_snap)
+        .build()?;
+    let sb = if opts.engine_opts.use_metadata_snap {
+        read_patched_superblock_snap(engine.as_ref())?
+    } else {
+        read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?
+    };
+
+    let runs = |dev_id| {
+        device_runs(
+            engine.clone(),
+            opts.report.clone(),
+            opts.sort_leaves,
+            &sb,
+            dev_id,
+            None,
+        )
+        .map(|(source, _)| source)
+    };
+
+    let stats = count_overlap(runs(opts.origin)?, runs(opts.snapshot)?)?;
+
+    let mean = |blocks: u64, runs: u64| blocks as f64 / std::cmp::max(runs, 1) as f64;
+    let origin_blocks = stats.origin_only + stats.overlapping;
+    let snap_blocks = stats.snapshot_only + stats.overlapping;
+    for line in [
+        format!(
+            "blocks only in origin {}: {}",
+            opts.origin, stats.origin_only
+        ),
+        format!(
+            "blocks only in snapshot {}: {}",
+            opts.snapshot, stats.snapshot_only
+        ),
+        format!("overlapping blocks: {}", stats.overlapping),
+        format!("merged mapped blocks: {}", stats.merged_blocks),
+        format!(
+            "origin runs: {}, {:.1} blocks each",
+            stats.origin_runs,
+            mean(origin_blocks, stats.origin_runs)
+        ),
+        format!(
+            "snapshot runs: {}, {:.1} blocks each",
+            stats.snapshot_runs,
+            mean(snap_blocks, stats.snapshot_runs)
+        ),
+        format!(
+            "merged runs: {}, {:.1} blocks each",
+            stats.merged_runs,
+            mean(stats.merged_blocks, stats.merged_runs)
+        ),
+    ] {
+        opts.report.info(&line);
+    }
+
+    Ok(stats)
+}
+
+//------------------------------------------
@@ -1,5 +1,7 @@
 use anyhow::{anyhow, Result};
 use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::sync::atomic::{self, AtomicU64};
 use std::sync::Arc;
 use thinp::io_engine::IoEngine;
 use thinp::thin::block_time::*;
@@ -8,40 +10,258 @@ use crate::mapping_iterator::MappingIterator;
 
 //------------------------------------------
 
+// Checked arithmetic over the (key, data block, length) runs, since crafted
+// metadata could place mappings at the end of the address spaces.
+pub trait VirtualRange {
+    // The key after the run
+    fn end(&self) -> Result<u64>;
+
+    // The data block after the run
+    fn data_end(&self) -> Result<u64>;
+
+    // Drops the first delta blocks from the run
+    fn advance(&mut self, delta: u64) -> Result<()>;
+}
+
+impl VirtualRange for (u64, BlockTime, u64) {
+    fn end(&self) -> Result<u64> {
+        self.0
+            .checked_add(self.2)
+            .ok_or_else(|| anyhow!("mapping key {} with length {} overflows", self.0, self.2))
+    }
+
+    fn data_end(&self) -> Result<u64> {
+        self.1.block.checked_add(self.2).ok_or_else(|| {
+            anyhow!(
+                "data block {} with length {} overflows",
+                self.1.block,
+                self.2
+            )
+        })
+    }
+
+    fn advance(&mut self, delta: u64) -> Result<()> {
+        let len = self
+            .2
+            .checked_sub(delta)
+            .ok_or_else(|| anyhow!("delta too long"))?;
+        self.0 = self
+            .0
+            .checked_add(delta)
+            .ok_or_else(|| anyhow!("key overflows"))?;
+        self.1.block = self
+            .1
+            .block
+            .checked_add(delta)
+            .ok_or_else(|| anyhow!("data block overflows"))?;
+        self.2 = len;
+        Ok(())
+    }
+}
+
+// Rejects runs reaching beyond the address spaces
+fn validate(run: Option<(u64, BlockTime, u64)>) -> Result<Option<(u64, BlockTime, u64)>> {
+    if let Some(r) = &run {
+        r.end()?;
+        r.data_end()?;
+    }
+    Ok(run)
+}
+
+//------------------------------------------
+
+/// A producer of mapping runs, moved to the worker thread feeding the restorer
+pub type RunSource = Box<dyn FnMut() -> Result<Option<(u64, BlockTime, u64)>> + Send>;
+
+/// Keeps only the runs mapped at the given time or newer, e.g., to extract the
+/// changes of a device since a snapshot was taken at that time
+pub fn since_time(mut source: RunSource, time: u32) -> RunSource {
+    Box::new(move || {
+        while let Some(run) = source()? {
+            if run.1.time >= time {
+                return Ok(Some(run));
+            }
+        }
+        Ok(None)
+    })
+}
+
+/// Counts of the degenerate runs dropped or joined by sanitize
+#[derive(Default)]
+pub struct SanitizeStats {
+    pub zero_length: AtomicU64,
+    pub joined: AtomicU64,
+}
+
+// Drops zero-length runs, and joins runs continuing the one before, i.e.,
+// contiguous in both the keys and the data blocks, at the same time. The
+// consume/skip logic of the overlay assumes neither exists.
+struct Sanitizer {
+    source: RunSource,
+    pending: Option<(u64, BlockTime, u64)>,
+    done: bool,
+    stats: Arc<SanitizeStats>,
+}
+
+impl Sanitizer {
+    fn next_nonempty(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
+        while !self.done {
+            match (self.source)()? {
+                Some(run) if run.2 == 0 => {
+                    self.stats
+                        .zero_length
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                }
+                Some(run) => return Ok(Some(run)),
+                None => self.done = true,
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_run(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
+        let mut run = match self.pending.take() {
+            Some(run) => run,
+            None => match self.next_nonempty()? {
+                Some(run) => run,
+                None => return Ok(None),
+            },
+        };
+
+        while let Some(next) = self.next_nonempty()? {
+            // the overlay assumes the runs of a tree never overlap
+            if next.0 < run.end()? {
+                return Err(anyhow!(
+                    "mapping run at key {} overlaps the run {}..{} before it",
+                    next.0,
+                    run.0,
+                    run.end()?
+                ));
+            }
+            if next.0 == run.end()? && next.1.block == run.data_end()? && next.1.time == run.1.time
+            {
+                run.2 = run
+                    .2
+                    .checked_add(next.2)
+                    .ok_or_else(|| anyhow!("mapping length overflows"))?;
+                self.stats.joined.fetch_add(1, atomic::Ordering::Relaxed);
+            } else {
+                self.pending = Some(next);
+                break;
+            }
+        }
+
+        validate(Some(run))
+    }
+}
+
+/// Sanitizes the runs of the producer ahead of an overlay, dropping the
+/// zero-length ones, and joining the fragments of a run, e.g., the one-block
+/// runs left by some repair tools, or the pieces of the lower levels of a
+/// chain. Runs overlapping the one before fail the producer.
+pub fn sanitize(source: RunSource, stats: Arc<SanitizeStats>) -> RunSource {
+    let mut s = Sanitizer {
+        source,
+        pending: None,
+        done: false,
+        stats,
+    };
+    Box::new(move || s.next_run())
+}
+
+/// A cursor over mapping runs that could be consumed partially, as needed to
+/// overlay runs of different lengths. Runs read from leaves are validated.
 pub struct MappingStream {
-    iter: MappingIterator,
+    source: RunSource,
     current: Option<(u64, BlockTime, u64)>,
+    ahead: VecDeque<(u64, BlockTime, u64)>, // taken from the source by peek_n
 }
 
 impl MappingStream {
+    /// Streams the runs held by the leaves of a mapping tree.
     pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, leaves: Vec<u64>) -> Result<Self> {
-        let mut iter = MappingIterator::new(engine, leaves)?;
-        let current = iter.next_range()?;
-        Ok(Self { iter, current })
+        let batch_size = engine.get_batch_size();
+        Self::with_batch_size(engine, leaves, batch_size)
     }
 
+    /// Like new, but reads the given number of leaves at a time, e.g., fewer
+    /// for a tree much smaller than the other one it's merged with.
+    pub fn with_batch_size(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        leaves: Vec<u64>,
+        batch_size: usize,
+    ) -> Result<Self> {
+        let iter = MappingIterator::with_batch_size(engine, leaves, batch_size)?;
+        Self::from_iterator(iter)
+    }
+
+    /// Streams the runs of an iterator set up by the caller, e.g., restricted
+    /// to a range of keys, validating them as new does. The iterator yields
+    /// the runs whole and in key order already.
+    pub fn from_iterator(mut iter: MappingIterator) -> Result<Self> {
+        Self::from_source(Box::new(move || validate(iter.next_range()?)))
+    }
+
+    /// Streams the runs of another producer, e.g., the merged runs of the lower
+    /// levels of a snapshot chain, taken as they come. See sanitize.
+    pub fn from_source(mut source: RunSource) -> Result<Self> {
+        let current = source()?;
+        Ok(Self {
+            source,
+            current,
+            ahead: VecDeque::new(),
+        })
+    }
+
+    // Takes the run after the current one
+    fn next_run(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
+        match self.ahead.pop_front() {
+            Some(run) => Ok(Some(run)),
+            None => (self.source)(),
+        }
+    }
+
+    /// Whether any runs remain.
     pub fn more_mappings(&self) -> bool {
         self.current.is_some()
     }
 
+    /// Returns the remainder of the current run without consuming it.
     pub fn get_mapping(&self) -> Option<&(u64, BlockTime, u64)> {
         self.current.as_ref()
     }
 
+    /// Returns up to n runs without consuming them, starting with the remainder
+    /// of the current run. Fewer are returned once the runs are exhausted.
+    pub fn peek_n(&mut self, n: usize) -> Result<Vec<(u64, BlockTime, u64)>> {
+        let Some(current) = self.current else {
+            return Ok(Vec::new());
+        };
+        while self.ahead.len() + 1 < n {
+            match (self.source)()? {
+                Some(run) => self.ahead.push_back(run),
+                None => break,
+            }
+        }
+        Ok(std::iter::once(current)
+            .chain(self.ahead.iter().copied())
+            .take(n)
+            .collect())
+    }
+
+    /// Returns the first delta blocks of the current run, and moves past them.
     pub fn consume(&mut self, delta: u64) -> Result<Option<(u64, BlockTime, u64)>> {
         match &mut self.current {
-            Some((key, bt, len)) => match delta.cmp(len) {
-                Ordering::Greater => Err(anyhow!("delta too lone")),
+            Some(run) => match delta.cmp(&run.2) {
+                Ordering::Greater => Err(anyhow!("delta too long")),
                 Ordering::Equal => {
                     let ret = self.current;
-                    self.current = self.iter.next_range()?;
+                    self.current = self.next_run()?;
                     Ok(ret)
                 }
                 Ordering::Less => {
-                    let ret = Some((*key, *bt, delta));
-                    *key += delta;
-                    bt.block += delta;
-                    *len -= delta;
+                    let ret = Some((run.0, run.1, delta));
+                    run.advance(delta)?;
                     Ok(ret)
                 }
             },
@@ -49,39 +269,36 @@ impl MappingStream {
         }
     }
 
-    // consume without returning
+    /// Consumes without returning.
     pub fn skip(&mut self, delta: u64) -> Result<()> {
-        if let Some((key, bt, len)) = &mut self.current {
-            match delta.cmp(len) {
-                Ordering::Greater => return Err(anyhow!("delta too lone")),
+        if let Some(run) = &mut self.current {
+            match delta.cmp(&run.2) {
+                Ordering::Greater => return Err(anyhow!("delta too long")),
                 Ordering::Equal => {
-                    self.current = self.iter.next_range()?;
-                }
-                Ordering::Less => {
-                    *key += delta;
-                    bt.block += delta;
-                    *len -= delta;
+                    self.current = self.next_run()?;
                 }
+                Ordering::Less => run.advance(delta)?,
             }
         }
 
         Ok(())
     }
 
+    /// Returns the remainder of the current run, and moves to the next run.
     pub fn consume_all(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
         if self.current.is_some() {
             let ret = self.current;
-            self.current = self.iter.next_range()?;
+            self.current = self.next_run()?;
             Ok(ret)
         } else {
             Ok(None)
         }
     }
 
-    // consume_all without returning
+    /// Consumes the remainder of the current run without returning.
     pub fn skip_all(&mut self) -> Result<()> {
         if self.current.is_some() {
-            self.current = self.iter.next_range()?;
+            self.current = self.next_run()?;
         }
 
         Ok(())
@@ -89,3 +306,241 @@ impl MappingStream {
 }
 
 //------------------------------------------
+
+// Walks the two producers side by side, in pieces where neither changes,
+// passing the key range of each piece, and the data block each of them maps
+// its beginning to, if any
+fn walk_pieces(
+    lhs: RunSource,
+    rhs: RunSource,
+    mut f: impl FnMut(u64, u64, Option<u64>, Option<u64>) -> Result<()>,
+) -> Result<()> {
+    walk_timed_pieces(lhs, rhs, |begin, len, l, r| {
+        f(begin, len, l.map(|bt| bt.block), r.map(|bt| bt.block))
+    })
+}
+
+/// Like walk_pieces, but passes the times of the mappings along with their
+/// data blocks
+pub(crate) fn walk_timed_pieces(
+    lhs: RunSource,
+    rhs: RunSource,
+    mut f: impl FnMut(u64, u64, Option<BlockTime>, Option<BlockTime>) -> Result<()>,
+) -> Result<()> {
+    let mut lhs = MappingStream::from_source(lhs)?;
+    let mut rhs = MappingStream::from_source(rhs)?;
+
+    loop {
+        let l = lhs.get_mapping().copied();
+        let r = rhs.get_mapping().copied();
+
+        // the leading piece of the runs, and the streams it comes from
+        let (begin, len, from_l, from_r) = match (l, r) {
+            (None, None) => break,
+            (Some(l), None) => (l.0, l.2, true, false),
+            (None, Some(r)) => (r.0, r.2, false, true),
+            (Some(l), Some(r)) if l.0 < r.0 => (l.0, l.2.min(r.0 - l.0), true, false),
+            (Some(l), Some(r)) if r.0 < l.0 => (r.0, r.2.min(l.0 - r.0), false, true),
+            (Some(l), Some(r)) => (l.0, l.2.min(r.2), true, true),
+        };
+        let l_bt = l.filter(|_| from_l).map(|l| l.1);
+        let r_bt = r.filter(|_| from_r).map(|r| r.1);
+
+        if from_l {
+            lhs.skip(len)?;
+        }
+        if from_r {
+            rhs.skip(len)?;
+        }
+
+        f(begin, len, l_bt, r_bt)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the key ranges, as half-open intervals, where the two producers map
+/// to different data blocks or only one of them maps. Times are ignored, so
+/// mappings sharing the data are taken as the same.
+pub fn diff_ranges(lhs: RunSource, rhs: RunSource) -> Result<Vec<(u64, u64)>> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    walk_pieces(lhs, rhs, |begin, len, l, r| {
+        if l.is_none() || l != r {
+            let end = begin
+                .checked_add(len)
+                .ok_or_else(|| anyhow!("mapping key {} with length {} overflows", begin, len))?;
+            match ranges.last_mut() {
+                Some(last) if last.1 == begin => last.1 = end,
+                _ => ranges.push((begin, end)),
+            }
+        }
+        Ok(())
+    })?;
+    Ok(ranges)
+}
+
+/// Counts the blocks both producers map to the same data blocks.
+pub fn count_shared(lhs: RunSource, rhs: RunSource) -> Result<u64> {
+    let mut shared = 0;
+    walk_pieces(lhs, rhs, |_, len, l, r| {
+        if l.is_some() && l == r {
+            shared += len;
+        }
+        Ok(())
+    })?;
+    Ok(shared)
+}
+
+/// Counts the blocks each of the others maps to the same data blocks as the
+/// base, walking the base once for all of them.
+pub fn count_shared_with(base: RunSource, others: Vec<RunSource>) -> Result<Vec<u64>> {
+    let mut base = MappingStream::from_source(base)?;
+    let mut others = others
+        .into_iter()
+        .map(MappingStream::from_source)
+        .collect::<Result<Vec<_>>>()?;
+    let mut shared = vec![0; others.len()];
+
+    while let Some(&(begin, bt, len)) = base.get_mapping() {
+        let end = begin.saturating_add(len);
+        for (other, shared) in others.iter_mut().zip(shared.iter_mut()) {
+            // the runs of the other one overlapping the base run
+            while let Some(&(b, obt, olen)) = other.get_mapping() {
+                if b >= end {
+                    break;
+                }
+                let oend = b.saturating_add(olen);
+                let lo = std::cmp::max(b, begin);
+                let hi = std::cmp::min(oend, end);
+                if lo < hi && obt.block.checked_add(lo - b) == bt.block.checked_add(lo - begin) {
+                    *shared += hi - lo;
+                }
+                if oend > end {
+                    other.skip(end - b)?;
+                    break;
+                }
+                other.skip(olen)?;
+            }
+        }
+        base.skip(len)?;
+    }
+
+    Ok(shared)
+}
+
+/// How a range of keys compares between two producers, along with the data
+/// blocks the range begins at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delta {
+    Same(u64),
+    Different(u64, u64),
+    LeftOnly(u64),
+    RightOnly(u64),
+}
+
+impl Delta {
+    fn new(l: Option<u64>, r: Option<u64>) -> Option<Self> {
+        match (l, r) {
+            (Some(l), Some(r)) if l == r => Some(Delta::Same(l)),
+            (Some(l), Some(r)) => Some(Delta::Different(l, r)),
+            (Some(l), None) => Some(Delta::LeftOnly(l)),
+            (None, Some(r)) => Some(Delta::RightOnly(r)),
+            (None, None) => None,
+        }
+    }
+
+    // Whether the other one picks up where this one leaves after len blocks
+    fn continued_by(&self, other: &Delta, len: u64) -> bool {
+        match (self, other) {
+            (Delta::Same(a), Delta::Same(b))
+            | (Delta::LeftOnly(a), Delta::LeftOnly(b))
+            | (Delta::RightOnly(a), Delta::RightOnly(b)) => a.checked_add(len) == Some(*b),
+            (Delta::Different(a, c), Delta::Different(b, d)) => {
+                a.checked_add(len) == Some(*b) && c.checked_add(len) == Some(*d)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Walks the two producers in key order, passing the begin, the length and the
+/// delta of the longest ranges comparing alike, as thin_delta reports them.
+/// Times are ignored.
+pub fn walk_deltas(
+    lhs: RunSource,
+    rhs: RunSource,
+    mut f: impl FnMut(u64, u64, Delta) -> Result<()>,
+) -> Result<()> {
+    let mut pending: Option<(u64, u64, Delta)> = None;
+    walk_pieces(lhs, rhs, |begin, len, l, r| {
+        let Some(delta) = Delta::new(l, r) else {
+            return Ok(());
+        };
+        if let Some(p) = &mut pending {
+            if p.0.checked_add(p.1) == Some(begin) && p.2.continued_by(&delta, p.1) {
+                p.1 += len;
+                return Ok(());
+            }
+        }
+        match pending.replace((begin, len, delta)) {
+            Some((b, l, d)) => f(b, l, d),
+            None => Ok(()),
+        }
+    })?;
+    match pending {
+        Some((b, l, d)) => f(b, l, d),
+        None => Ok(()),
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(key: u64, block: u64, len: u64, time: u32) -> (u64, BlockTime, u64) {
+        (key, BlockTime { block, time }, len)
+    }
+
+    fn sanitized(
+        runs: Vec<(u64, BlockTime, u64)>,
+    ) -> (Result<Vec<(u64, u64, u64, u32)>>, Arc<SanitizeStats>) {
+        let stats = Arc::new(SanitizeStats::default());
+        let mut runs = runs.into_iter();
+        let mut source = sanitize(Box::new(move || Ok(runs.next())), stats.clone());
+        let mut out = Vec::new();
+        let r = loop {
+            match source() {
+                Ok(Some((key, bt, len))) => out.push((key, bt.block, len, bt.time)),
+                Ok(None) => break Ok(out),
+                Err(e) => break Err(e),
+            }
+        };
+        (r, stats)
+    }
+
+    #[test]
+    fn fragments_are_joined() {
+        let (runs, stats) = sanitized(vec![
+            run(0, 100, 1, 1),
+            run(1, 101, 1, 1),
+            run(2, 102, 0, 1),
+            run(2, 102, 1, 1),
+            run(3, 103, 1, 2),
+            run(5, 105, 1, 2),
+        ]);
+        assert_eq!(
+            runs.unwrap(),
+            vec![(0, 100, 3, 1), (3, 103, 1, 2), (5, 105, 1, 2)]
+        );
+        assert_eq!(stats.zero_length.load(atomic::Ordering::Relaxed), 1);
+        assert_eq!(stats.joined.load(atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn overlapping_runs_fail() {
+        let (runs, _) = sanitized(vec![run(0, 100, 4, 1), run(2, 200, 1, 1)]);
+        assert!(runs.unwrap_err().to_string().contains("overlaps"));
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::schema::Versioned;
+
+//------------------------------------------
+
+// The wall time in seconds, to the millisecond
+mod secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(d.as_millis() as f64 / 1000.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(d)?;
+        Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The totals of a merge, written once it completes for scripts to consume
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub mapped_blocks: u64,   // over all the devices written
+    pub nr_runs: u64,         // the runs handed to the restorer
+    pub metadata_blocks: u64, // allocated in the output
+    #[serde(rename = "wall_time_secs", with = "secs")]
+    pub wall_time: Duration,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl RunSummary {
+    pub fn to_json(&self) -> String {
+        Versioned::new(self).to_json()
+    }
+
+    /// Writes the summary to the given file, or to stdout if the path is "-"
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = self.to_json();
+        if path == Path::new("-") {
+            let mut out = std::io::stdout().lock();
+            writeln!(out, "{}", json)?;
+            return Ok(());
+        }
+        std::fs::write(path, json + "\n")
+            .map_err(|e| anyhow!("unable to write the summary {}: {}", path.display(), e))
+    }
+}
+
+//------------------------------------------
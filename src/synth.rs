@@ -1,11 +1,18 @@
 use anyhow::{anyhow, Result};
 use rand::prelude::*;
+use rand::rngs::StdRng;
 use std::collections::{BTreeMap, VecDeque};
 use std::fs::OpenOptions;
 use std::ops::Range;
 use std::path::Path;
+use std::sync::Arc;
+use thinp::io_engine::{IoEngine, SyncIoEngine};
+use thinp::pdata::space_map::metadata::core_metadata_sm;
+use thinp::report::mk_quiet_report;
 use thinp::thin::ir::{self, MetadataVisitor};
+use thinp::thin::restore::Restorer;
 use thinp::thin::xml;
+use thinp::write_batcher::WriteBatcher;
 
 //------------------------------------------
 
@@ -25,6 +32,26 @@ pub fn write_xml(path: &Path, g: &mut dyn XmlGen) -> Result<()> {
     g.generate_xml(&mut w)
 }
 
+// Generates binary metadata straight into a preallocated file or device
+pub fn write_metadata(path: &Path, g: &mut dyn XmlGen) -> Result<()> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(path, true)?);
+    let sm = core_metadata_sm(engine.get_nr_blocks(), u32::MAX);
+    let batch_size = engine.get_batch_size();
+    let mut w = WriteBatcher::new(engine, sm, batch_size);
+    let mut restorer = Restorer::new(&mut w, Arc::new(mk_quiet_report()));
+
+    g.generate_xml(&mut restorer)?;
+    restorer.eof()?;
+    Ok(())
+}
+
+fn mk_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
 fn common_sb(nr_blocks: u64, time: u32) -> ir::Superblock {
     ir::Superblock {
         uuid: "".to_string(),
@@ -99,16 +126,56 @@ pub struct FragmentedS {
     pub thin_size: u64,
     pub old_nr_data_blocks: u64,
     pub new_nr_data_blocks: u64,
+    pub seed: Option<u64>,
 }
 
 impl FragmentedS {
     pub fn new(nr_thins: u32, thin_size: u64) -> Self {
-        let old_size = (nr_thins as u64) * thin_size;
+        FragmentedS::builder()
+            .nr_thins(nr_thins)
+            .thin_size(thin_size)
+            .build()
+    }
+
+    pub fn builder() -> FragmentedSBuilder {
+        FragmentedSBuilder {
+            nr_thins: 1,
+            thin_size: 1024,
+            seed: None,
+        }
+    }
+}
+
+pub struct FragmentedSBuilder {
+    nr_thins: u32,
+    thin_size: u64,
+    seed: Option<u64>,
+}
+
+impl FragmentedSBuilder {
+    pub fn nr_thins(mut self, nr_thins: u32) -> Self {
+        self.nr_thins = nr_thins;
+        self
+    }
+
+    pub fn thin_size(mut self, thin_size: u64) -> Self {
+        self.thin_size = thin_size;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> FragmentedS {
+        let old_size = (self.nr_thins as u64) * self.thin_size;
         FragmentedS {
-            nr_thins,
-            thin_size,
-            old_nr_data_blocks: (nr_thins as u64) * thin_size,
+            nr_thins: self.nr_thins,
+            thin_size: self.thin_size,
+            old_nr_data_blocks: old_size,
             new_nr_data_blocks: old_size * 3 / 4,
+            seed: self.seed,
         }
     }
 }
@@ -128,14 +195,16 @@ struct MappedRun {
     len: u64,
 }
 
-fn mk_runs(thin_id: u32, total_len: u64, run_len: std::ops::Range<u64>) -> Vec<ThinRun> {
+fn mk_runs(
+    rng: &mut StdRng,
+    thin_id: u32,
+    total_len: u64,
+    run_len: std::ops::Range<u64>,
+) -> Vec<ThinRun> {
     let mut runs = Vec::new();
     let mut b = 0u64;
     while b < total_len {
-        let len = u64::min(
-            total_len - b,
-            thread_rng().gen_range(run_len.start..run_len.end),
-        );
+        let len = u64::min(total_len - b, rng.gen_range(run_len.start..run_len.end));
         runs.push(ThinRun {
             thin_id,
             thin_begin: b,
@@ -177,14 +246,16 @@ fn count_mapped_blocks(runs: &[MappedRun]) -> Result<BTreeMap<u32, (u64, Range<u
 
 impl XmlGen for FragmentedS {
     fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        let mut rng = mk_rng(self.seed);
+
         // Allocate each thin fully, in runs between 1 and 16.
         let mut runs = Vec::new();
         for thin in 0..self.nr_thins {
-            runs.append(&mut mk_runs(thin, self.thin_size, 1..17));
+            runs.append(&mut mk_runs(&mut rng, thin, self.thin_size, 1..17));
         }
 
         // Shuffle
-        runs.shuffle(&mut rand::thread_rng());
+        runs.shuffle(&mut rng);
 
         // map across the data
         let mut maps = Vec::new();
@@ -255,20 +326,17 @@ struct Allocator {
 }
 
 impl Allocator {
-    fn new_shuffled(total_len: u64, run_len: Range<u64>) -> Allocator {
+    fn new_shuffled(rng: &mut StdRng, total_len: u64, run_len: Range<u64>) -> Allocator {
         let mut runs = Vec::new();
 
         let mut b = 0u64;
         while b < total_len {
-            let len = u64::min(
-                total_len - b,
-                thread_rng().gen_range(run_len.start..run_len.end),
-            );
+            let len = u64::min(total_len - b, rng.gen_range(run_len.start..run_len.end));
             runs.push(b..(b + len));
             b += len;
         }
 
-        runs.shuffle(&mut thread_rng());
+        runs.shuffle(rng);
         let runs: VecDeque<Range<u64>> = runs.iter().cloned().collect();
         Allocator { runs }
     }
@@ -429,6 +497,7 @@ enum SnapRunType {
 struct SnapRun(SnapRunType, u64);
 
 fn mk_origin(
+    rng: &mut StdRng,
     thin_id: u32,
     total_len: u64,
     percent_mapped: usize,
@@ -441,9 +510,9 @@ fn mk_origin(
     let mut b = 0;
 
     while b < total_len {
-        let len = u64::min(thread_rng().gen_range(16..64), total_len - b);
+        let len = u64::min(rng.gen_range(16..64), total_len - b);
 
-        let n = thread_rng().gen_range(0..100);
+        let n = rng.gen_range(0..100);
 
         if n < percent_mapped {
             for data in allocator.alloc(len)? {
@@ -473,6 +542,7 @@ fn mk_origin(
 }
 
 fn mk_snap_mapping(
+    rng: &mut StdRng,
     total_len: u64,
     run_len: Range<u64>,
     same_percent: usize,
@@ -482,12 +552,9 @@ fn mk_snap_mapping(
 
     let mut b = 0u64;
     while b < total_len {
-        let len = u64::min(
-            total_len - b,
-            thread_rng().gen_range(run_len.start..run_len.end),
-        );
+        let len = u64::min(total_len - b, rng.gen_range(run_len.start..run_len.end));
 
-        let n = thread_rng().gen_range(0..100);
+        let n = rng.gen_range(0..100);
 
         if n < same_percent {
             runs.push(SnapRun(SnapRunType::Same, len));
@@ -504,6 +571,7 @@ fn mk_snap_mapping(
 }
 
 fn mk_snapshot(
+    rng: &mut StdRng,
     thin_id: u32,
     origin: &ThinDev,
     percent_change: usize,
@@ -515,7 +583,7 @@ fn mk_snapshot(
     let same_percent = 100 - percent_change;
     let diff_percent = same_percent + percent_change / 2;
 
-    let snap_runs = mk_snap_mapping(origin.dev_size, 16..64, same_percent, diff_percent);
+    let snap_runs = mk_snap_mapping(rng, origin.dev_size, 16..64, same_percent, diff_percent);
     let (runs, total_mapped) = apply_snap_runs(&origin.runs, &snap_runs, allocator, creation_time)?;
 
     Ok(ThinDev {
@@ -601,30 +669,87 @@ pub struct SnapS {
     pub percent_change: usize,
     pub old_nr_data_blocks: u64,
     pub new_nr_data_blocks: u64,
+    pub seed: Option<u64>,
 }
 
 impl SnapS {
     pub fn new(len: u64, nr_snaps: u32, percent_change: usize) -> Self {
-        let delta = len * (nr_snaps as u64) * (percent_change as u64) / 100;
-        let old_nr_data_blocks = len + 3 * delta;
-        let new_nr_data_blocks = len + 2 * delta;
+        SnapS::builder()
+            .len(len)
+            .nr_snaps(nr_snaps)
+            .percent_change(percent_change)
+            .build()
+    }
+
+    pub fn builder() -> SnapSBuilder {
+        SnapSBuilder {
+            len: 1024,
+            nr_snaps: 2,
+            percent_change: 20,
+            seed: None,
+        }
+    }
+}
+
+pub struct SnapSBuilder {
+    len: u64,
+    nr_snaps: u32,
+    percent_change: usize,
+    seed: Option<u64>,
+}
+
+impl SnapSBuilder {
+    pub fn len(mut self, len: u64) -> Self {
+        self.len = len;
+        self
+    }
+
+    pub fn nr_snaps(mut self, nr_snaps: u32) -> Self {
+        self.nr_snaps = nr_snaps;
+        self
+    }
+
+    pub fn percent_change(mut self, percent_change: usize) -> Self {
+        self.percent_change = percent_change;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> SnapS {
+        let delta = self.len * (self.nr_snaps as u64) * (self.percent_change as u64) / 100;
+        let old_nr_data_blocks = self.len + 3 * delta;
+        let new_nr_data_blocks = self.len + 2 * delta;
 
         SnapS {
-            len,
-            nr_snaps,
-            percent_change,
+            len: self.len,
+            nr_snaps: self.nr_snaps,
+            percent_change: self.percent_change,
             old_nr_data_blocks,
             new_nr_data_blocks,
+            seed: self.seed,
         }
     }
 }
 
 impl XmlGen for SnapS {
     fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
-        let mut allocator = Allocator::new_shuffled(self.old_nr_data_blocks, 64..512);
+        let mut rng = mk_rng(self.seed);
+        let mut allocator = Allocator::new_shuffled(&mut rng, self.old_nr_data_blocks, 64..512);
         let mut creation_time = 0;
         let mut snap_time = if self.nr_snaps > 1 { 1 } else { 0 };
-        let mut origin = mk_origin(0, self.len, 50, &mut allocator, creation_time, snap_time)?;
+        let mut origin = mk_origin(
+            &mut rng,
+            0,
+            self.len,
+            50,
+            &mut allocator,
+            creation_time,
+            snap_time,
+        )?;
 
         let time = self.nr_snaps - 1; // timestamp increases by 1 as a snapshot is created
         v.superblock_b(&common_sb(self.old_nr_data_blocks, time))?;
@@ -638,6 +763,7 @@ impl XmlGen for SnapS {
                 snap_time += 1
             }
             let snap = mk_snapshot(
+                &mut rng,
                 thin,
                 &origin,
                 self.percent_change,
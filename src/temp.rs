@@ -0,0 +1,289 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, Once};
+use std::thread::{self, JoinHandle};
+
+//------------------------------------------
+
+// Every temporary file alive in this process, so they could be removed on any
+// exit path, including panics and termination signals. Each is tagged with the
+// scope of the merge registering it.
+static LIVE: Mutex<Vec<(usize, PathBuf)>> = Mutex::new(Vec::new());
+
+// Cleanups other than removing files, e.g., releasing a metadata snapshot,
+// keyed so their owners could withdraw them once done, and tagged as the files
+type Action = Box<dyn FnOnce() + Send>;
+static ACTIONS: Mutex<Vec<(usize, usize, Action)>> = Mutex::new(Vec::new());
+static NEXT_ACTION: AtomicUsize = AtomicUsize::new(0);
+
+// The scope of the merge running on this thread, so the merges running side
+// by side in one process, e.g., the jobs of a job file, clean up only after
+// themselves on a panic. Zero is outside of any merge.
+static NEXT_SCOPE: AtomicUsize = AtomicUsize::new(1);
+thread_local! {
+    static SCOPE: Cell<usize> = const { Cell::new(0) };
+}
+
+const SIGNALS: [libc::c_int; 4] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+
+fn current_scope() -> usize {
+    SCOPE.with(|s| s.get())
+}
+
+// The lock could be poisoned by a panic while holding it
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    match m.lock() {
+        Ok(guard) => guard,
+        Err(e) => e.into_inner(),
+    }
+}
+
+// Removes the files and runs the actions of the given scope, or of all the
+// scopes if none is given
+fn remove_scope(scope: Option<usize>) {
+    let owned = |s: usize| match scope {
+        Some(scope) => s == scope,
+        None => true,
+    };
+
+    let mut live = lock(&LIVE);
+    let mut kept = Vec::new();
+    for (s, path) in live.drain(..) {
+        if owned(s) {
+            let _ = std::fs::remove_file(path);
+        } else {
+            kept.push((s, path));
+        }
+    }
+    *live = kept;
+    drop(live);
+
+    let actions: Vec<_> = {
+        let mut actions = lock(&ACTIONS);
+        let (run, kept): (Vec<_>, Vec<_>) = actions.drain(..).partition(|(_, s, _)| owned(*s));
+        *actions = kept;
+        run
+    };
+    for (_, _, action) in actions {
+        action();
+    }
+}
+
+fn remove_all() {
+    remove_scope(None);
+}
+
+/// Tags the files and the cleanups registered by this thread as those of a
+/// merge of its own, until dropped
+pub struct CleanupScope {
+    outer: usize,
+}
+
+impl CleanupScope {
+    pub fn enter() -> CleanupScope {
+        let scope = NEXT_SCOPE.fetch_add(1, Ordering::Relaxed);
+        CleanupScope {
+            outer: SCOPE.with(|s| s.replace(scope)),
+        }
+    }
+}
+
+impl Drop for CleanupScope {
+    fn drop(&mut self) {
+        SCOPE.with(|s| s.set(self.outer));
+    }
+}
+
+/// Spawns a thread working for the merge of the caller, in the same scope
+pub fn spawn_scoped<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let scope = current_scope();
+    thread::spawn(move || {
+        SCOPE.with(|s| s.set(scope));
+        f()
+    })
+}
+
+// Runs the action on the same exit paths as the temporary files are removed,
+// unless withdrawn first
+pub fn defer_cleanup(action: impl FnOnce() + Send + 'static) -> usize {
+    let id = NEXT_ACTION.fetch_add(1, Ordering::Relaxed);
+    ACTIONS
+        .lock()
+        .unwrap()
+        .push((id, current_scope(), Box::new(action)));
+    id
+}
+
+// Withdraws the action, returning false if it has run already
+pub fn withdraw_cleanup(id: usize) -> bool {
+    let Ok(mut actions) = ACTIONS.lock() else {
+        return false;
+    };
+    let nr = actions.len();
+    actions.retain(|(i, _, _)| *i != id);
+    actions.len() < nr
+}
+
+// A temporary file registered for cleanup, removed once dropped
+pub struct TempPath(PathBuf);
+
+// A suffix other processes couldn't guess, drawn from the random keys of the
+// std hasher, which differ for every instance
+fn random_suffix() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+impl TempPath {
+    // Creates an empty file in the temporary directory, under a name of its
+    // own, so neither a concurrent merge nor a link planted by another user
+    // could take its place
+    pub fn new(tag: &str) -> std::io::Result<TempPath> {
+        loop {
+            let name = format!(
+                "thin_merge.{}.{}.{:016x}",
+                std::process::id(),
+                tag,
+                random_suffix()
+            );
+            let path = std::env::temp_dir().join(name);
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+            {
+                Ok(_) => {
+                    LIVE.lock().unwrap().push((current_scope(), path.clone()));
+                    return Ok(TempPath(path));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Creates a file of the given length next to the given one, so it could be
+    // renamed over it. The name is of its own, as for new(), so concurrent
+    // merges into the same output don't trip over each other, and a file left
+    // by someone else is never taken.
+    pub fn create_beside(path: &Path, len: u64) -> std::io::Result<TempPath> {
+        loop {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(
+                ".{}.{:016x}.tmp",
+                std::process::id(),
+                random_suffix()
+            ));
+            let path = PathBuf::from(name);
+            let file = match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            LIVE.lock().unwrap().push((current_scope(), path.clone()));
+            let tmp = TempPath(path);
+            file.set_len(len)?;
+            return Ok(tmp);
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    // Renames the file over the given one, which takes it out of the cleanup
+    pub fn persist(self, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.0, to)?;
+        if let Ok(mut live) = LIVE.lock() {
+            live.retain(|(_, p)| p != &self.0);
+        }
+        std::mem::forget(self);
+        Ok(())
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if let Ok(mut live) = LIVE.lock() {
+            live.retain(|(_, p)| p != &self.0);
+        }
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+//------------------------------------------
+
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for sig in SIGNALS {
+            libc::sigaddset(&mut set, sig);
+        }
+        set
+    }
+}
+
+// Waits for the termination signals on a dedicated thread, as removing files
+// isn't async-signal-safe. Once cleaned up, the signal is raised again with
+// the default action, so the exit status still tells the signal.
+fn spawn_signal_thread() {
+    let set = signal_set();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+
+    thread::spawn(move || {
+        let mut sig: libc::c_int = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+            return;
+        }
+        remove_all();
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+            libc::raise(sig);
+        }
+    });
+}
+
+// Removes the registered files left behind once dropped
+pub struct CleanupGuard;
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        remove_all();
+    }
+}
+
+// Installs the cleanup on panics and termination signals. Must be called
+// before spawning any threads, so they inherit the blocked signals.
+pub fn install_cleanup() -> CleanupGuard {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        spawn_signal_thread();
+
+        // only the merge panicking is cleaned up after, while the others
+        // running beside it go on
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            remove_scope(Some(current_scope()));
+            hook(info);
+        }));
+    });
+    CleanupGuard
+}
+
+//------------------------------------------
@@ -0,0 +1,271 @@
+//! C bindings, so the daemons managing the pools could merge in-process.
+//!
+//! The declarations for C, with the fields in the order of
+//! [`ThinMergeFfiOptions`]:
+//!
+//! ```c
+//! struct thin_merge_options {
+//!         const char *input;
+//!         const char *output;
+//!         uint64_t origin;
+//!         uint64_t snapshot;
+//!         bool has_snapshot;
+//!         bool rebase;
+//!         bool dry_run;
+//!         bool verify;
+//!         bool deterministic;
+//!         bool quiet;
+//! };
+//!
+//! int thin_merge_run(const struct thin_merge_options *opts,
+//!                    char *err_buf, size_t err_len);
+//!
+//! struct thin_merge_handle;
+//! typedef void (*thin_merge_progress_fn)(void *arg, const char *event);
+//!
+//! struct thin_merge_handle *thin_merge_handle_new(void);
+//! void thin_merge_handle_free(struct thin_merge_handle *handle);
+//! void thin_merge_handle_cancel(struct thin_merge_handle *handle);
+//! void thin_merge_handle_set_progress(struct thin_merge_handle *handle,
+//!                                     thin_merge_progress_fn fn, void *arg);
+//!
+//! int thin_merge_run_with(const struct thin_merge_options *opts,
+//!                         struct thin_merge_handle *handle,
+//!                         char *err_buf, size_t err_len);
+//! ```
+//!
+//! The calls may come from any thread, but the merges run one at a time, as
+//! the cleanup of the temporary files is shared by the whole process. An
+//! overlapping call waits for the merge running to finish.
+//!
+//! A handle passed to the merges cancels them, and takes their progress, from
+//! any thread. Once cancelled, a merge fails with 7 at its next check, leaving
+//! the output as any failure does, and so do the later ones given the handle.
+//! The progress callback takes the json events of --report-format json, one
+//! line at a time, called from the threads of the merge but never two at once.
+//! It could be registered or replaced while a merge runs, but not from within
+//! the callback itself. The handle is freed once the last merge holding it
+//! ends, so it could be freed while in use.
+
+use anyhow::{anyhow, Result};
+use std::ffi::{c_char, c_int, c_void, CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use thinp::report::{mk_quiet_report, mk_simple_report};
+
+use crate::failure::{classify, fail, FailureKind};
+use crate::merge::{merge_thins, ThinMergeOptions};
+use crate::progress::{ProgressEvent, ProgressSink};
+use crate::schema::Versioned;
+use crate::watchdog::CancellationToken;
+
+//------------------------------------------
+
+// Held through a merge, so the overlapping calls wait their turn
+static RUNNING: Mutex<()> = Mutex::new(());
+
+/// The options of thin_merge_run, a subset of those of the command line
+#[repr(C)]
+pub struct ThinMergeFfiOptions {
+    pub input: *const c_char,
+    pub output: *const c_char,
+    pub origin: u64,
+    pub snapshot: u64,
+    pub has_snapshot: bool, // the snapshot is left to the input otherwise
+    pub rebase: bool,
+    pub dry_run: bool,
+    pub verify: bool,
+    pub deterministic: bool,
+    pub quiet: bool, // the report to stderr is left to the errors
+}
+
+/// The progress callback, taking the argument registered along with it, and a
+/// json event living through the call
+pub type ThinMergeProgressFn = Option<unsafe extern "C" fn(arg: *mut c_void, event: *const c_char)>;
+
+// The callback registered, whose argument the caller vouches for being
+// usable from the threads of the merge
+struct Callback {
+    func: unsafe extern "C" fn(*mut c_void, *const c_char),
+    arg: *mut c_void,
+}
+
+unsafe impl Send for Callback {}
+
+/// Cancels the merges given the handle, and takes their progress
+#[derive(Default)]
+pub struct ThinMergeHandle {
+    cancel: CancellationToken,
+    callback: Mutex<Option<Callback>>,
+}
+
+impl ProgressSink for ThinMergeHandle {
+    // held through the call, so no event comes once the callback is replaced
+    fn event(&self, event: &ProgressEvent) {
+        let callback = self.callback.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cb) = &*callback {
+            // serde escapes any nul within the strings
+            let json = CString::new(Versioned::new(event).to_json()).unwrap();
+            // SAFETY: the caller registered a callback taking the argument
+            unsafe { (cb.func)(cb.arg, json.as_ptr()) };
+        }
+    }
+}
+
+fn to_path<'a>(s: *const c_char, name: &str) -> Result<&'a Path> {
+    if s.is_null() {
+        return Err(fail(FailureKind::Usage, anyhow!("no {} given", name)));
+    }
+    // SAFETY: the caller passes a nul terminated string living through the merge
+    let bytes = unsafe { CStr::from_ptr(s) }.to_bytes();
+    Ok(Path::new(OsStr::from_bytes(bytes)))
+}
+
+fn run(opts: &ThinMergeFfiOptions, handle: Option<Arc<ThinMergeHandle>>) -> Result<()> {
+    let input = to_path(opts.input, "input")?;
+    let output = to_path(opts.output, "output")?;
+    let report = if opts.quiet {
+        mk_quiet_report()
+    } else {
+        mk_simple_report()
+    };
+
+    let mut merge_opts =
+        ThinMergeOptions::from_paths(input, output, Arc::new(report), Some(opts.origin));
+    merge_opts.snapshot = opts.has_snapshot.then_some(opts.snapshot);
+    merge_opts.rebase = opts.rebase;
+    merge_opts.dry_run = opts.dry_run;
+    merge_opts.verify = opts.verify;
+    merge_opts.deterministic = opts.deterministic;
+    merge_opts.quiet = opts.quiet;
+    if let Some(handle) = handle {
+        merge_opts.cancel = Some(handle.cancel.clone());
+        merge_opts.progress_sink = Some(handle);
+    }
+    merge_thins(merge_opts)
+}
+
+// Copies the message into the buffer of the caller, truncated to fit along
+// with the nul terminator
+fn write_error(msg: &str, buf: *mut c_char, len: usize) {
+    if buf.is_null() || len == 0 {
+        return;
+    }
+    let n = std::cmp::min(msg.len(), len - 1);
+    // SAFETY: the caller passes a buffer of at least len bytes
+    unsafe {
+        std::ptr::copy_nonoverlapping(msg.as_ptr() as *const c_char, buf, n);
+        *buf.add(n) = 0;
+    }
+}
+
+/// Creates a handle to cancel the merges and take their progress, to be freed
+/// by thin_merge_handle_free
+#[no_mangle]
+pub extern "C" fn thin_merge_handle_new() -> *mut ThinMergeHandle {
+    Arc::into_raw(Arc::new(ThinMergeHandle::default())) as *mut ThinMergeHandle
+}
+
+/// Gives up the handle of the caller, while the merges holding it keep it
+/// until they end
+///
+/// # Safety
+///
+/// handle must be null, or returned by thin_merge_handle_new and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn thin_merge_handle_free(handle: *mut ThinMergeHandle) {
+    if !handle.is_null() {
+        drop(Arc::from_raw(handle));
+    }
+}
+
+/// Cancels the merge running with the handle, if any, and the later ones
+///
+/// # Safety
+///
+/// handle must be null, or a live handle of thin_merge_handle_new.
+#[no_mangle]
+pub unsafe extern "C" fn thin_merge_handle_cancel(handle: *mut ThinMergeHandle) {
+    if let Some(handle) = handle.as_ref() {
+        handle.cancel.cancel();
+    }
+}
+
+/// Registers the callback taking the progress events of the merges, replacing
+/// the previous one, or drops it if null
+///
+/// # Safety
+///
+/// handle must be null, or a live handle of thin_merge_handle_new, and arg
+/// usable by the callback from any thread until replaced.
+#[no_mangle]
+pub unsafe extern "C" fn thin_merge_handle_set_progress(
+    handle: *mut ThinMergeHandle,
+    func: ThinMergeProgressFn,
+    arg: *mut c_void,
+) {
+    if let Some(handle) = handle.as_ref() {
+        let mut callback = handle.callback.lock().unwrap_or_else(|e| e.into_inner());
+        *callback = func.map(|func| Callback { func, arg });
+    }
+}
+
+/// Runs a merge, returning 0 on success, or the exit code thin_merge would
+/// return with the error written into err_buf. A panic is caught rather than
+/// unwound into the caller, and fails the merge with 1. Waits for a merge
+/// running in another thread to finish first.
+///
+/// # Safety
+///
+/// opts must point to valid options whose strings are nul terminated, and
+/// err_buf, unless null, must hold err_len bytes.
+#[no_mangle]
+pub unsafe extern "C" fn thin_merge_run(
+    opts: *const ThinMergeFfiOptions,
+    err_buf: *mut c_char,
+    err_len: usize,
+) -> c_int {
+    thin_merge_run_with(opts, std::ptr::null_mut(), err_buf, err_len)
+}
+
+/// Runs a merge as thin_merge_run does, cancelled and reporting its progress
+/// through the handle, unless null
+///
+/// # Safety
+///
+/// As for thin_merge_run, and handle must be null, or a live handle of
+/// thin_merge_handle_new.
+#[no_mangle]
+pub unsafe extern "C" fn thin_merge_run_with(
+    opts: *const ThinMergeFfiOptions,
+    handle: *mut ThinMergeHandle,
+    err_buf: *mut c_char,
+    err_len: usize,
+) -> c_int {
+    // held through the merge, so the caller could free the handle meanwhile
+    let handle = (!handle.is_null()).then(|| {
+        Arc::increment_strong_count(handle);
+        Arc::from_raw(handle as *const ThinMergeHandle)
+    });
+
+    // the panics of a merge are caught within, so nothing is left half done
+    // behind a poisoned lock
+    let _running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    let result = match opts.as_ref() {
+        Some(opts) => catch_unwind(AssertUnwindSafe(|| run(opts, handle)))
+            .unwrap_or_else(|_| Err(anyhow!("the merge panicked"))),
+        None => Err(fail(FailureKind::Usage, anyhow!("no options given"))),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            write_error(&format!("{:#}", e), err_buf, err_len);
+            classify(&e).map_or(1, FailureKind::exit_code)
+        }
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,73 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use thinp::thin::block_time::BlockTime;
+
+//------------------------------------------
+
+fn describe(run: Option<&(u64, BlockTime, u64)>) -> String {
+    match run {
+        Some((key, bt, len)) => format!(
+            "[{}, {}) -> {} @ {}",
+            key,
+            key.saturating_add(*len),
+            bt.block,
+            bt.time
+        ),
+        None => "-".to_string(),
+    }
+}
+
+/// A log of the decisions taken by the merge iterators, one line each with the
+/// runs at the heads of the base and the snapshot streams, so a wrong output
+/// could be explained without the metadata it was merged from
+pub struct MergeTrace {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl MergeTrace {
+    pub fn new(out: Box<dyn Write + Send>) -> MergeTrace {
+        MergeTrace {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn create(path: &Path) -> Result<MergeTrace> {
+        let file = File::create(path)
+            .map_err(|e| anyhow!("unable to create the merge trace {}: {}", path.display(), e))?;
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+
+    /// Logs a decision of the iterator at the given level of the chain, along
+    /// with the blocks it consumed or skipped from either stream
+    pub fn record(
+        &self,
+        level: usize,
+        decision: &str,
+        base: Option<&(u64, BlockTime, u64)>,
+        snap: Option<&(u64, BlockTime, u64)>,
+        (base_len, snap_len): (u64, u64),
+    ) -> Result<()> {
+        let mut out = self.out.lock().unwrap();
+        writeln!(
+            out,
+            "level {}: {}, base {}, snap {}, consumed {} from base, {} from snap",
+            level,
+            decision,
+            describe(base),
+            describe(snap),
+            base_len,
+            snap_len
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.out.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+//------------------------------------------
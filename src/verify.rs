@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::Arc;
+use thinp::commands::engine::*;
+use thinp::report::Report;
+use thinp::thin::block_time::*;
+use thinp::thin::superblock::*;
+
+use crate::failure::{fail, FailureKind};
+use crate::merge::{device_runs, read_patched_superblock_snap};
+use crate::stream::{RunSource, VirtualRange};
+
+//------------------------------------------
+
+// Joins adjacent runs, as the merged runs could be split anywhere
+struct Coalesce {
+    source: RunSource,
+    pending: Option<(u64, BlockTime, u64)>,
+}
+
+impl Coalesce {
+    fn new(source: RunSource) -> Self {
+        Self {
+            source,
+            pending: None,
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<(u64, BlockTime, u64)>> {
+        loop {
+            let run = (self.source)()?;
+            match (&mut self.pending, run) {
+                (None, None) => return Ok(None),
+                (None, Some(r)) => self.pending = Some(r),
+                (Some(_), None) => return Ok(self.pending.take()),
+                (Some(p), Some(r)) => {
+                    if p.end()? == r.0 && p.data_end()? == r.1.block && p.1.time == r.1.time {
+                        p.2 += r.2;
+                    } else {
+                        return Ok(self.pending.replace(r));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn describe(run: &Option<(u64, BlockTime, u64)>) -> String {
+    match run {
+        Some((k, bt, l)) => format!(
+            "key {}, data block {}, length {}, time {}",
+            k, bt.block, l, bt.time
+        ),
+        None => "no mappings".to_string(),
+    }
+}
+
+//------------------------------------------
+
+pub struct ThinVerifyOptions<'a> {
+    pub before: &'a Path,
+    pub after: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+    pub origin: u64,
+    pub snapshot: Option<u64>,
+    pub rebase: bool,
+    pub sort_leaves: bool,
+}
+
+// Compares a previously merged output against the merge re-computed from the input
+pub fn verify_merge(opts: ThinVerifyOptions) -> Result<()> {
+    let engine_before = EngineBuilder::new(opts.before, &opts.engine_opts)
+        .exclusive(!opts.engine_opts.use_metadata_snap)
+        .build()?;
+    let mut after_opts = opts.engine_opts.clone();
+    after_opts.use_metadata_snap = false;
+    let engine_after = EngineBuilder::new(opts.after, &after_opts).build()?;
+
+    let sb_before = if opts.engine_opts.use_metadata_snap {
+        read_patched_superblock_snap(engine_before.as_ref())?
+    } else {
+        read_superblock(engine_before.as_ref(), SUPERBLOCK_LOCATION)?
+    };
+    let sb_after = read_superblock(engine_after.as_ref(), SUPERBLOCK_LOCATION)?;
+
+    let out_id = match (opts.rebase, opts.snapshot) {
+        (true, Some(snap_id)) => snap_id,
+        _ => opts.origin,
+    };
+
+    let (expected, _) = device_runs(
+        engine_before,
+        opts.report.clone(),
+        opts.sort_leaves,
+        &sb_before,
+        opts.origin,
+        opts.snapshot,
+    )?;
+    let (actual, details) = device_runs(
+        engine_after,
+        opts.report.clone(),
+        false,
+        &sb_after,
+        out_id,
+        None,
+    )?;
+
+    let mut expected = Coalesce::new(expected);
+    let mut actual = Coalesce::new(actual);
+    let mut mapped_blocks = 0;
+    loop {
+        let e = expected.next()?;
+        let a = actual.next()?;
+        if e != a {
+            return Err(fail(
+                FailureKind::VerifyFailed,
+                anyhow!(
+                    "device {} differs from the merge: expected {}, found {}",
+                    out_id,
+                    describe(&e),
+                    describe(&a)
+                ),
+            ));
+        }
+        match e {
+            Some((_, _, len)) => mapped_blocks += len,
+            None => break,
+        }
+    }
+
+    if mapped_blocks != details.mapped_blocks {
+        return Err(fail(
+            FailureKind::VerifyFailed,
+            anyhow!(
+                "device {} has {} mapped blocks, but its details say {}",
+                out_id,
+                mapped_blocks,
+                details.mapped_blocks
+            ),
+        ));
+    }
+
+    opts.report.info(&format!(
+        "device {} matches the merge, {} mapped blocks",
+        out_id, mapped_blocks
+    ));
+
+    Ok(())
+}
+
+//------------------------------------------
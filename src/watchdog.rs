@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use thinp::report::Report;
+
+use crate::failure::{fail, FailureKind};
+
+//------------------------------------------
+
+// The interval for checking the stages, and for the consumers to poll the watchdog
+pub const WATCHDOG_TICK: Duration = Duration::from_millis(500);
+
+pub struct Stage {
+    name: &'static str,
+    epoch: Instant,
+    position: AtomicU64,
+    last_update: AtomicU64, // in milliseconds since epoch
+    waiting: AtomicBool,
+    stalled: AtomicBool,
+}
+
+impl Stage {
+    fn new(name: &'static str, epoch: Instant) -> Self {
+        Self {
+            name,
+            epoch,
+            position: AtomicU64::new(0),
+            last_update: AtomicU64::new(epoch.elapsed().as_millis() as u64),
+            waiting: AtomicBool::new(false),
+            stalled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn update(&self, position: u64) {
+        self.position.store(position, Ordering::Relaxed);
+        self.last_update
+            .store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Blocking on the neighbouring stages isn't considered a stall
+    pub fn wait<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.waiting.store(true, Ordering::Relaxed);
+        let r = f();
+        self.last_update
+            .store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.waiting.store(false, Ordering::Relaxed);
+        r
+    }
+}
+
+//------------------------------------------
+
+/// Cancels a merge from another thread, e.g., one of the library user. The
+/// merge fails at the next check of its stages, cleaning up as on any failure.
+/// A token stays cancelled, so it cancels every merge it's given to afterwards.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(fail(
+                FailureKind::Cancelled,
+                anyhow!("the merge was cancelled"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct Shared {
+    stages: Mutex<Vec<Arc<Stage>>>,
+    tripped: Mutex<Option<String>>,
+    cancel: Option<CancellationToken>,
+}
+
+// Tells the threads of the stages, which don't hold the watchdog, that a
+// stall was detected in abort mode, or that the merge was cancelled
+#[derive(Clone)]
+pub struct Tripwire {
+    shared: Arc<Shared>,
+}
+
+impl Tripwire {
+    pub fn check(&self) -> Result<()> {
+        if let Some(cancel) = &self.shared.cancel {
+            cancel.check()?;
+        }
+        match &*self.shared.tripped.lock().unwrap() {
+            Some(msg) => Err(anyhow!("timed out: {}", msg)),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Watchdog {
+    epoch: Instant,
+    shared: Arc<Shared>,
+    stop: Option<mpsc::Sender<()>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    // A watchdog without a timeout never reports nor aborts, but for the
+    // cancellation through the token, if any
+    pub fn new(
+        report: Arc<Report>,
+        timeout: Option<Duration>,
+        abort: bool,
+        cancel: Option<CancellationToken>,
+    ) -> Self {
+        let epoch = Instant::now();
+        let shared = Arc::new(Shared {
+            stages: Mutex::new(Vec::new()),
+            tripped: Mutex::new(None),
+            cancel,
+        });
+
+        let (stop, worker) = match timeout {
+            Some(timeout) => {
+                let (tx, rx) = mpsc::channel::<()>();
+                let shared = shared.clone();
+                let worker = thread::spawn(move || {
+                    Self::watch(rx, shared, report, epoch, timeout, abort);
+                });
+                (Some(tx), Some(worker))
+            }
+            None => (None, None),
+        };
+
+        Self {
+            epoch,
+            shared,
+            stop,
+            worker,
+        }
+    }
+
+    fn watch(
+        stop: mpsc::Receiver<()>,
+        shared: Arc<Shared>,
+        report: Arc<Report>,
+        epoch: Instant,
+        timeout: Duration,
+        abort: bool,
+    ) {
+        let timeout_ms = timeout.as_millis() as u64;
+
+        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(WATCHDOG_TICK) {
+            let now = epoch.elapsed().as_millis() as u64;
+
+            let mut stages = shared.stages.lock().unwrap();
+
+            // stages are done once their owners drop them
+            stages.retain(|stage| Arc::strong_count(stage) > 1);
+
+            for stage in stages.iter() {
+                if stage.waiting.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                let idle = now.saturating_sub(stage.last_update.load(Ordering::Relaxed));
+                if idle < timeout_ms {
+                    stage.stalled.store(false, Ordering::Relaxed);
+                    continue;
+                }
+
+                // report once per stall
+                if stage.stalled.swap(true, Ordering::Relaxed) {
+                    continue;
+                }
+
+                let msg = format!(
+                    "{} stage stalled for {}s, last position {}",
+                    stage.name,
+                    idle / 1000,
+                    stage.position.load(Ordering::Relaxed)
+                );
+                report.warning(&msg);
+
+                if abort {
+                    let mut tripped = shared.tripped.lock().unwrap();
+                    if tripped.is_none() {
+                        *tripped = Some(msg);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn stage(&self, name: &'static str) -> Arc<Stage> {
+        let stage = Arc::new(Stage::new(name, self.epoch));
+        if self.worker.is_some() {
+            self.shared.stages.lock().unwrap().push(stage.clone());
+        }
+        stage
+    }
+
+    // Returns a timeout error once a stall was detected in abort mode, or the
+    // cancellation
+    pub fn check(&self) -> Result<()> {
+        self.tripwire().check()
+    }
+
+    // A handle to check the abort from the threads of the stages
+    pub fn tripwire(&self) -> Tripwire {
+        Tripwire {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+//------------------------------------------
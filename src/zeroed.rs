@@ -0,0 +1,143 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use thinp::thin::block_time::BlockTime;
+
+use crate::stream::RunSource;
+
+//------------------------------------------
+
+/// Data blocks known to hold only zeroes, as sorted and disjoint half-open
+/// ranges. The mappings to these blocks could be dropped, as an unmapped block
+/// reads back as zeroes too.
+#[derive(Debug, Default)]
+pub struct ZeroedBlocks {
+    ranges: Vec<(u64, u64)>,
+}
+
+// Parses a data block, or a half-open range of them written as begin..end
+fn parse_line(line: &str) -> Result<(u64, u64)> {
+    let (begin, end) = match line.split_once("..") {
+        Some((b, e)) => (b.trim().parse::<u64>()?, e.trim().parse::<u64>()?),
+        None => {
+            let b = line.parse::<u64>()?;
+            let e = b
+                .checked_add(1)
+                .ok_or_else(|| anyhow!("data block {} overflows", b))?;
+            (b, e)
+        }
+    };
+    if begin >= end {
+        return Err(anyhow!("empty range {}..{}", begin, end));
+    }
+    Ok((begin, end))
+}
+
+impl ZeroedBlocks {
+    /// Builds the set from ranges in any order, joining the overlapping ones
+    pub fn from_ranges(mut ranges: Vec<(u64, u64)>) -> Self {
+        ranges.retain(|(b, e)| b < e);
+        ranges.sort_unstable();
+
+        let mut joined: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (b, e) in ranges {
+            match joined.last_mut() {
+                Some(last) if b <= last.1 => last.1 = std::cmp::max(last.1, e),
+                _ => joined.push((b, e)),
+            }
+        }
+        Self { ranges: joined }
+    }
+
+    /// Reads a list of data blocks, one block or begin..end range per line.
+    /// Blank lines and the ones starting with # are ignored.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let ranges = read_block_list(path, "zeroed block list")?;
+        Ok(Self::from_ranges(ranges))
+    }
+
+    pub fn nr_ranges(&self) -> usize {
+        self.ranges.len()
+    }
+
+    // Splits the data range starting at the given block into the leading piece,
+    // and whether it is zeroed. The piece runs up to the next range boundary.
+    fn leading_piece(&self, block: u64, len: u64) -> (u64, bool) {
+        let idx = self.ranges.partition_point(|&(_, e)| e <= block);
+        match self.ranges.get(idx) {
+            Some(&(b, e)) if b <= block => (std::cmp::min(len, e - block), true),
+            Some(&(b, _)) => (std::cmp::min(len, b - block), false),
+            None => (len, false),
+        }
+    }
+}
+
+//------------------------------------------
+
+// Reads the ranges of a list of data blocks, one block or begin..end range per
+// line, skipping the blank lines and the ones starting with #. The list is
+// named after its use in the errors.
+pub(crate) fn read_block_list(path: &Path, what: &str) -> Result<Vec<(u64, u64)>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("unable to read the {} {}: {}", what, path.display(), e))?;
+
+    let mut ranges = Vec::new();
+    for (nr, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let range = parse_line(line).map_err(|e| {
+            anyhow!(
+                "invalid {} {} at line {}: {}",
+                what,
+                path.display(),
+                nr + 1,
+                e
+            )
+        })?;
+        ranges.push(range);
+    }
+    Ok(ranges)
+}
+
+/// Drops the mappings to zeroed data blocks from the runs of the source,
+/// splitting the runs partly zeroed. The number of blocks dropped is added to
+/// the given counter.
+pub fn skip_zeroed(
+    mut source: RunSource,
+    zeroed: Arc<ZeroedBlocks>,
+    dropped: Arc<AtomicU64>,
+) -> RunSource {
+    let mut pending: Option<(u64, BlockTime, u64)> = None;
+    Box::new(move || loop {
+        let (key, bt, len) = match pending.take() {
+            Some(run) => run,
+            None => match source()? {
+                Some(run) => run,
+                None => return Ok(None),
+            },
+        };
+
+        let (piece, is_zeroed) = zeroed.leading_piece(bt.block, len);
+        if piece < len {
+            pending = Some((
+                key + piece,
+                BlockTime {
+                    block: bt.block + piece,
+                    time: bt.time,
+                },
+                len - piece,
+            ));
+        }
+
+        if is_zeroed {
+            dropped.fetch_add(piece, Ordering::Relaxed);
+        } else {
+            return Ok(Some((key, bt, piece)));
+        }
+    })
+}
+
+//------------------------------------------
@@ -11,4 +11,3 @@ pub mod program;
 pub mod target;
 pub mod test_dir;
 pub mod thin;
-pub mod thin_xml_generator;
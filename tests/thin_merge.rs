@@ -1,4 +1,13 @@
 use anyhow::Result;
+use std::ffi::OsStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use thinp::io_engine::{Block, IoEngine};
+use thinp::pdata::btree::*;
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::report::mk_quiet_report;
+use thinp::thin::block_time::BlockTime;
+use thinp::thin::superblock::*;
 
 mod common;
 mod tools;
@@ -11,24 +20,104 @@ use common::process::*;
 use common::program::*;
 use common::target::*;
 use common::test_dir::*;
-use common::thin_xml_generator::*;
+use thin_merge::check::check_output;
+use thin_merge::memory::*;
+use thin_merge::merge::*;
+use thin_merge::schema::*;
+use thin_merge::synth::*;
+use thin_merge::temp::*;
+use thin_merge::{LeafSource, MappingIterator, MappingStream};
 use tools::verifier::*;
 
 //------------------------------------------
 
 const USAGE: &str = "Merge an external snapshot with its origin into one device
 
-Usage: thin_merge [OPTIONS] --origin <DEV_ID> --input <FILE> --output <FILE>
+Usage: thin_merge [OPTIONS]
+       thin_merge <COMMAND>
+
+Commands:
+  diff    List the ranges where a snapshot differs from its origin, as thin_delta does
+  help    Print this message or the help of the given subcommand(s)
+  stats   Report how a snapshot overlaps its origin, without merging
+  verify  Verify a previously merged output against its input metadata
 
 Options:
-  -h, --help               Print help
-  -i, --input <FILE>       Specify the input metadata
-  -m, --metadata-snap      Use metadata snapshot
-  -o, --output <FILE>      Specify the output metadata
-      --origin <DEV_ID>    The numeric identifier for the external origin
-      --rebase             Choose rebase instead of merge
-      --snapshot <DEV_ID>  The numeric identifier for the external snapshot
-  -V, --version            Print version";
+      --abort-on-stall                  Abort with an error once a stall is detected
+      --accept-diverged-origin          Merge even if the origin was written after the snapshot
+      --append                          Add the merged device to the metadata already in the output
+      --atomic-rename                   Merge into a file beside the output, then rename it over the output
+      --auto-repair                     Rebuild a damaged input superblock as thin_repair does
+      --auto-reserve-metasnap           Reserve the metadata snapshot of the live pool if missing, then release it
+      --auto-snapshot                   Check the snapshot against the devices, or list the candidates
+      --base-batch <LEAVES>             Read the given number of origin leaves at a time
+      --begin <BLOCK>                   Merge only the thin blocks from the given one
+      --buffer-len <RUNS>               Hand the runs to the writer in batches of the given number
+      --chain <DEV_IDS>                 Merge a chain of snapshots, listed from the origin up
+      --chunk-blocks <BLOCKS>           Checkpoint after every chunk of the given number of thin blocks
+      --chunk-pause <MS>                Sleep for the given milliseconds at every checkpoint
+      --clamp-times                     Clamp mapping times to the superblock time
+      --copy-data                       Merge with an external origin, copying its data into the pool
+      --data-block-size <SECTORS>       Rescale the mappings for the given data block size
+      --deterministic                   Zero the unused output blocks, so the same merge writes the same bytes
+      --doctor                          Report the capabilities of this host and exit
+      --dry-run                         Merge without writing the output, reporting the space it would take
+      --dump-threads <THREADS>          Dump the given number of kept devices at a time, beside the merge
+      --emit-residue                    Keep the origin device in the output when rebasing
+      --end <BLOCK>                     Merge only the thin blocks before the given one
+      --engine <ENGINE>                 Choose the io engine for the input [possible values: sync, async, auto]
+      --expand-nr-data-blocks <BLOCKS>  Pair the output with a data device of the given number of blocks
+      --force                           Merge from the metadata device of an active pool regardless
+      --format <FORMAT>                 Choose the output format, or by the output file extension [possible values: binary, xml, pack]
+  -h, --help                            Print help
+  -i, --input <FILE>                    Specify the input metadata, or - for stdin
+      --input-fd <FD>                   Read the input metadata from a descriptor opened by the caller
+      --jobs <FILE>                     Run the merges listed in the given job file
+      --keep-other-devices              Copy the devices not taking part in the merge into the output
+      --list-snapshots-of <DEV_ID>      List the devices sharing mappings with the given origin and exit
+  -m, --metadata-snap                   Use metadata snapshot
+      --max-mem <MIB>                   Bound the estimated memory use to the given MiB, reading fewer leaves at a time to fit
+      --new-dev-id <DEV_ID>             Write the merged device under the given identifier
+  -o, --output <FILE>                   Specify the output metadata, or - for stdout
+      --origin <DEV_ID>                 The numeric identifier for the external origin, or none if lost
+      --origin-dev <DEV>                The external origin device to copy the data from
+      --output-engine <ENGINE>          Choose the io engine for the output [default: auto] [possible values: sync, async, auto]
+      --output-fd <FD>                  Write the output metadata to a descriptor opened by the caller
+      --output-version <VERSION>        Write the output in the given metadata version rather than that of the input
+      --pool <DM_NAME>                  Merge from the metadata snapshot of the given live pool
+      --pool-data-dev <DEV>             The data device of the pool to copy the origin data into
+      --prefetch <BATCHES>              Read the given number of leaf batches ahead of the merge, per tree
+      --preserve-uuid                   Keep the uuid of the input superblock in the output
+  -q, --quiet                           Suppress all output but the errors
+      --queue-depth <BATCHES>           Queue the given number of run batches for the writer
+      --rebase                          Choose rebase instead of merge
+      --remap-data <POLICY>             Move the data blocks for another pool, by offset:BLOCKS or free-list:FILE
+      --remap-table <FILE>              Write where the data blocks were moved to the given file
+      --repair-compat-check             Check the output is structurally fit for thin_repair
+      --report-fd <FD>                  Write the json progress events to the given descriptor
+      --report-format <FORMAT>          Choose json for machine-readable progress events [default: text] [possible values: text, json]
+      --report-interval <INTERVAL>      Update the progress every given seconds, or blocks if suffixed by blocks
+      --reset-time                      Write every mapping, device and superblock time as 0
+      --run-as <USER>                   Switch to the given user once the metadata is opened
+      --since-time <TIME>               Merge only the snapshot mappings of the given time or newer
+      --skip-bad-blocks                 Move the nodes failing to write to other output blocks
+      --skip-bad-nodes                  Skip the damaged mapping leaves, losing their mappings
+      --skip-zeroed <FILE>              Drop the mappings to the data blocks listed as zeroed in the file
+      --snap-batch <LEAVES>             Read the given number of snapshot leaves at a time
+      --snapshot <DEV_ID>               The numeric identifier for the external snapshot
+      --sort-leaves                     Reorder mapping leaves with unordered key ranges
+      --stall-timeout <SECS>            Warn about stages making no progress for the given seconds
+      --stats                           Compare the source devices with the merged output
+      --summary-file <FILE>             Write the json summary of the merge to the given file, or - for stdout
+      --timings                         Report how long the reads and the writes waited on each other
+      --trace-merges <FILE>             Log every decision of the merge to the given file
+      --transaction-id <N>              Write the given transaction id to the output superblock
+      --uuid <UUID>                     Write the given uuid, up to 16 bytes, to the output superblock
+  -v, --verbose...                      Report the merge phases, or the batches too if given twice
+  -V, --version                         Print version
+      --verify                          Check the output once written, as thin_check would
+      --what-changes                    Report the ranges the merge would change in the origin, then exit
+      --write-batch <BLOCKS>            Write the given number of metadata blocks at a time";
 
 //------------------------------------------
 
@@ -269,7 +358,7 @@ fn merge_with_empty_snapshot() -> Result<()> {
     let xml_after = td.mk_path("after.xml");
 
     run_ok(thin_check_cmd(args![&meta_before]))?;
-    run_ok(thin_merge_cmd(args![
+    let output = run_ok_raw(thin_merge_cmd(args![
         "-i",
         &meta_before,
         "-o",
@@ -281,6 +370,9 @@ fn merge_with_empty_snapshot() -> Result<()> {
     ]))?;
     run_ok(thin_check_cmd(args![&meta_after]))?;
 
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("left the snapshots without mappings out of the merge: 20"));
+
     run_ok(thin_dump_cmd(args![
         &meta_before,
         "--dev-id",
@@ -378,7 +470,7 @@ fn merge_devices_share_the_same_root() -> Result<()> {
     let xml_after = td.mk_path("after.xml");
 
     run_ok(thin_check_cmd(args![&meta_before]))?;
-    run_ok(thin_merge_cmd(args![
+    let output = run_ok_raw(thin_merge_cmd(args![
         "-i",
         &meta_before,
         "-o",
@@ -390,6 +482,11 @@ fn merge_devices_share_the_same_root() -> Result<()> {
     ]))?;
     run_ok(thin_check_cmd(args![&meta_after]))?;
 
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("snapshot 50 shares the mapping tree of origin 40"));
+    assert!(messages.contains("takes the details of the origin 40"));
+    assert!(!messages.contains("mapped blocks;"));
+
     run_ok(thin_dump_cmd(args![
         &meta_before,
         "--dev-id",
@@ -404,35 +501,4397 @@ fn merge_devices_share_the_same_root() -> Result<()> {
     Ok(())
 }
 
+// The snapshot overrides every origin mapping
 #[test]
-fn out_of_metadata_space() -> Result<()> {
+fn merge_with_overriding_snapshot() -> Result<()> {
     let mut td = TestDir::new()?;
     let xml_before = td.mk_path("before.xml");
+    let xml_expected = td.mk_path("expected.xml");
+    let xml_after = td.mk_path("after.xml");
     let meta_before = mk_zeroed_md(&mut td)?;
-    let meta_after = td.mk_path("meta.bin");
-    thinp::file_utils::create_sized_file(&meta_after, 1_048_576)?; // 1MB
+    let meta_after = mk_zeroed_md(&mut td)?;
 
-    let mut s = FragmentedS::new(2, 131072);
-    write_xml(&xml_before, &mut s)?;
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"30\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"10\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+    <range_mapping origin_begin=\"40\" data_begin=\"200\" length=\"10\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"60\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"1000\" length=\"35\" time=\"1\"/>
+    <range_mapping origin_begin=\"35\" data_begin=\"2000\" length=\"25\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml_before, content)?;
     run_ok(thin_restore_cmd(args![
         "-i",
         &xml_before,
         "-o",
         &meta_before
     ]))?;
-    run_ok(thin_check_cmd(args![&meta_before]))?;
 
-    // the generated thin ids start by 0
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    run_ok(thin_dump_cmd(args![
+        &meta_before,
+        "--dev-id",
+        "2",
+        "-o",
+        &xml_expected
+    ]))?;
+    run_ok(system_cmd(
+        "sed",
+        args!["-i", "s/dev_id=\"2\"/dev_id=\"1\"/g", &xml_expected],
+    ))?;
+    run_ok(thin_dump_cmd(args![&meta_after, "-o", &xml_after]))?;
+    assert_eq!(md5(&xml_expected)?, md5(&xml_after)?);
+
+    Ok(())
+}
+
+// Mapping times newer than the superblock time
+#[test]
+fn merge_with_future_mapping_times() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"1\"/>
+    <range_mapping origin_begin=\"10\" data_begin=\"200\" length=\"10\" time=\"5\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    // bumps the superblock time by default
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1"
+    ]))?;
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.lines().next().unwrap().contains("time=\"5\""));
+
+    // or clamps the mapping times
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--clamp-times"
+    ]))?;
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.lines().next().unwrap().contains("time=\"1\""));
+    assert!(!dump.contains("time=\"5\""));
+
+    Ok(())
+}
+
+// The transaction id of the output is given rather than taken from the input
+#[test]
+fn merge_with_transaction_id() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--transaction-id",
+        "42"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    assert!(dump.lines().next().unwrap().contains("transaction=\"42\""));
+    // the device keeps its own
+    assert!(dump.contains("dev_id=\"30\" mapped_blocks=\"24\" transaction=\"0\""));
+
+    // but can't be left ahead of the pool
+    let xml = td.mk_path("ahead.xml");
+    let content = b"<superblock uuid=\"\" time=\"0\" transaction=\"5\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"5\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &xml,
+        "-o",
+        &md_out,
+        "--origin",
+        "1",
+        "--transaction-id",
+        "3"
+    ]))?;
+    assert!(stderr.contains("the transaction id 3 is below the transaction 5 of device 1"));
+
+    Ok(())
+}
+
+// The uuid of the output superblock is given, or taken from the input
+#[test]
+fn merge_with_uuid() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let uuid_of =
+        |md: &std::path::Path| -> Result<Vec<u8>> { Ok(std::fs::read(md)?[16..32].to_vec()) };
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--uuid",
+        "pool-0123"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    assert_eq!(uuid_of(&md_out)?, b"pool-0123\0\0\0\0\0\0\0".to_vec());
+
+    // stamp a uuid on the input to be preserved
+    let mut content = std::fs::read(&md_in)?;
+    content[16..32].copy_from_slice(b"0123456789abcdef");
+    thinp::checksum::write_checksum(&mut content[..4096], thinp::checksum::BT::SUPERBLOCK)?;
+    std::fs::write(&md_in, content)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--preserve-uuid"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    assert_eq!(uuid_of(&md_out)?, b"0123456789abcdef".to_vec());
+
+    // xml output carries it without the padding
+    let xml_out = td.mk_path("out.xml");
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &xml_out,
+        "--origin",
+        "30",
+        "--uuid",
+        "pool-0123"
+    ]))?;
+    assert!(std::fs::read_to_string(&xml_out)?.contains("uuid=\"pool-0123\""));
+
+    // converted input has none to preserve
+    let xml_in = td.mk_path("in.xml");
+    mk_default_xml(&xml_in)?;
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &xml_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--preserve-uuid"
+    ]))?;
+    assert!(stderr.contains("give it by --uuid"));
+
+    // longer than the superblock holds
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--uuid",
+        "0123456789abcdef0"
+    ]))?;
+    assert!(stderr.contains("longer than 16 bytes"));
+
+    Ok(())
+}
+
+// The same merge writes the same bytes, whatever the output held before
+#[test]
+fn merge_deterministic() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_zeroed = mk_zeroed_md(&mut td)?;
+    let md_dirty = mk_zeroed_md(&mut td)?;
+
+    // leftovers of some other metadata
+    let dirty = vec![0xa5; std::fs::metadata(&md_dirty)?.len() as usize];
+    write_file(&md_dirty, &dirty)?;
+
+    let merge = |output: &std::path::Path, deterministic: bool| {
+        let mut args = args![
+            "-i",
+            &md_in,
+            "-o",
+            output,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40"
+        ]
+        .to_vec();
+        if deterministic {
+            args.push(OsStr::new("--deterministic"));
+        }
+        run_ok(thin_merge_cmd(args))
+    };
+
+    merge(&md_zeroed, false)?;
+    merge(&md_dirty, false)?;
+    assert_ne!(md5(&md_zeroed)?, md5(&md_dirty)?);
+
+    merge(&md_zeroed, true)?;
+    merge(&md_dirty, true)?;
+    run_ok(thin_check_cmd(args![&md_dirty]))?;
+    assert_eq!(md5(&md_zeroed)?, md5(&md_dirty)?);
+
+    Ok(())
+}
+
+// Every time of the output is written as 0
+#[test]
+fn merge_reset_time() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--keep-other-devices",
+        "--reset-time"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    assert!(dump.lines().next().unwrap().contains(" time=\"0\""));
+    assert!(!dump.contains("time=\"1\""));
+    assert!(!dump.contains("time=\"2\""));
+
+    // conflicts with clamping the times to the superblock
     run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--reset-time",
+        "--clamp-times"
+    ]))?;
+
+    Ok(())
+}
+
+// Mappings to the data blocks listed as zeroed are left out
+#[test]
+fn merge_skip_zeroed() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let list = td.mk_path("zeroed.txt");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"30\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+    <range_mapping origin_begin=\"20\" data_begin=\"200\" length=\"10\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"0\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    write_file(&list, b"# zeroed extents\n105..110\n\n200\n")?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2",
+        "--skip-zeroed",
+        &list
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("dropped the mappings of 6 blocks to the 2 listed ranges"));
+
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.contains("mapped_blocks=\"24\""));
+    assert!(dump.contains("origin_begin=\"0\" data_begin=\"100\" length=\"5\""));
+    assert!(dump.contains("origin_begin=\"10\" data_begin=\"110\" length=\"10\""));
+    assert!(dump.contains("origin_begin=\"21\" data_begin=\"201\" length=\"9\""));
+
+    // a malformed list is rejected before merging
+    write_file(&list, b"105..100\n")?;
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--skip-zeroed",
+        &list
+    ]))?;
+    assert!(stderr.contains("at line 1"));
+
+    Ok(())
+}
+
+// The merge switches to the given user once the engines are open
+#[test]
+fn merge_run_as() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_before = mk_metadata(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
         "-i",
         &meta_before,
         "-o",
         &meta_after,
         "--origin",
+        "30",
+        "--run-as",
+        "no-such-user"
+    ]))?;
+    assert!(stderr.contains("unknown user 'no-such-user'"));
+
+    // only root is able to switch
+    if unsafe { libc::geteuid() } == 0 {
+        let output = run_ok_raw(thin_merge_cmd(args![
+            "-i",
+            &meta_before,
+            "-o",
+            &meta_after,
+            "--origin",
+            "30",
+            "--run-as",
+            "nobody"
+        ]))?;
+        let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+        assert!(messages.contains("running as nobody"));
+        run_ok(thin_check_cmd(args![&meta_after]))?;
+    }
+
+    Ok(())
+}
+
+// Nothing is parsed before the switch, so a corrupt input, xml that would be
+// staged otherwise, fails only after it, or the switch fails first if not
+// running as root
+#[test]
+fn merge_run_as_before_parsing() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_corrupt = td.mk_path("corrupt.xml");
+    write_file(&meta_corrupt, b"<superblock uuid=\"\" time=\"2\" <device")?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i",
+        &meta_corrupt,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--run-as",
+        "nobody"
+    ]))?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8(output.stderr)?;
+    if unsafe { libc::geteuid() } == 0 {
+        let messages = stdout + &stderr;
+        assert!(messages.contains("running as nobody"));
+    } else {
+        assert!(stderr.contains("unable to run as nobody"));
+    }
+
+    Ok(())
+}
+
+// The read batches of either tree don't change the merged output
+#[test]
+fn merge_with_stream_batches() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml_before = td.mk_path("before.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_default = mk_zeroed_md(&mut td)?;
+    let meta_tuned = mk_zeroed_md(&mut td)?;
+
+    let mut s = SnapS::new(65536, 2, 20);
+    write_xml(&xml_before, &mut s)?;
+    run_ok(thin_restore_cmd(args![
+        "-i",
+        &xml_before,
+        "-o",
+        &meta_before
+    ]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_default,
+        "--origin",
         "0",
         "--snapshot",
         "1"
     ]))?;
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_tuned,
+        "--origin",
+        "0",
+        "--snapshot",
+        "1",
+        "--base-batch",
+        "1",
+        "--snap-batch",
+        "3"
+    ]))?;
+
+    run_ok(thin_check_cmd(args![&meta_tuned]))?;
+    assert_eq!(md5(&meta_default)?, md5(&meta_tuned)?);
+
+    Ok(())
+}
+
+// The output could be paired with a data device of another size, as long as
+// it holds every mapped data block
+#[test]
+fn merge_expand_nr_data_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_before = mk_metadata(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--expand-nr-data-blocks",
+        "65536"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump
+        .lines()
+        .next()
+        .unwrap()
+        .contains("nr_data_blocks=\"65536\""));
+
+    // the last mapped data block of the device is 15486
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--expand-nr-data-blocks",
+        "15487"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--expand-nr-data-blocks",
+        "15486"
+    ]))?;
+    assert!(stderr.contains("device 30 maps data block 15486, beyond the 15486 data blocks"));
+
+    Ok(())
+}
+
+// The output could be written for a target pool of a larger data device, or
+// of another data block size the mappings are rescaled for
+#[test]
+fn merge_with_output_geometry() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_metadata(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--data-block-size",
+        "128",
+        "--nr-data-blocks",
+        "32768"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    let sb = dump.lines().next().unwrap();
+    assert!(sb.contains("data_block_size=\"128\""));
+    assert!(sb.contains("nr_data_blocks=\"32768\""));
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--data-block-size",
+        "200"
+    ]))?;
+    assert!(stderr.contains("invalid data block size of 200 sectors"));
+
+    // the runs of two times join into larger blocks only where they line up
+    let content = b"<superblock uuid=\"\" time=\"2\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"1025\">
+  <device dev_id=\"1\" mapped_blocks=\"12\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"4\" time=\"1\"/>
+    <range_mapping origin_begin=\"4\" data_begin=\"104\" length=\"4\" time=\"1\"/>
+    <range_mapping origin_begin=\"8\" data_begin=\"200\" length=\"4\" time=\"2\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"2\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"301\" length=\"2\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    let meta_scaled = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_scaled]))?;
+
+    let rescale = |dev: &str, size: &str| {
+        thin_merge_cmd(args![
+            "-i",
+            &meta_scaled,
+            "-o",
+            &meta_after,
+            "--origin",
+            dev,
+            "--data-block-size",
+            size
+        ])
+    };
+
+    run_ok(rescale("1", "512"))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.contains("data_block_size=\"512\" nr_data_blocks=\"256\""));
+    assert!(dump.contains("mapped_blocks=\"3\""));
+    assert!(dump.contains("origin_begin=\"0\" data_begin=\"25\" length=\"2\" time=\"1\""));
+    assert!(dump.contains("origin_block=\"2\" data_block=\"50\" time=\"2\""));
+
+    run_ok(rescale("2", "64"))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.contains("data_block_size=\"64\" nr_data_blocks=\"2050\""));
+    assert!(dump.contains("origin_begin=\"0\" data_begin=\"602\" length=\"4\""));
+
+    let stderr = run_fail(rescale("2", "256"))?;
+    assert!(stderr.contains("don't line up with data blocks of 256 sectors"));
+
+    Ok(())
+}
+
+// The external snapshot provisions its own data blocks after the origin was
+// last written, unlike the snapshots taken within the pool, but only the user
+// could pair it with the origin
+#[test]
+fn merge_auto_snapshot() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+    let meta_expected = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"2\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"0\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"1\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"3\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"5\" data_begin=\"500\" length=\"3\" time=\"2\"/>
+  </device>
+  <device dev_id=\"3\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"1\"/>
+  </device>
+  <device dev_id=\"4\" mapped_blocks=\"5\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"900\" length=\"5\" time=\"0\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    let merge = |snapshot: Option<&str>| {
+        let mut args = args![
+            "-i",
+            &meta_before,
+            "-o",
+            &meta_after,
+            "--origin",
+            "1",
+            "--auto-snapshot"
+        ]
+        .to_vec();
+        if let Some(snapshot) = snapshot {
+            args.extend([OsStr::new("--snapshot"), OsStr::new(snapshot)]);
+        }
+        thin_merge_cmd(args)
+    };
+
+    // the only candidate is listed, but not picked
+    let output = run_fail_raw(merge(None))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("snapshot 2: created at time 1"));
+    assert!(messages.contains("devices that could be the external snapshot of origin 1: 2;"));
+
+    // the snapshot taken within the pool, and the device older than the
+    // origin writes, don't fit
+    let stderr = run_fail(merge(Some("3")))?;
+    assert!(stderr.contains("device 3 shares 10 data blocks with origin 1"));
+    let stderr = run_fail(merge(Some("4")))?;
+    assert!(stderr.contains("device 4 was created at time 0"));
+
+    let output = run_ok_raw(merge(Some("2")))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("confirmed device 2 as the external snapshot of origin 1"));
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_expected,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2"
+    ]))?;
+    assert_eq!(md5(&meta_expected)?, md5(&meta_after)?);
+
+    // the empty devices could be the external snapshots of device 30 as well
+    let meta_default = mk_metadata(&mut td)?;
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_default,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--auto-snapshot"
+    ]))?;
+    assert!(stderr.contains("devices that could be the external snapshot of origin 30: "));
+
+    Ok(())
+}
+
+// Misordered keys within a leaf are reported along with the leaf
+#[test]
+fn iterate_leaf_with_misordered_keys() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_metadata(&mut td)?;
+    let engine = load_engine(&std::fs::read(&md)?)?;
+
+    // the few mappings of device 30 fit in the root leaf
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let leaf = roots[&30];
+
+    let b = engine.read(leaf)?;
+    let mut node = unpack_node::<BlockTime>(&[], b.get_data(), true, true)?;
+    if let Node::Leaf { ref mut keys, .. } = node {
+        keys.swap(1, 2);
+    }
+    let mut cursor = std::io::Cursor::new(b.get_data());
+    pack_node(&node, &mut cursor)?;
+    thinp::checksum::write_checksum(b.get_data(), thinp::checksum::BT::NODE)?;
+    engine.write(&b)?;
+
+    let err = match MappingIterator::new(engine, vec![leaf]) {
+        Ok(_) => panic!("misordered keys accepted"),
+        Err(e) => e.to_string(),
+    };
+    assert!(err.contains(&format!("mapping leaf at block {}", leaf)));
+
+    Ok(())
+}
+
+#[test]
+fn merge_jobs() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let jobs = td.mk_path("jobs.toml");
+    let meta_before = mk_metadata(&mut td)?;
+    let meta_1 = mk_zeroed_md(&mut td)?;
+    let meta_2 = mk_zeroed_md(&mut td)?;
+    let expected_1 = mk_zeroed_md(&mut td)?;
+    let expected_2 = mk_zeroed_md(&mut td)?;
+
+    let content = format!(
+        "# two merges of the same pool\nparallel = 2\n\n\
+         [[job]]\ninput = \"{}\"\noutput = \"{}\"\norigin = 30\nsnapshot = 40\n\n\
+         [[job]]\ninput = \"{}\"\noutput = \"{}\"\norigin = 40\nsnapshot = 50\nrebase = true\n",
+        meta_before.display(),
+        meta_1.display(),
+        meta_before.display(),
+        meta_2.display()
+    );
+    write_file(&jobs, content.as_bytes())?;
+
+    let output = run_ok_raw(thin_merge_cmd(args!["--jobs", &jobs]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("job 1: done"));
+    assert!(messages.contains("job 2: done"));
+    assert!(messages.contains("all 2 jobs done"));
+
+    // each job writes what a single run does
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &expected_1,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &expected_2,
+        "--origin",
+        "40",
+        "--snapshot",
+        "50",
+        "--rebase"
+    ]))?;
+    for (md, expected) in [(&meta_1, &expected_1), (&meta_2, &expected_2)] {
+        run_ok(thin_check_cmd(args![md]))?;
+        assert_eq!(
+            run_ok(thin_dump_cmd(args![md]))?,
+            run_ok(thin_dump_cmd(args![expected]))?
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn merge_jobs_with_a_failed_job() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let jobs = td.mk_path("jobs.toml");
+    let meta_before = mk_metadata(&mut td)?;
+    let meta_1 = mk_zeroed_md(&mut td)?;
+    let meta_2 = mk_zeroed_md(&mut td)?;
+    let job = |input: &std::path::Path, output: &std::path::Path, origin: &str| {
+        format!(
+            "[[job]]\ninput = \"{}\"\noutput = \"{}\"\norigin = {}\n",
+            input.display(),
+            output.display(),
+            origin
+        )
+    };
+
+    let content = job(&meta_before, &meta_1, "99") + &job(&meta_before, &meta_2, "30");
+    write_file(&jobs, content.as_bytes())?;
+
+    // the failure of the first job doesn't keep the second one from running
+    let manifest = td.mk_path("manifest.json");
+    let content = format!("manifest = \"{}\"\n", manifest.display()) + &content;
+    write_file(&jobs, content.as_bytes())?;
+    let stderr = run_fail(thin_merge_cmd(args!["--jobs", &jobs]))?;
+    assert!(stderr.contains("job 1: failed"));
+    assert!(stderr.contains("1 of 2 jobs failed"));
+    run_ok(thin_check_cmd(args![&meta_2]))?;
+
+    // the manifest tells the result of every job
+    let results: Versioned<JobManifest> =
+        serde_json::from_str(&std::fs::read_to_string(&manifest)?)?;
+    assert_eq!(results.schema_version, SCHEMA_VERSION);
+    let results = results.body;
+    assert_eq!(
+        (results.nr_done, results.nr_failed, results.nr_skipped),
+        (1, 1, 0)
+    );
+    assert_eq!(results.jobs[0].status, JobStatus::Failed);
+    assert!(results.jobs[0].error.is_some());
+    assert!(results.jobs[0].exit_code.is_some());
+    assert_eq!(results.jobs[1].status, JobStatus::Done);
+    assert_eq!(results.jobs[1].output, meta_2);
+    assert!(results.jobs[1].summary.as_ref().unwrap().mapped_blocks > 0);
+
+    // or stops it, if asked to
+    let meta_3 = mk_zeroed_md(&mut td)?;
+    let before = md5(&meta_3)?;
+    let content = format!(
+        "continue_on_failure = false\nmanifest = \"{}\"\n",
+        manifest.display()
+    ) + &job(&meta_before, &meta_1, "99")
+        + &job(&meta_before, &meta_3, "30");
+    write_file(&jobs, content.as_bytes())?;
+    let stderr = run_fail(thin_merge_cmd(args!["--jobs", &jobs]))?;
+    assert!(stderr.contains("job 2: skipped"));
+    assert!(stderr.contains("1 of 2 jobs failed, 1 skipped"));
+    assert_eq!(md5(&meta_3)?, before);
+    let results: Versioned<JobManifest> =
+        serde_json::from_str(&std::fs::read_to_string(&manifest)?)?;
+    assert_eq!(results.body.jobs[1].status, JobStatus::Skipped);
+
+    // jobs writing the same output are rejected up front, even if named
+    // by different paths
+    let mut alias = meta_1.parent().unwrap().join(".").into_os_string();
+    alias.push("/");
+    alias.push(meta_1.file_name().unwrap());
+    let content =
+        job(&meta_before, &meta_1, "30") + &job(&meta_before, std::path::Path::new(&alias), "40");
+    write_file(&jobs, content.as_bytes())?;
+    let stderr = run_fail(thin_merge_cmd(args!["--jobs", &jobs]))?;
+    assert!(stderr.contains("jobs 1 and 2 both write"));
+
+    // parallel jobs can't share the metadata snapshot of a pool
+    let content = "parallel = 2\n".to_string()
+        + &job(&meta_before, &meta_1, "30")
+        + &job(&meta_before, &meta_2, "40");
+    write_file(&jobs, content.as_bytes())?;
+    let output = run_fail_raw(thin_merge_cmd(args!["--jobs", &jobs, "-m"]))?;
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("jobs 1 and 2 both read the metadata snapshot"));
+    assert_eq!(output.status.code(), Some(2));
+
+    write_file(&jobs, b"[[job]]\ninput = \"a\"\norigin = 1\n")?;
+    let stderr = run_fail(thin_merge_cmd(args!["--jobs", &jobs]))?;
+    assert!(stderr.contains("missing field `output`"));
+
+    write_file(
+        &jobs,
+        b"[[job]]\ninput = \"a\"\noutput = \"b\"\norigin = \"lost\"\n",
+    )?;
+    let stderr = run_fail(thin_merge_cmd(args!["--jobs", &jobs]))?;
+    assert!(stderr.contains("job 1: origin takes a device id or none, not 'lost'"));
+
+    write_file(
+        &jobs,
+        b"parallel = 0\n[[job]]\ninput = \"a\"\noutput = \"b\"\norigin = 1\n",
+    )?;
+    let stderr = run_fail(thin_merge_cmd(args!["--jobs", &jobs]))?;
+    assert!(stderr.contains("parallel takes a positive integer"));
+    Ok(())
+}
+
+#[test]
+fn merge_in_chunks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--chunk-blocks",
+        "8",
+        "--chunk-pause",
+        "1",
+        "--report-format",
+        "json"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    // the runs split at the chunk bounds are written as before
+    assert_eq!(
+        run_ok(thin_dump_cmd(args![&md_out]))?,
+        run_ok(thin_dump_cmd(args![&md_expected]))?
+    );
+
+    // the chunks without mappings are skipped
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    let chunks: Vec<&str> = messages
+        .lines()
+        .filter(|l| l.contains("\"event\":\"chunk\""))
+        .collect();
+    assert_eq!(chunks.len(), 7);
+    assert!(chunks[0].contains("\"dev_id\":30,\"chunk_begin\":272,\"chunk_end\":280"));
+    assert!(chunks[6].contains("\"chunk_begin\":488,\"chunk_end\":496,\"mapped_blocks\":34"));
+    assert!(messages.contains("wrote the devices in 7 chunks of 8 blocks"));
+    Ok(())
+}
+
+#[test]
+fn merge_log_levels() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "-q"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.trim().is_empty());
+
+    // the errors are still reported
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "99",
+        "--snapshot",
+        "40",
+        "-q"
+    ]))?;
+    assert!(stderr.contains("Unable to find mapping tree for the device 99"));
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "-v"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("collected 1 leaves of the mapping tree at block"));
+    assert!(messages.contains("wrote device 30: 34 mapped blocks in 3 runs"));
+    assert!(!messages.contains("device 30: wrote 3 runs of 34 blocks"));
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "-vv"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("device 30: wrote 3 runs of 34 blocks up to key 492"));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    Ok(())
+}
+
+#[test]
+fn merge_summary_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let summary = td.mk_path("summary.json");
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--summary-file",
+        &summary
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let json = std::fs::read_to_string(&summary)?;
+    let field = |name: &str| -> u64 {
+        let key = format!("\"{}\":", name);
+        let rest = &json[json.find(&key).unwrap() + key.len()..];
+        let end = rest.find([',', '}']).unwrap();
+        rest[..end].parse().unwrap()
+    };
+    assert!(json.starts_with("{\"schema_version\":1,"));
+    assert!(json.contains("\"wall_time_secs\":"));
+
+    // read back through the types of the library
+    let read: Versioned<RunSummary> = serde_json::from_str(&json)?;
+    assert_eq!(read.schema_version, SCHEMA_VERSION);
+    assert_eq!(read.body.mapped_blocks, 34);
+    assert_eq!(field("mapped_blocks"), 34);
+    assert_eq!(field("nr_runs"), 3);
+    assert!(field("metadata_blocks") > 0);
+    assert!(field("bytes_read") > 0);
+    assert!(field("bytes_written") >= field("metadata_blocks") * 4096);
+
+    // a dry run has the summary printed instead
+    let stdout = run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--dry-run",
+        "--summary-file",
+        "-"
+    ]))?;
+    assert!(stdout.contains("\"mapped_blocks\":34,\"nr_runs\":3,"));
+    Ok(())
+}
+
+#[test]
+fn merge_with_output_version() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert!(run_ok(thin_dump_cmd(args![&md_out]))?.contains("version=\"2\""));
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--output-version",
+        "1"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("writing metadata version 1 for the input of version 2"));
+    assert!(run_ok(thin_dump_cmd(args![&md_out]))?.contains("version=\"1\""));
+
+    run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--output-version",
+        "3"
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn merge_needs_check_into_version_1() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let md_in = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" flags=\"1\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"0\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md_in]))?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "1",
+        "--output-version",
+        "1"
+    ]))?;
+    assert!(stderr.contains("the input is flagged as needing a check"));
+    Ok(())
+}
+
+// A failed merge leaves no superblock behind, rather than the previous one
+// pointing at the overwritten trees
+#[test]
+fn failed_merge_invalidates_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--expand-nr-data-blocks",
+        "100"
+    ]))?;
+    run_fail(thin_check_cmd(args![&md_out]))?;
+    Ok(())
+}
+
+// The files staged beside the output and left over by the merges
+fn staged_beside(output: &std::path::Path) -> Result<Vec<std::path::PathBuf>> {
+    let prefix = format!("{}.", output.file_name().unwrap().to_string_lossy());
+    let mut staged = Vec::new();
+    for entry in std::fs::read_dir(output.parent().unwrap())? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) && name.ends_with(".tmp") {
+            staged.push(path);
+        }
+    }
+    Ok(staged)
+}
+
+#[test]
+fn merge_atomic_rename() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let tmp = std::path::PathBuf::from(format!("{}.tmp", md_out.display()));
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--atomic-rename"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("renamed the merged output over"));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    assert!(staged_beside(&md_out)?.is_empty());
+
+    // the output is left untouched by a failed merge
+    let before = md5(&md_out)?;
+    run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--expand-nr-data-blocks",
+        "100",
+        "--atomic-rename"
+    ]))?;
+    assert_eq!(md5(&md_out)?, before);
+    assert!(staged_beside(&md_out)?.is_empty());
+
+    // a file left by someone else is neither taken nor removed
+    write_file(&tmp, b"")?;
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--atomic-rename"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    assert_eq!(std::fs::metadata(&tmp)?.len(), 0);
+    assert_eq!(staged_beside(&md_out)?, vec![tmp]);
+    Ok(())
+}
+
+#[test]
+fn merge_verify() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--verify"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("verified the output: 1 devices, 34 mapped blocks"));
+    Ok(())
+}
+
+// The check of the output catches what thin_check would
+#[test]
+fn check_corrupted_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_metadata(&mut td)?;
+    let engine = load_engine(&std::fs::read(&md)?)?;
+    check_output(engine.clone())?;
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let leaf = roots[&30];
+
+    // keys out of order within the root leaf, under a valid checksum
+    let b = engine.read(leaf)?;
+    let saved = b.get_data().to_vec();
+    let mut node = unpack_node::<BlockTime>(&[], b.get_data(), true, true)?;
+    if let Node::Leaf { ref mut keys, .. } = node {
+        keys.swap(1, 2);
+    }
+    let mut cursor = std::io::Cursor::new(b.get_data());
+    pack_node(&node, &mut cursor)?;
+    thinp::checksum::write_checksum(b.get_data(), thinp::checksum::BT::NODE)?;
+    engine.write(&b)?;
+    let err = check_output(engine.clone()).unwrap_err().to_string();
+    assert!(err.contains(&format!("mapping leaf at block {}", leaf)));
+
+    // a flipped byte
+    b.get_data().copy_from_slice(&saved);
+    b.get_data()[100] ^= 0xff;
+    engine.write(&b)?;
+    let err = check_output(engine).unwrap_err().to_string();
+    assert!(err.contains(&format!("bad checksum of the btree node at block {}", leaf)));
+
+    Ok(())
+}
+
+// The reference counts of the data space map are checked against the
+// mappings, with the blocks shared by the devices counted twice
+#[test]
+fn check_data_space_map() -> Result<()> {
+    use thinp::pdata::space_map::common::{Bitmap, BitmapEntry, IndexEntry, SMRoot};
+    use thinp::pdata::unpack::{unpack, Pack};
+
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--keep-other-devices",
+        "--verify"
+    ]))?;
+
+    let engine = load_engine(&std::fs::read(&md_out)?)?;
+    check_output(engine.clone())?;
+
+    // one more reference to the first data block in use
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let root = unpack::<SMRoot>(&sb.data_sm_root)?;
+    let index = btree_to_map::<IndexEntry>(&mut vec![], engine.clone(), false, root.bitmap_root)?;
+    let b = engine.read(index[&0].blocknr)?;
+    let mut bitmap = unpack::<Bitmap>(b.get_data())?;
+    let (block, n) = bitmap
+        .entries
+        .iter_mut()
+        .enumerate()
+        .find_map(|(i, e)| match e {
+            BitmapEntry::Small(n) if *n > 0 => Some((i, n)),
+            _ => None,
+        })
+        .unwrap();
+    *n += 1;
+    let mut cursor = std::io::Cursor::new(b.get_data());
+    bitmap.pack(&mut cursor)?;
+    thinp::checksum::write_checksum(b.get_data(), thinp::checksum::BT::BITMAP)?;
+    engine.write(&b)?;
+
+    let err = check_output(engine).unwrap_err().to_string();
+    assert!(err.contains(&format!("data block {} has", block)));
+
+    Ok(())
+}
+
+// The origin data under the holes of the snapshot is copied into the free
+// data blocks of the pool, which are the lowest ones here
+#[test]
+fn merge_copy_data() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let block_bytes = 128 * 512;
+
+    // each block of the origin is filled with its index plus one
+    let origin_dev = td.mk_path("origin.bin");
+    let mut content = Vec::new();
+    for b in 0..8u8 {
+        content.extend(std::iter::repeat(b + 1).take(block_bytes));
+    }
+    write_file(&origin_dev, &content)?;
+    let pool_data_dev = td.mk_path("data.bin");
+    thinp::file_utils::create_sized_file(&pool_data_dev, 16384 * block_bytes as u64)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--snapshot",
+        "40",
+        "--copy-data",
+        "--origin-dev",
+        &origin_dev,
+        "--pool-data-dev",
+        &pool_data_dev
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("copied 8 blocks of the origin"));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let engine = load_engine(&std::fs::read(&md_out)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let mappings = btree_to_map::<BlockTime>(&mut vec![], engine.clone(), false, roots[&40])?;
+    assert_eq!(mappings.len(), 18);
+
+    let pool = std::fs::File::open(&pool_data_dev)?;
+    let mut buf = vec![0; block_bytes];
+    for key in 0..8u64 {
+        pool.read_exact_at(&mut buf, mappings[&key].block * block_bytes as u64)?;
+        assert!(buf.iter().all(|&v| v == key as u8 + 1));
+    }
+    assert_eq!(mappings[&339].block, 1036);
+    Ok(())
+}
+
+// An origin ending halfway through a data block is padded with zeroes
+#[test]
+fn merge_copy_data_partial_block() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let block_bytes = 128 * 512;
+
+    let origin_dev = td.mk_path("origin.bin");
+    let content = vec![0xa5u8; 7 * block_bytes + block_bytes / 2];
+    write_file(&origin_dev, &content)?;
+    let pool_data_dev = td.mk_path("data.bin");
+    thinp::file_utils::create_sized_file(&pool_data_dev, 16384 * block_bytes as u64)?;
+    // stale data, which the padding has to overwrite
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&pool_data_dev)?
+        .write_all_at(&vec![0xffu8; 16 * block_bytes], 0)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--snapshot",
+        "40",
+        "--copy-data",
+        "--origin-dev",
+        &origin_dev,
+        "--pool-data-dev",
+        &pool_data_dev
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("copied 8 blocks of the origin"));
+
+    let engine = load_engine(&std::fs::read(&md_out)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let mappings = btree_to_map::<BlockTime>(&mut vec![], engine.clone(), false, roots[&40])?;
+
+    let pool = std::fs::File::open(&pool_data_dev)?;
+    let mut buf = vec![0; block_bytes];
+    pool.read_exact_at(&mut buf, mappings[&7].block * block_bytes as u64)?;
+    assert!(buf[..block_bytes / 2].iter().all(|&v| v == 0xa5));
+    assert!(buf[block_bytes / 2..].iter().all(|&v| v == 0));
+
+    Ok(())
+}
+
+#[test]
+fn merge_remap_data_by_offset() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--remap-data",
+        "offset:100"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let engine = load_engine(&std::fs::read(&md_out)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let mappings = btree_to_map::<BlockTime>(&mut vec![], engine.clone(), false, roots[&30])?;
+    assert_eq!(mappings[&274].block, 8540);
+    assert_eq!(mappings[&339].block, 1136);
+    assert_eq!(mappings[&485].block, 15580);
+
+    // moved past the data device of the target pool
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--remap-data",
+        "offset:1000"
+    ]))?;
+    assert!(stderr.contains("beyond the 16384 data blocks of the target pool"));
+    Ok(())
+}
+
+// The blocks shared between the devices are moved together
+#[test]
+fn merge_remap_data_by_free_list() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let free_list = td.mk_path("free.txt");
+    write_file(
+        &free_list,
+        b"# free in the target pool\n5000..5010\n6000..7000\n",
+    )?;
+    let table = td.mk_path("remap.txt");
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--keep-other-devices",
+        "--remap-data",
+        &format!("free-list:{}", free_list.display()),
+        "--remap-table",
+        &table
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("moved 34 data blocks for the target pool"));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let engine = load_engine(&std::fs::read(&md_out)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let merged = btree_to_map::<BlockTime>(&mut vec![], engine.clone(), false, roots[&30])?;
+    let other = btree_to_map::<BlockTime>(&mut vec![], engine.clone(), false, roots[&50])?;
+    assert_eq!(merged[&274].block, 5000);
+    assert_eq!(merged[&284].block, 6000);
+    assert_eq!(merged[&339].block, 6007);
+    assert_eq!(other[&339].block, 6007);
+
+    assert_eq!(
+        std::fs::read_to_string(&table)?,
+        "# source data blocks, and where they begin in the target pool\n\
+         1036..1046 6007\n\
+         8440..8450 5000\n\
+         8450..8457 6000\n\
+         15480..15487 6017\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn merge_key_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--begin",
+        "280",
+        "--end",
+        "345"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let engine = load_engine(&std::fs::read(&md_out)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let mappings = btree_to_map::<BlockTime>(&mut vec![], engine.clone(), false, roots[&30])?;
+    assert_eq!(mappings.len(), 17);
+    assert_eq!(mappings.keys().next(), Some(&280));
+    assert_eq!(mappings.keys().last(), Some(&344));
+    assert_eq!(mappings[&280].block, 8446);
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i", &md_in, "-o", &md_out, "--origin", "30", "--begin", "300", "--end", "300"
+    ]))?;
+    assert!(stderr.contains("the range of thin blocks to merge 300..300 is empty"));
+    Ok(())
+}
+
+// The runs crossing either end of the range are cut there
+#[test]
+fn iterate_key_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_metadata(&mut td)?;
+    let engine = load_engine(&std::fs::read(&md)?)?;
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let mut iter = MappingIterator::new(engine, vec![roots[&30]])?;
+    iter.restrict(280, 486)?;
+
+    let mut runs = Vec::new();
+    while let Some((key, bt, len)) = iter.next_range()? {
+        runs.push((key, bt.block, len));
+    }
+    assert_eq!(runs, vec![(280, 8446, 11), (485, 15480, 1)]);
+    Ok(())
+}
+
+// Leaves streamed from a channel are iterated as a list of them, and a failure
+// sent along is passed on
+#[test]
+fn iterate_streamed_leaves() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_metadata(&mut td)?;
+    let engine = load_engine(&std::fs::read(&md)?)?;
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    tx.send(Ok(roots[&30]))?;
+    drop(tx);
+    let mut iter = MappingIterator::from_source(engine.clone(), LeafSource::Stream(rx), 1, 1)?;
+    let mut runs = Vec::new();
+    while let Some((key, bt, len)) = iter.next_range()? {
+        runs.push((key, bt.block, len));
+    }
+    assert_eq!(runs, vec![(274, 8440, 17), (485, 15480, 7)]);
+
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    tx.send(Err(anyhow::anyhow!("the walk failed")))?;
+    let err = match MappingIterator::from_source(engine, LeafSource::Stream(rx), 1, 1) {
+        Ok(_) => panic!("a failed stream should fail the iterator"),
+        Err(e) => e.to_string(),
+    };
+    assert_eq!(err, "the walk failed");
+    Ok(())
+}
+
+// Streaming the leaves of large trees yields the same output as collecting
+// them all up front
+#[test]
+fn merge_streamed_leaves() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml_before = td.mk_path("before.xml");
+    let md_in = mk_zeroed_md(&mut td)?;
+    let md_streamed = td.mk_path("streamed.bin");
+    let md_collected = td.mk_path("collected.bin");
+    thinp::file_utils::create_sized_file(&md_streamed, 16 * 1024 * 1024)?;
+    thinp::file_utils::create_sized_file(&md_collected, 16 * 1024 * 1024)?;
+
+    let mut s = FragmentedS::new(2, 131072);
+    write_xml(&xml_before, &mut s)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml_before, "-o", &md_in]))?;
+
+    for (out, extra) in [
+        (&md_streamed, vec![]),
+        (&md_collected, vec!["--sort-leaves"]),
+    ] {
+        let mut args = args!["-i", &md_in, "-o", out, "--origin", "0", "--snapshot", "1"].to_vec();
+        args.extend(extra.into_iter().map(OsStr::new));
+        run_ok(thin_merge_cmd(args))?;
+    }
+
+    run_ok(thin_check_cmd(args![&md_streamed]))?;
+    assert_eq!(md5(&md_streamed)?, md5(&md_collected)?);
+    Ok(())
+}
+
+// Merging between in-memory engines yields the same output as the files
+#[test]
+fn merge_in_memory() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let md_mem = td.mk_path("mem.bin");
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+
+    let input = load_engine(&std::fs::read(&md_in)?)?;
+    let output = zeroed_engine(input.get_nr_blocks())?;
+    let mut opts =
+        ThinMergeOptions::in_memory(input, output.clone(), Arc::new(mk_quiet_report()), 30);
+    opts.snapshot = Some(40);
+    merge_thins(opts)?;
+
+    write_file(&md_mem, &save_engine(output.as_ref())?)?;
+    assert_eq!(md5(&md_out)?, md5(&md_mem)?);
+
+    Ok(())
+}
+
+// The merge iterator overlays runs from any source through the library API
+#[test]
+fn merge_runs_through_library() -> Result<()> {
+    fn stream(runs: Vec<(u64, BlockTime, u64)>) -> Result<MappingStream> {
+        let mut runs = runs.into_iter();
+        MappingStream::from_source(Box::new(move || Ok(runs.next())))
+    }
+    let bt = |block, time| BlockTime { block, time };
+
+    let base = stream(vec![(0, bt(100, 0), 20)])?;
+    let snap = stream(vec![(5, bt(300, 1), 5)])?;
+    let mut iter = RangeMergeIterator::new(
+        (base, Some(20)),
+        (snap, Some(10)),
+        Arc::new(AtomicU64::new(0)),
+    );
+
+    let mut runs = Vec::new();
+    while let Some(run) = iter.next_range()? {
+        runs.push(run);
+    }
+    assert_eq!(
+        runs,
+        vec![(0, bt(100, 0), 5), (5, bt(300, 1), 5), (10, bt(110, 0), 10)]
+    );
+
+    Ok(())
+}
+
+// Both iterators compose with the standard adaptors
+#[test]
+fn merge_runs_with_iterator_adaptors() -> Result<()> {
+    fn stream(runs: Vec<(u64, BlockTime, u64)>) -> Result<MappingStream> {
+        let mut runs = runs.into_iter();
+        MappingStream::from_source(Box::new(move || Ok(runs.next())))
+    }
+    let bt = |block, time| BlockTime { block, time };
+
+    let base = stream(vec![(0, bt(100, 0), 20)])?;
+    let snap = stream(vec![(5, bt(300, 1), 5)])?;
+    let iter = RangeMergeIterator::new(
+        (base, Some(20)),
+        (snap, Some(10)),
+        Arc::new(AtomicU64::new(0)),
+    );
+    let snap_blocks: u64 = iter
+        .filter(|run| !matches!(run, Ok((_, b, _)) if b.time == 0))
+        .map(|run| run.map(|(_, _, len)| len))
+        .sum::<Result<u64>>()?;
+    assert_eq!(snap_blocks, 5);
+
+    let mut td = TestDir::new()?;
+    let md = mk_metadata(&mut td)?;
+    let engine = load_engine(&std::fs::read(&md)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let keys = MappingIterator::new(engine, vec![roots[&30]])?
+        .map(|run| run.map(|(key, _, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec![274, 485]);
+
+    Ok(())
+}
+
+// Peeking at the runs ahead leaves them to be consumed in order
+#[test]
+fn peek_stream_runs() -> Result<()> {
+    let bt = |block, time| BlockTime { block, time };
+    let runs = vec![(0, bt(100, 0), 4), (10, bt(200, 1), 2), (20, bt(300, 0), 1)];
+    let mut source = runs.clone().into_iter();
+    let mut stream = MappingStream::from_source(Box::new(move || Ok(source.next())))?;
+
+    assert!(stream.peek_n(0)?.is_empty());
+    assert_eq!(stream.peek_n(2)?, runs[..2].to_vec());
+    assert_eq!(stream.peek_n(2)?, runs[..2].to_vec());
+
+    stream.skip(1)?;
+    assert_eq!(
+        stream.peek_n(5)?,
+        vec![(1, bt(101, 0), 3), runs[1], runs[2]]
+    );
+
+    let mut consumed = Vec::new();
+    while let Some(run) = stream.consume_all()? {
+        consumed.push(run);
+    }
+    assert_eq!(consumed, vec![(1, bt(101, 0), 3), runs[1], runs[2]]);
+    assert!(stream.peek_n(1)?.is_empty());
+    Ok(())
+}
+
+// Descriptors opened by a privileged wrapper stand in for the paths
+#[test]
+fn merge_through_descriptors() -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+
+    let input = std::fs::File::open(&md_in)?;
+    let output = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&md_out)?;
+    // let the child process inherit them
+    for f in [&input, &output] {
+        unsafe { libc::fcntl(f.as_raw_fd(), libc::F_SETFD, 0) };
+    }
+    let input_fd = input.as_raw_fd().to_string();
+    let output_fd = output.as_raw_fd().to_string();
+
+    run_ok(thin_merge_cmd(args![
+        "--input-fd",
+        &input_fd,
+        "--output-fd",
+        &output_fd,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert_eq!(md5(&md_expected)?, md5(&md_out)?);
+
+    // the output would overwrite the input being read
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "--input-fd",
+        &output_fd,
+        "--output-fd",
+        &output_fd,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)?.contains("both pass the descriptor"));
+
+    let before = md5(&md_in)?;
+    let stderr = run_fail(thin_merge_cmd(args![
+        "--input-fd",
+        &input_fd,
+        "-o",
+        &md_in,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert!(stderr.contains("the input and the output are the same file or device"));
+    assert_eq!(before, md5(&md_in)?);
+
+    Ok(())
+}
+
+#[test]
+fn merge_over_memory_cap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+
+    let input = load_engine(&std::fs::read(&md_in)?)?;
+    let output = zeroed_engine(input.get_nr_blocks())?;
+    let mut opts = ThinMergeOptions::in_memory(input, output, Arc::new(mk_quiet_report()), 30);
+    opts.snapshot = Some(40);
+    opts.max_mem = Some(4096);
+    let err = merge_thins(opts).unwrap_err();
+    assert!(err.to_string().contains("--max-mem"));
+
+    Ok(())
+}
+
+// Under a tight cap, the leaves are read fewer at a time rather than failing
+#[test]
+fn merge_with_smaller_leaf_batches() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml_before = td.mk_path("before.xml");
+    let md_in = mk_zeroed_md(&mut td)?;
+    let md_out = td.mk_path("out.bin");
+    thinp::file_utils::create_sized_file(&md_out, 16 * 1024 * 1024)?;
+
+    let mut s = FragmentedS::new(2, 131072);
+    write_xml(&xml_before, &mut s)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml_before, "-o", &md_in]))?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--output-engine",
+        "sync",
+        "--origin",
+        "0",
+        "--snapshot",
+        "1",
+        "--base-batch",
+        "65536",
+        "--snap-batch",
+        "65536",
+        "--max-memory",
+        "1"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("leaves at a time rather than"));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    Ok(())
+}
+
+// Reading the leaves ahead of the merge leaves the output as it was
+#[test]
+fn merge_with_prefetch() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml_before = td.mk_path("before.xml");
+    let md_in = mk_zeroed_md(&mut td)?;
+    let md_plain = td.mk_path("plain.bin");
+    let md_prefetched = td.mk_path("prefetched.bin");
+    thinp::file_utils::create_sized_file(&md_plain, 16 * 1024 * 1024)?;
+    thinp::file_utils::create_sized_file(&md_prefetched, 16 * 1024 * 1024)?;
+
+    let mut s = FragmentedS::new(2, 131072);
+    write_xml(&xml_before, &mut s)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml_before, "-o", &md_in]))?;
+
+    for (out, extra) in [
+        (&md_plain, vec![]),
+        (&md_prefetched, vec!["--prefetch", "2"]),
+    ] {
+        let mut args = args![
+            "-i",
+            &md_in,
+            "-o",
+            out,
+            "--origin",
+            "0",
+            "--snapshot",
+            "1",
+            "--base-batch",
+            "4",
+            "--snap-batch",
+            "4"
+        ]
+        .to_vec();
+        args.extend(extra.into_iter().map(OsStr::new));
+        run_ok(thin_merge_cmd(args))?;
+    }
+
+    run_ok(thin_check_cmd(args![&md_prefetched]))?;
+    assert_eq!(md5(&md_plain)?, md5(&md_prefetched)?);
+    Ok(())
+}
+
+// The sizes of the pipeline leave the output as it was
+#[test]
+fn merge_with_pipeline_sizes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_default = mk_zeroed_md(&mut td)?;
+    let md_tuned = td.mk_path("tuned.bin");
+    std::fs::copy(&md_default, &md_tuned)?;
+
+    for (out, extra) in [
+        (&md_default, vec![]),
+        (
+            &md_tuned,
+            vec![
+                "--queue-depth",
+                "1",
+                "--buffer-len",
+                "1",
+                "--write-batch",
+                "1",
+            ],
+        ),
+    ] {
+        let mut args = args![
+            "-i",
+            &md_in,
+            "-o",
+            out,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40"
+        ]
+        .to_vec();
+        args.extend(extra.into_iter().map(OsStr::new));
+        run_ok(thin_merge_cmd(args))?;
+    }
+
+    run_ok(thin_check_cmd(args![&md_tuned]))?;
+    assert_eq!(md5(&md_default)?, md5(&md_tuned)?);
+    Ok(())
+}
+
+// Fails the writes to the given blocks, like bad sectors
+struct BadBlocksEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    bad: Vec<u64>,
+}
+
+impl IoEngine for BadBlocksEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        1
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        1
+    }
+
+    fn read(&self, b: u64) -> std::io::Result<Block> {
+        self.inner.read(b)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> std::io::Result<Vec<std::io::Result<Block>>> {
+        self.inner.read_many(blocks)
+    }
+
+    fn write(&self, block: &Block) -> std::io::Result<()> {
+        if self.bad.contains(&block.loc) {
+            return Err(std::io::Error::other("bad sector"));
+        }
+        self.inner.write(block)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> std::io::Result<Vec<std::io::Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write(b)).collect())
+    }
+}
+
+#[test]
+fn merge_skip_bad_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_mem = td.mk_path("mem.bin");
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+
+    let merge = |skip_bad_blocks| -> Result<Arc<dyn IoEngine + Send + Sync>> {
+        let input = load_engine(&std::fs::read(&md_in)?)?;
+        let inner = zeroed_engine(input.get_nr_blocks())?;
+        let output = Arc::new(BadBlocksEngine {
+            inner: inner.clone(),
+            bad: vec![1, 2, 3, 4],
+        });
+        let mut opts = ThinMergeOptions::in_memory(input, output, Arc::new(mk_quiet_report()), 30);
+        opts.snapshot = Some(40);
+        opts.skip_bad_blocks = skip_bad_blocks;
+        merge_thins(opts)?;
+        Ok(inner)
+    };
+
+    assert!(merge(false).is_err());
+
+    // the same mappings, with the nodes moved off the bad blocks
+    let output = merge(true)?;
+    write_file(&md_mem, &save_engine(output.as_ref())?)?;
+    let expected = run_ok(thin_dump_cmd(args![&md_expected]))?;
+    let dump = run_ok(thin_dump_cmd(args![&md_mem]))?;
+    assert_eq!(expected, dump);
+
+    // the bad blocks are allocated with no owner, so they show as leaks
+    let output = run_fail_raw(thin_check_cmd(args![&md_mem]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("leaked"));
+    run_ok(thin_check_cmd(args![&md_mem, "--ignore-non-fatal-errors"]))?;
+
+    Ok(())
+}
+
+// A damaged input superblock is rebuilt from the roots found in the metadata
+#[test]
+fn merge_auto_repair() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let merge = |input: &std::path::Path, output: &std::path::Path, extra: &[&str]| {
+        let mut args = args![
+            "-i",
+            input,
+            "-o",
+            output,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40"
+        ]
+        .to_vec();
+        args.extend(extra.iter().map(OsStr::new));
+        thin_merge_cmd(args)
+    };
+    run_ok(merge(&md_in, &md_expected, &[]))?;
+
+    let engine = load_engine(&std::fs::read(&md_in)?)?;
+    let b = engine.read(SUPERBLOCK_LOCATION)?;
+    b.get_data()[100] ^= 0xff;
+    engine.write(&b)?;
+    write_file(&md_in, &save_engine(engine.as_ref())?)?;
+
+    run_fail(merge(&md_in, &md_out, &[]))?;
+
+    let output = run_ok_raw(merge(&md_in, &md_out, &["--auto-repair"]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("the input superblock is damaged"));
+    assert!(messages.contains("rebuilt the input superblock"));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    // the same devices, under a superblock of its own
+    let expected = run_ok(thin_dump_cmd(args![&md_expected]))?;
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    let devices = |dump: &str| dump.lines().skip(1).collect::<Vec<_>>().join("\n");
+    assert_eq!(devices(&expected), devices(&dump));
+
+    Ok(())
+}
+
+// A damaged mapping leaf aborts the merge, unless skipped along with its mappings
+#[test]
+fn merge_skip_bad_nodes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let md_in = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    // enough mappings for the tree to spread over several leaves
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"2000\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"2000\" time=\"0\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md_in]))?;
+
+    // flip a byte of the second leaf
+    let engine = load_engine(&std::fs::read(&md_in)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let root = engine.read(roots[&1])?;
+    let (leaf, begin, end) = match unpack_node::<u64>(&[], root.get_data(), true, true)? {
+        Node::Internal { keys, values, .. } => (values[1], keys[1], keys[2]),
+        Node::Leaf { .. } => panic!("the mappings fit in a leaf"),
+    };
+    let b = engine.read(leaf)?;
+    b.get_data()[100] ^= 0xff;
+    engine.write(&b)?;
+    write_file(&md_in, &save_engine(engine.as_ref())?)?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i", &md_in, "-o", &md_out, "--origin", "1"
+    ]))?;
+    assert!(stderr.contains(&format!(
+        "keys of the mapping leaf at block {} are out of order",
+        leaf
+    )));
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "1",
+        "--skip-bad-nodes"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains(&format!(
+        "skipped the damaged mapping leaf at block {}, losing the mappings from key {} up to key {}",
+        leaf, begin, end
+    )));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    // the mappings around the damaged leaf are kept
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    assert!(dump.contains(&format!(
+        "origin_begin=\"0\" data_begin=\"100\" length=\"{}\"",
+        begin
+    )));
+    assert!(dump.contains(&format!(
+        "origin_begin=\"{}\" data_begin=\"{}\" length=\"{}\"",
+        end,
+        100 + end,
+        2000 - end
+    )));
+
+    Ok(())
+}
+
+#[test]
+fn merge_with_stats() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--stats"
+    ]))?;
+
+    Ok(())
+}
+
+// Progress events are newline-delimited json on stderr
+#[test]
+fn merge_with_json_progress() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--report-format",
+        "json"
+    ]))?;
+    let stderr = String::from_utf8(output.stderr)?;
+    let events: Vec<&str> = stderr.lines().filter(|l| l.starts_with('{')).collect();
+    assert!(events[0].contains("\"event\":\"phase\",\"phase\":\"merge\""));
+    assert!(events
+        .iter()
+        .any(|e| e.contains("\"dev_id\":30") && e.contains("\"percent\":100.0")));
+    assert!(events.last().unwrap().contains("\"event\":\"done\""));
+
+    Ok(())
+}
+
+// The final update of a device is forced regardless of the interval
+#[test]
+fn merge_with_report_interval() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    for interval in ["3600", "1blocks"] {
+        let output = run_ok_raw(thin_merge_cmd(args![
+            "-i",
+            &md_in,
+            "-o",
+            &md_out,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40",
+            "--report-format",
+            "json",
+            "--report-interval",
+            interval
+        ]))?;
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(stderr
+            .lines()
+            .any(|e| e.contains("\"dev_id\":30") && e.contains("\"percent\":100.0")));
+    }
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--report-interval",
+        "0blocks"
+    ]))?;
+    assert!(stderr.contains("invalid report interval '0blocks'"));
+    Ok(())
+}
+
+// A dry run leaves the output untouched
+#[test]
+fn merge_dry_run() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let md_zeroed = mk_zeroed_md(&mut td)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--dry-run"
+    ]))?;
+    // the report could go to either stream
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("device 30 would have 34 mapped blocks in 3 runs"));
+    assert_eq!(md5(&md_zeroed)?, md5(&md_out)?);
+
+    Ok(())
+}
+
+#[test]
+fn merge_with_timings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--timings"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("channel stalls: reader blocked"));
+    assert!(messages.contains("over 1 sends"));
+
+    Ok(())
+}
+
+// Only the snapshot mappings sharing no data with the origin are reported
+#[test]
+fn merge_what_changes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"14\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"300\" length=\"5\" time=\"1\"/>
+    <range_mapping origin_begin=\"10\" data_begin=\"400\" length=\"5\" time=\"1\"/>
+    <range_mapping origin_begin=\"15\" data_begin=\"115\" length=\"2\" time=\"0\"/>
+    <range_mapping origin_begin=\"20\" data_begin=\"500\" length=\"2\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta]))?;
+
+    // no output is needed
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &meta,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2",
+        "--what-changes"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    for range in ["changed: 0..5", "changed: 10..15", "changed: 20..22"] {
+        assert!(messages.contains(range));
+    }
+    assert!(!messages.contains("changed: 15..17"));
+    assert!(messages.contains("the merge would change 12 blocks of origin 1 in 3 ranges"));
+
+    Ok(())
+}
+
+// The devices not taking part in the merge are copied as they are
+#[test]
+fn merge_keep_other_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--keep-other-devices"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    for id in ["10", "20", "30", "50"] {
+        assert!(dump.contains(&format!("dev_id=\"{}\"", id)));
+    }
+    // the snapshot is merged into the origin
+    assert!(!dump.contains("dev_id=\"40\""));
+
+    // the kept device has the mappings and details of the input, while the
+    // superblock differs
+    let device = |dump: &str| -> String {
+        let begin = dump.find("<device").unwrap();
+        let end = dump.find("</device>").unwrap();
+        dump[begin..end].to_string()
+    };
+    let dump_in = run_ok(thin_dump_cmd(args![&md_in, "--dev-id", "50"]))?;
+    let dump_out = run_ok(thin_dump_cmd(args![&md_out, "--dev-id", "50"]))?;
+    assert_eq!(device(&dump_in), device(&dump_out));
+
+    // the kept devices dumped one at a time or side by side write the same
+    let md_serial = mk_zeroed_md(&mut td)?;
+    let md_parallel = mk_zeroed_md(&mut td)?;
+    for (threads, md) in [("1", &md_serial), ("4", &md_parallel)] {
+        run_ok(thin_merge_cmd(args![
+            "-i",
+            &md_in,
+            "-o",
+            md,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40",
+            "--keep-other-devices",
+            "--deterministic",
+            "--dump-threads",
+            threads
+        ]))?;
+    }
+    run_ok(thin_check_cmd(args![&md_parallel]))?;
+    assert_eq!(md5(&md_serial)?, md5(&md_parallel)?);
+
+    Ok(())
+}
+
+// The leaves shared by the kept devices are written once, so the output shares
+// them as the input does
+#[test]
+fn merge_keep_shared_leaves() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let stderr = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "20",
+        "--keep-other-devices"
+    ]))?
+    .stderr;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+    assert!(String::from_utf8_lossy(&stderr).contains("the kept devices share 1 leaves"));
+
+    let engine = load_engine(&std::fs::read(&md_out)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    assert_eq!(roots[&40], roots[&50]);
+
+    let dump_in = run_ok(thin_dump_cmd(args![&md_in, "--dev-id", "50"]))?;
+    let dump_out = run_ok(thin_dump_cmd(args![&md_out, "--dev-id", "50"]))?;
+    let mappings = |dump: &str| -> Vec<String> {
+        dump.lines()
+            .filter(|l| l.contains("mapping"))
+            .map(|l| l.trim().to_string())
+            .collect()
+    };
+    assert_eq!(mappings(&dump_in), mappings(&dump_out));
+
+    Ok(())
+}
+
+// A shared leaf is checked as any other leaf before its mappings are written
+#[test]
+fn merge_keep_damaged_shared_leaf() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let engine = load_engine(&std::fs::read(&md_in)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let leaf = roots[&50];
+
+    // keys out of order within the shared leaf, under a valid checksum, and
+    // the last key in place
+    let b = engine.read(leaf)?;
+    let mut node = unpack_node::<BlockTime>(&[], b.get_data(), true, true)?;
+    if let Node::Leaf { ref mut keys, .. } = node {
+        keys.swap(0, 1);
+    }
+    let mut cursor = std::io::Cursor::new(b.get_data());
+    pack_node(&node, &mut cursor)?;
+    thinp::checksum::write_checksum(b.get_data(), thinp::checksum::BT::NODE)?;
+    engine.write(&b)?;
+    write_file(&md_in, &save_engine(engine.as_ref())?)?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "20",
+        "--keep-other-devices"
+    ]))?;
+    assert!(stderr.contains(&format!(
+        "keys of the mapping leaf at block {} are out of order",
+        leaf
+    )));
+
+    Ok(())
+}
+
+// A shared leaf intact but for its checksum is caught before it's unpacked
+#[test]
+fn merge_shared_leaf_with_bad_checksum() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let engine = load_engine(&std::fs::read(&md_in)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let leaf = roots[&50];
+
+    // the checksum leads the node header
+    let b = engine.read(leaf)?;
+    b.get_data()[0] ^= 0xff;
+    engine.write(&b)?;
+    write_file(&md_in, &save_engine(engine.as_ref())?)?;
+
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "20",
+        "--keep-other-devices"
+    ]))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!(
+        "bad checksum of the mapping leaf at block {}",
+        leaf
+    )));
+    assert_eq!(output.status.code(), Some(4));
+
+    Ok(())
+}
+
+// The merged device joins the devices already in the output
+#[test]
+fn merge_append_to_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let xml = td.mk_path("existing.xml");
+
+    let content = b"<superblock uuid=\"\" time=\"5\" transaction=\"3\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md_out]))?;
+
+    let merge = || {
+        thin_merge_cmd(args![
+            "-i",
+            &md_in,
+            "-o",
+            &md_out,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40",
+            "--append"
+        ])
+    };
+    // the blocks past the reservation are free again in the space map written
+    let output = run_ok_raw(merge())?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("reserved the first"));
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    assert!(dump.contains("dev_id=\"30\""));
+    assert!(dump.contains("origin_begin=\"0\" data_begin=\"100\" length=\"20\""));
+    assert!(dump.contains("transaction=\"3\""));
+
+    // the merged device is in the output already, which is left as it was
+    let before = md5(&md_out)?;
+    let stderr = run_fail(merge())?;
+    assert!(stderr.contains("device 30 already exists in the output"));
+    assert_eq!(md5(&md_out)?, before);
+
+    assert!(staged_beside(&md_out)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn merge_with_new_dev_id() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--new-dev-id",
+        "60"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md_out]))?;
+
+    // the same mappings under the new id
+    let expected = run_ok(thin_dump_cmd(args![&md_expected]))?;
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    assert_eq!(expected.replace("dev_id=\"30\"", "dev_id=\"60\""), dump);
+
+    // the new id collides with a device kept in the output
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--new-dev-id",
+        "50",
+        "--keep-other-devices"
+    ]))?;
+    assert!(stderr.contains("device id 50 is used twice in the output"));
+
+    Ok(())
+}
+
+#[test]
+fn merge_with_repair_compat_check() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--rebase",
+        "--emit-residue",
+        "--repair-compat-check"
+    ]))?;
+
+    Ok(())
+}
+
+// Splits the image into frames and appends the seek table
+#[cfg(feature = "zstd")]
+fn compress_seekable(input: &std::path::Path, output: &std::path::Path) -> Result<()> {
+    let data = std::fs::read(input)?;
+    let mut image = Vec::new();
+    let mut table = Vec::new();
+    for chunk in data.chunks(65536) {
+        let frame = zstd::bulk::compress(chunk, 3)?;
+        table.extend((frame.len() as u32).to_le_bytes());
+        table.extend((chunk.len() as u32).to_le_bytes());
+        image.extend(frame);
+    }
+    let nr_frames = (table.len() / 8) as u32;
+    table.extend(nr_frames.to_le_bytes());
+    table.push(0); // no checksums
+    table.extend(0x8f92eab1u32.to_le_bytes());
+
+    image.extend(0x184d2a5eu32.to_le_bytes());
+    image.extend((table.len() as u32).to_le_bytes());
+    image.extend(table);
+    write_file(output, &image)?;
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn merge_from_seekable_zstd() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_zst = td.mk_path("meta.bin.zst");
+    compress_seekable(&md_in, &md_zst)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    for (input, output) in [(&md_in, &md_expected), (&md_zst, &md_out)] {
+        run_ok(thin_merge_cmd(args![
+            "-i",
+            input,
+            "-o",
+            output,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40"
+        ]))?;
+    }
+    assert_eq!(md5(&md_expected)?, md5(&md_out)?);
+
+    Ok(())
+}
+
+// The C bindings merge as the command line does, and report the failures
+// through the exit code and the error buffer
+#[cfg(feature = "ffi")]
+#[test]
+fn merge_through_ffi() -> Result<()> {
+    use std::ffi::{c_char, CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use thin_merge::thin_merge_ffi::*;
+
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+
+    let c_path = |p: &std::path::Path| CString::new(p.as_os_str().as_bytes());
+    let input = c_path(&md_in)?;
+    let output = c_path(&md_out)?;
+    let mut opts = ThinMergeFfiOptions {
+        input: input.as_ptr(),
+        output: output.as_ptr(),
+        origin: 30,
+        snapshot: 40,
+        has_snapshot: true,
+        rebase: false,
+        dry_run: false,
+        verify: false,
+        deterministic: false,
+        quiet: true,
+    };
+    let mut err_buf = [0 as c_char; 256];
+    let rc = unsafe { thin_merge_run(&opts, err_buf.as_mut_ptr(), err_buf.len()) };
+    assert_eq!(rc, 0);
+    assert_eq!(md5(&md_expected)?, md5(&md_out)?);
+
+    // the progress events come through the callback of the handle
+    unsafe extern "C" fn collect(arg: *mut std::ffi::c_void, event: *const c_char) {
+        let events = &*(arg as *const std::sync::Mutex<Vec<String>>);
+        let event = CStr::from_ptr(event).to_string_lossy().into_owned();
+        events.lock().unwrap().push(event);
+    }
+    let events = std::sync::Mutex::new(Vec::new());
+    let handle = thin_merge_handle_new();
+    unsafe {
+        thin_merge_handle_set_progress(
+            handle,
+            Some(collect),
+            &events as *const _ as *mut std::ffi::c_void,
+        )
+    };
+    let rc = unsafe { thin_merge_run_with(&opts, handle, err_buf.as_mut_ptr(), err_buf.len()) };
+    assert_eq!(rc, 0);
+    unsafe { thin_merge_handle_set_progress(handle, None, std::ptr::null_mut()) };
+    let events = events.into_inner().unwrap();
+    let events: Vec<Versioned<ProgressEvent>> = events
+        .iter()
+        .map(|e| serde_json::from_str(e))
+        .collect::<std::result::Result<_, _>>()?;
+    assert!(events
+        .iter()
+        .any(|e| matches!(&e.body, ProgressEvent::Progress { dev_id: 30, .. })));
+    assert_eq!(events.last().unwrap().body, ProgressEvent::Done);
+
+    // a cancelled merge fails, leaving the output as it was
+    let before = md5(&md_out)?;
+    unsafe { thin_merge_handle_cancel(handle) };
+    let rc = unsafe { thin_merge_run_with(&opts, handle, err_buf.as_mut_ptr(), err_buf.len()) };
+    assert_eq!(rc, 7);
+    let err = unsafe { CStr::from_ptr(err_buf.as_ptr()) }.to_string_lossy();
+    assert!(err.contains("the merge was cancelled"));
+    assert_eq!(md5(&md_out)?, before);
+    unsafe { thin_merge_handle_free(handle) };
+
+    let missing = c_path(&td.mk_path("missing.bin"))?;
+    opts.input = missing.as_ptr();
+    let rc = unsafe { thin_merge_run(&opts, err_buf.as_mut_ptr(), err_buf.len()) };
+    assert_eq!(rc, 3);
+    let err = unsafe { CStr::from_ptr(err_buf.as_ptr()) }.to_string_lossy();
+    assert!(!err.is_empty());
+
+    Ok(())
+}
+
+// The input format is sniffed, and the output format follows the extension
+#[test]
+fn merge_with_xml_input_and_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml_in = td.mk_path("meta.xml");
+    mk_default_xml(&xml_in)?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let xml_expected = td.mk_path("expected.xml");
+    let xml_out = td.mk_path("out.xml");
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    run_ok(thin_dump_cmd(args![&md_expected, "-o", &xml_expected]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &xml_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert_eq!(md5(&md_expected)?, md5(&md_out)?);
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &xml_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert_eq!(md5(&xml_expected)?, md5(&xml_out)?);
+
+    // the staged copy of xml input is sized by the content if the output
+    // doesn't tell
+    let xml_out2 = td.mk_path("out2.xml");
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &xml_in,
+        "-o",
+        &xml_out2,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert_eq!(md5(&xml_expected)?, md5(&xml_out2)?);
+
+    Ok(())
+}
+
+// The merged metadata is packed as thin_metadata_pack would, and read back
+#[test]
+fn merge_with_pack_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let packed = td.mk_path("merged.out");
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+
+    // the format is given, as the extension doesn't tell
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &packed,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--output-format",
+        "pack"
+    ]))?;
+    let data = std::fs::read(&packed)?;
+    assert_eq!(data[0..8], 0xa537a0aa6309ef77u64.to_le_bytes());
+    assert!(data.len() < std::fs::metadata(&md_expected)?.len() as usize);
+
+    // copying the merged device back out of the pack
+    run_ok(thin_merge_cmd(args![
+        "-i", &packed, "-o", &md_out, "--origin", "30"
+    ]))?;
+    let expected = run_ok(thin_dump_cmd(args![&md_expected]))?;
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    assert_eq!(expected, dump);
+
+    Ok(())
+}
+
+// Mappings at the end of the address space fail the merge rather than wrapping around
+#[test]
+fn merge_with_overflowing_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"1\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <single_mapping origin_block=\"18446744073709551615\" data_block=\"200\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "2",
+        "--snapshot",
+        "1"
+    ]))?;
+    assert!(stderr.contains("overflows"));
+
+    Ok(())
+}
+
+// Xml streams through named pipes without temporary files
+#[test]
+fn merge_through_named_pipes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let xml_expected = td.mk_path("expected.xml");
+    let xml_out = td.mk_path("out.xml");
+    let fifo_in = td.mk_path("in.fifo");
+    let fifo_out = td.mk_path("out.fifo");
+    run_ok(system_cmd("mkfifo", args![&fifo_in, &fifo_out]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    run_ok(thin_dump_cmd(args![&md_expected, "-o", &xml_expected]))?;
+
+    // thin_dump | thin_merge
+    let writer = {
+        let (md_in, fifo_in) = (md_in.clone(), fifo_in.clone());
+        std::thread::spawn(move || run_ok(thin_dump_cmd(args![&md_in, "-o", &fifo_in])))
+    };
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &fifo_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    writer.join().unwrap()?;
+    assert_eq!(md5(&md_expected)?, md5(&md_out)?);
+
+    // thin_merge | cat
+    let reader = {
+        let (fifo_out, xml_out) = (fifo_out.clone(), xml_out.clone());
+        std::thread::spawn(move || -> Result<()> {
+            let output = run_ok_raw(system_cmd("cat", args![&fifo_out]))?;
+            write_file(&xml_out, &output.stdout)
+        })
+    };
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &fifo_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    reader.join().unwrap()?;
+    assert_eq!(md5(&xml_expected)?, md5(&xml_out)?);
+
+    Ok(())
+}
+
+// Xml streams through stdin and stdout, given as -
+#[test]
+fn merge_through_std_streams() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let xml_expected = td.mk_path("expected.xml");
+    let xml_out = td.mk_path("out.xml");
+    let redirected = td.mk_path("redirected.out");
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    run_ok(thin_dump_cmd(args![&md_expected, "-o", &xml_expected]))?;
+
+    // thin_dump | thin_merge, sized by the xml staged in a file
+    let merge = thin_merge_cmd(args![
+        "-i",
+        "-",
+        "-o",
+        "-",
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]);
+    let output = thin_dump_cmd(args![&md_in])
+        .to_expr()
+        .pipe(merge.to_expr())
+        .stdout_capture()
+        .run()?;
+    write_file(&xml_out, &output.stdout)?;
+    assert_eq!(md5(&xml_expected)?, md5(&xml_out)?);
+
+    // xml even if stdout is redirected to a file
+    let merge = thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        "-",
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]);
+    merge.to_expr().stdout_path(&redirected).run()?;
+    assert_eq!(md5(&xml_expected)?, md5(&redirected)?);
+
+    // stdout takes either the output or the summary
+    run_fail(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        "-",
+        "--origin",
+        "30",
+        "--summary-file",
+        "-"
+    ]))?;
+
+    Ok(())
+}
+
+// The origin written after the snapshot creation is refused by default
+#[test]
+fn merge_with_diverged_origin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"2\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"0\"/>
+    <range_mapping origin_begin=\"10\" data_begin=\"200\" length=\"10\" time=\"2\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"5\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"5\" data_begin=\"300\" length=\"5\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2"
+    ]))?;
+    assert!(stderr.contains("--accept-diverged-origin"));
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2",
+        "--accept-diverged-origin"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    Ok(())
+}
+
+// The engine choices don't affect the output
+#[test]
+fn merge_with_auto_engines() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--engine",
+        "auto",
+        "--output-engine",
+        "auto"
+    ]))?;
+    assert_eq!(md5(&md_expected)?, md5(&md_out)?);
+
+    Ok(())
+}
+
+// The failures worth telling apart exit with codes of their own
+#[test]
+fn exit_codes_by_failure() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let code = |output: std::process::Output| output.status.code();
+
+    // usage
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i", &md_in, "-o", &md_out, "--chain", "30"
+    ]))?;
+    assert_eq!(code(output), Some(2));
+
+    // input not found
+    let missing = td.mk_path("missing.bin");
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i", &missing, "-o", &md_out, "--origin", "30"
+    ]))?;
+    assert_eq!(code(output), Some(3));
+
+    // any other file missing isn't the input
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--skip-zeroed",
+        &missing
+    ]))?;
+    assert_eq!(code(output), Some(1));
+
+    // usage caught past the argument parser
+    let xml_out = td.mk_path("out.xml");
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i", &md_in, "-o", &xml_out, "--origin", "30", "--append"
+    ]))?;
+    assert_eq!(code(output), Some(2));
+
+    // corrupted metadata
+    let zeroed = mk_zeroed_md(&mut td)?;
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i", &zeroed, "-o", &md_out, "--origin", "30"
+    ]))?;
+    assert_eq!(code(output), Some(4));
+
+    // out of space in the output
+    let tiny = mk_zeroed_md_sized(&mut td, 4 * 4096)?;
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "-i", &md_in, "-o", &tiny, "--origin", "30"
+    ]))?;
+    assert!(String::from_utf8_lossy(&output.stderr).contains("output needs at least"));
+    assert_eq!(code(output), Some(5));
+
+    // verification failure
+    run_ok(thin_merge_cmd(args![
+        "-i", &md_in, "-o", &md_out, "--origin", "30"
+    ]))?;
+    let output = run_fail_raw(thin_merge_cmd(args![
+        "verify",
+        "--before",
+        &md_in,
+        "--after",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+    assert_eq!(code(output), Some(6));
+
+    Ok(())
+}
+
+// A merged output is verified against its input without dumping
+#[test]
+fn verify_merged_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--rebase"
+    ]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "verify",
+        "--before",
+        &md_in,
+        "--after",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--rebase"
+    ]))?;
+
+    // the output doesn't match the snapshot over another origin
+    let stderr = run_fail(thin_merge_cmd(args![
+        "verify",
+        "--before",
+        &md_in,
+        "--after",
+        &md_out,
+        "--origin",
+        "10",
+        "--snapshot",
+        "40",
+        "--rebase"
+    ]))?;
+    assert!(stderr.contains("differs from the merge"));
+
+    Ok(())
+}
+
+// The overlap of a snapshot with its origin is reported without merging
+#[test]
+fn stats_of_overlap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let md = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"30\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+    <range_mapping origin_begin=\"40\" data_begin=\"300\" length=\"10\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"15\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"10\" data_begin=\"500\" length=\"15\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    let before = md5(&md)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "stats",
+        "-i",
+        &md,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("blocks only in origin 1: 20"));
+    assert!(messages.contains("blocks only in snapshot 2: 5"));
+    assert!(messages.contains("overlapping blocks: 10"));
+    assert!(messages.contains("merged mapped blocks: 35"));
+    assert!(messages.contains("origin runs: 2"));
+    assert!(messages.contains("snapshot runs: 1"));
+
+    // nothing is written
+    assert_eq!(md5(&md)?, before);
+
+    Ok(())
+}
+
+// The ranges a snapshot differs from its origin are listed as thin_delta does
+#[test]
+fn diff_origin_and_snapshot() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let md = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"30\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+    <range_mapping origin_begin=\"40\" data_begin=\"300\" length=\"10\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"5\" time=\"0\"/>
+    <range_mapping origin_begin=\"10\" data_begin=\"500\" length=\"15\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    let diff = run_ok(thin_merge_cmd(args![
+        "diff",
+        "-i",
+        &md,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2"
+    ]))?;
+    let expected = "<diff left=\"1\" right=\"2\">
+  <same begin=\"0\" data_begin=\"100\" length=\"5\"/>
+  <left_only begin=\"5\" data_begin=\"105\" length=\"5\"/>
+  <different begin=\"10\" left_data_begin=\"110\" right_data_begin=\"500\" length=\"10\"/>
+  <right_only begin=\"20\" data_begin=\"510\" length=\"5\"/>
+  <left_only begin=\"40\" data_begin=\"300\" length=\"10\"/>
+</diff>";
+    assert_eq!(diff.trim(), expected);
+
+    let diff = run_ok(thin_merge_cmd(args![
+        "diff",
+        "-i",
+        &md,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2",
+        "--format",
+        "json"
+    ]))?;
+    assert!(diff.starts_with("{\"schema_version\":1,\"left\":1,\"right\":2,\"ranges\":["));
+    assert!(diff.contains(
+        "{\"kind\":\"different\",\"begin\":10,\"length\":10,\"left_data_begin\":110,\"right_data_begin\":500}"
+    ));
+    assert_eq!(diff.matches("\"kind\"").count(), 5);
+    let report: DiffReport = serde_json::from_str(&diff)?;
+    assert_eq!(report.ranges.len(), 5);
+    assert_eq!(
+        report.ranges[2],
+        DiffRange::Different {
+            begin: 10,
+            length: 10,
+            left_data_begin: 110,
+            right_data_begin: 500
+        }
+    );
+
+    // only the writes to the snapshot at time 1 are left, where the origin
+    // blocks overwritten since show as different, not right_only
+    let diff = run_ok(thin_merge_cmd(args![
+        "diff",
+        "-i",
+        &md,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2",
+        "--since-time",
+        "1"
+    ]))?;
+    let expected = "<diff left=\"1\" right=\"2\">
+  <left_only begin=\"0\" data_begin=\"100\" length=\"10\"/>
+  <different begin=\"10\" left_data_begin=\"110\" right_data_begin=\"500\" length=\"10\"/>
+  <right_only begin=\"20\" data_begin=\"510\" length=\"5\"/>
+  <left_only begin=\"40\" data_begin=\"300\" length=\"10\"/>
+</diff>";
+    assert_eq!(diff.trim(), expected);
+
+    Ok(())
+}
+
+// Snapshot runs remapping the origin blocks keep the origin times
+#[test]
+fn merge_with_identical_remaps() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"1\"/>
+    <range_mapping origin_begin=\"10\" data_begin=\"500\" length=\"10\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.contains(
+        "<range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"0\"/>"
+    ));
+    assert!(dump.contains(
+        "<range_mapping origin_begin=\"10\" data_begin=\"500\" length=\"10\" time=\"1\"/>"
+    ));
+
+    Ok(())
+}
+
+// Snapshots stacked over the origin are flattened in one pass
+#[test]
+fn merge_snapshot_chain() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"2\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"5\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"5\" data_begin=\"300\" length=\"5\" time=\"1\"/>
+  </device>
+  <device dev_id=\"3\" mapped_blocks=\"7\" transaction=\"0\" creation_time=\"2\" snap_time=\"2\">
+    <range_mapping origin_begin=\"8\" data_begin=\"400\" length=\"7\" time=\"2\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--chain",
+        "1,2,3"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.contains("dev_id=\"1\" mapped_blocks=\"20\""));
+    for run in [
+        "origin_begin=\"0\" data_begin=\"100\" length=\"5\" time=\"0\"",
+        "origin_begin=\"5\" data_begin=\"300\" length=\"3\" time=\"1\"",
+        "origin_begin=\"8\" data_begin=\"400\" length=\"7\" time=\"2\"",
+        "origin_begin=\"15\" data_begin=\"115\" length=\"5\" time=\"0\"",
+    ] {
+        assert!(dump.contains(run));
+    }
+
+    // a chain needs a snapshot over the origin
+    run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--chain",
+        "1"
+    ]))?;
+
+    Ok(())
+}
+
+// Snapshot mappings older than --since-time leave the origin untouched
+#[test]
+fn merge_since_time() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"3\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"3\" snap_time=\"3\">
+    <range_mapping origin_begin=\"0\" data_begin=\"300\" length=\"5\" time=\"1\"/>
+    <range_mapping origin_begin=\"10\" data_begin=\"400\" length=\"5\" time=\"3\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2",
+        "--since-time",
+        "2"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    for run in [
+        "origin_begin=\"0\" data_begin=\"100\" length=\"10\" time=\"0\"",
+        "origin_begin=\"10\" data_begin=\"400\" length=\"5\" time=\"3\"",
+        "origin_begin=\"15\" data_begin=\"115\" length=\"5\" time=\"0\"",
+    ] {
+        assert!(dump.contains(run));
+    }
+    assert!(!dump.contains("data_begin=\"300\""));
+
+    Ok(())
+}
+
+#[test]
+fn merge_without_origin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+
+    let content = b"<superblock uuid=\"\" time=\"3\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"20\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"100\" length=\"20\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"3\" snap_time=\"3\">
+    <range_mapping origin_begin=\"0\" data_begin=\"300\" length=\"5\" time=\"1\"/>
+    <range_mapping origin_begin=\"10\" data_begin=\"400\" length=\"5\" time=\"3\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &meta_before]))?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "none",
+        "--snapshot",
+        "2"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    // the hole in the middle is left unmapped rather than filled by the origin
+    let dump = run_ok(thin_dump_cmd(args![&meta_after]))?;
+    assert!(dump.contains("dev_id=\"2\""));
+    assert!(!dump.contains("dev_id=\"1\""));
+    assert!(!dump.contains("data_begin=\"100\""));
+    assert!(dump.contains("origin_begin=\"0\" data_begin=\"300\" length=\"5\""));
+    assert!(dump.contains("origin_begin=\"10\" data_begin=\"400\" length=\"5\""));
+
+    // a snapshot is required without the origin
+    run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "none"
+    ]))?;
+
+    Ok(())
+}
+
+// The origin is kept intact alongside the rebased device
+#[test]
+fn rebase_with_residue() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_before = mk_metadata(&mut td)?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+    let xml_expected = td.mk_path("expected.xml");
+    let xml_after = td.mk_path("after.xml");
+
+    run_ok(thin_check_cmd(args![&meta_before]))?;
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "30",
+        "--snapshot",
+        "20",
+        "--rebase",
+        "--emit-residue"
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+
+    run_ok(thin_dump_cmd(args![
+        &meta_before,
+        "--dev-id",
+        "30",
+        "-o",
+        &xml_expected
+    ]))?;
+    run_ok(thin_dump_cmd(args![
+        &meta_after,
+        "--dev-id",
+        "30",
+        "-o",
+        &xml_after
+    ]))?;
+    assert_eq!(md5(&xml_expected)?, md5(&xml_after)?);
+
+    Ok(())
+}
+
+#[test]
+fn out_of_metadata_space() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml_before = td.mk_path("before.xml");
+    let meta_before = mk_zeroed_md(&mut td)?;
+    let meta_after = td.mk_path("meta.bin");
+    thinp::file_utils::create_sized_file(&meta_after, 1_048_576)?; // 1MB
+
+    let mut s = FragmentedS::new(2, 131072);
+    write_xml(&xml_before, &mut s)?;
+    run_ok(thin_restore_cmd(args![
+        "-i",
+        &xml_before,
+        "-o",
+        &meta_before
+    ]))?;
+    run_ok(thin_check_cmd(args![&meta_before]))?;
+
+    // the generated thin ids start by 0, and the shortage is told before
+    // the output is touched
+    let before = md5(&meta_after)?;
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--origin",
+        "0",
+        "--snapshot",
+        "1"
+    ]))?;
+    assert!(stderr.contains("output needs at least"));
+    assert!(stderr.contains("blocks, has 256"));
+    assert_eq!(md5(&meta_after)?, before);
+
+    Ok(())
+}
+
+// The metadata device of a live pool is held open exclusively by the pool, so
+// checking for its metadata snapshot must not take it exclusively too
+#[test]
+fn reserve_metasnap_on_a_held_device() -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use thin_merge::pool::PoolSnapshot;
+
+    // only root is able to set up a loop device
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    let mut td = TestDir::new()?;
+    let md = mk_metadata(&mut td)?;
+
+    // a snapshot reserved already is left to its owner, without any pool
+    let engine = load_engine(&std::fs::read(&md)?)?;
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    sb.metadata_snap = 1;
+    write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb)?;
+    write_file(&md, &save_engine(engine.as_ref())?)?;
+
+    let Ok(output) = std::process::Command::new("losetup")
+        .args([OsStr::new("--find"), OsStr::new("--show"), md.as_os_str()])
+        .output()
+    else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        return Ok(());
+    }
+    let loop_dev = std::path::PathBuf::from(String::from_utf8(output.stdout)?.trim());
+
+    // held as the pool holds it
+    let held = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_EXCL)
+        .open(&loop_dev);
+    let result = held
+        .map_err(anyhow::Error::from)
+        .and_then(|_held| PoolSnapshot::reserve_if_missing(&loop_dev, Arc::new(mk_quiet_report())));
+    std::process::Command::new("losetup")
+        .arg("-d")
+        .arg(&loop_dev)
+        .status()?;
+
+    assert!(result?.is_none());
+    Ok(())
+}
+
+// Temporary files leaked on any exit path are removed by the cleanup guard
+#[test]
+fn cleanup_leaked_temp_files() -> Result<()> {
+    let tmp = TempPath::new("leaked")?;
+    write_file(tmp.path(), b"debris")?;
+    let path = tmp.path().to_path_buf();
+    std::mem::forget(tmp);
+
+    drop(CleanupGuard);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn doctor_without_merge_arguments() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_after = mk_zeroed_md(&mut td)?;
+    run_ok(thin_merge_cmd(args!["--doctor", "-o", &meta_after]))?;
+    Ok(())
+}
+
+#[test]
+fn list_snapshots_of_origin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_before = mk_metadata(&mut td)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "--list-snapshots-of",
+        "40"
+    ]))?;
+
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+    assert!(messages.contains("1 candidate snapshots of origin 40"));
+    assert!(messages.contains("snapshot 50:"));
+    assert!(messages.contains("shared with origin 40, 0.0% diverged"));
+    assert!(!messages.contains("snapshot 30:"));
+    Ok(())
+}
+
+#[test]
+fn list_snapshots_of_missing_origin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_before = mk_metadata(&mut td)?;
+
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &meta_before,
+        "--list-snapshots-of",
+        "99"
+    ]))?;
+    assert!(stderr.contains("Unable to find mapping tree for the device 99"));
+    Ok(())
+}
+
+// The pool is located and its metadata snapshot reserved through dmsetup, which
+// is stood in for by a script logging its arguments. The snapshot is released
+// even if the merge fails.
+#[test]
+fn merge_from_pool_releases_snapshot() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut td = TestDir::new()?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let bin = td.mk_path("bin");
+    std::fs::create_dir(&bin)?;
+    let log = td.mk_path("dmsetup.log");
+    let path = format!(
+        "{}:{}",
+        bin.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let run_with_table = |table: &str| -> Result<Option<i32>> {
+        let dmsetup = bin.join("dmsetup");
+        write_file(
+            &dmsetup,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\n[ \"$1\" = table ] && echo \"{}\"\nexit 0\n",
+                log.display(),
+                table
+            )
+            .as_bytes(),
+        )?;
+        std::fs::set_permissions(&dmsetup, std::fs::Permissions::from_mode(0o755))?;
+        let _ = std::fs::remove_file(&log);
+
+        let output = thin_merge_cmd(args![
+            "--pool",
+            "pool0",
+            "-o",
+            &md_out,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40"
+        ])
+        .to_expr()
+        .env("PATH", &path)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+        Ok(output.status.code())
+    };
+
+    // the metadata device of the pool is missing
+    let rc = run_with_table("0 2048 thin-pool 253:4095 253:4094 128 0 0")?;
+    assert_eq!(rc, Some(3));
+    assert_eq!(
+        std::fs::read_to_string(&log)?,
+        "table pool0\nmessage pool0 0 reserve_metadata_snap\nmessage pool0 0 release_metadata_snap\n"
+    );
+
+    // nothing is reserved on a device other than a pool
+    let rc = run_with_table("0 2048 linear 8:0 0")?;
+    assert_eq!(rc, Some(2));
+    assert_eq!(std::fs::read_to_string(&log)?, "table pool0\n");
+
+    Ok(())
+}
+
+// A metadata snapshot is reserved only if missing, on the pool found running on
+// the input, so the input without a pool fails before reserving anything
+#[test]
+fn merge_auto_reserve_metasnap() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let bin = td.mk_path("bin");
+    std::fs::create_dir(&bin)?;
+    let log = td.mk_path("dmsetup.log");
+    let dmsetup = bin.join("dmsetup");
+    write_file(
+        &dmsetup,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\necho \"pool0: 0 2048 thin-pool 253:4095 253:4094 128 0 0\"\n",
+            log.display()
+        )
+        .as_bytes(),
+    )?;
+    std::fs::set_permissions(&dmsetup, std::fs::Permissions::from_mode(0o755))?;
+    let path = format!(
+        "{}:{}",
+        bin.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_expected,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40"
+    ]))?;
+
+    let merge = || {
+        thin_merge_cmd(args![
+            "-i",
+            &md_in,
+            "-o",
+            &md_out,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40",
+            "-m",
+            "--auto-reserve-metasnap"
+        ])
+        .to_expr()
+        .env("PATH", &path)
+        .stderr_capture()
+        .unchecked()
+        .run()
+    };
+
+    let output = merge()?;
+    assert!(!output.status.success());
+    assert_eq!(std::fs::read_to_string(&log)?, "table --target thin-pool\n");
+    std::fs::remove_file(&log)?;
+
+    // a copy of the superblock at the last block stands for the snapshot
+    let engine = load_engine(&std::fs::read(&md_in)?)?;
+    let snap_loc = engine.get_nr_blocks() - 1;
+    let b = engine.read(SUPERBLOCK_LOCATION)?;
+    let snap = Block::new(snap_loc);
+    snap.get_data().copy_from_slice(b.get_data());
+    engine.write(&snap)?;
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    sb.metadata_snap = snap_loc;
+    write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb)?;
+    write_file(&md_in, &save_engine(engine.as_ref())?)?;
+
+    let output = merge()?;
+    assert!(output.status.success());
+    assert!(!log.exists());
+    assert_eq!(
+        run_ok(thin_dump_cmd(args![&md_expected]))?,
+        run_ok(thin_dump_cmd(args![&md_out]))?
+    );
+
+    Ok(())
+}
+
+// Every decision of the merge is logged along with the runs compared
+#[test]
+fn merge_with_trace() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+    let trace = td.mk_path("trace.log");
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--trace-merges",
+        &trace
+    ]))?;
+
+    let expected = "\
+level 1: base_ends_before, base [274, 291) -> 8440 @ 0, snap [339, 349) -> 1036 @ 0, consumed 17 from base, 0 from snap
+level 1: snap_ends_before, base [485, 492) -> 15480 @ 0, snap [339, 349) -> 1036 @ 0, consumed 0 from base, 10 from snap
+level 1: base_rest, base [485, 492) -> 15480 @ 0, snap -, consumed 7 from base, 0 from snap
+";
+    assert_eq!(std::fs::read_to_string(&trace)?, expected);
+
+    Ok(())
+}
+
+// Leaves holding keys outside the ranges their parents tell are placed by
+// their keys once sorted
+#[test]
+fn merge_sorted_leaves_misplaced_under_parent() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    let md_in = mk_zeroed_md(&mut td)?;
+    let md_swapped = mk_zeroed_md(&mut td)?;
+    let md_expected = mk_zeroed_md(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    // enough mappings for the origin to span several leaves
+    let content = b"<superblock uuid=\"\" time=\"1\" transaction=\"0\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"16384\">
+  <device dev_id=\"1\" mapped_blocks=\"1000\" transaction=\"0\" creation_time=\"0\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"1000\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"5\" data_begin=\"2000\" length=\"10\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, content)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md_in]))?;
+
+    // swap the contents of the first two leaves of the origin, so each holds
+    // the keys the parent tells the other one holds
+    let engine = load_engine(&std::fs::read(&md_in)?)?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let root = engine.read(roots[&1])?;
+    let leaves = match unpack_node::<u64>(&[], root.get_data(), true, true)? {
+        Node::Internal { values, .. } => values,
+        Node::Leaf { .. } => panic!("the origin fits in a leaf"),
+    };
+    let b0 = engine.read(leaves[0])?;
+    let b1 = engine.read(leaves[1])?;
+    let n0 = unpack_node::<BlockTime>(&[], b0.get_data(), true, false)?;
+    let n1 = unpack_node::<BlockTime>(&[], b1.get_data(), true, false)?;
+    for (b, mut node) in [(&b0, n1), (&b1, n0)] {
+        if let Node::Leaf { ref mut header, .. } = node {
+            header.block = b.loc;
+        }
+        let mut cursor = std::io::Cursor::new(b.get_data());
+        pack_node(&node, &mut cursor)?;
+        thinp::checksum::write_checksum(b.get_data(), thinp::checksum::BT::NODE)?;
+        engine.write(b)?;
+    }
+    write_file(&md_swapped, &save_engine(engine.as_ref())?)?;
+
+    let merge = |input: &std::path::Path, output: &std::path::Path, extra: &[&str]| {
+        let mut args = args![
+            "-i",
+            input,
+            "-o",
+            output,
+            "--origin",
+            "1",
+            "--snapshot",
+            "2"
+        ]
+        .to_vec();
+        args.extend(extra.iter().map(OsStr::new));
+        thin_merge_cmd(args)
+    };
+
+    // the parent ranges look in order, while the keys aren't
+    run_fail(merge(&md_swapped, &md_out, &[]))?;
+
+    run_ok(merge(&md_in, &md_expected, &[]))?;
+    run_ok(merge(&md_swapped, &md_out, &["--sort-leaves"]))?;
+    let expected = run_ok(thin_dump_cmd(args![&md_expected]))?;
+    let dump = run_ok(thin_dump_cmd(args![&md_out]))?;
+    assert_eq!(expected, dump);
+
+    Ok(())
+}
+
+// A descriptor for the progress events takes them in json without the format
+#[test]
+fn merge_with_report_fd() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let md_out = mk_zeroed_md(&mut td)?;
+
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_out,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--report-fd",
+        "1"
+    ]))?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let events: Vec<&str> = stdout.lines().filter(|l| l.starts_with('{')).collect();
+    assert!(events[0].contains("\"event\":\"phase\",\"phase\":\"merge\""));
+    assert!(events.last().unwrap().contains("\"event\":\"done\""));
+
+    Ok(())
+}
+
+// A dry run needs no output, the metadata space being sized from the input
+#[test]
+fn merge_dry_run_without_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let xml = td.mk_path("meta.xml");
+    mk_default_xml(&xml)?;
+
+    for input in [&md_in, &xml] {
+        let output = run_ok_raw(thin_merge_cmd(args![
+            "-i",
+            input,
+            "--origin",
+            "30",
+            "--snapshot",
+            "40",
+            "--dry-run"
+        ]))?;
+        let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+        assert!(messages.contains("device 30 would have 34 mapped blocks in 3 runs"));
+        assert!(messages.contains("dry run: the output would take"));
+    }
+
+    Ok(())
+}
+
+// Releases the metadata snapshot once anything past the superblocks is read,
+// as the pool could while merging from a live one
+struct ReleasingSnapEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    snap_loc: u64,
+    released: AtomicBool,
+}
+
+impl ReleasingSnapEngine {
+    fn release(&self, b: u64) -> std::io::Result<()> {
+        if b == SUPERBLOCK_LOCATION
+            || b == self.snap_loc
+            || self.released.swap(true, Ordering::SeqCst)
+        {
+            return Ok(());
+        }
+        let mut sb = read_superblock(self.inner.as_ref(), SUPERBLOCK_LOCATION)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        sb.metadata_snap = 0;
+        write_superblock(self.inner.as_ref(), SUPERBLOCK_LOCATION, &sb)
+            .map_err(|e| std::io::Error::other(e.to_string()))
+    }
+}
+
+impl IoEngine for ReleasingSnapEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        1
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        1
+    }
+
+    fn read(&self, b: u64) -> std::io::Result<Block> {
+        self.release(b)?;
+        self.inner.read(b)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> std::io::Result<Vec<std::io::Result<Block>>> {
+        Ok(blocks.iter().map(|&b| self.read(b)).collect())
+    }
+
+    fn write(&self, block: &Block) -> std::io::Result<()> {
+        self.inner.write(block)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> std::io::Result<Vec<std::io::Result<()>>> {
+        self.inner.write_many(blocks)
+    }
+}
+
+// A merge from the metadata snapshot fails if the snapshot is released midway
+#[test]
+fn merge_with_released_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+
+    // a copy of the superblock at the last block stands for the snapshot
+    let engine = load_engine(&std::fs::read(&md_in)?)?;
+    let snap_loc = engine.get_nr_blocks() - 1;
+    let b = engine.read(SUPERBLOCK_LOCATION)?;
+    let snap = Block::new(snap_loc);
+    snap.get_data().copy_from_slice(b.get_data());
+    engine.write(&snap)?;
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    sb.metadata_snap = snap_loc;
+    write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb)?;
+    let image = save_engine(engine.as_ref())?;
+
+    let merge = |released| -> Result<()> {
+        let input = Arc::new(ReleasingSnapEngine {
+            inner: load_engine(&image)?,
+            snap_loc,
+            released: AtomicBool::new(released),
+        });
+        let output = zeroed_engine(input.get_nr_blocks())?;
+        let mut opts = ThinMergeOptions::in_memory(input, output, Arc::new(mk_quiet_report()), 30);
+        opts.snapshot = Some(40);
+        opts.engine_opts.use_metadata_snap = true;
+        merge_thins(opts)
+    };
+
+    // held throughout
+    merge(true)?;
+
+    let err = merge(false).unwrap_err();
+    assert!(err.to_string().contains("the metadata snapshot at block"));
+    assert!(err.to_string().contains("was released during the merge"));
+
+    Ok(())
+}
+
+// The blocks the dry run takes lie between the reported bounds
+#[test]
+fn merge_reports_output_bounds() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+    let output = run_ok_raw(thin_merge_cmd(args![
+        "-i",
+        &md_in,
+        "--origin",
+        "30",
+        "--snapshot",
+        "40",
+        "--dry-run"
+    ]))?;
+    let messages = String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?;
+
+    let number_after = |prefix: &str| -> u64 {
+        let at = messages.find(prefix).unwrap() + prefix.len();
+        let digits: String = messages[at..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        digits.parse().unwrap()
+    };
+    let min = number_after("the output takes ");
+    let max = number_after(&format!("the output takes {} to ", min));
+    let taken = number_after("dry run: the output would take ");
+    assert!(min <= taken && taken <= max);
+
+    Ok(())
+}
+
+// Zero sizes of the pipeline are rejected through the library as well, as
+// nothing would be sent or written
+#[test]
+fn merge_rejects_zero_pipeline_sizes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_metadata(&mut td)?;
+
+    for field in 0..3 {
+        let input = load_engine(&std::fs::read(&md_in)?)?;
+        let output = zeroed_engine(input.get_nr_blocks())?;
+        let mut opts = ThinMergeOptions::in_memory(input, output, Arc::new(mk_quiet_report()), 30);
+        opts.snapshot = Some(40);
+        match field {
+            0 => opts.queue_depth = Some(0),
+            1 => opts.buffer_len = Some(0),
+            _ => opts.write_batch = Some(0),
+        }
+        let err = merge_thins(opts).unwrap_err();
+        assert!(err.to_string().contains("must be at least 1"));
+    }
 
     Ok(())
 }